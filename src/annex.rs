@@ -0,0 +1,229 @@
+use std::borrow::Borrow;
+
+use clap::Subcommand;
+use miniscript::bitcoin::hashes::Hash;
+use miniscript::bitcoin::hex::DisplayHex;
+use miniscript::bitcoin::psbt::raw::ProprietaryKey;
+use miniscript::bitcoin::psbt::Input;
+use miniscript::bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use miniscript::bitcoin::taproot::{LeafVersion, TapLeafHash};
+use miniscript::bitcoin::{
+    secp256k1, Psbt, TapSighashType, Transaction, TxOut, Witness, XOnlyPublicKey,
+};
+use miniscript::psbt::{PsbtExt, PsbtInputSatisfier, SighashError};
+use miniscript::{Miniscript, Tap};
+
+use crate::parse;
+
+/// The prefix of our proprietary PSBT fields.
+pub const PREFIX: &[u8] = b"miniscript-psbt";
+// PSBTs have no standard field for the annex, so it's kept in a proprietary
+// one of ours.
+const SUBTYPE_ANNEX: u8 = 0x00;
+
+#[derive(Subcommand)]
+pub enum AnnexCommand {
+    /// Set the annex a taproot input's signatures commit to and its witness
+    /// ends with.
+    Set {
+        /// The base64 PSBT.
+        #[arg(value_parser = parse::psbt)]
+        psbt: Psbt,
+        /// The index of the input.
+        input: usize,
+        /// The hex encoded annex, starting with 50.
+        // Spelled out so clap takes a single value rather than a list.
+        #[arg(value_parser = parse::annex)]
+        annex: std::vec::Vec<u8>,
+    },
+    /// Remove the annex of a taproot input.
+    Clear {
+        /// The base64 PSBT.
+        #[arg(value_parser = parse::psbt)]
+        psbt: Psbt,
+        /// The index of the input.
+        input: usize,
+    },
+}
+
+pub fn run(command: AnnexCommand) -> Result<(), String> {
+    let psbt = match command {
+        AnnexCommand::Set {
+            mut psbt,
+            input,
+            annex,
+        } => {
+            set(&mut psbt, input, Some(annex))?;
+            psbt
+        }
+        AnnexCommand::Clear { mut psbt, input } => {
+            set(&mut psbt, input, None)?;
+            psbt
+        }
+    };
+    println!("{}", psbt);
+    Ok(())
+}
+
+fn key() -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PREFIX.to_vec(),
+        subtype: SUBTYPE_ANNEX,
+        key: Vec::new(),
+    }
+}
+
+/// The annex set on `input`, if any.
+pub fn get(input: &Input) -> Option<&[u8]> {
+    input
+        .proprietary
+        .get(&key())
+        .map(Vec::as_slice)
+        .filter(|annex| Annex::new(annex).is_ok())
+}
+
+/// Set or, with `None`, remove the annex of taproot input `i`.
+///
+/// Signatures commit to the annex, so inputs already carrying some are
+/// refused rather than having them silently invalidated.
+pub fn set(psbt: &mut Psbt, i: usize, annex: Option<Vec<u8>>) -> Result<(), String> {
+    let input = psbt
+        .inputs
+        .get_mut(i)
+        .ok_or_else(|| format!("The PSBT has no input {}", i))?;
+    match &input.witness_utxo {
+        Some(utxo) if utxo.script_pubkey.is_p2tr() => {}
+        Some(_) => {
+            return Err(format!(
+                "Input {} isn't taproot, only they have an annex",
+                i
+            ))
+        }
+        None => {
+            return Err(format!(
+                "Input {} has no witness UTXO to tell it's taproot",
+                i
+            ))
+        }
+    }
+    if input.final_script_witness.is_some() {
+        return Err(format!("Input {} is already finalized", i));
+    }
+    if input.tap_key_sig.is_some() || !input.tap_script_sigs.is_empty() {
+        return Err(format!(
+            "Input {} is already signed and its signatures commit to the annex",
+            i
+        ));
+    }
+    match annex {
+        Some(annex) => {
+            input.proprietary.insert(key(), annex);
+        }
+        None => {
+            input.proprietary.remove(&key());
+        }
+    }
+    Ok(())
+}
+
+/// The hex encoded annex of `input`, for display.
+pub fn describe(input: &Input) -> Option<String> {
+    get(input).map(|annex| annex.as_hex().to_string())
+}
+
+/// The sighash message of input `i`, committing to its annex if it has one.
+///
+/// Miniscript's sighashes never include an annex, so those of annexed
+/// inputs are computed here instead.
+pub fn sighash_msg<T: Borrow<Transaction>>(
+    psbt: &Psbt,
+    i: usize,
+    cache: &mut SighashCache<T>,
+    leaf: Option<TapLeafHash>,
+) -> Result<secp256k1::Message, SighashError> {
+    let Some(annex) = psbt.inputs.get(i).and_then(get) else {
+        return Ok(psbt.sighash_msg(i, cache, leaf)?.to_secp_msg());
+    };
+    let prevouts: Vec<TxOut> = psbt
+        .inputs
+        .iter()
+        .map(|input| input.witness_utxo.clone())
+        .collect::<Option<_>>()
+        .ok_or(SighashError::MissingSpendUtxos)?;
+    let ty = match psbt.inputs[i].sighash_type {
+        None => TapSighashType::Default,
+        Some(ty) => ty
+            .taproot_hash_ty()
+            .map_err(|_| SighashError::InvalidSighashType)?,
+    };
+    let annex = Annex::new(annex).expect("checked by get");
+    let sighash = cache
+        .taproot_signature_hash(
+            i,
+            &Prevouts::All(&prevouts),
+            Some(annex),
+            leaf.map(|leaf| (leaf, 0xFFFFFFFF)),
+            ty,
+        )
+        .map_err(SighashError::SighashTaproot)?;
+    Ok(secp256k1::Message::from_digest(sighash.to_byte_array()))
+}
+
+/// Finalize the annexed inputs of `psbt`, ending their witness with the
+/// annex.
+///
+/// Miniscript's finalizer checks the witnesses it builds by recomputing
+/// sighashes without the annex, which fails for these inputs, so their
+/// witnesses are put together here and the finalizer leaves them alone.
+/// Their signatures need to have been verified beforehand.
+pub fn finalize(psbt: &mut Psbt) -> Result<(), String> {
+    for i in 0..psbt.inputs.len() {
+        if psbt.inputs[i].final_script_witness.is_some() {
+            continue;
+        }
+        let Some(annex) = get(&psbt.inputs[i]).map(<[u8]>::to_vec) else {
+            continue;
+        };
+        let mut witness = tap_witness(psbt, i).ok_or_else(|| {
+            format!(
+                "Can't finalize input {}: no signatures satisfy its key path or any of its leaves",
+                i
+            )
+        })?;
+        witness.push(annex);
+
+        let input = &mut psbt.inputs[i];
+        *input = Input {
+            witness_utxo: input.witness_utxo.take(),
+            non_witness_utxo: input.non_witness_utxo.take(),
+            final_script_witness: Some(Witness::from_slice(&witness)),
+            proprietary: std::mem::take(&mut input.proprietary),
+            unknown: std::mem::take(&mut input.unknown),
+            ..Default::default()
+        };
+    }
+    Ok(())
+}
+
+// The smallest witness spending taproot input `i`: the key path signature if
+// there is one, otherwise a satisfaction of one of its leaves followed by
+// the leaf's script and control block.
+fn tap_witness(psbt: &Psbt, i: usize) -> Option<Vec<Vec<u8>>> {
+    let input = &psbt.inputs[i];
+    if let Some(sig) = &input.tap_key_sig {
+        return Some(vec![sig.to_vec()]);
+    }
+    let satisfier = PsbtInputSatisfier::new(psbt, i);
+    input
+        .tap_scripts
+        .iter()
+        .filter(|(_, (_, version))| *version == LeafVersion::TapScript)
+        .filter_map(|(control_block, (script, _))| {
+            let ms = Miniscript::<XOnlyPublicKey, Tap>::parse(script).ok()?;
+            let mut witness = ms.satisfy(&satisfier).ok()?;
+            witness.push(script.to_bytes());
+            witness.push(control_block.serialize());
+            Some(witness)
+        })
+        .min_by_key(|witness| witness.iter().map(Vec::len).sum::<usize>())
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::str::FromStr;
+
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::{Address, Transaction};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::Descriptor;
+
+use crate::{annex, build, intent, network, parse};
+
+/// Anything the user may hand us on the command line.
+pub enum Artifact {
+    Psbt(Psbt),
+    Transaction(Transaction),
+    Descriptor(Descriptor<DescriptorPublicKey>),
+}
+
+impl Artifact {
+    fn kind(&self) -> &'static str {
+        match self {
+            Artifact::Psbt(_) => "PSBT",
+            Artifact::Transaction(_) => "raw transaction",
+            Artifact::Descriptor(_) => "descriptor",
+        }
+    }
+}
+
+/// Work out what `s` is by trying every interpretation.
+///
+/// Exactly one interpretation has to succeed, otherwise the error lists
+/// either every candidate (ambiguous input) or why each one was rejected.
+pub fn detect(s: &str) -> Result<Artifact, String> {
+    let s = s.trim();
+    let attempts = [psbt(s), transaction(s), descriptor(s)];
+
+    let mut found = Vec::new();
+    let mut errors = Vec::new();
+    for attempt in attempts {
+        match attempt {
+            Ok(artifact) => found.push(artifact),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    match found.len() {
+        1 => Ok(found.pop().unwrap()),
+        0 => Err(format!(
+            "Can't tell what the input is:\n  {}",
+            errors.join("\n  ")
+        )),
+        _ => Err(format!(
+            "Input is ambiguous, it parses as each of: {}",
+            found
+                .iter()
+                .map(Artifact::kind)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+fn psbt(s: &str) -> Result<Artifact, String> {
+    parse::psbt(s)
+        .map(Artifact::Psbt)
+        .map_err(|e| format!("as a PSBT: {}", e))
+}
+
+fn transaction(s: &str) -> Result<Artifact, String> {
+    parse::transaction(s)
+        .map(Artifact::Transaction)
+        .map_err(|e| format!("as a raw transaction: {}", e))
+}
+
+fn descriptor(s: &str) -> Result<Artifact, String> {
+    Descriptor::<DescriptorPublicKey>::from_str(s)
+        .map(Artifact::Descriptor)
+        .map_err(|e| format!("as a descriptor: {}", e))
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Artifact::Psbt(psbt) => {
+                writeln!(f, "PSBT spending {}", psbt.unsigned_tx.compute_txid())?;
+                if let Some(label) = intent::label(&psbt.proprietary) {
+                    writeln!(f, "  label  {}", label)?;
+                }
+                for (i, (txin, input)) in
+                    psbt.unsigned_tx.input.iter().zip(&psbt.inputs).enumerate()
+                {
+                    let value = build::spent_utxo(psbt, i)
+                        .map(|utxo| format!("{} sat", utxo.value.to_sat()))
+                        .unwrap_or_else(|| "unknown value".to_string());
+                    let state = if input.final_script_witness.is_some()
+                        || input.final_script_sig.is_some()
+                    {
+                        "finalized".to_string()
+                    } else {
+                        format!("{} partial signature(s)", input.partial_sigs.len())
+                    };
+                    writeln!(
+                        f,
+                        "  input  {} ({}, {})",
+                        txin.previous_output, value, state
+                    )?;
+                    if let Some(annex) = annex::describe(input) {
+                        writeln!(f, "         annex {}", annex)?;
+                    }
+                }
+                for (txout, output) in psbt.unsigned_tx.output.iter().zip(&psbt.outputs) {
+                    writeln!(
+                        f,
+                        "  output {} ({} sat)",
+                        display_address(txout),
+                        txout.value.to_sat()
+                    )?;
+                    if let Some(label) = intent::label(&output.proprietary) {
+                        writeln!(f, "         label {}", label)?;
+                    }
+                }
+                Ok(())
+            }
+            Artifact::Transaction(tx) => {
+                writeln!(f, "Transaction {}", tx.compute_txid())?;
+                for txin in &tx.input {
+                    writeln!(f, "  input  {}", txin.previous_output)?;
+                }
+                for txout in &tx.output {
+                    writeln!(
+                        f,
+                        "  output {} ({} sat)",
+                        display_address(txout),
+                        txout.value.to_sat()
+                    )?;
+                }
+                Ok(())
+            }
+            Artifact::Descriptor(descriptor) => {
+                writeln!(f, "Descriptor {}", descriptor)?;
+                let index = if descriptor.has_wildcard() {
+                    " (index 0)"
+                } else {
+                    ""
+                };
+                if let Ok(definite) = descriptor.at_derivation_index(0) {
+                    match definite.address(network()) {
+                        Ok(address) => writeln!(f, "  address {}{}", address, index)?,
+                        Err(_) => writeln!(f, "  script  {:x}{}", definite.script_pubkey(), index)?,
+                    }
+                }
+                if let Ok(weight) = descriptor.max_weight_to_satisfy() {
+                    writeln!(f, "  max satisfaction weight {}", weight.to_wu())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn display_address(txout: &miniscript::bitcoin::TxOut) -> String {
+    Address::from_script(&txout.script_pubkey, network())
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| format!("{:x}", txout.script_pubkey))
+}
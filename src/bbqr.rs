@@ -0,0 +1,355 @@
+use std::path::Path;
+
+use clap::{Args, Subcommand};
+use miniscript::bitcoin::consensus::encode::{deserialize, serialize, serialize_hex};
+use miniscript::bitcoin::hex::{DisplayHex, FromHex};
+use miniscript::bitcoin::Transaction;
+
+use crate::artifact::{self, Artifact};
+use crate::{psbtv2, qr};
+
+/// The largest number of parts the two base36 digits of a header can count.
+const MAX_PARTS: usize = 36 * 36 - 1;
+/// The length of a part's header, `B$`, the encoding, the file type, the
+/// number of parts and the part's index.
+const HEADER_LEN: usize = 8;
+/// The window BBQr compresses with, so decoders only need 1 KiB for it.
+const WINDOW: usize = 1024;
+
+#[derive(Subcommand)]
+pub enum BbqrCommand {
+    /// Split a PSBT, raw transaction or descriptor into BBQr parts, printed
+    /// one per line for showing as a sequence of QR codes.
+    Encode(EncodeArgs),
+    /// Join BBQr parts back into the PSBT, transaction or text they encode.
+    Decode {
+        /// The parts, as their text or images of their QR codes, in any
+        /// order.
+        #[arg(required = true)]
+        parts: Vec<String>,
+    },
+}
+
+#[derive(Args)]
+pub struct EncodeArgs {
+    /// The base64 PSBT, hex transaction or descriptor to encode.
+    input: String,
+    /// The most characters a part's QR code may hold, header included;
+    /// smaller parts scan more reliably but take more of them.
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(16..=4296))]
+    part_size: u64,
+    /// Split into this many parts instead, as evenly as possible.
+    #[arg(long, conflicts_with = "part_size", value_parser = clap::value_parser!(u64).range(1..=MAX_PARTS as u64))]
+    parts: Option<u64>,
+    /// Don't compress, for scanners that can't decompress.
+    #[arg(long)]
+    no_compress: bool,
+}
+
+pub fn run(command: BbqrCommand) -> Result<(), String> {
+    match command {
+        BbqrCommand::Encode(args) => {
+            let (file_type, data) = match artifact::detect(&args.input)? {
+                Artifact::Psbt(psbt) => ('P', psbt.serialize()),
+                Artifact::Transaction(tx) => ('T', serialize(&tx)),
+                Artifact::Descriptor(descriptor) => ('U', descriptor.to_string().into_bytes()),
+            };
+            let parts = encode(
+                file_type,
+                &data,
+                !args.no_compress,
+                args.part_size as usize,
+                args.parts.map(|n| n as usize),
+            )?;
+            for part in parts {
+                println!("{}", part);
+            }
+            Ok(())
+        }
+        BbqrCommand::Decode { parts } => {
+            let mut texts = Vec::new();
+            for part in parts {
+                if part.starts_with("B$") {
+                    texts.push(part);
+                } else {
+                    texts.extend(qr::decode_image(Path::new(&part))?);
+                }
+            }
+            let (file_type, data) = decode(&texts)?;
+            match file_type {
+                'P' => println!(
+                    "{}",
+                    psbtv2::deserialize_any(&data).map_err(|e| format!("Invalid PSBT: {}", e))?
+                ),
+                'T' => {
+                    let tx: Transaction =
+                        deserialize(&data).map_err(|e| format!("Invalid transaction: {}", e))?;
+                    println!("{}", serialize_hex(&tx));
+                }
+                'U' | 'J' => println!(
+                    "{}",
+                    String::from_utf8(data).map_err(|e| format!("Invalid text: {}", e))?
+                ),
+                _ => println!("{}", data.as_hex()),
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Split `data` of BBQr `file_type` into parts of at most `part_size`
+/// characters, or into `parts` parts if given, compressing it if that makes
+/// it smaller and `compress` allows.
+pub fn encode(
+    file_type: char,
+    data: &[u8],
+    compress: bool,
+    part_size: usize,
+    parts: Option<usize>,
+) -> Result<Vec<String>, String> {
+    let compressed = compress.then(|| deflate(data));
+    let (encoding, encoded) = match compressed {
+        Some(compressed) if compressed.len() < data.len() => ('Z', base32_encode(&compressed)),
+        _ => ('2', base32_encode(data)),
+    };
+    // Base32 parts hold whole groups of 8 characters, so each decodes to
+    // whole bytes on its own.
+    let chunk = match parts {
+        Some(parts) => encoded.len().div_ceil(parts).div_ceil(8) * 8,
+        None => (part_size - HEADER_LEN) / 8 * 8,
+    }
+    .max(8);
+    let chunks: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(chunk)
+        .map(|chunk| std::str::from_utf8(chunk).expect("base32 is ASCII"))
+        .collect();
+    if chunks.len() > MAX_PARTS {
+        return Err(format!(
+            "{} parts are needed but BBQr allows at most {}, raise --part-size",
+            chunks.len(),
+            MAX_PARTS
+        ));
+    }
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            format!(
+                "B${}{}{}{}{}",
+                encoding,
+                file_type,
+                base36(chunks.len()),
+                base36(i),
+                chunk
+            )
+        })
+        .collect())
+}
+
+/// Join BBQr `parts`, given in any order, returning the file type and data
+/// they encode.
+pub fn decode(parts: &[String]) -> Result<(char, Vec<u8>), String> {
+    let mut header = None;
+    let mut chunks: Vec<Option<&str>> = Vec::new();
+    for part in parts {
+        let part = part.trim();
+        if part.len() < HEADER_LEN || !part.starts_with("B$") || !part.is_ascii() {
+            return Err(format!("Not a BBQr part: {}", part));
+        }
+        let (encoding, file_type) = (part.as_bytes()[2] as char, part.as_bytes()[3] as char);
+        let count = usize::from_str_radix(&part[4..6], 36)
+            .map_err(|_| format!("Invalid part count in {}", &part[..HEADER_LEN]))?;
+        let index = usize::from_str_radix(&part[6..8], 36)
+            .map_err(|_| format!("Invalid part index in {}", &part[..HEADER_LEN]))?;
+        match header {
+            None => {
+                header = Some((encoding, file_type, count));
+                chunks = vec![None; count];
+            }
+            Some(first) if first != (encoding, file_type, count) => {
+                return Err(format!(
+                    "Part {} belongs to a different BBQr sequence than the others",
+                    &part[..HEADER_LEN]
+                ))
+            }
+            Some(_) => {}
+        }
+        let slot = chunks
+            .get_mut(index)
+            .ok_or_else(|| format!("Part {} is out of range of {}", index, count))?;
+        *slot = Some(&part[HEADER_LEN..]);
+    }
+    let (encoding, file_type, _) = header.ok_or("No BBQr parts given")?;
+    let missing: Vec<String> = chunks
+        .iter()
+        .enumerate()
+        .filter(|(_, chunk)| chunk.is_none())
+        .map(|(i, _)| i.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("Missing parts {}", missing.join(", ")));
+    }
+    let encoded: String = chunks.into_iter().flatten().collect();
+    let data = match encoding {
+        'H' => Vec::from_hex(&encoded).map_err(|e| format!("Invalid hex: {}", e))?,
+        '2' => base32_decode(&encoded)?,
+        'Z' => miniz_oxide::inflate::decompress_to_vec(&base32_decode(&encoded)?)
+            .map_err(|e| format!("Can't decompress the parts: {:?}", e.status))?,
+        _ => return Err(format!("Unknown BBQr encoding {}", encoding)),
+    };
+    Ok((file_type, data))
+}
+
+// Two digit, upper case base36.
+fn base36(n: usize) -> String {
+    const DIGITS: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    format!("{}{}", DIGITS[n / 36 % 36] as char, DIGITS[n % 36] as char)
+}
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// RFC 4648 base32 without padding.
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = buffer << 8 | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE32
+            .iter()
+            .position(|&b| b == c)
+            .ok_or_else(|| format!("Invalid base32 character {:?}", c as char))?;
+        buffer = buffer << 5 | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+// Raw DEFLATE with the fixed Huffman codes, only referring back `WINDOW`
+// bytes as BBQr requires, which general purpose compressors can't be told
+// to do.
+fn deflate(data: &[u8]) -> Vec<u8> {
+    const LENGTH_BASE: [u16; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+        131, 163, 195, 227, 258,
+    ];
+    const LENGTH_EXTRA: [u8; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+    ];
+    const DISTANCE_BASE: [u16; 20] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769,
+    ];
+    const DISTANCE_EXTRA: [u8; 20] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8];
+
+    let mut out = BitWriter::default();
+    // A single, final block with fixed codes.
+    out.bits(1, 1);
+    out.bits(1, 2);
+    let mut i = 0;
+    while i < data.len() {
+        let (mut length, mut distance) = (0, 0);
+        for start in (i.saturating_sub(WINDOW)..i).rev() {
+            let n = data[start..]
+                .iter()
+                .zip(&data[i..])
+                .take(258)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if n > length {
+                (length, distance) = (n, i - start);
+                if n == 258 {
+                    break;
+                }
+            }
+        }
+        if length < 3 {
+            out.literal(data[i] as u16);
+            i += 1;
+            continue;
+        }
+        let code = LENGTH_BASE
+            .iter()
+            .rposition(|&b| b as usize <= length)
+            .unwrap();
+        out.literal(257 + code as u16);
+        out.bits(
+            (length - LENGTH_BASE[code] as usize) as u32,
+            LENGTH_EXTRA[code],
+        );
+        let code = DISTANCE_BASE
+            .iter()
+            .rposition(|&b| b as usize <= distance)
+            .unwrap();
+        out.huffman(code as u32, 5);
+        out.bits(
+            (distance - DISTANCE_BASE[code] as usize) as u32,
+            DISTANCE_EXTRA[code],
+        );
+        i += length;
+    }
+    out.literal(256);
+    out.finish()
+}
+
+// Packs DEFLATE's bit stream, least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, len: u8) {
+        self.buffer |= value << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first.
+    fn huffman(&mut self, code: u32, len: u8) {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    // A literal, length or end of block symbol with its fixed code.
+    fn literal(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.huffman(0x30 + symbol, 8),
+            144..=255 => self.huffman(0x190 + symbol - 144, 9),
+            256..=279 => self.huffman(symbol - 256, 7),
+            _ => self.huffman(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
@@ -0,0 +1,173 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use miniscript::bitcoin::absolute::LockTime;
+use miniscript::bitcoin::hashes::{sha256, Hash};
+use miniscript::bitcoin::psbt::{self, Psbt};
+use miniscript::bitcoin::sighash::SighashCache;
+use miniscript::bitcoin::transaction::Version;
+use miniscript::bitcoin::{
+    self, secp256k1, Amount, Network, OutPoint, PrivateKey, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut,
+};
+use miniscript::descriptor::DefiniteDescriptorKey;
+use miniscript::psbt::{PsbtExt, PsbtInputExt};
+use miniscript::Descriptor;
+
+use crate::sign;
+
+/// Value of every synthetic UTXO spent by the benchmark PSBT.
+const UTXO_VALUE: Amount = Amount::from_sat(100_000);
+
+#[derive(Args)]
+pub struct BenchArgs {
+    /// Number of inputs in the synthetic PSBT.
+    #[arg(long, default_value_t = 100)]
+    inputs: u32,
+    /// Number of times the whole pipeline is repeated.
+    #[arg(long, default_value_t = 5)]
+    rounds: u32,
+}
+
+#[derive(Default)]
+struct Timings {
+    sighash: Duration,
+    sign: Duration,
+    verify: Duration,
+    finalize: Duration,
+}
+
+pub fn run(args: BenchArgs) {
+    let secp = secp256k1::Secp256k1::new();
+
+    // Deterministic keys keep runs comparable across machines.
+    let keys: Vec<PrivateKey> = (0..2u8).map(bench_key).collect();
+    let pubkeys: Vec<bitcoin::PublicKey> = keys.iter().map(|k| k.public_key(&secp)).collect();
+
+    let descriptor = Descriptor::<DefiniteDescriptorKey>::from_str(&format!(
+        "wsh(multi(2,{},{}))",
+        pubkeys[0], pubkeys[1]
+    ))
+    .unwrap();
+    let psbt = synthetic_psbt(&descriptor, args.inputs);
+
+    println!(
+        "Benchmarking {} rounds of a {}-input {} PSBT",
+        args.rounds, args.inputs, descriptor
+    );
+
+    let mut timings = Timings::default();
+    let mut msgs = Vec::with_capacity(psbt.inputs.len());
+    for _ in 0..args.rounds {
+        let start = Instant::now();
+        let mut sighash_cache = SighashCache::new(&psbt.unsigned_tx);
+        sign::sighash_msgs(&psbt, &mut sighash_cache, &mut msgs).unwrap();
+        timings.sighash += start.elapsed();
+
+        let start = Instant::now();
+        let sigs: Vec<Vec<secp256k1::ecdsa::Signature>> = msgs
+            .iter()
+            .map(|msg| {
+                keys.iter()
+                    .map(|k| secp.sign_ecdsa(msg, &k.inner))
+                    .collect()
+            })
+            .collect();
+        timings.sign += start.elapsed();
+
+        let start = Instant::now();
+        for (msg, input_sigs) in msgs.iter().zip(&sigs) {
+            for (sig, pk) in input_sigs.iter().zip(&pubkeys) {
+                secp.verify_ecdsa(msg, sig, &pk.inner)
+                    .expect("Benchmark signature doesn't verify");
+            }
+        }
+        timings.verify += start.elapsed();
+
+        let mut signed = psbt.clone();
+        for (input, input_sigs) in signed.inputs.iter_mut().zip(&sigs) {
+            for (sig, pk) in input_sigs.iter().zip(&pubkeys) {
+                input.partial_sigs.insert(
+                    *pk,
+                    bitcoin::ecdsa::Signature {
+                        signature: *sig,
+                        sighash_type: bitcoin::EcdsaSighashType::All,
+                    },
+                );
+            }
+        }
+        let start = Instant::now();
+        signed
+            .finalize_mut(&secp)
+            .expect("Benchmark PSBT doesn't finalize");
+        timings.finalize += start.elapsed();
+    }
+
+    let per_input = u64::from(args.inputs) * u64::from(args.rounds);
+    let per_sig = per_input * keys.len() as u64;
+    report("sighash", per_input, timings.sighash);
+    report("sign", per_sig, timings.sign);
+    report("verify", per_sig, timings.verify);
+    report("finalize", per_input, timings.finalize);
+}
+
+fn bench_key(i: u8) -> PrivateKey {
+    let secret = sha256::Hash::hash(&[b'b', b'e', b'n', b'c', b'h', i]);
+    PrivateKey::new(
+        secp256k1::SecretKey::from_slice(&secret.to_byte_array()).unwrap(),
+        Network::Regtest,
+    )
+}
+
+// Build a PSBT spending `inputs` outputs of a single fake funding transaction.
+fn synthetic_psbt(descriptor: &Descriptor<DefiniteDescriptorKey>, inputs: u32) -> Psbt {
+    let funding_tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn::default()],
+        output: (0..inputs)
+            .map(|_| TxOut {
+                value: UTXO_VALUE,
+                script_pubkey: descriptor.script_pubkey(),
+            })
+            .collect(),
+    };
+    let txid = funding_tx.compute_txid();
+
+    let spend_tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: (0..inputs)
+            .map(|vout| TxIn {
+                previous_output: OutPoint::new(txid, vout),
+                sequence: Sequence::MAX,
+                ..Default::default()
+            })
+            .collect(),
+        output: vec![TxOut {
+            value: UTXO_VALUE * u64::from(inputs) / 2,
+            script_pubkey: ScriptBuf::new_op_return([]),
+        }],
+    };
+
+    let mut psbt = Psbt::from_unsigned_tx(spend_tx).unwrap();
+    for (input, utxo) in psbt.inputs.iter_mut().zip(funding_tx.output) {
+        input.update_with_descriptor_unchecked(descriptor).unwrap();
+        input.witness_utxo = Some(utxo);
+    }
+    psbt.outputs = vec![psbt::Output::default()];
+    psbt
+}
+
+fn report(stage: &str, ops: u64, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { ops as f64 / secs } else { 0.0 };
+    println!(
+        "{:<10} {:>8} ops {:>10.2} ms {:>12.0} ops/s",
+        stage,
+        ops,
+        secs * 1000.0,
+        rate
+    );
+}
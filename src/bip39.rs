@@ -0,0 +1,200 @@
+use clap::Args;
+use miniscript::bitcoin::bip32::Xpriv;
+use miniscript::bitcoin::hashes::{hmac, sha256, sha512, Hash, HashEngine};
+
+use crate::{network, secret};
+
+/// The BIP39 English wordlist, one word per line in index order.
+const WORDLIST: &str = include_str!("bip39_wordlist.txt");
+/// The PBKDF2 rounds stretching a mnemonic into its seed.
+const SEED_ITERATIONS: u32 = 2048;
+
+/// A master key given as a BIP39 mnemonic.
+#[derive(Args)]
+pub struct MnemonicArgs {
+    /// Derive the master key from this BIP39 mnemonic to sign for the
+    /// inputs' BIP32 derivations, as the descriptor's key origins give
+    /// them: `file:<PATH>`, `env:<VAR>` or `-` to be prompted for it.
+    #[arg(long, value_name = "WORDS", value_parser = secret::parser(secret::string))]
+    mnemonic: Option<String>,
+    /// The mnemonic's passphrase, given the same ways.
+    #[arg(long, requires = "mnemonic", value_parser = secret::parser(secret::string))]
+    passphrase: Option<String>,
+}
+
+impl MnemonicArgs {
+    /// The master key of the mnemonic, if one was given.
+    pub fn master(&self) -> Result<Option<Xpriv>, String> {
+        let Some(mnemonic) = &self.mnemonic else {
+            return Ok(None);
+        };
+        let seed = seed(mnemonic, self.passphrase.as_deref().unwrap_or_default())?;
+        Xpriv::new_master(network(), &seed)
+            .map(Some)
+            .map_err(|e| format!("Can't derive the master key: {}", e))
+    }
+}
+
+/// Encode `entropy` as a BIP39 mnemonic, its checksum appended.
+pub fn mnemonic(entropy: &[u8]) -> String {
+    let words: Vec<&str> = WORDLIST.lines().collect();
+    let checksum = sha256::Hash::hash(entropy).to_byte_array()[0];
+    let mut bits: Vec<bool> = entropy
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+        .collect();
+    bits.extend((0..entropy.len() * 8 / 32).map(|i| checksum >> (7 - i) & 1 == 1));
+    bits.chunks(11)
+        .map(|chunk| words[chunk.iter().fold(0, |acc, &bit| acc << 1 | bit as usize)])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The 64 byte seed of the mnemonic `words` and `passphrase`, after
+/// checking the words and the checksum.
+pub fn seed(words: &str, passphrase: &str) -> Result<[u8; 64], String> {
+    let words: Vec<String> = words.split_whitespace().map(str::to_lowercase).collect();
+    if ![12, 15, 18, 21, 24].contains(&words.len()) {
+        return Err(format!(
+            "A mnemonic has 12, 15, 18, 21 or 24 words, not {}",
+            words.len()
+        ));
+    }
+    let wordlist: Vec<&str> = WORDLIST.lines().collect();
+    let mut bits = Vec::new();
+    for word in &words {
+        let index = wordlist
+            .iter()
+            .position(|w| w == word)
+            .ok_or_else(|| format!("\"{}\" isn't a BIP39 word", word))?;
+        bits.extend((0..11).rev().map(|i| index >> i & 1 == 1));
+    }
+    let entropy: Vec<u8> = bits[..words.len() * 32 / 3]
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+        .collect();
+    if mnemonic(&entropy) != words.join(" ") {
+        return Err("The mnemonic's checksum doesn't match, a word is wrong".to_string());
+    }
+    // BIP39 normalizes both to NFKD, which leaves ASCII as it is.
+    if !passphrase.is_ascii() {
+        return Err("Only ASCII passphrases are supported".to_string());
+    }
+    let salt = format!("mnemonic{}", passphrase);
+    Ok(pbkdf2_sha512(words.join(" ").as_bytes(), salt.as_bytes()))
+}
+
+// PBKDF2 with HMAC-SHA512, for the one block of a seed.
+fn pbkdf2_sha512(password: &[u8], salt: &[u8]) -> [u8; 64] {
+    let keyed = hmac::HmacEngine::<sha512::Hash>::new(password);
+    let mut engine = keyed.clone();
+    engine.input(salt);
+    engine.input(&1u32.to_be_bytes());
+    let mut u = hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+    let mut result = u;
+    for _ in 1..SEED_ITERATIONS {
+        let mut engine = keyed.clone();
+        engine.input(&u);
+        u = hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+        for (r, u) in result.iter_mut().zip(&u) {
+            *r ^= u;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use miniscript::bitcoin::hex::{DisplayHex, FromHex};
+
+    use super::*;
+
+    // The BIP39 reference vectors: entropy, mnemonic and the seed with the
+    // passphrase "TREZOR".
+    const VECTORS: [(&str, &str, &str); 9] = [
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e5349553\
+             1f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6f\
+             a457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "80808080808080808080808080808080",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+            "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30\
+             fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13\
+             332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+        ),
+        (
+            "000000000000000000000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon abandon abandon abandon abandon agent",
+            "035895f2f481b1b0f01fcf8c289c794660b289981a78f8106447707fdd9666ca\
+             06da5a9a565181599b79f53b844d8a71dd9f439c52a3d7b3e8a79c906ac845fa",
+        ),
+        (
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon abandon abandon art",
+            "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd30971\
+             70af7a4d73245cafa9c3cca8d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo \
+             zoo zoo vote",
+            "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e16\
+             13912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad",
+        ),
+        (
+            "9e885d952ad362caeb4efe34a8e91bd2",
+            "ozone drill grab fiber curtain grace pudding thank cruise elder eight picnic",
+            "274ddc525802f7c828d8ef7ddbcdc5304e87ac3535913611fbbfa986d0c9e547\
+             6c91689f9c8a54fd55bd38606aa6a8595ad213d4c9c9f9aca3fb217069a41028",
+        ),
+        (
+            "68a79eaca2324873eacc50cb9c6eca8cc68ea5d936f98787c60c7ebc74e6ce7c",
+            "hamster diagram private dutch cause delay private meat slide toddler razor book \
+             happy fancy gospel tennis maple dilemma loan word shrug inflict delay length",
+            "64c87cde7e12ecf6704ab95bb1408bef047c22db4cc7491c4271d170a1b213d2\
+             0b385bc1588d9c7b38f1b39d415665b8a9030c9ec653d75e65f847d8fc1fc440",
+        ),
+    ];
+
+    #[test]
+    fn entropy_encodes_to_mnemonic() {
+        for (entropy, words, _) in VECTORS {
+            assert_eq!(mnemonic(&Vec::<u8>::from_hex(entropy).unwrap()), words);
+        }
+    }
+
+    #[test]
+    fn mnemonic_stretches_to_seed() {
+        for (_, words, expected) in VECTORS {
+            assert_eq!(
+                seed(words, "TREZOR").unwrap().to_lower_hex_string(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+                     abandon abandon abandon";
+        assert!(seed(words, "TREZOR").is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use std::str::FromStr;
+
+use clap::{Args, Subcommand};
+use miniscript::bitcoin::bip32::{ChainCode, ChildNumber, Fingerprint, Xpriv, Xpub};
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::consensus::serialize;
+use miniscript::bitcoin::hashes::{sha256, sha512, Hash, HashEngine, Hmac, HmacEngine};
+use miniscript::bitcoin::script::PushBytes;
+use miniscript::bitcoin::secp256k1::{self, PublicKey, Scalar, SecretKey};
+use miniscript::bitcoin::{
+    base58, Address, Amount, CompressedPublicKey, EcdsaSighashType, NetworkKind, OutPoint,
+    PrivateKey, ScriptBuf, Transaction, TxOut,
+};
+
+use crate::wallet::{self, State};
+use crate::{build, history, network, parse, policy, secret, sign, utxo};
+
+/// The version byte prefixed to serialized payment codes, making them start
+/// with "PM8T".
+const PREFIX: u8 = 0x47;
+/// The length of a payment code's payload.
+const PAYLOAD_LEN: usize = 80;
+
+/// A version 1 BIP47 reusable payment code.
+#[derive(Clone)]
+pub struct PaymentCode {
+    public_key: PublicKey,
+    chain_code: ChainCode,
+}
+
+impl PaymentCode {
+    /// The payment code of the BIP47 account whose extended private key,
+    /// derived at m/47'/coin'/account', is `account`.
+    pub fn from_account<C: secp256k1::Signing>(
+        secp: &secp256k1::Secp256k1<C>,
+        account: &Xpriv,
+    ) -> PaymentCode {
+        let xpub = Xpub::from_priv(secp, account);
+        PaymentCode {
+            public_key: xpub.public_key,
+            chain_code: xpub.chain_code,
+        }
+    }
+
+    /// The 80 byte payload, as embedded in notification transactions.
+    pub fn payload(&self) -> [u8; PAYLOAD_LEN] {
+        let mut payload = [0; PAYLOAD_LEN];
+        payload[0] = 1;
+        payload[2..35].copy_from_slice(&self.public_key.serialize());
+        payload[35..67].copy_from_slice(self.chain_code.as_bytes());
+        payload
+    }
+
+    /// The public key at index `index` of the payment code.
+    pub fn derive<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+        index: u32,
+    ) -> Result<PublicKey, String> {
+        let xpub = Xpub {
+            network: NetworkKind::Main,
+            depth: 3,
+            parent_fingerprint: Fingerprint::default(),
+            child_number: ChildNumber::from_hardened_idx(0).unwrap(),
+            public_key: self.public_key,
+            chain_code: self.chain_code,
+        };
+        let child = ChildNumber::from_normal_idx(index)
+            .map_err(|e| format!("Invalid payment code index {}: {}", index, e))?;
+        xpub.derive_pub(secp, &[child])
+            .map(|xpub| xpub.public_key)
+            .map_err(|e| format!("Can't derive index {} of the payment code: {}", index, e))
+    }
+
+    /// The key whose P2PKH address receives notification transactions.
+    pub fn notification_key<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Result<CompressedPublicKey, String> {
+        self.derive(secp, 0).map(CompressedPublicKey)
+    }
+}
+
+impl fmt::Display for PaymentCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data = vec![PREFIX];
+        data.extend_from_slice(&self.payload());
+        f.write_str(&base58::encode_check(&data))
+    }
+}
+
+impl FromStr for PaymentCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let data = base58::decode_check(s).map_err(|e| format!("not a payment code: {}", e))?;
+        if data.len() != PAYLOAD_LEN + 1 || data[0] != PREFIX {
+            return Err("not a payment code".to_string());
+        }
+        let payload = &data[1..];
+        if payload[0] != 1 {
+            return Err(format!(
+                "payment code version {} isn't supported, only version 1 is",
+                payload[0]
+            ));
+        }
+        let public_key = PublicKey::from_slice(&payload[2..35])
+            .map_err(|e| format!("payment code has an invalid public key: {}", e))?;
+        let chain_code = ChainCode::from(<[u8; 32]>::try_from(&payload[35..67]).unwrap());
+        Ok(PaymentCode {
+            public_key,
+            chain_code,
+        })
+    }
+}
+
+/// Blind `ours` for the notification transaction sending it to `theirs`,
+/// whose designated input spends `outpoint` with `input_key`.
+pub fn blinded_payload<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    ours: &PaymentCode,
+    theirs: &PaymentCode,
+    outpoint: &OutPoint,
+    input_key: &SecretKey,
+) -> Result<[u8; PAYLOAD_LEN], String> {
+    let secret = shared_x(secp, input_key, &theirs.notification_key(secp)?.0)?;
+    let mut engine = HmacEngine::<sha512::Hash>::new(&serialize(outpoint));
+    engine.input(&secret);
+    let mask = Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+
+    let mut payload = ours.payload();
+    for (byte, mask) in payload[3..67].iter_mut().zip(mask) {
+        *byte ^= mask;
+    }
+    Ok(payload)
+}
+
+/// The key of the `index`th payment to `theirs` from the account whose
+/// extended private key is `account`.
+pub fn payment_key<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    account: &Xpriv,
+    theirs: &PaymentCode,
+    index: u32,
+) -> Result<CompressedPublicKey, String> {
+    let ours = account
+        .derive_priv(secp, &[ChildNumber::from_normal_idx(0).unwrap()])
+        .map_err(|e| format!("Can't derive the account's first key: {}", e))?;
+    let theirs = theirs.derive(secp, index)?;
+    let secret = shared_x(secp, &ours.private_key, &theirs)?;
+    let tweak = Scalar::from_be_bytes(sha256::Hash::hash(&secret).to_byte_array())
+        .map_err(|_| format!("Payment {} has an invalid shared secret, skip it", index))?;
+    theirs
+        .add_exp_tweak(secp, &tweak)
+        .map(CompressedPublicKey)
+        .map_err(|e| format!("Can't derive the key of payment {}: {}", index, e))
+}
+
+// The x coordinate of the point shared between `secret` and `public`.
+fn shared_x<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    secret: &SecretKey,
+    public: &PublicKey,
+) -> Result<[u8; 32], String> {
+    let point = public
+        .mul_tweak(secp, &Scalar::from(*secret))
+        .map_err(|e| format!("Can't compute the shared secret: {}", e))?;
+    Ok(point.serialize()[1..].try_into().unwrap())
+}
+
+#[derive(Subcommand)]
+pub enum Bip47Command {
+    /// Print the payment code of a BIP47 account and its notification
+    /// address.
+    Code {
+        /// The account's extended private key, derived at m/47'/coin'/account'.
+        #[arg(value_parser = secret::parser(parse::xpriv))]
+        account: Xpriv,
+    },
+    /// Build and sign the transaction notifying a payment code of ours,
+    /// which has to be sent before paying it.
+    Notify(NotifyArgs),
+    /// Derive the next address to pay a payment code at.
+    NextAddress(NextAddressArgs),
+}
+
+#[derive(Args)]
+pub struct NotifyArgs {
+    /// The wallet paying the payment code.
+    wallet: String,
+    /// The payment code to notify, or a contact saved with it.
+    #[arg(value_parser = parse::payment_code)]
+    payment_code: PaymentCode,
+    /// Our BIP47 account's extended private key.
+    #[arg(long, value_parser = secret::parser(parse::xpriv))]
+    account: Xpriv,
+    /// The transaction funding the notification.
+    #[arg(long, value_parser = parse::transaction)]
+    funding: Transaction,
+    /// The private key of the funding output, which has to pay to its
+    /// P2WPKH address so the recipient can find its public key.
+    #[arg(long, value_parser = secret::parser(parse::private_key))]
+    key: PrivateKey,
+    /// The funding output to spend, if the key is paid more than once.
+    #[arg(long, value_parser = parse::outpoint)]
+    outpoint: Option<OutPoint>,
+    /// Where to send what's left after the notification and fee.
+    #[arg(long, value_parser = parse::recipient)]
+    change: Option<ScriptBuf>,
+    /// The fee to pay.
+    #[arg(long, value_parser = parse::amount, default_value = "500")]
+    fee: Amount,
+    /// How long to lock the funding output against other commands spending
+    /// it, e.g. until the notification confirms.
+    #[arg(long, value_parser = parse::duration, default_value = "1h")]
+    lock_for: u64,
+}
+
+#[derive(Args)]
+pub struct NextAddressArgs {
+    /// The wallet paying the payment code.
+    wallet: String,
+    /// The payment code to pay, or a contact saved with it.
+    #[arg(value_parser = parse::payment_code)]
+    payment_code: PaymentCode,
+    /// Our BIP47 account's extended private key.
+    #[arg(long, value_parser = secret::parser(parse::xpriv))]
+    account: Xpriv,
+}
+
+pub fn run(command: Bip47Command) -> Result<(), String> {
+    let secp = secp256k1::Secp256k1::new();
+    match command {
+        Bip47Command::Code { account } => {
+            let code = PaymentCode::from_account(&secp, &account);
+            println!("Payment code: {}", code);
+            println!(
+                "Notification address: {}",
+                Address::p2pkh(code.notification_key(&secp)?, network())
+            );
+            Ok(())
+        }
+        Bip47Command::Notify(args) => notify(&secp, args),
+        Bip47Command::NextAddress(args) => next_address(&secp, args),
+    }
+}
+
+fn notify(secp: &secp256k1::Secp256k1<secp256k1::All>, args: NotifyArgs) -> Result<(), String> {
+    let pubkey = args.key.public_key(secp);
+    let descriptor = parse::descriptor(&format!("wpkh({})", pubkey))?;
+    let (outpoint, utxo) = utxo::find(&args.funding, args.outpoint, &descriptor)?;
+
+    let ours = PaymentCode::from_account(secp, &args.account);
+    let payload = blinded_payload(secp, &ours, &args.payment_code, &outpoint, &args.key.inner)?;
+    let notification =
+        ScriptBuf::new_p2pkh(&args.payment_code.notification_key(secp)?.pubkey_hash());
+    let amount = notification.minimal_non_dust();
+    let mut outputs = vec![
+        TxOut {
+            script_pubkey: notification,
+            value: amount,
+        },
+        TxOut {
+            script_pubkey: ScriptBuf::new_op_return(<&PushBytes>::try_from(&payload[..]).unwrap()),
+            value: Amount::ZERO,
+        },
+    ];
+    let utxo_value = utxo.value;
+    let split = build::split(utxo.value, amount, args.fee, false)?;
+    if split.change > Amount::ZERO {
+        let change = args.change.ok_or_else(|| {
+            format!(
+                "{} sat of the input would be left unaccounted for, pass --change or raise --fee",
+                split.change.to_sat()
+            )
+        })?;
+        outputs.push(TxOut {
+            script_pubkey: change,
+            value: split.change,
+        });
+    }
+
+    let mut psbt = build::create_psbt(&[outpoint], outputs);
+    build::update_input(
+        &mut psbt.inputs[0],
+        &descriptor,
+        utxo,
+        Some(args.funding.clone()),
+    )?;
+    sign::check_utxos(&psbt, std::slice::from_ref(&args.funding))?;
+    policy::check_standard(&psbt, &[&descriptor])?;
+    wallet::lock_coins(&args.wallet, &[outpoint], args.lock_for)?;
+    sign::sign_inputs(
+        secp,
+        &mut psbt,
+        &[args.key],
+        EcdsaSighashType::All,
+        |_, _, _| {},
+    )
+    .map_err(|e| format!("Can't compute the sighash: {}", e))?;
+    build::finalize(secp, &mut psbt)?;
+    let tx = psbt.extract_tx_unchecked_fee_rate();
+
+    let mut state = State::load(&args.wallet)?;
+    state.contact(&args.payment_code.to_string()).notification =
+        Some(tx.compute_txid().to_string());
+    state.save(&args.wallet)?;
+    let label = Some(format!("BIP47 notification of {}", args.payment_code));
+    if let Err(e) = history::record(&args.wallet, &tx, utxo_value, label, false) {
+        eprintln!(
+            "Can't record the notification in the wallet's history: {}",
+            e
+        );
+    }
+    println!("Notification transaction {}", tx.compute_txid());
+    println!("raw: {}", serialize_hex(&tx));
+    Ok(())
+}
+
+fn next_address(
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+    args: NextAddressArgs,
+) -> Result<(), String> {
+    let code = args.payment_code.to_string();
+    let mut state = State::load(&args.wallet)?;
+    let contact = state.contact(&code);
+    if contact.notification.is_none() {
+        return Err(format!(
+            "{} hasn't been notified of our payment code yet, send the transaction from \
+             \"bip47 notify\" first",
+            code
+        ));
+    }
+    // A shared secret outside the curve order is skipped, so is its index.
+    let (index, key) = loop {
+        let index = contact.next_index;
+        contact.next_index += 1;
+        match payment_key(secp, &args.account, &args.payment_code, index) {
+            Ok(key) => break (index, key),
+            Err(e) => eprintln!("{}", e),
+        }
+    };
+    state.save(&args.wallet)?;
+    println!(
+        "Payment {} to {}: {}",
+        index,
+        code,
+        Address::p2pkh(key, network())
+    );
+    Ok(())
+}
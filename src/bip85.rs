@@ -0,0 +1,223 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Args, Subcommand, ValueEnum};
+use miniscript::bitcoin::bip32::{ChainCode, ChildNumber, DerivationPath, Xpriv};
+use miniscript::bitcoin::hashes::{hmac, sha512, Hash, HashEngine};
+use miniscript::bitcoin::{secp256k1, PrivateKey};
+
+use crate::{bip39, network, parse, secret, slip39};
+
+/// The purpose every BIP85 derivation path starts with, "DRNG" on a phone
+/// keypad.
+const PURPOSE: u32 = 83696968;
+
+#[derive(Subcommand)]
+pub enum Bip85Command {
+    /// Derive a child mnemonic, WIF or extended key from a master key,
+    /// recoverable from the master key alone.
+    Derive(DeriveArgs),
+}
+
+#[derive(Args)]
+pub struct DeriveArgs {
+    /// What to derive.
+    #[arg(value_enum)]
+    application: Application,
+    /// The child's index, one per purpose such as a service.
+    #[arg(long, default_value_t = 0)]
+    index: u32,
+    /// The number of words of a mnemonic.
+    #[arg(
+        long,
+        default_value = "12",
+        value_parser = PossibleValuesParser::new(["12", "15", "18", "21", "24"])
+            .map(|s| s.parse::<u32>().unwrap())
+    )]
+    words: u32,
+    /// The master extended private key.
+    #[arg(long, value_parser = secret::parser(parse::xpriv), required_unless_present = "slip39")]
+    xpriv: Option<Xpriv>,
+    /// Recover the master key from SLIP-39 shares entered interactively.
+    #[arg(long, conflicts_with = "xpriv")]
+    slip39: bool,
+}
+
+/// The BIP85 applications we derive children for.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Application {
+    /// An English BIP39 mnemonic.
+    Mnemonic,
+    /// A compressed private key in wallet import format.
+    Wif,
+    /// An extended private key.
+    Xprv,
+}
+
+pub fn run(command: Bip85Command) -> Result<(), String> {
+    let Bip85Command::Derive(args) = command;
+    let secp = secp256k1::Secp256k1::new();
+    let master = match args.xpriv {
+        Some(xpriv) => xpriv,
+        None => Xpriv::new_master(network(), &slip39::recover_interactively()?)
+            .map_err(|e| format!("Can't derive the master key: {}", e))?,
+    };
+    println!(
+        "{}",
+        derive(&secp, &master, args.application, args.words, args.index)?
+    );
+    Ok(())
+}
+
+/// The child of `master` for `application` at `index`: a mnemonic of
+/// `words` words, a WIF or an extended key, on the master key's network.
+pub fn derive<C: secp256k1::Signing>(
+    secp: &secp256k1::Secp256k1<C>,
+    master: &Xpriv,
+    application: Application,
+    words: u32,
+    index: u32,
+) -> Result<String, String> {
+    let hardened = |n: u32| {
+        ChildNumber::from_hardened_idx(n).map_err(|_| format!("{} is too large an index", n))
+    };
+    match application {
+        Application::Mnemonic => {
+            // English is language 0.
+            let path = [39, 0, words, index]
+                .map(hardened)
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
+            let entropy = derive_entropy(secp, master, &path)?;
+            let len = words as usize * 4 / 3;
+            Ok(bip39::mnemonic(&entropy[..len]))
+        }
+        Application::Wif => {
+            let entropy = derive_entropy(secp, master, &[hardened(2)?, hardened(index)?])?;
+            let key = secp256k1::SecretKey::from_slice(&entropy[..32])
+                .map_err(|e| format!("The derived key is invalid, try another index: {}", e))?;
+            Ok(PrivateKey::new(key, master.network).to_wif())
+        }
+        Application::Xprv => {
+            let entropy = derive_entropy(secp, master, &[hardened(32)?, hardened(index)?])?;
+            let xprv = Xpriv {
+                network: master.network,
+                depth: 0,
+                parent_fingerprint: Default::default(),
+                child_number: ChildNumber::from_normal_idx(0).unwrap(),
+                private_key: secp256k1::SecretKey::from_slice(&entropy[32..])
+                    .map_err(|e| format!("The derived key is invalid, try another index: {}", e))?,
+                chain_code: ChainCode::from(<[u8; 32]>::try_from(&entropy[..32]).unwrap()),
+            };
+            Ok(xprv.to_string())
+        }
+    }
+}
+
+/// The 64 bytes of entropy BIP85 derives from `master` for the application
+/// at `path`, below the BIP85 purpose.
+pub fn derive_entropy<C: secp256k1::Signing>(
+    secp: &secp256k1::Secp256k1<C>,
+    master: &Xpriv,
+    path: &[ChildNumber],
+) -> Result<[u8; 64], String> {
+    let mut full = vec![ChildNumber::from_hardened_idx(PURPOSE).unwrap()];
+    full.extend_from_slice(path);
+    let path = DerivationPath::from(full);
+    let key = master
+        .derive_priv(secp, &path)
+        .map_err(|e| format!("Can't derive {}: {}", path, e))?;
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(b"bip-entropy-from-k");
+    engine.input(&key.private_key.secret_bytes());
+    Ok(hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use miniscript::bitcoin::hex::DisplayHex;
+
+    use super::*;
+
+    // The master key of the BIP85 test vectors.
+    fn master() -> Xpriv {
+        Xpriv::from_str(
+            "xprv9s21ZrQH143K2LBWUUQRFXhucrQqBpKdRRxNVq2zBqsx8HVqFk2uYo8kmbaLLHRdqtQpUm98uKfu3vca1LqdGhUtyoFnCNkfmXRyPXLjbKb",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn entropy_vectors() {
+        let secp = secp256k1::Secp256k1::new();
+        let vectors = [
+            (
+                0,
+                "efecfbccffea313214232d29e71563d941229afb4338c21f9517c41aaa0d16f0\
+                 0b83d2a09ef747e7a64e8e2bd5a14869e693da66ce94ac2da570ab7ee48618f7",
+            ),
+            (
+                1,
+                "70c6e3e8ebee8dc4c0dbba66076819bb8c09672527c4277ca8729532ad711872\
+                 218f826919f6b67218adde99018a6df9095ab2b58d803b5b93ec9802085a690e",
+            ),
+        ];
+        for (index, entropy) in vectors {
+            let path = [
+                ChildNumber::from_hardened_idx(0).unwrap(),
+                ChildNumber::from_hardened_idx(index).unwrap(),
+            ];
+            assert_eq!(
+                derive_entropy(&secp, &master(), &path)
+                    .unwrap()
+                    .to_lower_hex_string(),
+                entropy
+            );
+        }
+    }
+
+    #[test]
+    fn mnemonic_vectors() {
+        let secp = secp256k1::Secp256k1::new();
+        let vectors = [
+            (
+                12,
+                "girl mad pet galaxy egg matter matrix prison refuse sense ordinary nose",
+            ),
+            (
+                18,
+                "near account window bike charge season chef number sketch tomorrow excuse \
+                 sniff circle vital hockey outdoor supply token",
+            ),
+            (
+                24,
+                "puppy ocean match cereal symbol another shed magic wrap hammer bulb intact \
+                 gadget divorce twin tonight reason outdoor destroy simple truth cigar social \
+                 volcano",
+            ),
+        ];
+        for (words, mnemonic) in vectors {
+            assert_eq!(
+                derive(&secp, &master(), Application::Mnemonic, words, 0).unwrap(),
+                mnemonic
+            );
+        }
+    }
+
+    #[test]
+    fn wif_vector() {
+        let secp = secp256k1::Secp256k1::new();
+        assert_eq!(
+            derive(&secp, &master(), Application::Wif, 12, 0).unwrap(),
+            "Kzyv4uF39d4Jrw2W7UryTHwZr1zQVNk4dAFyqE6BuMrMh1Za7uhp"
+        );
+    }
+
+    #[test]
+    fn xprv_vector() {
+        let secp = secp256k1::Secp256k1::new();
+        assert_eq!(
+            derive(&secp, &master(), Application::Xprv, 12, 0).unwrap(),
+            "xprv9s21ZrQH143K2srSbCSg4m4kLvPMzcWydgmKEnMmoZUurYuBuYG46c6P71UGXMzmriLzCCBvKQWBUv3vPB3m1SATMhp3uEjXHJ42jFg7myX"
+        );
+    }
+}
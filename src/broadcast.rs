@@ -0,0 +1,307 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use clap::Args;
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::{Transaction, Txid};
+use serde_json::json;
+
+use crate::artifact::{self, Artifact};
+use crate::events::Events;
+use crate::{parse, rpc, stage};
+
+/// How long a backend has to answer before it's given up on. Tor circuits
+/// to onion services take a while to build.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Args)]
+pub struct BroadcastArgs {
+    /// The hex encoded transaction, or a finalized base64 PSBT.
+    tx: String,
+    #[command(flatten)]
+    backends: BackendArgs,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+}
+
+/// Where to push transactions, for every command broadcasting them.
+#[derive(Args)]
+pub struct BackendArgs {
+    /// Push the transaction through this backend: `core` for the node of
+    /// the --rpc-* options, `esplora:<URL>` for an Esplora HTTP API, or
+    /// `tor:<URL>` for an Esplora reached only through Tor, such as an
+    /// onion service. May be repeated to fall back on the next backend
+    /// when one fails, in the order given. Defaults to `core`.
+    #[arg(long = "via", value_parser = parse::backend)]
+    via: Vec<Backend>,
+    /// Push through every backend at once rather than one after the other
+    /// until one accepts, so the transaction propagates from several
+    /// places.
+    #[arg(long)]
+    parallel: bool,
+    /// Tor's SOCKS5 proxy, for the tor: backends.
+    #[arg(long, default_value = "127.0.0.1:9050")]
+    tor_proxy: String,
+}
+
+/// A way of getting a transaction to the network.
+#[derive(Clone)]
+pub enum Backend {
+    Core,
+    Esplora(String),
+    Tor(String),
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Core => write!(f, "core"),
+            Backend::Esplora(url) => write!(f, "esplora {}", url),
+            Backend::Tor(url) => write!(f, "tor {}", url),
+        }
+    }
+}
+
+impl BackendArgs {
+    /// Push through `backend` when no --via says otherwise, rather than
+    /// the node.
+    pub fn default_to(&mut self, backend: Backend) {
+        if self.via.is_empty() {
+            self.via.push(backend);
+        }
+    }
+
+    /// Push `tx` through the backends, printing how each one fared, and
+    /// succeed if any of them accepted it.
+    pub fn broadcast(&self, rpc: &rpc::RpcArgs, tx: &Transaction) -> Result<Txid, String> {
+        let txid = tx.compute_txid();
+        let backends = if self.via.is_empty() {
+            vec![Backend::Core]
+        } else {
+            self.via.clone()
+        };
+        let push = |backend: &Backend| {
+            let result = match backend {
+                Backend::Core => rpc.connect().and_then(|node| node.broadcast(tx)),
+                Backend::Esplora(url) => esplora(url, tx),
+                Backend::Tor(url) => tor(&self.tor_proxy, url, tx),
+            };
+            match result {
+                Ok(accepted) if accepted != txid => {
+                    Err(format!("answered with {} rather than {}", accepted, txid))
+                }
+                Err(e) if already_known(&e) => Ok(txid),
+                result => result,
+            }
+        };
+
+        let mut accepted = false;
+        if self.parallel {
+            let results: Vec<_> = thread::scope(|scope| {
+                let handles: Vec<_> = backends
+                    .iter()
+                    .map(|backend| scope.spawn(move || push(backend)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("backend thread panicked"))
+                    .collect()
+            });
+            for (backend, result) in backends.iter().zip(results) {
+                accepted |= report(backend, result);
+            }
+        } else {
+            for backend in &backends {
+                if report(backend, push(backend)) {
+                    accepted = true;
+                    break;
+                }
+            }
+        }
+        if !accepted {
+            return Err(format!("No backend accepted {}", txid));
+        }
+        Ok(txid)
+    }
+}
+
+/// Push `tx` through `backends` like [`BackendArgs::broadcast`], with a
+/// `broadcast` event once it's accepted or a `broadcast_failed` one.
+pub fn push(
+    backends: &BackendArgs,
+    rpc: &rpc::RpcArgs,
+    tx: &Transaction,
+    events: &mut Events,
+) -> Result<Txid, String> {
+    let txid = tx.compute_txid().to_string();
+    match backends.broadcast(rpc, tx) {
+        Ok(accepted) => {
+            events.emit("broadcast", json!({ "txid": txid }));
+            Ok(accepted)
+        }
+        Err(e) => {
+            events.emit("broadcast_failed", json!({ "txid": txid, "message": e }));
+            Err(e)
+        }
+    }
+}
+
+/// Push a raw transaction or finalized PSBT to the network.
+pub fn run(args: BroadcastArgs, events: &mut Events) -> Result<(), String> {
+    let tx = match artifact::detect(&args.tx)? {
+        Artifact::Transaction(tx) => tx,
+        Artifact::Psbt(psbt) => stage::extract_tx(psbt)?,
+        Artifact::Descriptor(_) => {
+            return Err("Expected a transaction or PSBT, not a descriptor".to_string())
+        }
+    };
+    println!(
+        "Broadcast {}",
+        push(&args.backends, &args.rpc, &tx, events)?
+    );
+    Ok(())
+}
+
+// Print how `backend` fared, returning whether it accepted the transaction.
+fn report(backend: &Backend, result: Result<Txid, String>) -> bool {
+    match result {
+        Ok(_) => {
+            eprintln!("{}: accepted", backend);
+            true
+        }
+        Err(e) => {
+            eprintln!("{}: failed: {}", backend, e);
+            false
+        }
+    }
+}
+
+// Whether a rejection only says the transaction was already relayed, e.g.
+// by another backend pushing it at the same time.
+fn already_known(error: &str) -> bool {
+    [
+        "txn-already-in-mempool",
+        "txn-already-known",
+        "already in block chain",
+        "already in utxo set",
+    ]
+    .iter()
+    .any(|reason| error.contains(reason))
+}
+
+// POST the transaction to an Esplora API, which answers with its txid.
+fn esplora(url: &str, tx: &Transaction) -> Result<Txid, String> {
+    let response = minreq::post(format!("{}/tx", url.trim_end_matches('/')))
+        .with_header("Content-Type", "text/plain")
+        .with_body(serialize_hex(tx))
+        .with_timeout(TIMEOUT.as_secs())
+        .send()
+        .map_err(|e| e.to_string())?;
+    let body = response.as_str().unwrap_or_default().trim();
+    if response.status_code != 200 {
+        return Err(format!(
+            "answered {} {}: {}",
+            response.status_code, response.reason_phrase, body
+        ));
+    }
+    body.parse()
+        .map_err(|_| format!("answered {:?} rather than a txid", body))
+}
+
+// POST the transaction to an Esplora API through Tor's SOCKS5 proxy, so the
+// request never leaves the Tor network for an onion service.
+//
+// The proxy resolves the host itself, leaking nothing to local DNS. Onion
+// services are end to end encrypted already, so only plain HTTP is spoken.
+fn tor(proxy: &str, url: &str, tx: &Transaction) -> Result<Txid, String> {
+    let rest = url
+        .strip_prefix("http://")
+        .expect("checked by parse::backend");
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>()
+                .map_err(|_| format!("invalid port in {}", url))?,
+        ),
+        None => (authority, 80),
+    };
+    let host_len = u8::try_from(host.len()).map_err(|_| "the host name is too long")?;
+
+    let mut stream = TcpStream::connect(proxy)
+        .map_err(|e| format!("Can't reach the Tor proxy at {}: {}", proxy, e))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    let io = |e: std::io::Error| format!("Tor proxy connection failed: {}", e);
+
+    // Greet the proxy without authentication, then ask it to connect to
+    // the host by name.
+    stream.write_all(&[5, 1, 0]).map_err(io)?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).map_err(io)?;
+    if reply != [5, 0] {
+        return Err(format!(
+            "{} isn't a SOCKS5 proxy without authentication",
+            proxy
+        ));
+    }
+    let mut request = vec![5, 1, 0, 3, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).map_err(io)?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).map_err(io)?;
+    if reply[1] != 0 {
+        return Err(format!(
+            "Tor can't reach {}:{}, SOCKS error {}",
+            host, port, reply[1]
+        ));
+    }
+    // Skip the address the proxy bound, which depends on its type.
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len).map_err(io)?;
+            len[0] as usize
+        }
+        ty => return Err(format!("Tor proxy answered with address type {}", ty)),
+    };
+    stream.read_exact(&mut vec![0; bound + 2]).map_err(io)?;
+
+    let body = serialize_hex(tx);
+    let path = path.trim_end_matches('/');
+    write!(
+        stream,
+        "POST /{}{}tx HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        if path.is_empty() { "" } else { "/" },
+        host,
+        body.len(),
+        body
+    )
+    .map_err(io)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(io)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("answered with an invalid HTTP response")?;
+    let status = head.lines().next().unwrap_or_default();
+    let body = body.trim();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!(
+            "answered {}: {}",
+            status.split_once(' ').map_or(status, |(_, status)| status),
+            body
+        ));
+    }
+    body.parse()
+        .map_err(|_| format!("answered {:?} rather than a txid", body))
+}
@@ -0,0 +1,191 @@
+use miniscript::bitcoin::absolute::LockTime;
+use miniscript::bitcoin::psbt::{self, Psbt};
+use miniscript::bitcoin::transaction::Version;
+use miniscript::bitcoin::{secp256k1, Amount, OutPoint, Sequence, Transaction, TxIn, TxOut};
+use miniscript::descriptor::DefiniteDescriptorKey;
+use miniscript::psbt::{PsbtExt, PsbtInputExt};
+use miniscript::{Descriptor, MiniscriptKey};
+
+use crate::error::Error;
+use crate::{annex, sign};
+
+/// How the value of the spent coins is divided up.
+pub struct Split {
+    /// What the recipient receives.
+    pub recipient: Amount,
+    /// What's left over to be paid back as change.
+    pub change: Amount,
+}
+
+/// Divide `input` between the recipient, the fee and the change.
+///
+/// With `subtract_fee` the fee is taken out of `amount`, otherwise the
+/// recipient gets `amount` in full and the fee is paid from the rest of the
+/// input.
+pub fn split(
+    input: Amount,
+    amount: Amount,
+    fee: Amount,
+    subtract_fee: bool,
+) -> Result<Split, String> {
+    let (recipient, spent) = if subtract_fee {
+        let recipient = amount.checked_sub(fee).ok_or_else(|| {
+            format!(
+                "The fee of {} sat exceeds the amount of {} sat",
+                fee.to_sat(),
+                amount.to_sat()
+            )
+        })?;
+        (recipient, amount)
+    } else {
+        let spent = amount
+            .checked_add(fee)
+            .ok_or("The amount plus the fee overflows")?;
+        (amount, spent)
+    };
+    let change = input.checked_sub(spent).ok_or_else(|| {
+        format!(
+            "Insufficient funds: the input is worth {} sat but {} sat are needed ({} sat to the recipient, {} sat fee)",
+            input.to_sat(),
+            spent.to_sat(),
+            recipient.to_sat(),
+            fee.to_sat()
+        )
+    })?;
+    Ok(Split { recipient, change })
+}
+
+/// Create an unsigned PSBT spending `outpoints` to `outputs`.
+pub fn create_psbt(outpoints: &[OutPoint], outputs: Vec<TxOut>) -> Psbt {
+    let spend_tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: outpoints
+            .iter()
+            .map(|outpoint| TxIn {
+                previous_output: *outpoint,
+                sequence: Sequence::MAX,
+                ..Default::default()
+            })
+            .collect(),
+        output: outputs,
+    };
+
+    Psbt::from_unsigned_tx(spend_tx).expect("the transaction is unsigned")
+}
+
+/// Append an input spending `outpoint` to `psbt`.
+pub fn add_input(psbt: &mut Psbt, outpoint: OutPoint) {
+    psbt.unsigned_tx.input.push(TxIn {
+        previous_output: outpoint,
+        sequence: Sequence::MAX,
+        ..Default::default()
+    });
+    psbt.inputs.push(psbt::Input::default());
+}
+
+/// Set the lock time of `psbt`, enabling it on inputs whose sequence is
+/// final.
+pub fn set_lock_time(psbt: &mut Psbt, lock_time: LockTime) {
+    psbt.unsigned_tx.lock_time = lock_time;
+    for txin in &mut psbt.unsigned_tx.input {
+        if txin.sequence == Sequence::MAX {
+            txin.sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+        }
+    }
+}
+
+/// Append `output` to `psbt`.
+pub fn add_output(psbt: &mut Psbt, output: TxOut) {
+    psbt.unsigned_tx.output.push(output);
+    psbt.outputs.push(psbt::Output::default());
+}
+
+/// Populate a PSBT input with the scripts of the descriptor controlling it
+/// and the UTXO it spends. `prev_tx`, the transaction creating the UTXO, is
+/// only needed by legacy and nested segwit inputs.
+pub fn update_input(
+    input: &mut psbt::Input,
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+    utxo: TxOut,
+    prev_tx: Option<Transaction>,
+) -> Result<(), String> {
+    input
+        .update_with_descriptor_unchecked(descriptor)
+        .map_err(|e| format!("Can't update the input with the descriptor: {}", e))?;
+    add_utxo(input, descriptor, utxo, prev_tx)
+}
+
+/// Attach the UTXO an input spends the way its descriptor needs it: segwit
+/// inputs get the output as their witness UTXO, and legacy and nested
+/// segwit ones the whole previous transaction as their non-witness UTXO,
+/// which their signers hash or check the spent value against.
+///
+/// Segwit v0 inputs get the previous transaction too when it's known: their
+/// signatures only commit to their own input's value, so signers check
+/// the witness UTXO against it.
+pub fn add_utxo(
+    input: &mut psbt::Input,
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+    utxo: TxOut,
+    prev_tx: Option<Transaction>,
+) -> Result<(), String> {
+    if needs_prev_tx(descriptor) && prev_tx.is_none() {
+        return Err(format!(
+            "Spending {} needs the whole previous transaction, not just the output",
+            descriptor
+        ));
+    }
+    // Taproot signatures commit to every input's value already.
+    if let Some(prev_tx) = prev_tx.filter(|_| !matches!(descriptor, Descriptor::Tr(_))) {
+        if !prev_tx.output.contains(&utxo) {
+            return Err(format!(
+                "Transaction {} doesn't create the spent output",
+                prev_tx.compute_txid()
+            ));
+        }
+        input.non_witness_utxo = Some(prev_tx);
+    }
+    if descriptor.desc_type().segwit_version().is_some() {
+        input.witness_utxo = Some(utxo);
+    }
+    Ok(())
+}
+
+/// Whether inputs spending `descriptor` need the previous transaction as
+/// their non-witness UTXO: legacy ones sign it, and signers of nested
+/// segwit ones can't tell a p2sh output from a legacy one otherwise.
+pub fn needs_prev_tx<Pk: MiniscriptKey>(descriptor: &Descriptor<Pk>) -> bool {
+    matches!(
+        descriptor,
+        Descriptor::Bare(_) | Descriptor::Pkh(_) | Descriptor::Sh(_)
+    )
+}
+
+/// The output input `i` of `psbt` spends, from its witness UTXO or else
+/// its non-witness UTXO.
+pub fn spent_utxo(psbt: &Psbt, i: usize) -> Option<TxOut> {
+    let input = psbt.inputs.get(i)?;
+    if let Some(utxo) = &input.witness_utxo {
+        return Some(utxo.clone());
+    }
+    let vout = psbt.unsigned_tx.input.get(i)?.previous_output.vout;
+    input
+        .non_witness_utxo
+        .as_ref()?
+        .output
+        .get(vout as usize)
+        .cloned()
+}
+
+/// Finalize every input of `psbt` after checking all of its signatures,
+/// ending the witness of taproot inputs with their annex if they have one.
+pub fn finalize<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    psbt: &mut Psbt,
+) -> Result<(), Error> {
+    sign::verify_signatures(secp, psbt).map_err(Error::Signature)?;
+    annex::finalize(psbt).map_err(Error::Finalize)?;
+    psbt.finalize_mut(secp)
+        .map_err(|errors| Error::finalizing(&errors))
+}
@@ -0,0 +1,90 @@
+use std::ops::RangeInclusive;
+
+use miniscript::bitcoin::{OutPoint, TxOut};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::Descriptor;
+
+use crate::esplora::Esplora;
+use crate::{build, rpc, verify, Utxo};
+
+/// Where coins are looked up: the node of the --rpc-* options, or an
+/// Esplora server standing in for it.
+pub enum Chain {
+    Node(rpc::Node),
+    Esplora(Esplora),
+}
+
+impl Chain {
+    /// The Esplora server at `esplora` if given, or else the node.
+    pub fn connect(esplora: Option<&str>, rpc: &rpc::RpcArgs) -> Result<Chain, String> {
+        match esplora {
+            Some(url) => Ok(Chain::Esplora(Esplora::new(url))),
+            None => rpc.connect().map(Chain::Node),
+        }
+    }
+
+    /// The output `outpoint` spends, along with the transaction creating
+    /// it when it can be fetched.
+    pub fn prevout(&self, outpoint: &OutPoint) -> Result<rpc::Prevout, String> {
+        match self {
+            Chain::Node(node) => node.prevout(outpoint),
+            Chain::Esplora(esplora) => esplora.prevout(outpoint),
+        }
+    }
+
+    /// The output `outpoint` refers to if it's confirmed and unspent.
+    pub fn unspent(&self, outpoint: &OutPoint) -> Result<Option<TxOut>, String> {
+        match self {
+            Chain::Node(node) => node.unspent(outpoint),
+            Chain::Esplora(esplora) => esplora.unspent(outpoint),
+        }
+    }
+
+    /// The confirmed unspent outputs paying `descriptor`, at the derivation
+    /// indexes `range` if it has wildcards.
+    pub fn scan(
+        &self,
+        descriptor: &Descriptor<DescriptorPublicKey>,
+        range: &RangeInclusive<u32>,
+    ) -> Result<Vec<(OutPoint, TxOut)>, String> {
+        match self {
+            Chain::Node(node) => {
+                eprintln!("Scanning the node's UTXO set for the descriptor's outputs");
+                node.scan(descriptor, range)
+            }
+            Chain::Esplora(esplora) => {
+                eprintln!("Looking up the descriptor's outputs on the Esplora server");
+                esplora.scan(descriptor, range)
+            }
+        }
+    }
+
+    /// Like [`Chain::scan`], but with each coin's descriptor derived and,
+    /// for legacy and nested segwit ones, the transaction creating it.
+    pub fn utxos(
+        &self,
+        descriptor: &Descriptor<DescriptorPublicKey>,
+        range: &RangeInclusive<u32>,
+    ) -> Result<Vec<Utxo>, String> {
+        let descriptors = [descriptor.clone()];
+        let mut utxos = Vec::new();
+        for (outpoint, txout) in self.scan(descriptor, range)? {
+            let Some((derived, _)) =
+                verify::find(&descriptors, range, &txout).map_err(|e| e.to_string())?
+            else {
+                continue;
+            };
+            let tx = match build::needs_prev_tx(&derived) {
+                true => self.prevout(&outpoint)?.tx,
+                false => None,
+            };
+            utxos.push(Utxo {
+                outpoint,
+                txout,
+                descriptor: derived,
+                tx,
+            });
+        }
+        Ok(utxos)
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+
+use miniscript::bitcoin::bip32::ChildNumber;
+use miniscript::bitcoin::psbt::{Output, Psbt};
+use miniscript::bitcoin::{Address, Script};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::Descriptor;
+
+use crate::network;
+
+/// Whose an output of a PSBT is, as far as our descriptors can tell.
+pub enum Ownership {
+    /// A payment to someone else.
+    External,
+    /// Change back to the descriptor at this position, derived at an index
+    /// for ranged descriptors.
+    Change(usize, Option<u32>),
+    /// The output carries derivation paths or scripts suggesting it's
+    /// change, but none of our descriptors derive it.
+    Unverified,
+}
+
+/// Decide which outputs of `psbt` genuinely pay back to `descriptors`.
+///
+/// Candidate indexes of ranged descriptors are taken from the last step of
+/// the output's derivation paths, so a PSBT claiming some output as change
+/// has to get the derivation right for it to be recognized.
+pub fn classify(psbt: &Psbt, descriptors: &[Descriptor<DescriptorPublicKey>]) -> Vec<Ownership> {
+    psbt.unsigned_tx
+        .output
+        .iter()
+        .zip(&psbt.outputs)
+        .map(|(txout, output)| {
+            let indexes = derivation_indexes(output);
+            for (n, descriptor) in descriptors.iter().enumerate() {
+                if !descriptor.has_wildcard() {
+                    if derives(descriptor, 0, &txout.script_pubkey) {
+                        return Ownership::Change(n, None);
+                    }
+                    continue;
+                }
+                for &index in &indexes {
+                    if derives(descriptor, index, &txout.script_pubkey) {
+                        return Ownership::Change(n, Some(index));
+                    }
+                }
+            }
+            if indexes.is_empty() && output.witness_script.is_none() {
+                Ownership::External
+            } else {
+                Ownership::Unverified
+            }
+        })
+        .collect()
+}
+
+fn derives(descriptor: &Descriptor<DescriptorPublicKey>, index: u32, spk: &Script) -> bool {
+    descriptor
+        .at_derivation_index(index)
+        .is_ok_and(|d| d.script_pubkey() == *spk)
+}
+
+fn derivation_indexes(output: &Output) -> Vec<u32> {
+    let paths = output
+        .bip32_derivation
+        .values()
+        .map(|(_, path)| path)
+        .chain(output.tap_key_origins.values().map(|(_, (_, path))| path));
+    let mut indexes: Vec<u32> = paths
+        .filter_map(|path| match path.as_ref().last() {
+            Some(ChildNumber::Normal { index }) => Some(*index),
+            _ => None,
+        })
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+}
+
+/// A summary of where a PSBT's money goes, for approval before signing.
+pub struct Summary<'a> {
+    pub psbt: &'a Psbt,
+    pub ownership: &'a [Ownership],
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let outputs = self.psbt.unsigned_tx.output.iter().zip(self.ownership);
+        for (i, (txout, ownership)) in outputs.enumerate() {
+            let to = match Address::from_script(&txout.script_pubkey, network()) {
+                Ok(address) => address.to_string(),
+                Err(_) => txout.script_pubkey.to_string(),
+            };
+            let kind = match ownership {
+                Ownership::External => "payment".to_string(),
+                Ownership::Change(n, None) => format!("change to descriptor {}", n),
+                Ownership::Change(n, Some(index)) => {
+                    format!("change to descriptor {} at index {}", n, index)
+                }
+                Ownership::Unverified => {
+                    "WARNING: claims to be change but isn't derived from our descriptors"
+                        .to_string()
+                }
+            };
+            writeln!(
+                f,
+                "output {}: {} sat to {}, {}",
+                i,
+                txout.value.to_sat(),
+                to,
+                kind
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+// Each entry is a (copy, paste) command pair for one platform clipboard.
+#[cfg(target_os = "macos")]
+fn commands() -> Vec<(&'static [&'static str], &'static [&'static str])> {
+    vec![(&["pbcopy"], &["pbpaste"])]
+}
+
+#[cfg(target_os = "windows")]
+fn commands() -> Vec<(&'static [&'static str], &'static [&'static str])> {
+    vec![(
+        &["clip"],
+        &["powershell", "-NoProfile", "-Command", "Get-Clipboard"],
+    )]
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn commands() -> Vec<(&'static [&'static str], &'static [&'static str])> {
+    let mut commands: Vec<(&'static [&'static str], &'static [&'static str])> = Vec::new();
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        commands.push((&["wl-copy"], &["wl-paste", "--no-newline"]));
+    }
+    commands.push((
+        &["xclip", "-selection", "clipboard"],
+        &["xclip", "-selection", "clipboard", "-o"],
+    ));
+    commands.push((
+        &["xsel", "--clipboard", "--input"],
+        &["xsel", "--clipboard", "--output"],
+    ));
+    commands
+}
+
+/// Read the system clipboard as text.
+pub fn read() -> io::Result<String> {
+    let mut last_err = unavailable();
+    for (_, paste) in commands() {
+        match Command::new(paste[0]).args(&paste[1..]).output() {
+            Ok(output) if output.status.success() => {
+                return String::from_utf8(output.stdout)
+                    .map(|s| s.trim().to_string())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            Ok(output) => last_err = failed(paste[0], &output.stderr),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Replace the contents of the system clipboard with `text`.
+pub fn write(text: &str) -> io::Result<()> {
+    let mut last_err = unavailable();
+    for (copy, _) in commands() {
+        let mut child = match Command::new(copy[0])
+            .args(&copy[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                last_err = e;
+                continue;
+            }
+        };
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(text.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if output.status.success() {
+            return Ok(());
+        }
+        last_err = failed(copy[0], &output.stderr);
+    }
+    Err(last_err)
+}
+
+fn unavailable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        "no clipboard tool found (install wl-clipboard, xclip or xsel)",
+    )
+}
+
+fn failed(tool: &str, stderr: &[u8]) -> io::Error {
+    io::Error::other(format!(
+        "{} failed: {}",
+        tool,
+        String::from_utf8_lossy(stderr).trim()
+    ))
+}
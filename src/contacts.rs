@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::Subcommand;
+use miniscript::bitcoin::ScriptBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::bip47::PaymentCode;
+use crate::{parse, wallet};
+
+#[derive(Subcommand)]
+pub enum ContactsCommand {
+    /// Save a recipient under a name, which commands then accept in place
+    /// of the recipient.
+    Add {
+        /// Letters, digits, '-', '_' and '.'.
+        name: String,
+        /// An address, a descriptor prefixed with `<index>:` if it has
+        /// wildcards, or a BIP47 payment code.
+        recipient: String,
+    },
+    /// List the saved contacts.
+    List,
+    /// Forget a contact.
+    Remove {
+        /// The name of the contact.
+        name: String,
+    },
+}
+
+/// A recipient saved in the address book.
+#[derive(Serialize, Deserialize)]
+pub struct Contact {
+    pub name: String,
+    pub recipient: String,
+}
+
+impl Contact {
+    fn kind(&self) -> &'static str {
+        if PaymentCode::from_str(&self.recipient).is_ok() {
+            "payment code"
+        } else if parse::address(&self.recipient).is_ok() {
+            "address"
+        } else {
+            "descriptor"
+        }
+    }
+
+    /// The script paying the contact.
+    pub fn script_pubkey(&self) -> Result<ScriptBuf, String> {
+        if PaymentCode::from_str(&self.recipient).is_ok() {
+            return Err(format!(
+                "{} is a BIP47 payment code, derive an address to pay with \"bip47 next-address\"",
+                self.name
+            ));
+        }
+        parse::recipient(&self.recipient)
+    }
+}
+
+pub fn run(command: ContactsCommand) -> Result<(), String> {
+    let mut contacts = load()?;
+    match command {
+        ContactsCommand::Add { name, recipient } => {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            {
+                return Err(format!(
+                    "Invalid name \"{}\", use letters, digits, '-', '_' and '.'",
+                    name
+                ));
+            }
+            if parse::address(&name).is_ok() {
+                return Err(format!("The name {} is an address", name));
+            }
+            if let Some(contact) = contacts.iter().find(|c| c.name == name) {
+                return Err(format!(
+                    "{} is already saved as {}, remove it first",
+                    name, contact.recipient
+                ));
+            }
+            let contact = Contact { name, recipient };
+            // Contacts resolve to other contacts no further than this.
+            if find(&contact.recipient)?.is_some() {
+                return Err(format!("{} is the name of a contact", contact.recipient));
+            }
+            if PaymentCode::from_str(&contact.recipient).is_err() {
+                contact
+                    .script_pubkey()
+                    .map_err(|e| format!("Invalid recipient: {}", e))?;
+            }
+            println!("Saved {} ({})", contact.name, contact.kind());
+            contacts.push(contact);
+            save(&contacts)
+        }
+        ContactsCommand::List => {
+            if contacts.is_empty() {
+                println!("No contacts saved");
+            }
+            for contact in &contacts {
+                println!(
+                    "{:<20} {:<12} {}",
+                    contact.name,
+                    contact.kind(),
+                    contact.recipient
+                );
+            }
+            Ok(())
+        }
+        ContactsCommand::Remove { name } => {
+            let before = contacts.len();
+            contacts.retain(|contact| contact.name != name);
+            if contacts.len() == before {
+                return Err(format!("No contact named {}", name));
+            }
+            println!("Removed {}", name);
+            save(&contacts)
+        }
+    }
+}
+
+fn path() -> PathBuf {
+    wallet::data_dir().join("contacts.json")
+}
+
+/// Load the address book, empty if nothing was saved yet.
+pub fn load() -> Result<Vec<Contact>, String> {
+    let path = path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let json =
+        fs::read_to_string(&path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    serde_json::from_str(&json).map_err(|e| format!("{} is corrupt: {}", path.display(), e))
+}
+
+fn save(contacts: &[Contact]) -> Result<(), String> {
+    let path = path();
+    fs::create_dir_all(path.parent().unwrap())
+        .map_err(|e| format!("Can't create {}: {}", path.display(), e))?;
+    let json = serde_json::to_string_pretty(contacts).unwrap();
+    fs::write(&path, json).map_err(|e| format!("Can't write {}: {}", path.display(), e))
+}
+
+/// The contact called `name`, if there is one.
+pub fn find(name: &str) -> Result<Option<Contact>, String> {
+    Ok(load()?.into_iter().find(|contact| contact.name == name))
+}
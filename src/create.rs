@@ -0,0 +1,849 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use clap::Args;
+use miniscript::bitcoin::absolute::LockTime;
+use miniscript::bitcoin::{
+    Amount, FeeRate, OutPoint, Psbt, ScriptBuf, Sequence, Transaction, TxOut, Weight,
+};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::psbt::PsbtExt;
+use miniscript::Descriptor;
+use serde_json::json;
+
+use crate::chain::Chain;
+use crate::error::Error;
+use crate::select::{self, CoinControl};
+use crate::{build, estimate, events, intent, parse, policy, rpc, stage, trace, utxo};
+
+#[derive(Args)]
+pub struct CreateArgs {
+    /// The spend intent to compile, a YAML or JSON document declaring the
+    /// wallet, recipients, feerate and optionally the locktime, change,
+    /// labels, coin constraints and preferred policy branch.
+    #[arg(
+        long,
+        conflicts_with = "FundingArgs",
+        required_unless_present = "rawtx"
+    )]
+    intent: Option<PathBuf>,
+    #[command(flatten)]
+    funding: Option<FundingArgs>,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+    #[command(flatten)]
+    out: stage::PsbtOut,
+}
+
+/// The outputs of a funding transaction a spend pays from, and where it
+/// pays them to.
+#[derive(Args)]
+pub struct FundingArgs {
+    /// The raw transaction we're attempting to spend, or `node` to spend
+    /// every confirmed output paying the descriptor that the node of the
+    /// --rpc-* options, or the --esplora server, finds in its UTXO set
+    /// (just the --outpoint one if given).
+    #[arg(value_parser = parse::source)]
+    pub rawtx: Source,
+    /// The address we're spending to, a saved contact, or a descriptor to
+    /// derive its script from, prefixed with `<index>:` if it has wildcards.
+    #[arg(value_parser = parse::recipient)]
+    recipient: ScriptBuf,
+    /// The amount the address receives, in sats or with a denomination
+    /// (e.g. "0.001 BTC").
+    #[arg(value_parser = parse::amount)]
+    amount: Amount,
+    /// Also pay another address, as `<address>:<amount>`. May be repeated.
+    /// Only the first recipient pays the fee with --subtract-fee.
+    #[arg(long = "output", value_parser = parse::output)]
+    outputs: Vec<TxOut>,
+    /// Add an OP_RETURN output carrying this hex encoded data.
+    #[arg(long, value_parser = parse::op_return)]
+    op_return: Option<ScriptBuf>,
+    /// The descriptor to sign. With wildcards, the funding transaction is
+    /// searched for the index it pays unless --index is given.
+    #[arg(value_parser = parse::ranged_descriptor)]
+    descriptor: Descriptor<DescriptorPublicKey>,
+    /// The derivation index of a descriptor with wildcards.
+    #[arg(long)]
+    index: Option<u32>,
+    /// The derivation indexes to search the funding transaction for, for a
+    /// descriptor with wildcards.
+    #[arg(long, value_parser = parse::range, default_value = "0-999", conflicts_with = "index")]
+    range: RangeInclusive<u32>,
+    /// The funding transaction output to spend, if it pays the descriptor
+    /// more than once.
+    #[arg(long, value_parser = parse::outpoint)]
+    outpoint: Option<OutPoint>,
+    /// Also spend another output of the funding transaction under its own
+    /// descriptor, as `<vout>:<descriptor>`, e.g. to sweep coins held under
+    /// an older policy in the same transaction. May be repeated.
+    #[arg(long = "input-descriptor", value_parser = parse::input_descriptor)]
+    input_descriptors: Vec<(u32, Descriptor<DefiniteDescriptorKey>)>,
+    /// Also spend an output of another transaction under its own
+    /// descriptor, as `<txid>:<vout>:<descriptor>`, with the transaction
+    /// passed to --prev-tx. May be repeated.
+    #[arg(long = "input", value_parser = parse::input)]
+    inputs: Vec<(OutPoint, Descriptor<DefiniteDescriptorKey>)>,
+    /// A raw transaction whose outputs --input or --utxo spend. May be
+    /// repeated.
+    #[arg(long = "prev-tx", value_parser = parse::transaction)]
+    pub prev_txs: Vec<Transaction>,
+    /// Look coins up through this Esplora HTTP API instead of a node, for
+    /// those not running one: with `node` the descriptor's confirmed
+    /// outputs, along with the transactions creating them for the inputs
+    /// needing them, and the --utxo ones. Spends broadcast through it too
+    /// unless --via says otherwise.
+    #[arg(long, value_name = "URL", value_parser = parse::url)]
+    pub esplora: Option<String>,
+    /// Also spend this output paying the descriptor, as `<txid>:<vout>`,
+    /// from the funding transaction, a --prev-tx or, with `node`, the
+    /// node's UTXO set, which then isn't scanned for others. May be
+    /// repeated.
+    #[arg(long = "utxo", value_parser = parse::outpoint)]
+    utxos: Vec<OutPoint>,
+    /// Only spend the coins this strategy picks to pay for the outputs and
+    /// the fee, out of the outputs paying the descriptor: all of the
+    /// funding transaction's, the --utxo ones, or those the node finds.
+    #[arg(long, value_enum, conflicts_with = "outpoint")]
+    select: Option<select::Strategy>,
+    /// Override the coins --select picks, as `include:<txid>:<vout>` to
+    /// always spend an output or `exclude:<txid>:<vout>` to never spend
+    /// it. May be repeated.
+    #[arg(long = "coin-control", value_parser = parse::coin_control, requires = "select")]
+    coin_control: Vec<select::CoinControl>,
+    /// The fee to pay.
+    #[arg(long, value_parser = parse::amount, default_value = "500")]
+    fee: Amount,
+    /// Pay this feerate in sat/vB instead of a fixed fee, estimating the
+    /// size from each input descriptor's largest satisfaction. Change goes
+    /// back to the descriptor unless --change says otherwise, and is paid
+    /// as fee when it would be dust.
+    #[arg(long, value_parser = parse::feerate, conflicts_with = "fee")]
+    feerate: Option<FeeRate>,
+    /// Pay the fee out of the amount, so the address receives the amount
+    /// minus the fee.
+    #[arg(long)]
+    subtract_fee: bool,
+    /// Where to send what's left of the input after the amount and fee, as
+    /// an address or descriptor; required unless they add up to the whole
+    /// input.
+    #[arg(long, value_parser = parse::recipient)]
+    change: Option<ScriptBuf>,
+    /// Send the change to this descriptor, prefixed with the derivation
+    /// index as `<index>:` if it has wildcards, and fill in the change
+    /// output's scripts and key derivations from it so the signers can
+    /// recognize it as ours.
+    #[arg(long, value_parser = parse::derived_descriptor, conflicts_with = "change")]
+    change_descriptor: Option<Descriptor<DefiniteDescriptorKey>>,
+    /// Lock the transaction until this block height or UNIX time, e.g. to
+    /// satisfy an after() of the descriptor. Defaults to the lowest one
+    /// meeting the after() every way of spending an input needs, if any.
+    #[arg(long)]
+    locktime: Option<u32>,
+    /// The sequence of every input, e.g. to satisfy an older() of the
+    /// descriptor. Defaults to the lowest one meeting the older() every
+    /// way of spending the input needs, if any, or else final.
+    #[arg(long)]
+    sequence: Option<u32>,
+    /// Signal replaceability (BIP125) on inputs that need no sequence, so
+    /// the transaction can be replaced with bump-fee while unconfirmed.
+    #[arg(long, conflicts_with = "sequence")]
+    rbf: bool,
+    /// Spend the descriptor by this path: its number, as inspect lists
+    /// them, or what it needs, comma separated as for estimate-weight
+    /// --branch, e.g. a key as written in the descriptor to take the first
+    /// path it signs. Only the path's scripts and keys are added to the
+    /// inputs, so signers can't satisfy another, and the lock time and
+    /// sequences it needs are set.
+    #[arg(long, value_name = "PATH")]
+    spend_path: Option<String>,
+}
+
+/// Where the coins a spend pays from come from.
+#[derive(Clone)]
+pub enum Source {
+    /// A funding transaction paying the descriptor.
+    Transaction(Transaction),
+    /// The node's UTXO set, scanned for the descriptor's outputs.
+    Node,
+}
+
+/// An unsigned PSBT spending a funding transaction.
+pub struct Funded {
+    pub psbt: Psbt,
+    /// The descriptor of each input.
+    pub descriptors: Vec<Descriptor<DefiniteDescriptorKey>>,
+    /// What the inputs are worth.
+    pub total: Amount,
+    /// What the recipients receive.
+    pub paid: Amount,
+    /// What the miners receive.
+    pub fee: Amount,
+    /// The derivation index of the first input's descriptor, if it has
+    /// wildcards.
+    pub index: Option<u32>,
+    /// The spend path of each input, if --spend-path chose one for it.
+    pub spend_paths: Vec<Option<estimate::SpendPath>>,
+}
+
+impl FundingArgs {
+    /// Build the unsigned PSBT paying the recipient from the funding
+    /// transaction or the node's UTXO set, with each input updated from its
+    /// own descriptor.
+    pub fn build(
+        &self,
+        rpc: &rpc::RpcArgs,
+        tracer: &mut trace::Tracer,
+        events: &mut events::Events,
+    ) -> Result<Funded, Error> {
+        let mut node = None;
+        let funding_tx = match &self.rawtx {
+            Source::Transaction(tx) => Some(tx),
+            Source::Node => None,
+        };
+        let mut inputs = Vec::new();
+        let mut descriptors = Vec::new();
+        let mut indexes = Vec::new();
+        match &self.rawtx {
+            Source::Transaction(tx) if self.select.is_none() => {
+                let paid: Vec<&ScriptBuf> = match self.outpoint {
+                    Some(outpoint) => tx
+                        .output
+                        .get(outpoint.vout as usize)
+                        .map(|txout| &txout.script_pubkey)
+                        .into_iter()
+                        .collect(),
+                    None => tx.output.iter().map(|txout| &txout.script_pubkey).collect(),
+                };
+                let (descriptor, index) = self.derive(&paid)?;
+                inputs.push(utxo::find(tx, self.outpoint, &descriptor)?);
+                descriptors.push(descriptor);
+                indexes.push(index);
+            }
+            // Every output paying the descriptor is a coin to select from.
+            Source::Transaction(tx) => {
+                for (vout, txout) in tx.output.iter().enumerate() {
+                    let (descriptor, index) = match self.derive(&[&txout.script_pubkey]) {
+                        Ok(derived) => derived,
+                        Err(Error::UtxoNotFound(_)) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    if descriptor.script_pubkey() == txout.script_pubkey {
+                        let outpoint = OutPoint::new(tx.compute_txid(), vout as u32);
+                        inputs.push((outpoint, txout.clone()));
+                        descriptors.push(descriptor);
+                        indexes.push(index);
+                    }
+                }
+            }
+            Source::Node => {
+                if !self.input_descriptors.is_empty() {
+                    return Err(Error::Other(
+                        "--input-descriptor needs the funding transaction, pass the other \
+                         outputs with --input"
+                            .to_string(),
+                    ));
+                }
+                if self.utxos.is_empty() {
+                    inputs = self.fetch(node.insert(self.connect(rpc)?))?;
+                    for (outpoint, txout) in &inputs {
+                        let (descriptor, index) = self.derive(&[&txout.script_pubkey])?;
+                        if descriptor.script_pubkey() != txout.script_pubkey {
+                            return Err(Error::UtxoNotFound(format!(
+                                "{} doesn't pay the descriptor",
+                                outpoint
+                            )));
+                        }
+                        descriptors.push(descriptor);
+                        indexes.push(index);
+                    }
+                }
+            }
+        }
+        for outpoint in &self.utxos {
+            if inputs.iter().any(|(spent, _)| spent == outpoint) {
+                continue;
+            }
+            let known = funding_tx
+                .into_iter()
+                .chain(&self.prev_txs)
+                .find(|tx| tx.compute_txid() == outpoint.txid);
+            let txout = match (known, &self.rawtx) {
+                (Some(tx), _) => {
+                    tx.output
+                        .get(outpoint.vout as usize)
+                        .cloned()
+                        .ok_or_else(|| {
+                            Error::UtxoNotFound(format!("{} has no such output", outpoint))
+                        })?
+                }
+                (None, Source::Node) => {
+                    if node.is_none() {
+                        node = Some(self.connect(rpc)?);
+                    }
+                    node.as_ref()
+                        .expect("connected above")
+                        .prevout(outpoint)?
+                        .txout
+                }
+                (None, Source::Transaction(_)) => {
+                    return Err(Error::Other(format!(
+                        "Transaction {} is unknown, pass it with --prev-tx",
+                        outpoint.txid
+                    )))
+                }
+            };
+            let (descriptor, index) = self.derive(&[&txout.script_pubkey])?;
+            if descriptor.script_pubkey() != txout.script_pubkey {
+                return Err(Error::UtxoNotFound(format!(
+                    "{} doesn't pay the descriptor",
+                    outpoint
+                )));
+            }
+            inputs.push((*outpoint, txout));
+            descriptors.push(descriptor);
+            indexes.push(index);
+        }
+        // The --input-descriptor and --input outputs are always spent.
+        let selectable = inputs.len();
+        let mut ours: Vec<bool> = vec![true; selectable];
+        let funding = self.input_descriptors.iter().map(|(vout, descriptor)| {
+            let tx = funding_tx.expect("checked above");
+            (OutPoint::new(tx.compute_txid(), *vout), descriptor)
+        });
+        let others = self
+            .inputs
+            .iter()
+            .map(|(outpoint, descriptor)| (*outpoint, descriptor));
+        for (outpoint, descriptor) in funding.chain(others) {
+            if inputs.iter().any(|(spent, _)| *spent == outpoint) {
+                return Err(Error::Other(format!(
+                    "Output {} is spent more than once",
+                    outpoint
+                )));
+            }
+            let tx = funding_tx
+                .into_iter()
+                .chain(&self.prev_txs)
+                .find(|tx| tx.compute_txid() == outpoint.txid)
+                .ok_or_else(|| {
+                    format!(
+                        "Transaction {} is unknown, pass it with --prev-tx",
+                        outpoint.txid
+                    )
+                })?;
+            inputs.push(utxo::find(tx, Some(outpoint), descriptor)?);
+            descriptors.push(descriptor.clone());
+            indexes.push(None);
+            ours.push(false);
+        }
+        let mut changeless = false;
+        if let Some(strategy) = self.select {
+            let selection = self.pick(strategy, &inputs, &descriptors, selectable)?;
+            eprintln!(
+                "Selected {} of {} coins",
+                selection.picked.len(),
+                inputs.len()
+            );
+            changeless = selection.changeless;
+            inputs = only(inputs, &selection.picked);
+            descriptors = only(descriptors, &selection.picked);
+            indexes = only(indexes, &selection.picked);
+            ours = only(ours, &selection.picked);
+        }
+        if inputs.is_empty() {
+            return Err(Error::UtxoNotFound(
+                "No outputs paying the descriptor were found to spend".to_string(),
+            ));
+        }
+        let index = indexes[0];
+        let mut total = Amount::ZERO;
+        for (outpoint, witness_utxo) in &inputs {
+            events.emit(
+                "input_selected",
+                json!({ "outpoint": outpoint.to_string(), "value": witness_utxo.value.to_sat() }),
+            );
+            total = total
+                .checked_add(witness_utxo.value)
+                .ok_or("The input values overflow")?;
+        }
+
+        // The other outputs are paid in full, leaving the rest to split
+        // between the first recipient, the fee and change.
+        let extras = self.extras();
+        let mut paid = Amount::ZERO;
+        for txout in &extras {
+            paid = paid
+                .checked_add(txout.value)
+                .ok_or("The output values overflow")?;
+        }
+        let available = total.checked_sub(paid).ok_or_else(|| {
+            format!(
+                "Insufficient funds: the inputs are worth {} sat but the --output ones take {} \
+                 sat",
+                total.to_sat(),
+                paid.to_sat()
+            )
+        })?;
+        let outpoints: Vec<OutPoint> = inputs.iter().map(|(outpoint, _)| *outpoint).collect();
+        let (split, fee, change) = match self.feerate {
+            Some(feerate) => {
+                self.estimate(&outpoints, &descriptors, available, feerate, changeless)
+            }
+            None => build::split(available, self.amount, self.fee, self.subtract_fee)
+                .map(|split| (split, self.fee, self.change())),
+        }
+        .map_err(|e| match paid {
+            Amount::ZERO => e,
+            paid => format!(
+                "{}, after the {} sat of the --output ones",
+                e,
+                paid.to_sat()
+            ),
+        })?;
+        let mut outputs = vec![TxOut {
+            script_pubkey: self.recipient.clone(),
+            value: split.recipient,
+        }];
+        outputs.extend(extras);
+        for (i, txout) in outputs.iter().enumerate() {
+            let dust = txout.script_pubkey.minimal_non_dust();
+            if !txout.script_pubkey.is_op_return() && txout.value < dust {
+                return Err(Error::Other(format!(
+                    "Output {} of {} sat is below the dust threshold of {} sat",
+                    i,
+                    txout.value.to_sat(),
+                    dust.to_sat()
+                )));
+            }
+        }
+        let mut change_output = None;
+        if split.change > Amount::ZERO {
+            let change = change.ok_or_else(|| {
+                format!(
+                    "{} sat of the input would be left unaccounted for, pass --change or raise \
+                     --fee",
+                    split.change.to_sat()
+                )
+            })?;
+            change_output = Some(outputs.len());
+            outputs.push(TxOut {
+                script_pubkey: change,
+                value: split.change,
+            });
+        }
+        let mut psbt = build::create_psbt(&outpoints, outputs);
+        // Only the inputs spending the descriptor take its spend path.
+        let mut plans = Vec::new();
+        for (i, (descriptor, ours)) in descriptors.iter().zip(&ours).enumerate() {
+            plans.push(match &self.spend_path {
+                Some(selector) if *ours => {
+                    let (n, path) = estimate::select_path(&self.descriptor, descriptor, selector)
+                        .map_err(Error::Descriptor)?;
+                    let plan = descriptor.clone().plan(&path.assets()).map_err(|_| {
+                        Error::Descriptor(format!(
+                            "Input {} can't be planned for spend path {}: {}",
+                            i,
+                            n,
+                            path.describe().join(", ")
+                        ))
+                    })?;
+                    eprintln!(
+                        "Input {} spends path {}: {}",
+                        i,
+                        n,
+                        path.describe().join(", ")
+                    );
+                    Some((path, plan))
+                }
+                _ => None,
+            });
+        }
+        let mut locktime = self.locktime;
+        for (i, ((txin, descriptor), planned)) in psbt
+            .unsigned_tx
+            .input
+            .iter_mut()
+            .zip(&descriptors)
+            .zip(&plans)
+            .enumerate()
+        {
+            let (after, older) = match planned {
+                Some((_, plan)) => (
+                    plan.absolute_timelock.map(|lock| lock.to_consensus_u32()),
+                    plan.relative_timelock.map(|lock| lock.to_sequence()),
+                ),
+                None => policy::needed_timelocks(descriptor)?,
+            };
+            if self.locktime.is_none() && after > locktime {
+                eprintln!(
+                    "Input {} needs a lock time of {}",
+                    i,
+                    after.expect("above none")
+                );
+                locktime = after;
+            }
+            match (self.sequence, older) {
+                (Some(sequence), _) => txin.sequence = Sequence::from_consensus(sequence),
+                (None, Some(older)) => {
+                    eprintln!("Input {} needs a sequence of {}", i, older);
+                    txin.sequence = older;
+                }
+                (None, None) if self.rbf => txin.sequence = Sequence::ENABLE_RBF_NO_LOCKTIME,
+                (None, None) => {}
+            }
+        }
+        if let Some(locktime) = locktime {
+            build::set_lock_time(&mut psbt, LockTime::from_consensus(locktime));
+        }
+        tracer.stage("created", &psbt);
+
+        // Plan each input's satisfaction with its own descriptor. Legacy
+        // and nested segwit inputs also need the transaction they spend,
+        // which the node has to look up for the coins it found.
+        for (((input, (outpoint, utxo)), descriptor), planned) in psbt
+            .inputs
+            .iter_mut()
+            .zip(inputs)
+            .zip(&descriptors)
+            .zip(&plans)
+        {
+            let mut prev_tx = funding_tx
+                .into_iter()
+                .chain(&self.prev_txs)
+                .find(|tx| tx.compute_txid() == outpoint.txid)
+                .cloned();
+            if let (None, Some(node)) = (&prev_tx, &node) {
+                if build::needs_prev_tx(descriptor) {
+                    prev_tx = node.prevout(&outpoint)?.tx;
+                }
+            }
+            match planned {
+                // Leaving out the other paths' keys and scripts.
+                Some((_, plan)) => {
+                    plan.update_psbt_input(input);
+                    build::add_utxo(input, descriptor, utxo, prev_tx)?;
+                }
+                None => build::update_input(input, descriptor, utxo, prev_tx)?,
+            }
+        }
+        // Change back to a descriptor we know gets its scripts and key
+        // derivations too.
+        if let Some(i) = change_output {
+            let script_pubkey = &psbt.unsigned_tx.output[i].script_pubkey;
+            let descriptor = self
+                .change_descriptor
+                .iter()
+                .chain(&descriptors)
+                .find(|descriptor| descriptor.script_pubkey() == *script_pubkey)
+                .cloned();
+            if let Some(descriptor) = descriptor {
+                psbt.update_output_with_descriptor(i, &descriptor)
+                    .map_err(|e| {
+                        Error::Descriptor(format!("Can't update the change output: {}", e))
+                    })?;
+            }
+        }
+        tracer.stage("descriptor update", &psbt);
+        Ok(Funded {
+            psbt,
+            descriptors,
+            total,
+            paid: paid + split.recipient,
+            fee,
+            index,
+            spend_paths: plans
+                .into_iter()
+                .map(|planned| planned.map(|(path, _)| path))
+                .collect(),
+        })
+    }
+
+    // Where --change or --change-descriptor sends the change, if either
+    // does.
+    fn change(&self) -> Option<ScriptBuf> {
+        self.change.clone().or_else(|| {
+            self.change_descriptor
+                .as_ref()
+                .map(|descriptor| descriptor.script_pubkey())
+        })
+    }
+
+    // The outputs besides the first recipient and change: the --output
+    // ones, then the OP_RETURN one.
+    fn extras(&self) -> Vec<TxOut> {
+        let op_return = self.op_return.iter().map(|script_pubkey| TxOut {
+            script_pubkey: script_pubkey.clone(),
+            value: Amount::ZERO,
+        });
+        self.outputs.iter().cloned().chain(op_return).collect()
+    }
+
+    // Pick which of `inputs` to spend by `strategy`. Those from
+    // `selectable` on, the --input-descriptor and --input ones, are always
+    // spent, as are those --coin-control includes.
+    fn pick(
+        &self,
+        strategy: select::Strategy,
+        inputs: &[(OutPoint, TxOut)],
+        descriptors: &[Descriptor<DefiniteDescriptorKey>],
+        selectable: usize,
+    ) -> Result<select::Selection, Error> {
+        let controlled = |outpoint: &OutPoint| {
+            self.coin_control.iter().find_map(|control| match control {
+                CoinControl::Include(o) if o == outpoint => Some(true),
+                CoinControl::Exclude(o) if o == outpoint => Some(false),
+                _ => None,
+            })
+        };
+        for control in &self.coin_control {
+            let (CoinControl::Include(outpoint) | CoinControl::Exclude(outpoint)) = control;
+            if !inputs.iter().any(|(o, _)| o == outpoint) {
+                return Err(Error::UtxoNotFound(format!(
+                    "--coin-control names {}, which isn't an output paying the descriptor",
+                    outpoint
+                )));
+            }
+        }
+
+        let mut paid = self.amount;
+        for txout in self.extras() {
+            paid = paid
+                .checked_add(txout.value)
+                .ok_or("The output values overflow")?;
+        }
+        // At a feerate each coin pays for its own input, and the selection
+        // for the rest of the transaction.
+        let feerate = self.feerate.filter(|_| !self.subtract_fee);
+        let fee = |feerate: FeeRate, weight: u64| {
+            feerate
+                .fee_wu(Weight::from_wu(weight))
+                .ok_or("The fee overflows")
+        };
+        let (target, cost_of_change) = match feerate {
+            _ if self.subtract_fee => (paid, Amount::ZERO),
+            None => (
+                paid.checked_add(self.fee).ok_or("The fee overflows")?,
+                Amount::ZERO,
+            ),
+            Some(feerate) => {
+                let outputs = std::iter::once(TxOut {
+                    script_pubkey: self.recipient.clone(),
+                    value: Amount::ZERO,
+                })
+                .chain(self.extras())
+                .collect();
+                let base = build::create_psbt(&[], outputs);
+                let base_fee = fee(feerate, policy::satisfied_weight(&base, &[]))?;
+                let change = TxOut {
+                    script_pubkey: self
+                        .change()
+                        .unwrap_or_else(|| descriptors[0].script_pubkey()),
+                    value: Amount::ZERO,
+                };
+                let spend_change = policy::max_satisfaction_weight(&descriptors[0])
+                    .map_err(|e| format!("Can't estimate the satisfaction weight: {}", e))?;
+                let cost_of_change = fee(feerate, change.weight().to_wu())?
+                    + fee(feerate, estimate::input_weight(spend_change))?;
+                (
+                    paid.checked_add(base_fee).ok_or("The fee overflows")?,
+                    cost_of_change,
+                )
+            }
+        };
+        let mut candidates = Vec::new();
+        let mut pool = Vec::new();
+        for (i, ((outpoint, txout), descriptor)) in inputs.iter().zip(descriptors).enumerate() {
+            let included = controlled(outpoint);
+            if included == Some(false) {
+                continue;
+            }
+            let fee = match feerate {
+                Some(feerate) => {
+                    let satisfaction = policy::max_satisfaction_weight(descriptor)
+                        .map_err(|e| format!("Can't estimate the satisfaction weight: {}", e))?;
+                    fee(feerate, estimate::input_weight(satisfaction))?
+                }
+                None => Amount::ZERO,
+            };
+            candidates.push(select::Candidate {
+                effective: txout.value.to_sat() as i64 - fee.to_sat() as i64,
+                required: i >= selectable || included == Some(true),
+            });
+            pool.push(i);
+        }
+        let selection = select::select(strategy, &candidates, target, cost_of_change)?;
+        Ok(select::Selection {
+            picked: selection.picked.into_iter().map(|i| pool[i]).collect(),
+            changeless: selection.changeless,
+        })
+    }
+
+    // The descriptor at the index given or, failing that, the first index
+    // in the range deriving one of the `paid` scripts.
+    fn derive(
+        &self,
+        paid: &[&ScriptBuf],
+    ) -> Result<(Descriptor<DefiniteDescriptorKey>, Option<u32>), Error> {
+        let descriptors = self
+            .descriptor
+            .clone()
+            .into_single_descriptors()
+            .map_err(|e| Error::Descriptor(format!("Can't split the descriptor: {}", e)))?;
+        let at = |descriptor: &Descriptor<DescriptorPublicKey>, index: u32| {
+            descriptor
+                .at_derivation_index(index)
+                .map_err(|e| Error::Descriptor(format!("Can't derive index {}: {}", index, e)))
+        };
+        if !self.descriptor.has_wildcard() {
+            return Ok((at(&descriptors[0], 0)?, None));
+        }
+        if let Some(index) = self.index {
+            if descriptors.len() > 1 {
+                return Err(Error::Descriptor(
+                    "Pass one path of the multipath descriptor with --index, or let the funding \
+                     transaction be searched"
+                        .to_string(),
+                ));
+            }
+            return Ok((at(&descriptors[0], index)?, Some(index)));
+        }
+
+        for index in self.range.clone() {
+            for descriptor in &descriptors {
+                let derived = at(descriptor, index)?;
+                if paid.contains(&&derived.script_pubkey()) {
+                    return Ok((derived, Some(index)));
+                }
+            }
+        }
+        Err(Error::UtxoNotFound(format!(
+            "The funding transaction pays none of the descriptor's scripts at indexes {}-{}, \
+             pass --index or widen --range",
+            self.range.start(),
+            self.range.end()
+        )))
+    }
+
+    // The node of the --rpc-* options, or the --esplora server standing in
+    // for it.
+    fn connect(&self, rpc: &rpc::RpcArgs) -> Result<Chain, String> {
+        Chain::connect(self.esplora.as_deref(), rpc)
+    }
+
+    // The outputs to spend from the node: the --outpoint one, or else every
+    // one paying the descriptor at the derivation index or range given.
+    fn fetch(&self, chain: &Chain) -> Result<Vec<(OutPoint, TxOut)>, Error> {
+        if let Some(outpoint) = self.outpoint {
+            return Ok(vec![(outpoint, chain.prevout(&outpoint)?.txout)]);
+        }
+        let range = self.index.map_or(self.range.clone(), |index| index..=index);
+        let utxos = chain.scan(&self.descriptor, &range)?;
+        if utxos.is_empty() {
+            return Err(Error::UtxoNotFound(format!(
+                "The node's UTXO set holds no outputs paying the descriptor at indexes {}-{}",
+                range.start(),
+                range.end()
+            )));
+        }
+        Ok(utxos)
+    }
+
+    // Work out the fee paying `feerate` for spending `outpoints` under
+    // `descriptors`, along with the change output's script if there's one.
+    // `total` is what's left of the inputs after the extra outputs. Coins
+    // selected to need no change get no change output.
+    fn estimate(
+        &self,
+        outpoints: &[OutPoint],
+        descriptors: &[Descriptor<DefiniteDescriptorKey>],
+        total: Amount,
+        feerate: FeeRate,
+        changeless: bool,
+    ) -> Result<(build::Split, Amount, Option<ScriptBuf>), String> {
+        let satisfactions = descriptors
+            .iter()
+            .map(policy::max_satisfaction_weight)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Can't estimate the satisfaction weight: {}", e))?;
+        let change = self
+            .change()
+            .unwrap_or_else(|| descriptors[0].script_pubkey());
+        let fee = |scripts: Vec<ScriptBuf>| {
+            let outputs = scripts
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    script_pubkey,
+                    value: Amount::ZERO,
+                })
+                .chain(self.extras())
+                .collect();
+            let psbt = build::create_psbt(outpoints, outputs);
+            let vsize = policy::satisfied_weight(&psbt, &satisfactions).div_ceil(4);
+            feerate.fee_vb(vsize).ok_or("The fee overflows")
+        };
+
+        if !changeless {
+            let with_change = fee(vec![self.recipient.clone(), change.clone()])?;
+            // Without the change output the fee is lower, which may be what
+            // makes the spend affordable.
+            let split = build::split(total, self.amount, with_change, self.subtract_fee).ok();
+            if let Some(split) = split.filter(|split| split.change >= change.minimal_non_dust()) {
+                return Ok((split, with_change, Some(change)));
+            }
+        }
+        // Change too small to be worth an output goes to the miners.
+        let without = fee(vec![self.recipient.clone()])?;
+        let split = build::split(total, self.amount, without, self.subtract_fee)?;
+        if split.change > Amount::ZERO {
+            eprintln!(
+                "Paying the {} sat of change as fee, it's too small for an output",
+                split.change.to_sat()
+            );
+        }
+        let recipient = build::Split {
+            recipient: split.recipient,
+            change: Amount::ZERO,
+        };
+        Ok((recipient, without + split.change, None))
+    }
+}
+
+// The `picked` items, in order.
+fn only<T>(items: Vec<T>, picked: &[usize]) -> Vec<T> {
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| picked.contains(i))
+        .map(|(_, item)| item)
+        .collect()
+}
+
+/// Create the unsigned PSBT for the signers, from an intent file or a
+/// funding transaction.
+pub fn run(
+    args: CreateArgs,
+    tracer: &mut trace::Tracer,
+    events: &mut events::Events,
+) -> Result<(), Error> {
+    let psbt = match (&args.intent, &args.funding) {
+        (Some(path), _) => intent::compile(path, &args.rpc)?,
+        (None, Some(funding)) => {
+            let funded = funding.build(&args.rpc, tracer, events)?;
+            let descriptors: Vec<_> = funded.descriptors.iter().collect();
+            policy::check_standard(&funded.psbt, &descriptors)?;
+            policy::check_locktime(&funded.psbt, &descriptors)?;
+            eprintln!(
+                "Paying {} sat from {} inputs worth {} sat with a fee of {} sat",
+                funded.paid.to_sat(),
+                funded.psbt.inputs.len(),
+                funded.total.to_sat(),
+                funded.fee.to_sat()
+            );
+            funded.psbt
+        }
+        (None, None) => unreachable!("clap requires one or the other"),
+    };
+    Ok(args.out.store(psbt)?)
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+
+use miniscript::bitcoin::hashes::{sha256, Hash};
+use miniscript::bitcoin::hex::DisplayHex;
+use miniscript::bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use miniscript::bitcoin::taproot::TapLeafHash;
+use miniscript::bitcoin::{Address, EcdsaSighashType, Psbt, TapSighashType, Transaction, TxOut};
+use miniscript::psbt::PsbtExt;
+
+use crate::{annex, build, network, sign, utxo};
+
+/// A detailed, field by field breakdown of a transaction.
+pub struct Breakdown<'a>(pub &'a Transaction);
+
+impl fmt::Display for Breakdown<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tx = self.0;
+        writeln!(f, "Transaction {}", tx.compute_txid())?;
+        writeln!(f, "  wtxid    {}", tx.compute_wtxid())?;
+        writeln!(f, "  version  {}", tx.version)?;
+        let lock_time = if tx.lock_time.to_consensus_u32() == 0 {
+            "none".to_string()
+        } else if tx.lock_time.is_block_height() {
+            format!("block height {}", tx.lock_time)
+        } else {
+            format!("UNIX time {}", tx.lock_time)
+        };
+        writeln!(f, "  locktime {}", lock_time)?;
+        writeln!(
+            f,
+            "  size     {} bytes, weight {} WU, {} vB",
+            tx.total_size(),
+            tx.weight().to_wu(),
+            tx.vsize()
+        )?;
+
+        writeln!(f, "  inputs ({}):", tx.input.len())?;
+        for (i, txin) in tx.input.iter().enumerate() {
+            if tx.is_coinbase() {
+                writeln!(f, "    {}: coinbase", i)?;
+            } else {
+                writeln!(f, "    {}: {}", i, txin.previous_output)?;
+            }
+            let sequence = txin.sequence;
+            let mut meaning = Vec::new();
+            if sequence.is_rbf() {
+                meaning.push("signals RBF".to_string());
+            }
+            if let Some(lock) = sequence.to_relative_lock_time() {
+                meaning.push(format!("relative lock time of {}", lock));
+            }
+            if sequence.is_final() {
+                meaning.push("final".to_string());
+            }
+            writeln!(
+                f,
+                "       sequence   {:#010x} ({})",
+                sequence.0,
+                meaning.join(", ")
+            )?;
+            if !txin.script_sig.is_empty() {
+                writeln!(f, "       script_sig {}", txin.script_sig)?;
+            }
+            if !txin.witness.is_empty() {
+                writeln!(f, "       witness ({} items):", txin.witness.len())?;
+                for (j, item) in txin.witness.iter().enumerate() {
+                    if item.is_empty() {
+                        writeln!(f, "         {}: <empty>", j)?;
+                    } else {
+                        writeln!(
+                            f,
+                            "         {}: {} ({} bytes)",
+                            j,
+                            item.as_hex(),
+                            item.len()
+                        )?;
+                    }
+                }
+            }
+        }
+
+        writeln!(f, "  outputs ({}):", tx.output.len())?;
+        for (i, txout) in tx.output.iter().enumerate() {
+            let spk = &txout.script_pubkey;
+            let destination = match Address::from_script(spk, network()) {
+                Ok(address) => format!("{} ({})", address, utxo::script_type(spk)),
+                Err(_) if spk.is_op_return() => "OP_RETURN data".to_string(),
+                Err(_) => utxo::script_type(spk).to_string(),
+            };
+            writeln!(
+                f,
+                "    {}: {} sat to {}",
+                i,
+                txout.value.to_sat(),
+                destination
+            )?;
+            writeln!(f, "       script {}", spk)?;
+        }
+        let total: u64 = tx.output.iter().map(|o| o.value.to_sat()).sum();
+        writeln!(f, "  total output {} sat", total)
+    }
+}
+
+/// The message every input of a PSBT commits to, for checking against what
+/// a signing device displays.
+///
+/// Segwit v0 inputs show their BIP143 preimage, whose double SHA256 is the
+/// sighash. Taproot inputs show the BIP341 message of the key path and of
+/// every leaf, prefixed with the epoch and hashed with the TapSighash tag,
+/// which commits to the input's annex if it has one.
+pub struct Sighashes<'a>(pub &'a Psbt);
+
+impl fmt::Display for Sighashes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let psbt = self.0;
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        let prevouts: Option<Vec<TxOut>> = (0..psbt.inputs.len())
+            .map(|i| build::spent_utxo(psbt, i))
+            .collect();
+        writeln!(f, "Sighashes of {}", psbt.unsigned_tx.compute_txid())?;
+        for (i, input) in psbt.inputs.iter().enumerate() {
+            writeln!(f, "  input {}:", i)?;
+            let spk = input.witness_utxo.as_ref().map(|utxo| &utxo.script_pubkey);
+            if !spk.is_some_and(|spk| spk.is_p2tr()) {
+                let digest = match psbt.sighash_msg(i, &mut cache, None) {
+                    Ok(msg) => msg.to_secp_msg(),
+                    Err(e) => {
+                        writeln!(f, "    can't compute the sighash: {}", e)?;
+                        continue;
+                    }
+                };
+                let ty = input
+                    .sighash_type
+                    .map(|ty| ty.ecdsa_hash_ty())
+                    .unwrap_or(Ok(EcdsaSighashType::All));
+                match &ty {
+                    Ok(ty) => writeln!(f, "    sighash type {}", ty)?,
+                    Err(e) => writeln!(f, "    sighash type invalid: {}", e)?,
+                }
+                if let Some(preimage) = sign::segwit_v0_preimage(psbt, i, &mut cache) {
+                    writeln!(f, "    BIP143 preimage {}", preimage.as_hex())?;
+                    let hashed = sha256::Hash::hash(sha256::Hash::hash(&preimage).as_ref());
+                    writeln!(
+                        f,
+                        "    sighash SHA256d(preimage) {}",
+                        hashed.as_byte_array().as_hex()
+                    )?;
+                    if hashed.as_byte_array() != digest.as_ref() {
+                        writeln!(
+                            f,
+                            "    but the signatures commit to {}",
+                            digest.as_ref().as_hex()
+                        )?;
+                    }
+                } else {
+                    writeln!(f, "    sighash {}", digest.as_ref().as_hex())?;
+                }
+                continue;
+            }
+
+            let prevouts = match &prevouts {
+                Some(prevouts) => Prevouts::All(prevouts),
+                None => {
+                    writeln!(f, "    needs the witness UTXO of every input")?;
+                    continue;
+                }
+            };
+            let ty = match input.sighash_type.map(|ty| ty.taproot_hash_ty()) {
+                None => TapSighashType::Default,
+                Some(Ok(ty)) => ty,
+                Some(Err(e)) => {
+                    writeln!(f, "    sighash type invalid: {}", e)?;
+                    continue;
+                }
+            };
+            writeln!(f, "    sighash type {}", ty)?;
+            let annex = annex::get(input).map(|annex| Annex::new(annex).expect("checked by get"));
+            if let Some(annex) = &annex {
+                writeln!(f, "    annex {}", annex.as_bytes().as_hex())?;
+            }
+            let tag = sha256::Hash::hash(b"TapSighash");
+            writeln!(
+                f,
+                "    tag SHA256(\"TapSighash\") {}",
+                tag.as_byte_array().as_hex()
+            )?;
+            writeln!(
+                f,
+                "    sighash SHA256(tag || tag || message), each message starting with epoch 00"
+            )?;
+            let leaves = input
+                .tap_scripts
+                .values()
+                .map(|(script, version)| TapLeafHash::from_script(script, *version));
+            let paths = std::iter::once(None).chain(leaves.map(Some));
+            for leaf in paths {
+                let leaf_code = leaf.map(|leaf| (leaf, 0xFFFFFFFF));
+                let mut msg = Vec::new();
+                let digest = cache
+                    .taproot_encode_signing_data_to(
+                        &mut msg,
+                        i,
+                        &prevouts,
+                        annex.clone(),
+                        leaf_code,
+                        ty,
+                    )
+                    .map_err(|e| e.to_string())
+                    .and_then(|()| {
+                        cache
+                            .taproot_signature_hash(i, &prevouts, annex.clone(), leaf_code, ty)
+                            .map_err(|e| e.to_string())
+                    });
+                match (leaf, digest) {
+                    (_, Err(e)) => writeln!(f, "    can't compute the sighash: {}", e)?,
+                    (None, Ok(digest)) => {
+                        writeln!(f, "    key path message {}", msg.as_hex())?;
+                        writeln!(
+                            f,
+                            "    key path sighash {}",
+                            digest.as_byte_array().as_hex()
+                        )?;
+                    }
+                    (Some(leaf), Ok(digest)) => {
+                        writeln!(f, "    leaf {}", leaf)?;
+                        writeln!(f, "      message {}", msg.as_hex())?;
+                        writeln!(f, "      sighash {}", digest.as_byte_array().as_hex())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use miniscript::psbt::{Error as PsbtError, InputError};
+
+/// What went wrong, told apart so scripts driving the tool can react to
+/// the exit code rather than parse the message.
+#[derive(Debug)]
+pub enum Error {
+    /// A descriptor that can't be split, derived or spent from.
+    Descriptor(String),
+    /// No output paying the descriptor could be found to spend.
+    UtxoNotFound(String),
+    /// The PSBT's signatures and preimages satisfy none of the ways of
+    /// spending an input yet.
+    Unsatisfied(String),
+    /// A signature is invalid or commits to another sighash type than the
+    /// input requires.
+    Signature(String),
+    /// The PSBT can't be finalized or its transaction extracted.
+    Finalize(String),
+    /// An input's UTXO contradicts the previous transaction it spends, or
+    /// there's no previous transaction to check it against.
+    UtxoMismatch(String),
+    /// Anything else, such as bad input files or an unreachable node.
+    Other(String),
+}
+
+impl Error {
+    /// The process exit code reporting this error. Clap exits with 2 on
+    /// bad arguments, so the codes start at 3.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Descriptor(_) => 3,
+            Error::UtxoNotFound(_) => 4,
+            Error::Unsatisfied(_) => 5,
+            Error::Signature(_) => 6,
+            Error::Finalize(_) => 7,
+            Error::UtxoMismatch(_) => 8,
+        }
+    }
+
+    /// What to try next, if there's anything more to say than the message.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Error::Descriptor(_) => {
+                Some("Check the descriptor with `inspect` or `verify-address`.")
+            }
+            Error::UtxoNotFound(_) => Some(
+                "Check that the funding transaction pays the descriptor, and the --index, \
+                 --range or --outpoint given.",
+            ),
+            Error::Unsatisfied(_) => Some(
+                "Pass the PSBT on to the remaining signers, or add the --preimage it needs; \
+                 `inspect` lists what each spend path requires.",
+            ),
+            Error::Signature(_) => {
+                Some("Have the signer named sign again, with the sighash type the input requires.")
+            }
+            Error::UtxoMismatch(_) => Some(
+                "Check the --prev-tx transactions given, or get the PSBT again from a \
+                 coordinator you trust.",
+            ),
+            Error::Finalize(_) | Error::Other(_) => None,
+        }
+    }
+
+    /// Classify the errors of finalizing a PSBT: missing signatures or
+    /// preimages, bad signatures, or something else wrong with the inputs.
+    pub fn finalizing(errors: &[PsbtError]) -> Error {
+        let message = format!(
+            "Can't finalize the PSBT: {}",
+            errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let input_errors = || {
+            errors.iter().filter_map(|e| match e {
+                PsbtError::InputError(e, _) => Some(e),
+                _ => None,
+            })
+        };
+        if input_errors().any(|e| {
+            matches!(
+                e,
+                InputError::InvalidSignature { .. } | InputError::WrongSighashFlag { .. }
+            )
+        }) {
+            Error::Signature(message)
+        } else if input_errors().any(|e| {
+            matches!(
+                e,
+                InputError::CouldNotSatisfyTr
+                    | InputError::MiniscriptError(miniscript::Error::CouldNotSatisfy)
+            )
+        }) {
+            Error::Unsatisfied(message)
+        } else {
+            Error::Finalize(message)
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Descriptor(message)
+            | Error::UtxoNotFound(message)
+            | Error::Unsatisfied(message)
+            | Error::Signature(message)
+            | Error::Finalize(message)
+            | Error::UtxoMismatch(message)
+            | Error::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Most of the tool reports errors as plain messages, which are passed
+// through unclassified.
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Other(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Error {
+        Error::Other(message.to_string())
+    }
+}
+
+impl From<Error> for String {
+    fn from(error: Error) -> String {
+        error.to_string()
+    }
+}
@@ -0,0 +1,171 @@
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use miniscript::bitcoin::consensus::encode::deserialize_hex;
+use miniscript::bitcoin::hashes::{sha256, Hash};
+use miniscript::bitcoin::{OutPoint, Script, Transaction, TxOut, Txid};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::Descriptor;
+use serde::Deserialize;
+
+use crate::rpc::Prevout;
+
+/// How long the server has to answer before it's given up on.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How many derivation indexes in a row without coins end the search of a
+/// descriptor with wildcards, as wallets stop handing out addresses past
+/// the last one used.
+const GAP_LIMIT: u32 = 20;
+
+/// An Esplora HTTP API looking up coins and transactions for those not
+/// running a node.
+pub struct Esplora {
+    url: String,
+}
+
+// An unspent output as /scripthash/:hash/utxo lists it.
+#[derive(Deserialize)]
+struct Utxo {
+    txid: Txid,
+    vout: u32,
+    status: Status,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    confirmed: bool,
+}
+
+impl Esplora {
+    pub fn new(url: &str) -> Esplora {
+        Esplora {
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// The transaction `txid`, confirmed or in the mempool.
+    pub fn transaction(&self, txid: &Txid) -> Result<Transaction, String> {
+        let hex = self
+            .get(&format!("/tx/{}/hex", txid))
+            .map_err(|e| format!("Can't fetch {}: {}", txid, e))?;
+        deserialize_hex(hex.trim())
+            .map_err(|e| format!("{} isn't a valid transaction: {}", txid, e))
+    }
+
+    /// The output `outpoint` spends, along with the transaction creating
+    /// it.
+    pub fn prevout(&self, outpoint: &OutPoint) -> Result<Prevout, String> {
+        let tx = self.transaction(&outpoint.txid)?;
+        let txout = tx
+            .output
+            .get(outpoint.vout as usize)
+            .cloned()
+            .ok_or_else(|| format!("{} has no such output", outpoint))?;
+        Ok(Prevout {
+            txout,
+            tx: Some(tx),
+        })
+    }
+
+    /// The output `outpoint` refers to if it's confirmed and unspent, even
+    /// by a transaction in the mempool.
+    pub fn unspent(&self, outpoint: &OutPoint) -> Result<Option<TxOut>, String> {
+        let lookup = |path: String| -> Result<serde_json::Value, String> {
+            let body = self
+                .get(&path)
+                .map_err(|e| format!("Can't look up {}: {}", outpoint, e))?;
+            serde_json::from_str(&body)
+                .map_err(|e| format!("The server answered with invalid JSON: {}", e))
+        };
+        let status = lookup(format!("/tx/{}/status", outpoint.txid))?;
+        if status["confirmed"] != true {
+            return Ok(None);
+        }
+        let outspend = lookup(format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout))?;
+        if outspend["spent"] != false {
+            return Ok(None);
+        }
+        self.prevout(outpoint).map(|prevout| Some(prevout.txout))
+    }
+
+    /// Find the confirmed unspent outputs paying `descriptor`, at the
+    /// derivation indexes `range` if it has wildcards, up to the first
+    /// [`GAP_LIMIT`] indexes in a row paying none.
+    pub fn scan(
+        &self,
+        descriptor: &Descriptor<DescriptorPublicKey>,
+        range: &RangeInclusive<u32>,
+    ) -> Result<Vec<(OutPoint, TxOut)>, String> {
+        let singles = descriptor
+            .clone()
+            .into_single_descriptors()
+            .map_err(|e| format!("Can't split the descriptor: {}", e))?;
+        let range = if descriptor.has_wildcard() {
+            range.clone()
+        } else {
+            0..=0
+        };
+        let mut found = Vec::new();
+        for single in &singles {
+            let mut gap = 0;
+            for index in range.clone() {
+                let script_pubkey = single
+                    .at_derivation_index(index)
+                    .map_err(|e| format!("Can't derive index {}: {}", index, e))?
+                    .script_pubkey();
+                let utxos = self.utxos(&script_pubkey)?;
+                if utxos.is_empty() {
+                    gap += 1;
+                    if gap == GAP_LIMIT {
+                        break;
+                    }
+                    continue;
+                }
+                gap = 0;
+                for outpoint in utxos {
+                    found.push((outpoint, self.prevout(&outpoint)?.txout));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    // The confirmed outputs paying `script_pubkey` that are still unspent.
+    fn utxos(&self, script_pubkey: &Script) -> Result<Vec<OutPoint>, String> {
+        // Esplora names scripts by the hex of their SHA256, as Electrum
+        // servers do but not reversed.
+        let scripthash = sha256::Hash::hash(script_pubkey.as_bytes());
+        let body = self
+            .get(&format!("/scripthash/{}/utxo", scripthash))
+            .map_err(|e| {
+                format!(
+                    "Can't look up the outputs paying {:x}: {}",
+                    script_pubkey, e
+                )
+            })?;
+        let utxos: Vec<Utxo> = serde_json::from_str(&body)
+            .map_err(|e| format!("The server answered with an invalid UTXO list: {}", e))?;
+        Ok(utxos
+            .into_iter()
+            .filter(|utxo| utxo.status.confirmed)
+            .map(|utxo| OutPoint::new(utxo.txid, utxo.vout))
+            .collect())
+    }
+
+    fn get(&self, path: &str) -> Result<String, String> {
+        let response = minreq::get(format!("{}{}", self.url, path))
+            .with_timeout(TIMEOUT.as_secs())
+            .send()
+            .map_err(|e| e.to_string())?;
+        let body = response.as_str().unwrap_or_default().to_string();
+        if response.status_code != 200 {
+            return Err(format!(
+                "answered {} {}: {}",
+                response.status_code,
+                response.reason_phrase,
+                body.trim()
+            ));
+        }
+        Ok(body)
+    }
+}
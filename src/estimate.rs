@@ -0,0 +1,483 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use clap::Args;
+use miniscript::bitcoin::hashes::{hash160, ripemd160, sha256};
+use miniscript::bitcoin::{secp256k1, PublicKey, Weight};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::plan::Assets;
+use miniscript::policy::{semantic::Policy, Liftable};
+use miniscript::{hash256, AbsLockTime, Descriptor, ForEachKey, RelLockTime};
+use serde_json::json;
+
+use crate::{parse, policy};
+
+/// How many spend paths are listed before giving up and asking for one.
+const MAX_PATHS: usize = 1024;
+/// The weight of an input before it's satisfied: its outpoint, sequence and
+/// script_sig length.
+const TXIN_WEIGHT: u64 = (36 + 4 + 1) * 4;
+
+#[derive(Args)]
+pub struct EstimateWeightArgs {
+    /// The descriptor of the inputs. Wildcards don't change the weight, so
+    /// ranged descriptors are measured at index 0.
+    #[arg(value_parser = parse::ranged_descriptor)]
+    descriptor: Descriptor<DescriptorPublicKey>,
+    /// Only estimate the cheapest path satisfied with these, comma
+    /// separated: keys as written in the descriptor, after(N), older(N),
+    /// and sha256(H), hash256(H), ripemd160(H) or hash160(H) for preimages.
+    #[arg(long)]
+    branch: Option<String>,
+    /// Print the estimates as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+/// What satisfies a spend path.
+#[derive(Clone, Default, PartialEq)]
+pub struct SpendPath {
+    /// The keys that have to sign.
+    pub keys: Vec<DescriptorPublicKey>,
+    /// The lock time the transaction needs, if any.
+    pub after: Option<AbsLockTime>,
+    /// The sequence the input needs, if any.
+    pub older: Option<RelLockTime>,
+    /// The hashes whose preimages have to be revealed.
+    pub hashes: Vec<Hashlock>,
+}
+
+/// A hash whose preimage is revealed.
+#[derive(Clone, PartialEq)]
+pub enum Hashlock {
+    Sha256(sha256::Hash),
+    Hash256(hash256::Hash),
+    Ripemd160(ripemd160::Hash),
+    Hash160(hash160::Hash),
+}
+
+impl fmt::Display for Hashlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hashlock::Sha256(h) => write!(f, "sha256({})", h),
+            Hashlock::Hash256(h) => write!(f, "hash256({})", h),
+            Hashlock::Ripemd160(h) => write!(f, "ripemd160({})", h),
+            Hashlock::Hash160(h) => write!(f, "hash160({})", h),
+        }
+    }
+}
+
+/// What satisfying one spend path of a descriptor costs.
+pub struct Estimate {
+    /// What satisfies the path: keys, timelocks and hashlocks.
+    pub path: Vec<String>,
+    /// The weight of the path's satisfaction.
+    pub satisfaction: Weight,
+    /// What an input satisfied this way adds to a segwit transaction, in
+    /// weight units.
+    pub input: u64,
+}
+
+/// Estimate every spend path of `descriptor` that doesn't need more than
+/// another, or only the cheapest one satisfied by `branch`, along with the
+/// largest satisfaction weight of any path.
+///
+/// Wildcards don't change the weight, so ranged descriptors are measured at
+/// index 0.
+pub fn estimate(
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    branch: Option<&str>,
+) -> Result<(Vec<Estimate>, Weight), String> {
+    let definite = descriptor
+        .clone()
+        .into_single_descriptors()
+        .map_err(|e| format!("Can't split the descriptor: {}", e))?
+        .remove(0)
+        .at_derivation_index(0)
+        .map_err(|e| format!("Can't derive index 0: {}", e))?;
+
+    let paths = match branch {
+        Some(branch) => vec![branch_path(descriptor, branch)?],
+        None => spend_paths(&definite)?,
+    };
+
+    let mut estimates = Vec::new();
+    for path in &paths {
+        let plan = definite.clone().plan(&path.assets()).map_err(|_| {
+            format!(
+                "The descriptor can't be satisfied with {}",
+                path.describe().join(", ")
+            )
+        })?;
+        let satisfaction = policy::plan_weight(&plan, &definite);
+        estimates.push(Estimate {
+            path: path.describe(),
+            satisfaction,
+            input: input_weight(satisfaction),
+        });
+    }
+    let max = policy::max_satisfaction_weight(&definite)
+        .map_err(|e| format!("Can't estimate the satisfaction weight: {}", e))?;
+    Ok((estimates, max))
+}
+
+/// The spend path of `definite`, derived from `descriptor`, that `selector`
+/// picks, and its number: the number itself, as inspect lists the paths,
+/// or comma separated items as for --branch, picking the first path that
+/// needs them, e.g. one key.
+pub fn select_path(
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    definite: &Descriptor<DefiniteDescriptorKey>,
+    selector: &str,
+) -> Result<(usize, SpendPath), String> {
+    let paths = spend_paths(definite)?;
+    if let Ok(n) = selector.parse::<usize>() {
+        return paths.get(n).map(|path| (n, path.clone())).ok_or_else(|| {
+            format!(
+                "The descriptor has {} spend paths, numbered from 0",
+                paths.len()
+            )
+        });
+    }
+    let mut wanted = branch_path(descriptor, selector)?;
+    // The keys as written stand for those derived at the same place.
+    let mut written = Vec::new();
+    descriptor.for_each_key(|key| {
+        written.push(key.clone());
+        true
+    });
+    let mut derived = Vec::new();
+    definite.for_each_key(|key| {
+        derived.push(key.as_descriptor_public_key().clone());
+        true
+    });
+    for key in &mut wanted.keys {
+        let at = written
+            .iter()
+            .position(|written| written == key)
+            .expect("checked to be the descriptor's");
+        *key = derived[at].clone();
+    }
+    paths
+        .into_iter()
+        .enumerate()
+        .find(|(_, path)| wanted.implied_by(path))
+        .ok_or_else(|| format!("No spend path needs just {}", selector))
+}
+
+/// Every spend path of `descriptor` that doesn't need more than another.
+pub fn spend_paths(
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> Result<Vec<SpendPath>, String> {
+    let policy = descriptor
+        .lift()
+        .map_err(|e| format!("Can't lift the descriptor to a policy: {}", e))?;
+    Ok(minimal(paths(&policy)?))
+}
+
+/// The weight an input with a `satisfaction` adds to a segwit transaction,
+/// in weight units.
+pub fn input_weight(satisfaction: Weight) -> u64 {
+    // Inputs of segwit transactions all count their witness items, even
+    // those without any.
+    TXIN_WEIGHT + satisfaction.to_wu() + 1
+}
+
+/// Print the satisfaction weight of every spend path of the descriptor and
+/// what an input spending it adds to a transaction, for wallets planning
+/// transactions that include our inputs.
+pub fn run(args: EstimateWeightArgs) -> Result<(), String> {
+    let (estimates, max) = estimate(&args.descriptor, args.branch.as_deref())?;
+    let segwit = args.descriptor.desc_type().segwit_version().is_some();
+
+    if args.json {
+        let paths: Vec<_> = estimates
+            .iter()
+            .map(|estimate| {
+                json!({
+                    "path": estimate.path,
+                    "satisfaction_weight": estimate.satisfaction.to_wu(),
+                    "input_weight": estimate.input,
+                    "input_vbytes": estimate.input as f64 / 4.0,
+                })
+            })
+            .collect();
+        let report = json!({
+            "descriptor": args.descriptor.to_string(),
+            "segwit": segwit,
+            "max_satisfaction_weight": max.to_wu(),
+            "max_input_weight": input_weight(max),
+            "paths": paths,
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return Ok(());
+    }
+
+    println!(
+        "Satisfaction weights of {}, with what each input adds to a segwit transaction:",
+        args.descriptor
+    );
+    for estimate in &estimates {
+        println!(
+            "  {} WU, input {} WU ({:.2} vB): {}",
+            estimate.satisfaction.to_wu(),
+            estimate.input,
+            estimate.input as f64 / 4.0,
+            estimate.path.join(", ")
+        );
+    }
+    println!(
+        "  {} WU at most, input {} WU ({:.2} vB)",
+        max.to_wu(),
+        input_weight(max),
+        input_weight(max) as f64 / 4.0
+    );
+    Ok(())
+}
+
+impl SpendPath {
+    /// What satisfying the path takes, to plan it.
+    pub fn assets(&self) -> Assets {
+        let mut assets = Assets::new();
+        for key in &self.keys {
+            assets = assets.add(key.clone());
+        }
+        if let Some(after) = self.after {
+            assets = assets.after(after.into());
+        }
+        if let Some(older) = self.older {
+            assets = assets.older(older.into());
+        }
+        for hash in &self.hashes {
+            match hash {
+                Hashlock::Sha256(h) => assets.sha256_preimages.insert(*h),
+                Hashlock::Hash256(h) => assets.hash256_preimages.insert(*h),
+                Hashlock::Ripemd160(h) => assets.ripemd160_preimages.insert(*h),
+                Hashlock::Hash160(h) => assets.hash160_preimages.insert(*h),
+            };
+        }
+        policy::explicit_sighash(assets)
+    }
+
+    /// What satisfies the path, one item per key, timelock and hashlock.
+    pub fn describe(&self) -> Vec<String> {
+        let mut items: Vec<String> = self.keys.iter().map(|key| key.to_string()).collect();
+        items.extend(self.after.map(|after| format!("after({})", after)));
+        items.extend(self.older.map(|older| format!("older({})", older)));
+        items.extend(self.hashes.iter().map(|hash| hash.to_string()));
+        if items.is_empty() {
+            items.push("nothing".to_string());
+        }
+        items
+    }
+
+    /// The public keys that have to sign, for a path of a descriptor
+    /// without wildcards.
+    pub fn public_keys<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Result<Vec<PublicKey>, String> {
+        self.keys
+            .iter()
+            .map(|key| {
+                DefiniteDescriptorKey::new(key.clone())
+                    .ok_or_else(|| format!("{} has a wildcard", key))?
+                    .derive_public_key(secp)
+                    .map_err(|e| format!("Can't derive {}: {}", key, e))
+            })
+            .collect()
+    }
+
+    // What satisfies both paths at once, unless their timelocks conflict.
+    fn and(&self, other: &SpendPath) -> Option<SpendPath> {
+        let mut path = self.clone();
+        for key in &other.keys {
+            if !path.keys.contains(key) {
+                path.keys.push(key.clone());
+            }
+        }
+        for hash in &other.hashes {
+            if !path.hashes.contains(hash) {
+                path.hashes.push(hash.clone());
+            }
+        }
+        path.after = match (self.after, other.after) {
+            (Some(a), Some(b)) if a.is_block_height() != b.is_block_height() => return None,
+            (Some(a), Some(b)) => Some(if a.to_consensus_u32() > b.to_consensus_u32() {
+                a
+            } else {
+                b
+            }),
+            (a, b) => a.or(b),
+        };
+        path.older = match (self.older, other.older) {
+            (Some(a), Some(b)) if a.is_height_locked() != b.is_height_locked() => return None,
+            (Some(a), Some(b)) => Some(if a.to_consensus_u32() > b.to_consensus_u32() {
+                a
+            } else {
+                b
+            }),
+            (a, b) => a.or(b),
+        };
+        Some(path)
+    }
+
+    // Whether whatever satisfies `other` also satisfies this path.
+    fn implied_by(&self, other: &SpendPath) -> bool {
+        self.keys.iter().all(|key| other.keys.contains(key))
+            && self.hashes.iter().all(|hash| other.hashes.contains(hash))
+            && self.after.is_none_or(|a| {
+                other.after.is_some_and(|b| {
+                    a.is_block_height() == b.is_block_height()
+                        && a.to_consensus_u32() <= b.to_consensus_u32()
+                })
+            })
+            && self.older.is_none_or(|a| {
+                other.older.is_some_and(|b| {
+                    a.is_height_locked() == b.is_height_locked()
+                        && a.to_consensus_u32() <= b.to_consensus_u32()
+                })
+            })
+    }
+}
+
+// Every combination of conditions satisfying `policy`.
+fn paths(policy: &Policy<DefiniteDescriptorKey>) -> Result<Vec<SpendPath>, String> {
+    let single = |path: SpendPath| Ok(vec![path]);
+    match policy {
+        Policy::Unsatisfiable => Ok(Vec::new()),
+        Policy::Trivial => single(SpendPath::default()),
+        Policy::Key(key) => single(SpendPath {
+            keys: vec![key.as_descriptor_public_key().clone()],
+            ..Default::default()
+        }),
+        Policy::After(after) => single(SpendPath {
+            after: Some(*after),
+            ..Default::default()
+        }),
+        Policy::Older(older) => single(SpendPath {
+            older: Some(*older),
+            ..Default::default()
+        }),
+        Policy::Sha256(h) => single(hashlock(Hashlock::Sha256(*h))),
+        Policy::Hash256(h) => single(hashlock(Hashlock::Hash256(*h))),
+        Policy::Ripemd160(h) => single(hashlock(Hashlock::Ripemd160(*h))),
+        Policy::Hash160(h) => single(hashlock(Hashlock::Hash160(*h))),
+        Policy::Thresh(thresh) => {
+            let subs = thresh
+                .iter()
+                .map(|sub| paths(sub))
+                .collect::<Result<Vec<_>, _>>()?;
+            let too_many = || {
+                format!(
+                    "The descriptor has over {} spend paths, pick one with --branch",
+                    MAX_PATHS
+                )
+            };
+            let mut combined = Vec::new();
+            // Walk every choice of k of the n subpolicies.
+            let (n, k) = (thresh.n(), thresh.k());
+            let mut chosen: Vec<usize> = (0..k).collect();
+            loop {
+                let mut partial = vec![SpendPath::default()];
+                for &i in &chosen {
+                    partial = partial
+                        .iter()
+                        .flat_map(|a| subs[i].iter().filter_map(move |b| a.and(b)))
+                        .collect();
+                    if partial.len() > MAX_PATHS {
+                        return Err(too_many());
+                    }
+                }
+                combined.extend(partial);
+                if combined.len() > MAX_PATHS {
+                    return Err(too_many());
+                }
+                let Some(i) = (0..k).rev().find(|&i| chosen[i] < n - k + i) else {
+                    break;
+                };
+                chosen[i] += 1;
+                for j in i + 1..k {
+                    chosen[j] = chosen[j - 1] + 1;
+                }
+            }
+            Ok(combined)
+        }
+    }
+}
+
+fn hashlock(hash: Hashlock) -> SpendPath {
+    SpendPath {
+        hashes: vec![hash],
+        ..Default::default()
+    }
+}
+
+// Drop the paths needing more than another path does, and duplicates.
+fn minimal(paths: Vec<SpendPath>) -> Vec<SpendPath> {
+    let mut kept: Vec<SpendPath> = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        let redundant = paths.iter().enumerate().any(|(j, other)| {
+            j != i && other.implied_by(path) && (!path.implied_by(other) || j < i)
+        });
+        if !redundant {
+            kept.push(path.clone());
+        }
+    }
+    kept
+}
+
+// The path `branch` describes, checking its keys are the descriptor's.
+fn branch_path(
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    branch: &str,
+) -> Result<SpendPath, String> {
+    let mut path = SpendPath::default();
+    let mut keys = BTreeSet::new();
+    descriptor.for_each_key(|key| {
+        keys.insert(key.to_string());
+        true
+    });
+    for item in branch.split(',').map(str::trim) {
+        let (name, arg) = match item.strip_suffix(')').and_then(|s| s.split_once('(')) {
+            Some((name, arg)) => (name, arg),
+            None => ("", item),
+        };
+        let invalid = |e: String| format!("Invalid {} in --branch: {}", item, e);
+        match name {
+            "after" => {
+                let n = arg.parse().map_err(|e| invalid(format!("{}", e)))?;
+                path.after =
+                    Some(AbsLockTime::from_consensus(n).map_err(|e| invalid(e.to_string()))?);
+            }
+            "older" => {
+                let n = arg.parse().map_err(|e| invalid(format!("{}", e)))?;
+                path.older =
+                    Some(RelLockTime::from_consensus(n).map_err(|e| invalid(e.to_string()))?);
+            }
+            "sha256" => path.hashes.push(Hashlock::Sha256(
+                sha256::Hash::from_str(arg).map_err(|e| invalid(e.to_string()))?,
+            )),
+            "hash256" => path.hashes.push(Hashlock::Hash256(
+                hash256::Hash::from_str(arg).map_err(|e| invalid(e.to_string()))?,
+            )),
+            "ripemd160" => path.hashes.push(Hashlock::Ripemd160(
+                ripemd160::Hash::from_str(arg).map_err(|e| invalid(e.to_string()))?,
+            )),
+            "hash160" => path.hashes.push(Hashlock::Hash160(
+                hash160::Hash::from_str(arg).map_err(|e| invalid(e.to_string()))?,
+            )),
+            _ if keys.contains(item) => path
+                .keys
+                .push(DescriptorPublicKey::from_str(item).map_err(|e| invalid(e.to_string()))?),
+            _ => {
+                return Err(format!(
+                    "{} in --branch isn't a key of the descriptor",
+                    item
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum EventFormat {
+    /// One JSON object per line.
+    Ndjson,
+}
+
+/// Structured progress events for orchestration systems.
+///
+/// Every event is a JSON object with an `event` name and a unix `time`,
+/// plus event specific fields. Events go to stderr unless a file is given.
+pub struct Events {
+    sink: Option<Box<dyn Write>>,
+}
+
+impl Events {
+    pub fn new(format: Option<EventFormat>, file: Option<&Path>) -> io::Result<Self> {
+        let sink: Option<Box<dyn Write>> = match (format, file) {
+            (None, _) => None,
+            (Some(EventFormat::Ndjson), None) => Some(Box::new(io::stderr())),
+            (Some(EventFormat::Ndjson), Some(path)) => {
+                Some(Box::new(LineWriter::new(File::create(path)?)))
+            }
+        };
+        Ok(Events { sink })
+    }
+
+    pub fn emit(&mut self, event: &str, fields: Value) {
+        let sink = match &mut self.sink {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        // Losing a progress event must never abort the actual work.
+        let _ = writeln!(sink, "{}", record(event, fields));
+    }
+}
+
+/// The JSON object of `event`: its name, the unix time and `fields`.
+pub fn record(event: &str, fields: Value) -> Value {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut record = json!({ "event": event, "time": time });
+    if let (Value::Object(record), Value::Object(fields)) = (&mut record, fields) {
+        record.extend(fields);
+    }
+    record
+}
@@ -0,0 +1,393 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use miniscript::bitcoin::{
+    Address, Amount, EcdsaSighashType, Psbt, ScriptBuf, SignedAmount, Transaction,
+};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::Descriptor;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::change::{self, Ownership};
+use crate::intent::{self, Sats};
+use crate::{network, parse, sign};
+
+/// A signing policy, as its file declares it. Every rule is optional.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rules {
+    /// The most a transaction may pay to outputs that aren't change.
+    #[serde(default)]
+    max_amount: Option<Sats>,
+    /// The addresses, contacts or descriptors outputs that aren't change
+    /// may pay. Any destination is allowed without the list.
+    #[serde(default)]
+    destinations: Option<Vec<String>>,
+    /// Descriptors whose outputs are change.
+    #[serde(default)]
+    change: Vec<String>,
+    /// Bounds on the fee, which needs the previous transactions of all
+    /// but taproot inputs.
+    #[serde(default)]
+    min_fee: Option<Sats>,
+    #[serde(default)]
+    max_fee: Option<Sats>,
+    /// The sighash types inputs may be signed with, only ALL by default.
+    #[serde(default)]
+    sighashes: Option<Vec<String>>,
+}
+
+/// What a transaction must keep to for `sign` to sign it, so an automated
+/// cosigner can't be talked into paying too much, to the wrong place, with
+/// an absurd fee or with a signature others could reuse.
+pub struct SigningPolicy {
+    path: PathBuf,
+    max_amount: Option<Amount>,
+    destinations: Option<Vec<ScriptBuf>>,
+    change: Vec<Descriptor<DescriptorPublicKey>>,
+    min_fee: Option<Amount>,
+    max_fee: Option<Amount>,
+    sighashes: Vec<EcdsaSighashType>,
+}
+
+impl SigningPolicy {
+    /// Read the policy at `path`, a JSON object or a TOML file of
+    /// `key = value` lines.
+    pub fn load(path: &Path) -> Result<SigningPolicy, String> {
+        let invalid = |e: String| format!("{} isn't a valid signing policy: {}", path.display(), e);
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let rules: Rules = document(&text)
+            .and_then(|document| serde_json::from_value(document).map_err(|e| e.to_string()))
+            .map_err(invalid)?;
+        let amount = |sats: &Option<Sats>, key: &str| {
+            sats.as_ref()
+                .map(|sats| {
+                    sats.amount()
+                        .map_err(|e| invalid(format!("{}: {}", key, e)))
+                })
+                .transpose()
+        };
+        let destinations = match &rules.destinations {
+            Some(destinations) => Some(
+                destinations
+                    .iter()
+                    .map(|to| parse::recipient(to).map_err(|e| invalid(format!("{}: {}", to, e))))
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        };
+        let change = rules
+            .change
+            .iter()
+            .map(|descriptor| {
+                parse::ranged_descriptor(descriptor).map_err(|e| invalid(e.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        let sighashes = match &rules.sighashes {
+            Some(types) => types
+                .iter()
+                .map(|ty| parse::sighash_type(ty).map_err(|e| invalid(format!("{}: {}", ty, e))))
+                .collect::<Result<_, _>>()?,
+            None => vec![EcdsaSighashType::All],
+        };
+        Ok(SigningPolicy {
+            path: path.to_path_buf(),
+            max_amount: amount(&rules.max_amount, "max_amount")?,
+            destinations,
+            change,
+            min_fee: amount(&rules.min_fee, "min_fee")?,
+            max_fee: amount(&rules.max_fee, "max_fee")?,
+            sighashes,
+        })
+    }
+
+    /// Check that signing `psbt`'s inputs with `hash_tys`, one per input,
+    /// keeps to the policy, listing every rule it breaks otherwise.
+    ///
+    /// Outputs paying back to `ours` or the policy's change descriptors
+    /// don't count as leaving the wallet. Those only claiming to, with
+    /// derivation paths the descriptors don't bear out, do.
+    pub fn check(
+        &self,
+        psbt: &Psbt,
+        hash_tys: &[EcdsaSighashType],
+        ours: &[Descriptor<DescriptorPublicKey>],
+        prev_txs: &[Transaction],
+    ) -> Result<(), String> {
+        let mut violations = Vec::new();
+
+        let mut descriptors = self.change.clone();
+        descriptors.extend(ours.iter().cloned());
+        let ownership = change::classify(psbt, &descriptors);
+        let mut paid = Amount::ZERO;
+        for (i, (txout, owner)) in psbt.unsigned_tx.output.iter().zip(&ownership).enumerate() {
+            if let Ownership::Change(..) = owner {
+                continue;
+            }
+            paid += txout.value;
+            // Data carriers hold no coins to send anywhere.
+            if txout.script_pubkey.is_op_return() && txout.value == Amount::ZERO {
+                continue;
+            }
+            let allowed = self
+                .destinations
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&txout.script_pubkey));
+            if !allowed {
+                let to = match Address::from_script(&txout.script_pubkey, network()) {
+                    Ok(address) => address.to_string(),
+                    Err(_) => format!("script {:x}", txout.script_pubkey),
+                };
+                violations.push(format!(
+                    "Output {} pays {}, which isn't an allowed destination",
+                    i, to
+                ));
+            }
+        }
+        if let Some(max) = self.max_amount.filter(|max| paid > *max) {
+            violations.push(format!(
+                "It pays out {} sat, more than the {} sat allowed",
+                paid.to_sat(),
+                max.to_sat()
+            ));
+        }
+
+        if self.min_fee.is_some() || self.max_fee.is_some() {
+            match fee(psbt, prev_txs) {
+                Err(e) => violations.push(e),
+                Ok(fee) if fee.is_negative() => violations.push(format!(
+                    "Its outputs are worth {} sat more than its inputs",
+                    -fee.to_sat()
+                )),
+                Ok(fee) => {
+                    let fee = fee.unsigned_abs();
+                    if let Some(min) = self.min_fee.filter(|min| fee < *min) {
+                        violations.push(format!(
+                            "Its fee of {} sat is below the minimum of {} sat",
+                            fee.to_sat(),
+                            min.to_sat()
+                        ));
+                    }
+                    if let Some(max) = self.max_fee.filter(|max| fee > *max) {
+                        violations.push(format!(
+                            "Its fee of {} sat is above the maximum of {} sat",
+                            fee.to_sat(),
+                            max.to_sat()
+                        ));
+                    }
+                }
+            }
+        }
+
+        for (i, hash_ty) in hash_tys.iter().enumerate() {
+            if !self.sighashes.contains(hash_ty) {
+                violations.push(format!(
+                    "Input {} would be signed with {}, which isn't permitted",
+                    i, hash_ty
+                ));
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Refusing to sign, the transaction breaks the signing policy of {}:\n  {}",
+                self.path.display(),
+                violations.join("\n  ")
+            ))
+        }
+    }
+}
+
+// What the inputs of `psbt` are worth over its outputs, counting the
+// values of inputs other than taproot ones from their previous
+// transactions, found in `prev_txs` or the inputs.
+fn fee(psbt: &Psbt, prev_txs: &[Transaction]) -> Result<SignedAmount, String> {
+    let mut fee = SignedAmount::ZERO;
+    for i in 0..psbt.inputs.len() {
+        let utxo = sign::verified_utxo(psbt, i, prev_txs)
+            .map_err(|e| format!("Can't check the fee: {}", e))?;
+        fee += utxo.value.to_signed().map_err(|e| e.to_string())?;
+    }
+    for txout in &psbt.unsigned_tx.output {
+        fee -= txout.value.to_signed().map_err(|e| e.to_string())?;
+    }
+    Ok(fee)
+}
+
+// Read a policy: JSON, or TOML's top-level `key = value` pairs, with lists
+// allowed to span lines.
+fn document(text: &str) -> Result<Value, String> {
+    if text.trim_start().starts_with('{') {
+        return serde_json::from_str(text).map_err(|e| e.to_string());
+    }
+    let mut map = serde_json::Map::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line = intent::strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(format!("line {}: tables aren't supported", i + 1));
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected `key = value`", i + 1))?;
+        let key = match intent::scalar(key.trim()) {
+            Ok(Value::String(key)) => key,
+            _ => return Err(format!("line {}: invalid key {}", i + 1, key.trim())),
+        };
+        let mut value = value.trim().to_string();
+        while open_brackets(&value) > 0 {
+            let (_, next) = lines
+                .next()
+                .ok_or_else(|| format!("line {}: unterminated list", i + 1))?;
+            value.push(' ');
+            value.push_str(intent::strip_comment(next).trim());
+        }
+        // TOML lists may end with a comma, and integers have `_` between
+        // digits.
+        if let Some(items) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            value = format!("[{}]", items.trim_end().trim_end_matches(','));
+        } else if value.starts_with(|c: char| c.is_ascii_digit())
+            && value.chars().all(|c| c.is_ascii_digit() || c == '_')
+        {
+            value = value.replace('_', "");
+        }
+        let value = intent::scalar(&value).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        if map.insert(key.clone(), value).is_some() {
+            return Err(format!("line {}: {} is repeated", i + 1, key));
+        }
+    }
+    Ok(Value::Object(map))
+}
+
+// How many of `text`'s brackets are left open.
+fn open_brackets(text: &str) -> i32 {
+    intent::unquoted(text)
+        .into_iter()
+        .map(|(_, c)| match c {
+            '[' => 1,
+            ']' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use miniscript::bitcoin::hashes::Hash;
+    use miniscript::bitcoin::{absolute, transaction, OutPoint, Transaction, TxOut, WPubkeyHash};
+    use serde_json::json;
+
+    use super::*;
+    use crate::build;
+
+    #[test]
+    fn toml_lists_span_lines_and_end_with_commas() {
+        let text = r#"
+            # Where the treasury's coins may go.
+            destinations = [
+                "bc1qa",  # cold storage
+                'bc1qb',
+            ]
+            sighashes = ["all", "single",]
+        "#;
+        assert_eq!(
+            document(text).unwrap(),
+            json!({
+                "destinations": ["bc1qa", "bc1qb"],
+                "sighashes": ["all", "single"],
+            })
+        );
+    }
+
+    #[test]
+    fn toml_integers_have_underscores() {
+        let text = "max_amount = 1_000_000\nmin_fee = 200\nmax_fee = \"0.0001 BTC\"\n";
+        assert_eq!(
+            document(text).unwrap(),
+            json!({"max_amount": 1000000, "min_fee": 200, "max_fee": "0.0001 BTC"})
+        );
+    }
+
+    #[test]
+    fn toml_keys_are_not_repeated() {
+        let err = document("min_fee = 1\nmin_fee = 2\n").unwrap_err();
+        assert_eq!(err, "line 2: min_fee is repeated");
+    }
+
+    #[test]
+    fn toml_tables_are_refused() {
+        let err = document("[limits]\nmax_fee = 1\n").unwrap_err();
+        assert_eq!(err, "line 1: tables aren't supported");
+        let err = document("destinations = [\n\"bc1qa\",\n").unwrap_err();
+        assert_eq!(err, "line 1: unterminated list");
+    }
+
+    #[test]
+    fn json_is_read_as_is() {
+        let text = r#"{"max_amount": "1 BTC", "change": []}"#;
+        assert_eq!(
+            document(text).unwrap(),
+            json!({"max_amount": "1 BTC", "change": []})
+        );
+    }
+
+    fn prev_tx(value: u64, script_pubkey: ScriptBuf) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: Vec::new(),
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey,
+            }],
+        }
+    }
+
+    fn spending(prev: &Transaction, value: u64) -> Psbt {
+        build::create_psbt(
+            &[OutPoint::new(prev.compute_txid(), 0)],
+            vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new_op_return([]),
+            }],
+        )
+    }
+
+    #[test]
+    fn fee_of_segwit_v0_inputs_comes_from_the_previous_transaction() {
+        let wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let prev = prev_tx(100_000, wpkh.clone());
+        let mut psbt = spending(&prev, 90_000);
+        // A coordinator understating the input hides 9000 sat of fee.
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(91_000),
+            script_pubkey: wpkh.clone(),
+        });
+        assert!(fee(&psbt, &[]).is_err());
+
+        psbt.inputs[0].non_witness_utxo = Some(prev.clone());
+        assert_eq!(fee(&psbt, &[]).unwrap(), SignedAmount::from_sat(10_000));
+
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx(91_000, wpkh));
+        assert!(fee(&psbt, &[]).is_err());
+    }
+
+    #[test]
+    fn fee_of_taproot_inputs_comes_from_the_witness_utxo() {
+        let tr = ScriptBuf::from_bytes([[0x51, 32].as_slice(), &[1; 32]].concat());
+        let prev = prev_tx(100_000, tr.clone());
+        let mut psbt = spending(&prev, 90_000);
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: tr,
+        });
+        assert_eq!(fee(&psbt, &[]).unwrap(), SignedAmount::from_sat(10_000));
+    }
+}
@@ -0,0 +1,164 @@
+use clap::Args;
+use miniscript::bitcoin::{Amount, Transaction};
+use serde_json::json;
+
+use crate::wallet::{self, Entry, Profile, State};
+use crate::{price, rescan};
+
+#[derive(Args)]
+pub struct HistoryArgs {
+    /// The wallet whose transactions to list.
+    wallet: String,
+    /// Print a JSON array instead of a table, for reporting.
+    #[arg(long)]
+    json: bool,
+    #[command(flatten)]
+    price: price::PriceArgs,
+}
+
+/// List the wallet's transactions, oldest first, with those still
+/// unconfirmed last.
+pub fn run(args: HistoryArgs) -> Result<(), String> {
+    let mut history = State::load(&args.wallet)?.history;
+    history.sort_by_key(|entry| (entry.height == 0, entry.height, entry.time));
+    let price = args.price.price();
+
+    if args.json {
+        let entries: Vec<_> = history
+            .iter()
+            .map(|entry| {
+                json!({
+                    "date": date(entry.time),
+                    "time": entry.time,
+                    "txid": entry.txid,
+                    "height": entry.height,
+                    "status": status(entry),
+                    "direction": direction(entry),
+                    "amount": amount(entry),
+                    "received": entry.received,
+                    "sent": entry.sent,
+                    "fee": entry.fee,
+                    "label": entry.label,
+                    "fiat": price.as_ref().map(|price| price.json(Amount::from_sat(entry.received.abs_diff(entry.sent)))),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return Ok(());
+    }
+
+    if history.is_empty() {
+        println!("{} has no transactions yet", args.wallet);
+    }
+    for entry in &history {
+        let fiat = price
+            .as_ref()
+            .map(|price| {
+                let value = price.value(Amount::from_sat(entry.received.abs_diff(entry.sent)));
+                format!(" ({})", value)
+            })
+            .unwrap_or_default();
+        let fee = entry
+            .fee
+            .map(|fee| format!("fee {} sat", fee))
+            .unwrap_or_default();
+        let line = format!(
+            "{}  {}  {:<8} {:>+14} sat{}  {:<16} {:<20} {}",
+            date(entry.time),
+            entry.txid,
+            direction(entry),
+            amount(entry),
+            fiat,
+            fee,
+            status(entry),
+            entry.label.as_deref().unwrap_or("")
+        );
+        println!("{}", line.trim_end());
+    }
+    if let Some(price) = price {
+        println!("{}", price.notice());
+    }
+    Ok(())
+}
+
+/// Add `tx`, which we created spending `spent` of the coins of the wallet
+/// called `name`, to the wallet's history.
+///
+/// Outputs paying the wallet's scripts count as received, and the fee is
+/// what the outputs leave of `spent`.
+pub fn record(
+    name: &str,
+    tx: &Transaction,
+    spent: Amount,
+    label: Option<String>,
+    broadcast: bool,
+) -> Result<(), String> {
+    let scripts = rescan::scripts(&Profile::load(name)?, &(0..=999))?;
+    let received: u64 = tx
+        .output
+        .iter()
+        .filter(|txout| scripts.contains_key(&txout.script_pubkey))
+        .map(|txout| txout.value.to_sat())
+        .sum();
+    let paid: u64 = tx.output.iter().map(|txout| txout.value.to_sat()).sum();
+    let mut state = State::load(name)?;
+    state.record(Entry {
+        txid: tx.compute_txid().to_string(),
+        height: 0,
+        received,
+        sent: spent.to_sat(),
+        time: wallet::now(),
+        fee: spent.to_sat().checked_sub(paid),
+        label,
+        created: true,
+        broadcast,
+    });
+    state.save(name)?;
+    Ok(())
+}
+
+// What the transaction did to the wallet's balance, the fee included.
+fn amount(entry: &Entry) -> i64 {
+    entry.received as i64 - entry.sent as i64
+}
+
+fn direction(entry: &Entry) -> &'static str {
+    match entry.sent {
+        0 => "received",
+        sent if entry.received + entry.fee.unwrap_or(0) >= sent => "self",
+        _ => "sent",
+    }
+}
+
+fn status(entry: &Entry) -> String {
+    if entry.height > 0 {
+        format!("confirmed at {}", entry.height)
+    } else if entry.broadcast {
+        "broadcast".to_string()
+    } else {
+        "unconfirmed".to_string()
+    }
+}
+
+// Format seconds since the UNIX epoch as a UTC date and time.
+fn date(time: u64) -> String {
+    let (days, seconds) = (time / 86_400, time % 86_400);
+    // Howard Hinnant's days_from_civil, inverted.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds % 3_600 / 60
+    )
+}
@@ -0,0 +1,105 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use clap::Args;
+use serde_json::Value;
+
+use crate::events;
+
+/// Where watchers announce the lifecycle of the transactions they make,
+/// e.g. to post them to a chat or alerting system.
+#[derive(Args)]
+pub struct HookArgs {
+    /// POST every lifecycle event as a JSON object to this URL, with a
+    /// `text` summary as chat webhooks such as Slack's expect. May be
+    /// repeated.
+    #[arg(long = "webhook")]
+    webhooks: Vec<String>,
+    /// Run this shell command on every lifecycle event, with the event as
+    /// JSON on stdin and its name in $MINISCRIPT_PSBT_EVENT. May be
+    /// repeated.
+    #[arg(long = "exec-hook")]
+    exec_hooks: Vec<String>,
+}
+
+/// A step in the life of a transaction.
+#[derive(Clone, Copy)]
+pub enum Lifecycle {
+    /// A PSBT still needing signatures was published.
+    PsbtCreated,
+    /// Every input is signed and finalized.
+    FullySigned,
+    Broadcast,
+    Confirmed,
+}
+
+impl Lifecycle {
+    fn name(self) -> &'static str {
+        match self {
+            Lifecycle::PsbtCreated => "psbt_created",
+            Lifecycle::FullySigned => "fully_signed",
+            Lifecycle::Broadcast => "broadcast",
+            Lifecycle::Confirmed => "confirmed",
+        }
+    }
+}
+
+impl HookArgs {
+    /// Tell every hook about `event`, summarized by `text`.
+    ///
+    /// Hooks run one after the other. One failing only prints a warning, so
+    /// an unreachable alerting system never stops a watcher.
+    pub fn fire(&self, event: Lifecycle, text: &str, fields: Value) {
+        if self.webhooks.is_empty() && self.exec_hooks.is_empty() {
+            return;
+        }
+        let mut record = events::record(event.name(), fields);
+        record["text"] = Value::String(text.to_string());
+        let body = record.to_string();
+        for url in &self.webhooks {
+            if let Err(e) = post(url, &body) {
+                eprintln!("WARNING: webhook {} failed: {}", url, e);
+            }
+        }
+        for command in &self.exec_hooks {
+            if let Err(e) = exec(command, event, &body) {
+                eprintln!("WARNING: hook `{}` failed: {}", command, e);
+            }
+        }
+    }
+}
+
+fn post(url: &str, body: &str) -> Result<(), String> {
+    let response = minreq::post(url)
+        .with_header("Content-Type", "application/json")
+        .with_body(body)
+        .with_timeout(10)
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !(200..300).contains(&response.status_code) {
+        return Err(format!(
+            "answered {} {}",
+            response.status_code, response.reason_phrase
+        ));
+    }
+    Ok(())
+}
+
+fn exec(command: &str, event: Lifecycle, body: &str) -> Result<(), String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("MINISCRIPT_PSBT_EVENT", event.name())
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let mut stdin = child.stdin.take().unwrap();
+    // The hook may not read its input, which isn't a failure.
+    let _ = writeln!(stdin, "{}", body);
+    drop(stdin);
+    let status = child.wait().map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+    Ok(())
+}
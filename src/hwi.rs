@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use clap::Args;
+use miniscript::bitcoin::{Network, Psbt};
+use serde_json::Value;
+
+use crate::network;
+
+/// How to reach a hardware wallet, such as a Trezor, Ledger or Coldcard,
+/// through the HWI command line tool.
+#[derive(Args)]
+pub struct HwiArgs {
+    /// Have the hardware wallet with this master key fingerprint sign, or
+    /// the one connected device of this type, e.g. `trezor` or `ledger`.
+    /// It signs the inputs whose BIP32 derivations start at its
+    /// fingerprint, so the descriptor's keys need their origins, as in
+    /// `[d34db33f/48'/1'/0'/2']tpub...`.
+    #[arg(long)]
+    hww: Option<String>,
+    /// The passphrase of the hardware wallet's seed, if it has one.
+    #[arg(long, requires = "hww")]
+    hww_passphrase: Option<String>,
+    /// The HWI executable.
+    #[arg(long, default_value = "hwi")]
+    hwi: PathBuf,
+}
+
+impl HwiArgs {
+    /// Whether a hardware wallet was asked to sign.
+    pub fn enabled(&self) -> bool {
+        self.hww.is_some()
+    }
+
+    /// Hand `psbt` to the hardware wallet, if one was asked for, and merge
+    /// the signatures it returns, returning the inputs and keys of those
+    /// added.
+    pub fn sign(&self, psbt: &mut Psbt) -> Result<Vec<(usize, String)>, String> {
+        let Some(device) = &self.hww else {
+            return Ok(Vec::new());
+        };
+        let chain = match network() {
+            Network::Bitcoin => "main",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+            _ => "test",
+        };
+        let mut command = Command::new(&self.hwi);
+        command.arg(format!("--chain={}", chain));
+        if device.len() == 8 && device.chars().all(|c| c.is_ascii_hexdigit()) {
+            command.arg(format!("--fingerprint={}", device));
+        } else {
+            command.arg(format!("--device-type={}", device));
+        }
+        if let Some(passphrase) = &self.hww_passphrase {
+            command.arg(format!("--password={}", passphrase));
+        }
+        command.arg("signtx").arg(psbt.to_string());
+        eprintln!("Confirm the transaction on the {} hardware wallet", device);
+        let output = command
+            .output()
+            .map_err(|e| format!("Can't run {}: {}", self.hwi.display(), e))?;
+        // HWI reports its own errors as JSON on stdout.
+        let reply: Value = serde_json::from_slice(&output.stdout).map_err(|_| {
+            format!(
+                "HWI signtx failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        })?;
+        if let Some(error) = reply.get("error") {
+            return Err(format!(
+                "HWI signtx failed: {}",
+                error.as_str().unwrap_or_default()
+            ));
+        }
+        let signed: Psbt = reply["psbt"]
+            .as_str()
+            .ok_or("HWI returned no PSBT")?
+            .parse()
+            .map_err(|e| format!("HWI returned an invalid PSBT: {}", e))?;
+
+        let before = signatures(psbt);
+        psbt.combine(signed).map_err(|e| {
+            format!(
+                "Can't merge the hardware wallet's signatures, it changed the transaction: {}",
+                e
+            )
+        })?;
+        Ok(signatures(psbt)
+            .into_iter()
+            .filter(|signature| !before.contains(signature))
+            .collect())
+    }
+}
+
+// The inputs and keys of every signature in `psbt`.
+fn signatures(psbt: &Psbt) -> Vec<(usize, String)> {
+    let mut signatures = Vec::new();
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        let keys = input
+            .partial_sigs
+            .keys()
+            .map(|pk| pk.to_string())
+            .chain(input.tap_script_sigs.keys().map(|(pk, _)| pk.to_string()))
+            .chain(
+                input
+                    .tap_key_sig
+                    .and(input.tap_internal_key)
+                    .map(|pk| pk.to_string()),
+            );
+        signatures.extend(keys.map(|pk| (i, pk)));
+    }
+    signatures
+}
@@ -0,0 +1,140 @@
+use clap::Args;
+use miniscript::bitcoin::{FeeRate, Weight};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::policy::Liftable;
+use miniscript::Descriptor;
+use serde_json::json;
+
+use crate::estimate;
+use crate::{network, parse, policy};
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// The descriptor to audit. Ranged descriptors are inspected at index 0.
+    #[arg(value_parser = parse::ranged_descriptor)]
+    descriptor: Descriptor<DescriptorPublicKey>,
+    /// The feerate to price each spend path at, in sat/vB.
+    #[arg(long, value_parser = parse::feerate, default_value = "1")]
+    feerate: FeeRate,
+    /// Print the report as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+/// Audit a descriptor before funding it: its abstract policy, every spend
+/// path with the keys, timelocks and preimages it needs, and what an input
+/// spending each path weighs and costs in fees.
+pub fn run(args: InspectArgs) -> Result<(), String> {
+    let definite = args
+        .descriptor
+        .clone()
+        .into_single_descriptors()
+        .map_err(|e| format!("Can't split the descriptor: {}", e))?
+        .remove(0)
+        .at_derivation_index(0)
+        .map_err(|e| format!("Can't derive index 0: {}", e))?;
+    let lifted = definite
+        .lift()
+        .map_err(|e| format!("Can't lift the descriptor to a policy: {}", e))?
+        .normalized();
+    let address = definite.address(network()).ok();
+    let (after, older) = policy::needed_timelocks(&definite)?;
+    let (estimates, max) = estimate::estimate(&args.descriptor, None)?;
+    let fee = |input: u64| {
+        args.feerate
+            .fee_wu(Weight::from_wu(input))
+            .ok_or("The fee overflows")
+    };
+    let worst = fee(estimate::input_weight(max))?;
+
+    if args.json {
+        let paths = estimates
+            .iter()
+            .enumerate()
+            .map(|(i, estimate)| {
+                Ok(json!({
+                    "index": i,
+                    "path": estimate.path,
+                    "satisfaction_weight": estimate.satisfaction.to_wu(),
+                    "input_weight": estimate.input,
+                    "fee": fee(estimate.input)?.to_sat(),
+                }))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let report = json!({
+            "descriptor": args.descriptor.to_string(),
+            "type": format!("{:?}", definite.desc_type()),
+            "address": address.map(|address| address.to_string()),
+            "policy": lifted.to_string(),
+            "keys": lifted.n_keys(),
+            "min_signatures": lifted.minimum_n_keys(),
+            "absolute_timelocks": lifted.absolute_timelocks(),
+            "relative_timelocks": lifted.relative_timelocks(),
+            "needed_lock_time": after,
+            "needed_sequence": older.map(|older| older.to_consensus_u32()),
+            "feerate_sat_per_vb": args.feerate.to_sat_per_vb_ceil(),
+            "paths": paths,
+            "max_satisfaction_weight": max.to_wu(),
+            "max_fee": worst.to_sat(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return Ok(());
+    }
+
+    println!("Descriptor: {}", args.descriptor);
+    println!("Type: {:?}", definite.desc_type());
+    if let Some(address) = address {
+        println!("Address at index 0: {}", address);
+    }
+    println!("Policy: {}", lifted);
+    match lifted.minimum_n_keys() {
+        Some(n) => println!(
+            "Keys: {}, with at least {} signing any spend",
+            lifted.n_keys(),
+            n
+        ),
+        None => println!("Keys: {}, but nothing can spend it", lifted.n_keys()),
+    }
+    let timelocks: Vec<String> = lifted
+        .absolute_timelocks()
+        .iter()
+        .map(|n| format!("after({})", n))
+        .chain(
+            lifted
+                .relative_timelocks()
+                .iter()
+                .map(|n| format!("older({})", n)),
+        )
+        .collect();
+    if !timelocks.is_empty() {
+        println!("Timelocks: {}", timelocks.join(", "));
+    }
+    if let Some(after) = after {
+        println!("Every spend needs a lock time of at least {}", after);
+    }
+    if let Some(older) = older {
+        println!("Every spend needs a sequence of at least {}", older);
+    }
+    println!(
+        "Spend paths, numbered for --spend-path, with what an input adds to a segwit \
+         transaction at {} sat/vB:",
+        args.feerate.to_sat_per_vb_ceil()
+    );
+    for (i, estimate) in estimates.iter().enumerate() {
+        println!(
+            "  {}. {} WU, input {:.2} vB, fee {} sat: {}",
+            i,
+            estimate.satisfaction.to_wu(),
+            estimate.input as f64 / 4.0,
+            fee(estimate.input)?.to_sat(),
+            estimate.path.join(", ")
+        );
+    }
+    println!(
+        "  worst case {} WU, input {:.2} vB, fee {} sat",
+        max.to_wu(),
+        estimate::input_weight(max) as f64 / 4.0,
+        worst.to_sat()
+    );
+    Ok(())
+}
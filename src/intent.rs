@@ -0,0 +1,625 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use miniscript::bitcoin::absolute::{self, LockTime};
+use miniscript::bitcoin::psbt::raw::ProprietaryKey;
+use miniscript::bitcoin::{relative, Amount, FeeRate, OutPoint, Psbt, ScriptBuf, TxOut, Weight};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::plan::{Assets, Plan};
+use miniscript::Descriptor;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::wallet::{self, Profile, State};
+use crate::{annex, build, parse, policy, rescan, rpc};
+
+// Labels travel with the PSBT in proprietary fields, next to the annex's.
+const SUBTYPE_LABEL: u8 = 0x01;
+
+/// A spend, as declared by an intent file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Intent {
+    /// The wallet whose coins are spent.
+    wallet: String,
+    recipients: Vec<Recipient>,
+    /// In sat/vB.
+    feerate: f64,
+    #[serde(default)]
+    locktime: Option<u32>,
+    /// Where the change goes, as an address, contact or descriptor.
+    #[serde(default)]
+    change: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    coins: Coins,
+    #[serde(default)]
+    prefer: Option<Branch>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Recipient {
+    /// An address, contact or descriptor.
+    to: String,
+    amount: Sats,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+/// An amount, in sats or as text with a denomination.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Sats {
+    Number(u64),
+    Text(String),
+}
+
+impl Sats {
+    pub fn amount(&self) -> Result<Amount, String> {
+        match self {
+            Sats::Number(sat) => Ok(Amount::from_sat(*sat)),
+            Sats::Text(text) => parse::amount(text),
+        }
+    }
+}
+
+/// Which of the wallet's coins may be spent.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Coins {
+    /// Spend exactly these coins.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Never spend these coins.
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// 1 by default.
+    #[serde(default)]
+    min_confirmations: Option<u32>,
+}
+
+/// The spending path to plan the inputs' satisfactions for.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Branch {
+    /// The signers, by name or key, expected to sign.
+    #[serde(default)]
+    signers: Vec<String>,
+    /// Use an after() up to this lock time.
+    #[serde(default)]
+    after: Option<u32>,
+    /// Use an older() up to this many blocks.
+    #[serde(default)]
+    older: Option<u16>,
+}
+
+/// A coin of the wallet picked to be spent.
+struct Coin {
+    outpoint: OutPoint,
+    descriptor: Descriptor<DefiniteDescriptorKey>,
+    prevout: rpc::Prevout,
+    plan: Option<Plan>,
+    satisfaction: Weight,
+}
+
+/// Compile the intent into an unsigned PSBT.
+///
+/// The same intent over the same coins always compiles to the same PSBT:
+/// outputs keep the order they're declared in with change last, and unless
+/// the intent names the coins to spend, the largest are picked first until
+/// they pay for the outputs and fee.
+pub fn compile(path: &Path, rpc: &rpc::RpcArgs) -> Result<Psbt, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let intent: Intent = document(&text)
+        .and_then(|document| serde_json::from_value(document).map_err(|e| e.to_string()))
+        .map_err(|e| format!("{} isn't a valid intent: {}", path.display(), e))?;
+
+    let profile = Profile::load(&intent.wallet)?;
+    let state = State::load(&intent.wallet)?;
+    let descriptors = parse::ranged_descriptor(&profile.descriptor)?
+        .into_single_descriptors()
+        .map_err(|e| format!("Can't split the wallet's descriptor: {}", e))?;
+    let feerate = parse::feerate(&intent.feerate.to_string())?;
+
+    if intent.recipients.is_empty() {
+        return Err("The intent has no recipients".to_string());
+    }
+    let mut outputs = Vec::new();
+    for (i, recipient) in intent.recipients.iter().enumerate() {
+        let script_pubkey =
+            parse::recipient(&recipient.to).map_err(|e| format!("Recipient {}: {}", i, e))?;
+        let value = recipient
+            .amount
+            .amount()
+            .map_err(|e| format!("Recipient {}: {}", i, e))?;
+        outputs.push(TxOut {
+            script_pubkey,
+            value,
+        });
+    }
+    let paid = outputs
+        .iter()
+        .try_fold(Amount::ZERO, |sum, output| sum.checked_add(output.value))
+        .ok_or("The amounts overflow")?;
+    let change = intent
+        .change
+        .as_deref()
+        .map(parse::recipient)
+        .transpose()
+        .map_err(|e| format!("The change: {}", e))?;
+
+    let node = rpc.connect()?;
+    let tip = node.tip_height()?;
+    let assets = intent
+        .prefer
+        .as_ref()
+        .map(|branch| assets(&profile, branch))
+        .transpose()?;
+    let candidates = candidates(&intent, &state, tip)?;
+    // Included coins are all spent, the others only as far as needed.
+    let required = intent.coins.include.len();
+
+    // Add coins until they cover the outputs and the fee of spending them.
+    let mut coins: Vec<Coin> = Vec::new();
+    let mut candidates = candidates.into_iter();
+    let (mut psbt, fee) = loop {
+        if coins.len() >= required {
+            if let Some(fit) = fit(&coins, &outputs, change.as_ref(), paid, feerate)? {
+                break fit;
+            }
+        }
+        let Some((outpoint, index)) = candidates.next() else {
+            let total: u64 = coins
+                .iter()
+                .map(|coin| coin.prevout.txout.value.to_sat())
+                .sum();
+            return Err(format!(
+                "Insufficient funds: the spendable coins are worth {} sat, not enough for {} sat \
+                 of outputs and the fee",
+                total,
+                paid.to_sat()
+            ));
+        };
+        let prevout = node.prevout(&outpoint)?;
+        let descriptor = rescan::derive(&descriptors, &outpoint, index, &prevout.txout)?;
+        let (plan, satisfaction) = match &assets {
+            Some(assets) => {
+                let plan = descriptor.clone().plan(assets).map_err(|_| {
+                    format!("{} can't be spent with the preferred branch", outpoint)
+                })?;
+                let satisfaction = policy::plan_weight(&plan, &descriptor);
+                (Some(plan), satisfaction)
+            }
+            None => {
+                let satisfaction = policy::max_satisfaction_weight(&descriptor)
+                    .map_err(|e| format!("Can't estimate the satisfaction weight: {}", e))?;
+                (None, satisfaction)
+            }
+        };
+        coins.push(Coin {
+            outpoint,
+            descriptor,
+            prevout,
+            plan,
+            satisfaction,
+        });
+    };
+
+    let lock_time = intent.locktime.or_else(|| {
+        coins
+            .iter()
+            .filter_map(|coin| coin.plan.as_ref()?.absolute_timelock)
+            .map(|lock| lock.to_consensus_u32())
+            .max()
+    });
+    if let Some(lock_time) = lock_time {
+        build::set_lock_time(&mut psbt, LockTime::from_consensus(lock_time));
+    }
+    for (i, coin) in coins.iter().enumerate() {
+        let input = &mut psbt.inputs[i];
+        match &coin.plan {
+            Some(plan) => {
+                // Only the keys and scripts of the preferred branch are
+                // added, so signers are asked for just what it needs.
+                plan.update_psbt_input(input);
+                build::add_utxo(
+                    input,
+                    &coin.descriptor,
+                    coin.prevout.txout.clone(),
+                    coin.prevout.tx.clone(),
+                )?;
+                if let Some(lock) = plan.relative_timelock {
+                    psbt.unsigned_tx.input[i].sequence = lock.to_sequence();
+                }
+            }
+            None => build::update_input(
+                input,
+                &coin.descriptor,
+                coin.prevout.txout.clone(),
+                coin.prevout.tx.clone(),
+            )?,
+        }
+        psbt.inputs[i].non_witness_utxo = coin.prevout.tx.clone();
+    }
+    let descriptors: Vec<&Descriptor<DefiniteDescriptorKey>> =
+        coins.iter().map(|coin| &coin.descriptor).collect();
+    policy::check_standard(&psbt, &descriptors)?;
+    policy::check_locktime(&psbt, &descriptors)?;
+
+    if let Some(label) = &intent.label {
+        psbt.proprietary
+            .insert(label_key(), label.as_bytes().to_vec());
+    }
+    for (output, recipient) in psbt.outputs.iter_mut().zip(&intent.recipients) {
+        if let Some(label) = &recipient.label {
+            output
+                .proprietary
+                .insert(label_key(), label.as_bytes().to_vec());
+        }
+    }
+
+    eprintln!("Spending {} coins of {}:", coins.len(), intent.wallet);
+    for coin in &coins {
+        eprintln!(
+            "  {} ({} sat)",
+            coin.outpoint,
+            coin.prevout.txout.value.to_sat()
+        );
+    }
+    eprintln!(
+        "Paying {} sat to {} recipients with a fee of {} sat",
+        paid.to_sat(),
+        outputs.len(),
+        fee.to_sat()
+    );
+    Ok(psbt)
+}
+
+/// The label an intent gave the transaction or output whose proprietary
+/// fields are `proprietary`.
+pub fn label(proprietary: &BTreeMap<ProprietaryKey, Vec<u8>>) -> Option<String> {
+    proprietary
+        .get(&label_key())
+        .map(|label| String::from_utf8_lossy(label).into_owned())
+}
+
+fn label_key() -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: annex::PREFIX.to_vec(),
+        subtype: SUBTYPE_LABEL,
+        key: Vec::new(),
+    }
+}
+
+// The coins the intent allows spending with their derivation indexes, in
+// the order they're picked.
+fn candidates(intent: &Intent, state: &State, tip: u32) -> Result<Vec<(OutPoint, u32)>, String> {
+    let outpoints = |list: &[String]| -> Result<Vec<OutPoint>, String> {
+        list.iter()
+            .map(|outpoint| parse::outpoint(outpoint))
+            .collect()
+    };
+    let include = outpoints(&intent.coins.include)?;
+    let exclude = outpoints(&intent.coins.exclude)?;
+    let min_confirmations = intent.coins.min_confirmations.unwrap_or(1);
+    let locked = wallet::locked_coins(&intent.wallet)?;
+
+    let mut spendable = Vec::new();
+    let mut reasons = Vec::new();
+    for utxo in &state.utxos {
+        let outpoint = parse::outpoint(&utxo.outpoint)?;
+        let reason = if exclude.contains(&outpoint) {
+            Some("excluded")
+        } else if locked.iter().any(|lock| lock.outpoint == utxo.outpoint) {
+            Some("locked by another command")
+        } else if tip + 1 < utxo.height + min_confirmations {
+            Some("not confirmed enough")
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => reasons.push((outpoint, reason)),
+            None => spendable.push((outpoint, utxo.index, utxo.value)),
+        }
+    }
+
+    if include.is_empty() {
+        spendable.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        return Ok(spendable
+            .into_iter()
+            .map(|(outpoint, index, _)| (outpoint, index))
+            .collect());
+    }
+    let mut violations = Vec::new();
+    let mut picked = Vec::new();
+    for outpoint in include {
+        if let Some((_, index, _)) = spendable.iter().find(|coin| coin.0 == outpoint) {
+            picked.push((outpoint, *index));
+        } else if let Some((_, reason)) = reasons.iter().find(|(o, _)| *o == outpoint) {
+            violations.push(format!("{} is {}", outpoint, reason));
+        } else {
+            violations.push(format!("{} isn't an unspent coin of the wallet", outpoint));
+        }
+    }
+    if !violations.is_empty() {
+        return Err(format!(
+            "The intent includes coins it can't spend:\n  {}",
+            violations.join("\n  ")
+        ));
+    }
+    Ok(picked)
+}
+
+// What the preferred branch can use: the signers' keys and timelocks.
+fn assets(profile: &Profile, branch: &Branch) -> Result<Assets, String> {
+    let mut assets = Assets::new();
+    for signer in &branch.signers {
+        let key = match profile.signers.iter().find(|s| s.name == *signer) {
+            Some(s) => &s.key,
+            None => signer,
+        };
+        let key = DescriptorPublicKey::from_str(key)
+            .map_err(|_| format!("{} is neither a signer of the wallet nor a key", signer))?;
+        assets = assets.add(key);
+    }
+    if let Some(after) = branch.after {
+        assets = assets.after(absolute::LockTime::from_consensus(after));
+    }
+    if let Some(older) = branch.older {
+        assets = assets.older(relative::LockTime::from_height(older));
+    }
+    Ok(policy::explicit_sighash(assets))
+}
+
+// The PSBT spending `coins` to `outputs` and its fee, if they cover it,
+// paying what's left to `change` unless it would be dust.
+fn fit(
+    coins: &[Coin],
+    outputs: &[TxOut],
+    change: Option<&ScriptBuf>,
+    paid: Amount,
+    feerate: FeeRate,
+) -> Result<Option<(Psbt, Amount)>, String> {
+    let total = coins
+        .iter()
+        .try_fold(Amount::ZERO, |sum, coin| {
+            sum.checked_add(coin.prevout.txout.value)
+        })
+        .ok_or("The input values overflow")?;
+    let outpoints: Vec<OutPoint> = coins.iter().map(|coin| coin.outpoint).collect();
+    let satisfactions: Vec<Weight> = coins.iter().map(|coin| coin.satisfaction).collect();
+    let fee = |psbt: &Psbt| {
+        let vsize = policy::satisfied_weight(psbt, &satisfactions).div_ceil(4);
+        feerate.fee_vb(vsize).ok_or("The fee overflows")
+    };
+
+    if let Some(change) = change {
+        let mut with_change = outputs.to_vec();
+        with_change.push(TxOut {
+            script_pubkey: change.clone(),
+            value: Amount::ZERO,
+        });
+        let mut psbt = build::create_psbt(&outpoints, with_change);
+        let fee = fee(&psbt)?;
+        let left = total
+            .checked_sub(paid)
+            .and_then(|left| left.checked_sub(fee));
+        if let Some(left) = left.filter(|left| *left >= change.minimal_non_dust()) {
+            psbt.unsigned_tx.output.last_mut().unwrap().value = left;
+            return Ok(Some((psbt, fee)));
+        }
+    }
+    let psbt = build::create_psbt(&outpoints, outputs.to_vec());
+    let fee = fee(&psbt)?;
+    match total
+        .checked_sub(paid)
+        .and_then(|left| left.checked_sub(fee))
+    {
+        None => Ok(None),
+        // Anything past the dust limit of the smallest outputs is worth
+        // paying back.
+        Some(left) if change.is_none() && left >= Amount::from_sat(546) => Err(format!(
+            "{} sat of the coins would be left unaccounted for, declare where the change goes \
+             with `change`",
+            left.to_sat()
+        )),
+        // Change too small to be worth an output goes to the miners.
+        Some(left) => Ok(Some((psbt, fee + left))),
+    }
+}
+
+// Read an intent document: JSON, or the YAML subset of block mappings and
+// sequences, `[a, b]` lists, quoted and plain scalars and `#` comments.
+fn document(text: &str) -> Result<Value, String> {
+    if text.trim_start().starts_with('{') {
+        return serde_json::from_str(text).map_err(|e| e.to_string());
+    }
+    let mut lines = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = strip_comment(line);
+        let content = line.trim_start_matches(' ');
+        if content.trim().is_empty() || content.trim() == "---" {
+            continue;
+        }
+        if content.starts_with('\t') {
+            return Err(format!("line {}: indent with spaces, not tabs", i + 1));
+        }
+        lines.push(Line {
+            number: i + 1,
+            indent: line.len() - content.len(),
+            text: content.trim_end().to_string(),
+        });
+    }
+    if lines.is_empty() {
+        return Ok(Value::Null);
+    }
+    let mut pos = 0;
+    let indent = lines[0].indent;
+    let value = block(&mut lines, &mut pos, indent)?;
+    match lines.get(pos) {
+        Some(line) => Err(format!("line {}: unexpected indentation", line.number)),
+        None => Ok(value),
+    }
+}
+
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+// Read the mapping or sequence starting at `lines[*pos]`, whose entries are
+// indented by `indent`.
+fn block(lines: &mut [Line], pos: &mut usize, indent: usize) -> Result<Value, String> {
+    if is_item(&lines[*pos].text) {
+        let mut items = Vec::new();
+        while *pos < lines.len() && lines[*pos].indent == indent && is_item(&lines[*pos].text) {
+            let line = &mut lines[*pos];
+            let rest = line.text[1..].trim_start().to_string();
+            if rest.is_empty() {
+                *pos += 1;
+                items.push(nested(lines, pos, indent)?);
+            } else if split_key(&rest).is_some() {
+                // `- key: value` starts a mapping indented past the dash.
+                line.indent += line.text.len() - rest.len();
+                line.text = rest;
+                let indent = line.indent;
+                items.push(block(lines, pos, indent)?);
+            } else {
+                let number = line.number;
+                items.push(scalar(&rest).map_err(|e| format!("line {}: {}", number, e))?);
+                *pos += 1;
+            }
+        }
+        return Ok(Value::Array(items));
+    }
+
+    let mut map = serde_json::Map::new();
+    while *pos < lines.len() && lines[*pos].indent == indent {
+        let number = lines[*pos].number;
+        let (key, rest) = split_key(&lines[*pos].text)
+            .ok_or_else(|| format!("line {}: expected `key: value`", number))?;
+        if map.contains_key(&key) {
+            return Err(format!("line {}: {} is repeated", number, key));
+        }
+        *pos += 1;
+        let value = if !rest.is_empty() {
+            scalar(&rest).map_err(|e| format!("line {}: {}", number, e))?
+        } else if lines
+            .get(*pos)
+            .is_some_and(|line| line.indent == indent && is_item(&line.text))
+        {
+            // A sequence may sit at its key's indentation.
+            block(lines, pos, indent)?
+        } else {
+            nested(lines, pos, indent)?
+        };
+        map.insert(key, value);
+    }
+    Ok(Value::Object(map))
+}
+
+// Read the block indented past `indent` at `lines[*pos]`, if there is one.
+fn nested(lines: &mut [Line], pos: &mut usize, indent: usize) -> Result<Value, String> {
+    match lines.get(*pos) {
+        Some(line) if line.indent > indent => {
+            let indent = line.indent;
+            block(lines, pos, indent)
+        }
+        _ => Ok(Value::Null),
+    }
+}
+
+fn is_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+// Split `key: value` at the first colon outside quotes followed by a space
+// or the end of the line.
+fn split_key(text: &str) -> Option<(String, String)> {
+    let (i, _) = unquoted(text)
+        .into_iter()
+        .find(|&(i, c)| c == ':' && text[i + 1..].chars().next().is_none_or(|next| next == ' '))?;
+    let key = match scalar(text[..i].trim()).ok()? {
+        Value::String(key) => key,
+        key => key.to_string(),
+    };
+    Some((key, text[i + 1..].trim().to_string()))
+}
+
+/// Read a scalar or `[a, b]` list: a quoted string, boolean, number, null
+/// or else a plain string.
+pub fn scalar(text: &str) -> Result<Value, String> {
+    if text.starts_with('"') {
+        return serde_json::from_str::<String>(text)
+            .map(Value::String)
+            .map_err(|e| format!("invalid quoted string: {}", e));
+    }
+    if let Some(quoted) = text.strip_prefix('\'') {
+        return quoted
+            .strip_suffix('\'')
+            .map(|inner| Value::String(inner.replace("''", "'")))
+            .ok_or_else(|| "unterminated quoted string".to_string());
+    }
+    if let Some(list) = text.strip_prefix('[') {
+        let list = list.strip_suffix(']').ok_or("unterminated list")?;
+        if list.trim().is_empty() {
+            return Ok(Value::Array(Vec::new()));
+        }
+        let mut items = Vec::new();
+        let mut start = 0;
+        let commas = unquoted(list).into_iter().filter(|&(_, c)| c == ',');
+        for end in commas.map(|(i, _)| i).chain([list.len()]) {
+            items.push(scalar(list[start..end].trim())?);
+            start = end + 1;
+        }
+        return Ok(Value::Array(items));
+    }
+    Ok(match text {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "null" | "~" => Value::Null,
+        _ if text.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+            && text
+                .chars()
+                .all(|c| c.is_ascii_digit() || "-+.eE".contains(c)) =>
+        {
+            serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+        }
+        _ => Value::String(text.to_string()),
+    })
+}
+
+/// `line` up to a `#` comment outside quotes.
+pub fn strip_comment(line: &str) -> &str {
+    let comment = unquoted(line)
+        .into_iter()
+        .find(|&(i, c)| c == '#' && (i == 0 || line[..i].ends_with([' ', '\t'])));
+    match comment {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+/// The characters of `text` outside quotes, with their byte offsets.
+pub fn unquoted(text: &str) -> Vec<(usize, char)> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut chars = Vec::new();
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => chars.push((i, c)),
+        }
+    }
+    chars
+}
@@ -0,0 +1,158 @@
+//! Build, sign and finalize PSBTs spending miniscript descriptors.
+//!
+//! The `miniscript-psbt` command line tool is a thin wrapper around this
+//! crate: each of its commands lives in the module of the same name, and
+//! the functions below cover the plain build, sign and finalize flow for
+//! use from other programs.
+
+use std::sync::OnceLock;
+
+use miniscript::bitcoin::absolute::LockTime;
+use miniscript::bitcoin::{
+    secp256k1, EcdsaSighashType, Network, OutPoint, PrivateKey, Psbt, Transaction, TxOut,
+};
+use miniscript::descriptor::DefiniteDescriptorKey;
+use miniscript::Descriptor;
+
+pub mod annex;
+pub mod artifact;
+pub mod bbqr;
+pub mod bench;
+pub mod bip39;
+pub mod bip47;
+pub mod bip85;
+pub mod broadcast;
+pub mod build;
+pub mod chain;
+pub mod change;
+pub mod clipboard;
+pub mod contacts;
+pub mod create;
+pub mod decode;
+pub mod error;
+pub mod esplora;
+pub mod estimate;
+pub mod events;
+pub mod guard;
+pub mod history;
+pub mod hooks;
+pub mod hwi;
+pub mod inspect;
+pub mod intent;
+pub mod locks;
+pub mod merge;
+pub mod musig;
+pub mod parse;
+pub mod policy;
+pub mod price;
+pub mod psbtv2;
+pub mod qr;
+pub mod rbf;
+pub mod repl;
+pub mod rescan;
+pub mod reserves;
+pub mod rpc;
+pub mod schedule;
+pub mod secret;
+pub mod select;
+pub mod selftest;
+pub mod setup;
+pub mod sign;
+pub mod slip39;
+pub mod stage;
+pub mod sweep;
+pub mod tpm;
+pub mod trace;
+pub mod tui;
+pub mod ur;
+pub mod utxo;
+pub mod verify;
+pub mod wallet;
+pub mod yubihsm;
+
+static NETWORK: OnceLock<Network> = OnceLock::new();
+
+/// The network addresses are encoded for and validated against, regtest
+/// unless [`set_network`] chose another.
+pub fn network() -> Network {
+    NETWORK.get().copied().unwrap_or(Network::Regtest)
+}
+
+/// Choose the network to operate on, once and before anything is parsed.
+///
+/// Fails if another network was chosen already.
+pub fn set_network(network: Network) -> Result<(), String> {
+    match NETWORK.get_or_init(|| network) {
+        chosen if *chosen == network => Ok(()),
+        chosen => Err(format!("The network is {} already", chosen)),
+    }
+}
+
+/// A coin to spend.
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    /// The output the coin is.
+    pub txout: TxOut,
+    /// The descriptor controlling the output.
+    pub descriptor: Descriptor<DefiniteDescriptorKey>,
+    /// The transaction creating the output, which legacy and nested segwit
+    /// descriptors need.
+    pub tx: Option<Transaction>,
+}
+
+/// Build an unsigned PSBT spending `utxos` to `outputs`, with every input
+/// updated with its descriptor's scripts and key origins.
+///
+/// Fails with [`error::Error::Descriptor`] if a descriptor can't be spent
+/// from the way it's given or the lock time can't satisfy the descriptors'
+/// timelocks, and with [`error::Error::Other`] if the transaction wouldn't
+/// be relayed as standard.
+pub fn build_psbt(
+    utxos: &[Utxo],
+    outputs: Vec<TxOut>,
+    lock_time: Option<LockTime>,
+) -> Result<Psbt, error::Error> {
+    let outpoints: Vec<OutPoint> = utxos.iter().map(|utxo| utxo.outpoint).collect();
+    let mut psbt = build::create_psbt(&outpoints, outputs);
+    if let Some(lock_time) = lock_time {
+        build::set_lock_time(&mut psbt, lock_time);
+    }
+    for (input, utxo) in psbt.inputs.iter_mut().zip(utxos) {
+        build::update_input(input, &utxo.descriptor, utxo.txout.clone(), utxo.tx.clone())
+            .map_err(error::Error::Descriptor)?;
+    }
+    let descriptors: Vec<_> = utxos.iter().map(|utxo| &utxo.descriptor).collect();
+    policy::check_standard(&psbt, &descriptors)?;
+    policy::check_locktime(&psbt, &descriptors).map_err(error::Error::Descriptor)?;
+    Ok(psbt)
+}
+
+/// Add `key`'s signatures to input `index` of `psbt`, leaving the other
+/// inputs untouched.
+///
+/// Fails with [`error::Error::Signature`] if the input's sighash can't be
+/// computed, e.g. for lack of the UTXOs it commits to.
+pub fn sign_psbt_input(
+    psbt: &mut Psbt,
+    index: usize,
+    key: &PrivateKey,
+    hash_ty: EcdsaSighashType,
+) -> Result<(), error::Error> {
+    if index >= psbt.inputs.len() {
+        return Err(error::Error::Other(format!(
+            "The PSBT has {} inputs, there's no input {}",
+            psbt.inputs.len(),
+            index
+        )));
+    }
+    let secp = secp256k1::Secp256k1::new();
+    sign::sign_selected_inputs(&secp, psbt, &[index], &[*key], hash_ty, |_, _, _| {})
+        .map_err(|e| error::Error::Signature(format!("Can't compute the sighash: {}", e)))
+}
+
+/// Check every signature of `psbt`, finalize its inputs and extract the
+/// signed transaction.
+pub fn finalize_and_extract(mut psbt: Psbt) -> Result<Transaction, error::Error> {
+    build::finalize(&secp256k1::Secp256k1::verification_only(), &mut psbt)?;
+    stage::extract_tx(psbt)
+}
@@ -0,0 +1,42 @@
+use clap::Subcommand;
+use miniscript::bitcoin::OutPoint;
+
+use crate::{parse, wallet};
+
+#[derive(Subcommand)]
+pub enum LocksCommand {
+    /// List a wallet's locked coins and when their locks lapse.
+    List {
+        /// The wallet whose locks to list.
+        wallet: String,
+    },
+    /// Release locks before they lapse, e.g. once a transaction was
+    /// abandoned.
+    Release {
+        /// The wallet whose coins to release.
+        wallet: String,
+        /// The coins to release, every locked coin if none are given.
+        #[arg(value_parser = parse::outpoint)]
+        outpoints: Vec<OutPoint>,
+    },
+}
+
+pub fn run(command: LocksCommand) -> Result<(), String> {
+    match command {
+        LocksCommand::List { wallet } => {
+            for lock in wallet::locked_coins(&wallet)? {
+                println!("{} until {}", lock.outpoint, lock.expires);
+            }
+            Ok(())
+        }
+        LocksCommand::Release { wallet, outpoints } => {
+            wallet::unlock_coins(&wallet, &outpoints)?;
+            if outpoints.is_empty() {
+                println!("Released every coin of {}", wallet);
+            } else {
+                println!("Released {} coins of {}", outpoints.len(), wallet);
+            }
+            Ok(())
+        }
+    }
+}
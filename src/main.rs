@@ -1,172 +1,773 @@
-use std::collections::BTreeMap;
-use std::str::FromStr;
-
-use bitcoin::consensus::serialize;
-use bitcoin::util::sighash::SighashCache;
-use bitcoin::{PackedLockTime, PrivateKey};
-use bitcoind::bitcoincore_rpc::jsonrpc::base64;
-use bitcoind::bitcoincore_rpc::RawTx;
-use miniscript::bitcoin::consensus::encode::deserialize;
-use miniscript::bitcoin::hashes::hex::FromHex;
-use miniscript::bitcoin::util::psbt;
-use miniscript::bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
-use miniscript::bitcoin::{
-    self, secp256k1, Address, Network, OutPoint, Script, Sequence, Transaction, TxIn, TxOut,
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use bitcoin::bip32::Xpriv;
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::PrivateKey;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::{self, secp256k1, Address, Network, OutPoint, Transaction};
+use serde_json::json;
+
+use miniscript_psbt::error::Error;
+use miniscript_psbt::{
+    annex, artifact, bbqr, bench, bip39, bip47, bip85, broadcast, build, clipboard, contacts,
+    create, decode, estimate, events, history, hwi, inspect, locks, merge, musig, network, parse,
+    policy, price, rbf, repl, rescan, reserves, rpc, schedule, secret, selftest, set_network,
+    setup, sign, stage, sweep, tpm, trace, tui, ur, verify, wallet,
 };
-use miniscript::psbt::{PsbtExt, PsbtInputExt};
-use miniscript::Descriptor;
-use clap::Parser;
 
-/// Search for a pattern in a file and display the lines that contain it.
+/// Build, sign and finalize PSBTs spending miniscript descriptors.
 #[derive(Parser)]
+#[command(
+    after_help = "Exit codes: 1 for errors not listed here, 2 for bad arguments, \
+    3 for a descriptor that doesn't parse or can't be used, 4 for no output to spend, \
+    5 for missing signatures or preimages, 6 for a bad signature, 7 for a PSBT that can't be \
+    finalized otherwise and 8 for an input UTXO that doesn't match its previous transaction."
+)]
 struct Cli {
-    /// The raw transaction we're attempting to spend.
-    rawtx: String,
-    /// The address we're spending to.
-    address: String,
-    /// The amount in sats to send to the specified address.
-    amount: u64,
-    /// The descriptor to sign.
-    descriptor: String,
-    /// The hot wallet private key.
-    hotkey: String,
-    /// The cosigner's private key.
-    cosigner: String
+    #[command(subcommand)]
+    command: Command,
+    /// Report each processing stage on stderr; repeat to include the PSBT.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Emit a structured event for every significant step.
+    #[arg(long, value_enum, global = true)]
+    events: Option<events::EventFormat>,
+    /// Write events to this file instead of stderr.
+    #[arg(long, requires = "events", global = true)]
+    events_file: Option<PathBuf>,
+    /// The network to encode addresses for and to check addresses and keys
+    /// against: mainnet, testnet, testnet4, signet or regtest.
+    #[arg(long, value_parser = parse::network, default_value = "regtest", global = true)]
+    network: Network,
+    /// Accept private keys, mnemonics and passwords given on the command
+    /// line as they are, where other users see them in ps and the shell
+    /// history keeps them. Pass them as `file:<PATH>`, `env:<VAR>` or `-`
+    /// to be prompted for them instead.
+    #[arg(long, global = true)]
+    allow_insecure_argv: bool,
+    /// Grind ECDSA signatures to a low R value like Bitcoin Core, so each
+    /// is at most 71 bytes rather than 72, and estimate fees for that.
+    #[arg(long, global = true)]
+    low_r: bool,
+}
+
+#[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Spend a descriptor output of a funding transaction to an address.
+    Spend(SpendArgs),
+    /// Create an unsigned PSBT from a spend intent file or a funding
+    /// transaction.
+    Create(create::CreateArgs),
+    /// Sign a PSBT with private keys, extended private keys, a master key
+    /// recovered from SLIP-39 shares or a key held on a YubiHSM2.
+    Sign(sign::SignArgs),
+    /// Check a PSBT's signatures and finalize its inputs.
+    Finalize(stage::StageArgs),
+    /// Print the raw transaction of a finalized PSBT.
+    Extract(stage::PsbtArg),
+    /// Show a PSBT, raw transaction or descriptor, detecting which it is.
+    Show {
+        /// Base64 PSBTs, hex transactions or descriptors.
+        #[arg(required_unless_present = "clipboard")]
+        inputs: Vec<String>,
+        /// Also show what's currently on the system clipboard.
+        #[arg(long)]
+        clipboard: bool,
+        /// Print the sighash each input of a PSBT is signed over, to check
+        /// against a signing device or by hand.
+        #[arg(long)]
+        sighashes: bool,
+    },
+    /// Print a field by field breakdown of a raw transaction.
+    DecodeTx {
+        /// The hex encoded transaction.
+        #[arg(value_parser = parse::transaction)]
+        tx: Transaction,
+    },
+    /// Encode and decode the BBQr multi-part QR format.
+    Bbqr {
+        #[command(subcommand)]
+        command: bbqr::BbqrCommand,
+    },
+    /// Encode and decode PSBTs as UR crypto-psbt parts, the animated QR
+    /// codes of air-gapped signers.
+    Ur {
+        #[command(subcommand)]
+        command: ur::UrCommand,
+    },
+    /// Rewrite a PSBT as version 0 or, with --psbt-version 2, as version 2
+    /// (BIP370), for signers that only read one of them. Every command
+    /// reads both.
+    ConvertPsbt(stage::StageArgs),
+    /// Add our MuSig2 nonce to the taproot inputs whose internal key
+    /// aggregates the participants' keys, the first round of signing them
+    /// with a single key path signature.
+    MusigNonce(musig::NonceArgs),
+    /// Add our MuSig2 partial signature to the inputs once every
+    /// participant's nonce is in, e.g. combined from their PSBTs.
+    MusigPartialSign(musig::PartialSignArgs),
+    /// Aggregate the participants' MuSig2 partial signatures into each
+    /// input's key path signature, to finalize.
+    MusigAggregate(stage::StageArgs),
+    /// Merge PSBTs for the same transaction, e.g. signed by different
+    /// cosigners.
+    Combine {
+        /// Base64 PSBTs to combine.
+        #[arg(value_parser = parse::psbt, num_args = 2.., required = true)]
+        psbts: Vec<Psbt>,
+        #[command(flatten)]
+        out: stage::PsbtOut,
+    },
+    /// Join PSBTs for different transactions into a single transaction.
+    Join {
+        /// Base64 PSBTs to join.
+        #[arg(value_parser = parse::psbt, num_args = 2.., required = true)]
+        psbts: Vec<Psbt>,
+        #[command(flatten)]
+        out: stage::PsbtOut,
+    },
+    /// Seal private keys to the TPM so they're only usable on this machine.
+    Tpm {
+        #[command(subcommand)]
+        command: tpm::TpmCommand,
+    },
+    /// Set or clear the annex of taproot inputs.
+    Annex {
+        #[command(subcommand)]
+        command: annex::AnnexCommand,
+    },
+    /// Check that a PSBT would be accepted as a BIP125 replacement.
+    CheckReplacement(rbf::CheckReplacementArgs),
+    /// Push a transaction to the network through one or more backends.
+    Broadcast(broadcast::BroadcastArgs),
+    /// Fee-bump an unconfirmed transaction fetched from a node with a
+    /// signed replacement.
+    Replace(rbf::ReplaceArgs),
+    /// Rebuild a transaction or PSBT at a higher feerate, paid out of its
+    /// change or an added coin, as an unsigned PSBT to sign again.
+    BumpFee(rbf::BumpFeeArgs),
+    /// Interactively compose, sign and finalize a spend.
+    Tui(tui::TuiArgs),
+    /// Build a PSBT step by step in an interactive session.
+    Repl(repl::ReplArgs),
+    /// Interactively create a multisig wallet profile.
+    Setup,
+    /// Rebuild a wallet's coins and history by scanning the node's blocks.
+    Rescan(rescan::RescanArgs),
+    /// Save, list and remove named recipients.
+    Contacts {
+        #[command(subcommand)]
+        command: contacts::ContactsCommand,
+    },
+    /// List a wallet's transactions with their amounts, fees and labels.
+    History(history::HistoryArgs),
+    /// Inspect and release the coins commands locked while spending them.
+    Locks {
+        #[command(subcommand)]
+        command: locks::LocksCommand,
+    },
+    /// Watch a wallet for confirmed coins and sweep them to cold storage.
+    Sweep(sweep::SweepArgs),
+    /// Hold signed PSBTs until a delay passes, as a dead man's switch.
+    Schedule {
+        #[command(subcommand)]
+        command: schedule::ScheduleCommand,
+    },
+    /// Pay BIP47 reusable payment codes.
+    Bip47 {
+        #[command(subcommand)]
+        command: bip47::Bip47Command,
+    },
+    /// Derive per-purpose child keys from a master key with BIP85.
+    Bip85 {
+        #[command(subcommand)]
+        command: bip85::Bip85Command,
+    },
+    /// Check that an address is derived from a descriptor and at which index.
+    VerifyAddress(verify::VerifyAddressArgs),
+    /// Check the signatures of a PSBT and what each input still needs to be
+    /// finalized, without finalizing it.
+    Verify(verify::VerifyArgs),
+    /// Build a BIP127 proof of reserves, a PSBT spending a descriptor's
+    /// coins that commits to a message and can never be mined.
+    ProveReserves(reserves::ProveArgs),
+    /// Check a proof of reserves against the message and the UTXO set.
+    VerifyReserves(reserves::VerifyArgs),
+    /// Measure sighash, signing, verification and finalization throughput.
+    Bench(bench::BenchArgs),
+    /// Start a throwaway regtest node and fund, create, sign, finalize and
+    /// broadcast a spend of each kind of descriptor against it, for
+    /// development.
+    Selftest(selftest::SelftestArgs),
+    /// Estimate the weight of inputs spending a descriptor, per spend path,
+    /// for other wallets' coin selection.
+    EstimateWeight(estimate::EstimateWeightArgs),
+    /// Audit a descriptor before funding it: its policy, spend paths and
+    /// what spending each one costs.
+    Inspect(inspect::InspectArgs),
+    /// Print a shell completion script to stdout.
+    Completions {
+        /// The shell to generate completions for.
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Write man pages for the command and every subcommand to a directory.
+    Manpages {
+        /// The directory to write the pages to.
+        dir: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct SpendArgs {
+    #[command(flatten)]
+    funding: create::FundingArgs,
+    /// The hot wallet private key, as `file:<PATH>`, `env:<VAR>` or `-` to
+    /// be prompted for it, or `tpm:<name>` for one sealed to the TPM.
+    /// Without both keys the partially signed PSBT is printed for the
+    /// other signers, unless the given ones already satisfy the descriptor.
+    #[arg(value_parser = secret::parser(parse::private_key))]
+    hotkey: Option<PrivateKey>,
+    /// The cosigner's private key, given the same ways. Leave it out for
+    /// --hww to sign in its place.
+    #[arg(value_parser = secret::parser(parse::private_key))]
+    cosigner: Option<PrivateKey>,
+    /// Read the hot wallet private key from this file instead.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["hotkey", "hotkey_env"])]
+    hotkey_file: Option<PathBuf>,
+    /// Read the hot wallet private key from this environment variable
+    /// instead.
+    #[arg(long, value_name = "VAR", conflicts_with = "hotkey")]
+    hotkey_env: Option<String>,
+    /// Read the cosigner's private key from this file instead.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["cosigner", "cosigner_env"])]
+    cosigner_file: Option<PathBuf>,
+    /// Read the cosigner's private key from this environment variable
+    /// instead.
+    #[arg(long, value_name = "VAR", conflicts_with = "cosigner")]
+    cosigner_env: Option<String>,
+    #[command(flatten)]
+    hwi: hwi::HwiArgs,
+    /// An extended private key, the master key or one of the descriptor's,
+    /// to derive the keys the inputs' BIP32 derivations name. May be
+    /// repeated.
+    #[arg(long = "xpriv", value_parser = secret::parser(parse::xpriv))]
+    xprivs: Vec<Xpriv>,
+    #[command(flatten)]
+    mnemonic: bip39::MnemonicArgs,
+    /// A 32 byte hex preimage to reveal, for inputs whose scripts have a
+    /// sha256(), hash256(), ripemd160() or hash160() of it. May be
+    /// repeated.
+    #[arg(long = "preimage", value_parser = parse::preimage)]
+    preimages: Vec<Vec<u8>>,
+    #[command(flatten)]
+    sighashes: sign::SighashArgs,
+    #[command(flatten)]
+    out: stage::PsbtOut,
+    /// Print a JSON report of the descriptor, PSBT and transaction on stdout
+    /// for scripts, with everything else on stderr.
+    #[arg(long)]
+    json: bool,
+    /// Sign despite an unusual sighash type or output.
+    #[arg(long)]
+    allow_risky: bool,
+    /// Only build the unsigned PSBT and report what the transaction would
+    /// cost once signed, estimated from each input descriptor's largest
+    /// satisfaction, without using any key.
+    #[arg(long)]
+    dry_run: bool,
+    /// Also copy the signed PSBT to the system clipboard.
+    #[arg(long)]
+    clipboard: bool,
+    /// The wallet the coins belong to, to lock them so other commands
+    /// running at the same time don't spend them too, and to record the
+    /// transaction in its history.
+    #[arg(long)]
+    wallet: Option<String>,
+    /// How long to keep the coins locked, e.g. until the transaction
+    /// confirms.
+    #[arg(long, value_parser = parse::duration, default_value = "1h", requires = "wallet")]
+    lock_for: u64,
+    /// A label for the transaction in the wallet's history.
+    #[arg(long, requires = "wallet")]
+    label: Option<String>,
+    /// Broadcast the transaction once the keys fully sign it, printing its
+    /// txid.
+    #[arg(long)]
+    broadcast: bool,
+    #[command(flatten)]
+    backends: broadcast::BackendArgs,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+    #[command(flatten)]
+    price: price::PriceArgs,
 }
 
 fn main() {
-    let args = Cli::parse();
+    // Addresses and keys are checked against the network as they're
+    // parsed, so it has to be chosen first. An invalid one is left for
+    // clap to report.
+    let args: Vec<String> = env::args().collect();
+    let chosen = args
+        .windows(2)
+        .find_map(|pair| (pair[0] == "--network").then_some(pair[1].as_str()))
+        .or_else(|| args.iter().find_map(|arg| arg.strip_prefix("--network=")));
+    if let Some(Ok(network)) = chosen.map(parse::network) {
+        set_network(network).expect("nothing chose it yet");
+    }
+    // So are secrets, which are only read from the command line when
+    // allowed.
+    if args.iter().any(|arg| arg == "--allow-insecure-argv") {
+        secret::allow_argv();
+    }
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        // Descriptors are parsed along with the arguments, but a bad one
+        // exits like any other descriptor that can't be used.
+        let source = std::error::Error::source(&e).and_then(|e| e.downcast_ref::<Error>());
+        if let Some(error @ Error::Descriptor(_)) = source {
+            let _ = e.print();
+            process::exit(error.exit_code());
+        }
+        e.exit()
+    });
+    set_network(cli.network).expect("chosen above");
+    if cli.allow_insecure_argv {
+        secret::allow_argv();
+    }
+    if cli.low_r {
+        sign::grind_low_r();
+    }
+    let mut tracer = trace::Tracer::new(cli.verbose);
+    let mut events =
+        events::Events::new(cli.events, cli.events_file.as_deref()).unwrap_or_else(|e| {
+            eprintln!("Can't open the events file: {}", e);
+            process::exit(1);
+        });
 
-    let secp256k1 = secp256k1::Secp256k1::new();
+    if let Err(e) = run(cli.command, &mut tracer, &mut events) {
+        events.emit(
+            "error",
+            json!({ "message": e.to_string(), "exit_code": e.exit_code() }),
+        );
+        eprintln!("{}", e);
+        if let Some(hint) = e.hint() {
+            eprintln!("{}", hint);
+        }
+        process::exit(e.exit_code());
+    }
+}
 
-    let descriptor = Descriptor::from_str(&args.descriptor).unwrap();
-    assert!(descriptor.sanity_check().is_ok());
-    println!(
-        "Descriptor pubkey script: {}",
-        descriptor.script_pubkey()
-    );
-    println!(
-        "Descriptor address: {}",
-        descriptor.address(Network::Regtest).unwrap()
-    );
-    println!(
-        "Weight for witness satisfaction cost {}",
-        descriptor.max_satisfaction_weight().unwrap()
-    );
+// Run the command, with the errors of the spend stages classified by what
+// went wrong.
+fn run(
+    command: Command,
+    tracer: &mut trace::Tracer,
+    events: &mut events::Events,
+) -> Result<(), Error> {
+    match command {
+        Command::Spend(args) => spend(args, tracer, events)?,
+        Command::Create(args) => create::run(args, tracer, events)?,
+        Command::Sign(args) => sign::run(args)?,
+        Command::Finalize(arg) => stage::finalize(arg)?,
+        Command::Extract(arg) => stage::extract(arg)?,
+        Command::Show {
+            inputs,
+            clipboard,
+            sighashes,
+        } => show(inputs, clipboard, sighashes)?,
+        Command::DecodeTx { tx } => {
+            print!("{}", decode::Breakdown(&tx));
+        }
+        Command::Bbqr { command } => bbqr::run(command)?,
+        Command::Ur { command } => ur::run(command)?,
+        Command::ConvertPsbt(args) => args.out.store(args.psbt.load()?)?,
+        Command::MusigNonce(args) => musig::nonce(args)?,
+        Command::MusigPartialSign(args) => musig::partial_sign(args)?,
+        Command::MusigAggregate(args) => musig::aggregate(args)?,
+        Command::Combine { psbts, out } => {
+            merge::combine(psbts).and_then(|psbt| out.store(psbt))?
+        }
+        Command::Join { psbts, out } => merge::join(psbts).and_then(|psbt| out.store(psbt))?,
+        Command::Tpm { command } => tpm::run(command)?,
+        Command::Annex { command } => annex::run(command)?,
+        Command::CheckReplacement(args) => rbf::run(args)?,
+        Command::Broadcast(args) => broadcast::run(args, events)?,
+        Command::Replace(args) => rbf::replace(args)?,
+        Command::BumpFee(args) => rbf::bump_fee(args)?,
+        Command::Tui(args) => tui::run(args)?,
+        Command::Repl(args) => repl::run(args)?,
+        Command::Setup => setup::run()?,
+        Command::Rescan(args) => rescan::run(args)?,
+        Command::Sweep(args) => sweep::run(args)?,
+        Command::Contacts { command } => contacts::run(command)?,
+        Command::History(args) => history::run(args)?,
+        Command::Locks { command } => locks::run(command)?,
+        Command::Schedule { command } => schedule::run(command)?,
+        Command::Bip47 { command } => bip47::run(command)?,
+        Command::Bip85 { command } => bip85::run(command)?,
+        Command::VerifyAddress(args) => verify::run(args)?,
+        Command::Verify(args) => verify::check(args)?,
+        Command::ProveReserves(args) => reserves::prove(args)?,
+        Command::VerifyReserves(args) => reserves::verify(args)?,
+        Command::EstimateWeight(args) => estimate::run(args)?,
+        Command::Inspect(args) => inspect::run(args)?,
+        Command::Bench(args) => {
+            bench::run(args);
+        }
+        Command::Selftest(args) => selftest::run(args)?,
+        Command::Completions { shell } => {
+            let mut cmd = Cli::command();
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(shell, &mut cmd, name, &mut io::stdout());
+        }
+        Command::Manpages { dir } => manpages(&dir)?,
+    }
+    Ok(())
+}
 
-    let hotkey_private =
-        PrivateKey::from_str(&args.hotkey).expect("Can't parse the hot wallet private key");
+fn manpages(dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    clap_mangen::generate_to(Cli::command(), dir)
+        .map_err(|e| format!("Can't write man pages to {}: {}", dir.display(), e))
+}
 
-    println!(
-        "Hot wallet public key: {}",
-        hotkey_private.public_key(&secp256k1)
-    );
+fn show(mut inputs: Vec<String>, clipboard: bool, sighashes: bool) -> Result<(), String> {
+    if clipboard {
+        inputs.push(clipboard::read().map_err(|e| format!("Can't read the clipboard: {}", e))?);
+    }
+    for input in &inputs {
+        let artifact = artifact::detect(input)?;
+        print!("{}", artifact);
+        if let (true, artifact::Artifact::Psbt(psbt)) = (sighashes, &artifact) {
+            print!("{}", decode::Sighashes(psbt));
+        }
+    }
+    Ok(())
+}
 
-    let cosigner_private =
-        PrivateKey::from_str(&args.cosigner).expect("Can't parse cosigner private key");
+// Report the inputs, outputs, fee and estimated size of the unsigned spend
+// and hand the PSBT on, with the report as JSON on stdout for --json.
+fn dry_run(funded: create::Funded, out: &stage::PsbtOut, json: bool) -> Result<(), Error> {
+    let psbt = funded.psbt;
+    let descriptors: Vec<_> = funded.descriptors.iter().collect();
+    let weight = policy::estimate_weight(&psbt, &descriptors)?;
+    let vsize = weight.div_ceil(4);
+    let feerate = funded.fee.to_sat() as f64 / vsize as f64;
+    let outputs: Vec<_> = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|txout| {
+            let dust = !txout.script_pubkey.is_op_return()
+                && txout.value < txout.script_pubkey.minimal_non_dust();
+            (txout, dust)
+        })
+        .collect();
+    let paid: u64 = outputs.iter().map(|(txout, _)| txout.value.to_sat()).sum();
 
+    if json {
+        let report = json!({
+            "inputs": psbt.inputs.len(),
+            "input_total": funded.total.to_sat(),
+            "outputs": outputs
+                .iter()
+                .map(|(txout, dust)| json!({
+                    "script_pubkey": txout.script_pubkey.to_hex_string(),
+                    "value": txout.value.to_sat(),
+                    "dust": dust,
+                }))
+                .collect::<Vec<_>>(),
+            "output_total": paid,
+            "fee": funded.fee.to_sat(),
+            "estimated_weight": weight,
+            "estimated_vsize": vsize,
+            "feerate_sat_per_vb": feerate,
+            "psbt": psbt.to_string(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        if out.to_file() {
+            out.store(psbt)?;
+        }
+        return Ok(());
+    }
+    println!("Dry run, nothing is signed:");
     println!(
-        "The cosigner public key: {}",
-        cosigner_private.public_key(&secp256k1)
+        "  {} inputs worth {} sat",
+        psbt.inputs.len(),
+        funded.total.to_sat()
     );
+    println!("  {} outputs paying {} sat:", outputs.len(), paid);
+    for (i, (txout, dust)) in outputs.iter().enumerate() {
+        let to = Address::from_script(&txout.script_pubkey, network())
+            .map_or_else(|_| txout.script_pubkey.to_string(), |a| a.to_string());
+        println!(
+            "    {}: {} sat to {}{}",
+            i,
+            txout.value.to_sat(),
+            to,
+            if *dust {
+                ", below the dust threshold"
+            } else {
+                ""
+            }
+        );
+    }
+    println!("  Fee: {} sat", funded.fee.to_sat());
+    println!(
+        "  Estimated size once signed: {} vB ({} WU), at most",
+        vsize, weight
+    );
+    println!("  Effective feerate: {:.2} sat/vB, at least", feerate);
+    if outputs.iter().any(|(_, dust)| *dust) {
+        eprintln!(
+            "WARNING: An output is below the dust threshold, nodes won't relay the transaction"
+        );
+    }
+    out.store(psbt)?;
+    Ok(())
+}
 
-    let spend_tx = Transaction {
-        version: 2,
-        lock_time: PackedLockTime(0),
-        input: vec![],
-        output: vec![],
-    };
-
-    let mut psbt = Psbt {
-        unsigned_tx: spend_tx,
-        unknown: BTreeMap::new(),
-        proprietary: BTreeMap::new(),
-        xpub: BTreeMap::new(),
-        version: 0,
-        inputs: vec![],
-        outputs: vec![],
+fn spend(
+    args: SpendArgs,
+    tracer: &mut trace::Tracer,
+    events: &mut events::Events,
+) -> Result<(), Error> {
+    let secp256k1 = secp256k1::Secp256k1::new();
+    // With --json stdout carries only the report, so the rest goes to
+    // stderr.
+    let json = args.json;
+    let say = |line: String| {
+        if json {
+            eprintln!("{}", line)
+        } else {
+            println!("{}", line)
+        }
     };
 
-    let depo_tx: Transaction = deserialize(&Vec::<u8>::from_hex(&args.rawtx).unwrap()).unwrap();
-    let receiver = Address::from_str(&args.address).unwrap();
-
-    let (outpoint, witness_utxo) = get_vout(&depo_tx, descriptor.script_pubkey());
-
-    let mut txin = TxIn::default();
-    txin.previous_output = outpoint;
+    let funded = args.funding.build(&args.rpc, tracer, events)?;
+    let descriptor = &funded.descriptors[0];
+    if let Some(index) = funded.index {
+        say(format!("Descriptor derivation index: {}", index));
+    }
+    say(format!(
+        "Descriptor pubkey script: {}",
+        descriptor.script_pubkey()
+    ));
+    let address = descriptor
+        .address(network())
+        .map_err(|e| Error::Descriptor(format!("The descriptor has no address: {}", e)))?;
+    say(format!("Descriptor address: {}", address));
+    let satisfaction_weight = policy::max_satisfaction_weight(descriptor)
+        .map_err(|e| Error::Descriptor(format!("Can't estimate the satisfaction weight: {}", e)))?;
+    say(format!(
+        "Weight for witness satisfaction cost {}",
+        satisfaction_weight
+    ));
+    if args.dry_run {
+        return dry_run(funded, &args.out, json);
+    }
 
-    txin.sequence = Sequence::MAX;
-    psbt.unsigned_tx.input.push(txin);
+    let hotkey = match args.hotkey {
+        Some(hotkey) => Some(hotkey),
+        None => secret::private_key(args.hotkey_file.as_deref(), args.hotkey_env.as_deref())?,
+    };
+    let cosigner = match args.cosigner {
+        Some(cosigner) => Some(cosigner),
+        None => secret::private_key(args.cosigner_file.as_deref(), args.cosigner_env.as_deref())?,
+    };
+    if let Some(hotkey) = &hotkey {
+        say(format!(
+            "Hot wallet public key: {}",
+            hotkey.public_key(&secp256k1)
+        ));
+    }
+    if let Some(cosigner) = &cosigner {
+        say(format!(
+            "The cosigner public key: {}",
+            cosigner.public_key(&secp256k1)
+        ));
+    }
+    let mut keys: Vec<PrivateKey> = hotkey.into_iter().chain(cosigner).collect();
 
-    psbt.unsigned_tx.output.push(TxOut {
-        script_pubkey: receiver.script_pubkey(),
-        value: args.amount - 500,
+    let mut report = json!({
+        "address": address.to_string(),
+        "script_pubkey": descriptor.script_pubkey().to_hex_string(),
+        "satisfaction_weight": satisfaction_weight.to_wu(),
+        "derivation_index": funded.index,
+        "paid": funded.paid.to_sat(),
+        "fee": funded.fee.to_sat(),
     });
 
-    // Generate signatures & witness data.
-    let mut input = psbt::Input::default();
-    input
-        .update_with_descriptor_unchecked(&descriptor)
-        .unwrap();
-
-    input.witness_utxo = Some(witness_utxo.clone());
-    psbt.inputs.push(input);
-    psbt.outputs.push(psbt::Output::default());
+    let descriptors: Vec<_> = funded.descriptors.iter().collect();
+    let mut psbt = funded.psbt;
+    for (i, (input, descriptor)) in psbt.unsigned_tx.input.iter().zip(&descriptors).enumerate() {
+        let satisfaction_weight = policy::max_satisfaction_weight(descriptor).map_err(|e| {
+            Error::Descriptor(format!("Can't estimate the satisfaction weight: {}", e))
+        })?;
+        let utxo = build::spent_utxo(&psbt, i).expect("updated by build");
+        say(format!(
+            "Input {}: {} ({} sat) under {}, satisfaction weight {}",
+            i,
+            input.previous_output,
+            utxo.value.to_sat(),
+            descriptor,
+            satisfaction_weight
+        ));
+    }
+    if let Some(price) = args.price.price() {
+        say(format!(
+            "Paying {} sat ({}) with a fee of {} sat ({})",
+            funded.paid.to_sat(),
+            price.value(funded.paid),
+            funded.fee.to_sat(),
+            price.value(funded.fee)
+        ));
+        say(price.notice().to_string());
+    }
 
-    let mut sighash_cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut xprivs = args.xprivs;
+    xprivs.extend(args.mnemonic.master()?);
+    for key in sign::derive_keys(&secp256k1, &psbt, &xprivs)? {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
 
-    let msg = psbt
-        .sighash_msg(0, &mut sighash_cache, None)
-        .unwrap()
-        .to_secp_msg();
+    let hash_tys = args.sighashes.resolve(&psbt)?;
+    let risks = sign::risks(&psbt, &hash_tys);
+    if !risks.is_empty() && !args.allow_risky {
+        return Err(Error::Other(format!(
+            "Refusing to sign, pass --allow-risky to go ahead:\n  {}",
+            risks.join("\n  ")
+        )));
+    }
 
-    // Fixme: Take as parameter.
-    let hash_ty = bitcoin::EcdsaSighashType::All;
+    // Outputs found in the node's UTXO set come with no transaction to
+    // check them against, the node is trusted for those.
+    if let create::Source::Transaction(rawtx) = &args.funding.rawtx {
+        let mut prev_txs = args.funding.prev_txs.clone();
+        prev_txs.push(rawtx.clone());
+        sign::check_utxos(&psbt, &prev_txs)?;
+    }
+    policy::check_standard(&psbt, &descriptors)?;
+    policy::check_locktime(&psbt, &descriptors).map_err(Error::Descriptor)?;
+    if let Some(wallet) = &args.wallet {
+        let outpoints: Vec<OutPoint> = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        wallet::lock_coins(wallet, &outpoints, args.lock_for)?;
+    }
 
-    let sk1 = hotkey_private.inner;
-    let sk2 = cosigner_private.inner;
+    sign::add_preimages(&mut psbt, &args.preimages)?;
 
     // Construct the signatures and add them to the psbt.
-    let sig1 = secp256k1.sign_ecdsa(&msg, &sk1);
-    let pk1 = hotkey_private.public_key(&secp256k1);
-    assert!(secp256k1.verify_ecdsa(&msg, &sig1, &pk1.inner).is_ok());
-
-    let sig2 = secp256k1.sign_ecdsa(&msg, &sk2);
-    let pk2 = cosigner_private.public_key(&secp256k1);
-    assert!(secp256k1.verify_ecdsa(&msg, &sig2, &pk2.inner).is_ok());
-
-    psbt.inputs[0].partial_sigs.insert(
-        pk1,
-        bitcoin::EcdsaSig {
-            sig: sig1,
-            hash_ty: hash_ty,
-        },
-    );
-
-    psbt.inputs[0].partial_sigs.insert(
-        pk2,
-        bitcoin::EcdsaSig {
-            sig: sig2,
-            hash_ty: hash_ty,
+    let all: Vec<usize> = (0..psbt.inputs.len()).collect();
+    sign::sign_with_types(
+        &secp256k1,
+        &mut psbt,
+        &all,
+        &keys,
+        &hash_tys,
+        |psbt, i, pk| {
+            events.emit(
+                "signature_added",
+                json!({ "input": i, "pubkey": pk.to_string() }),
+            );
+            tracer.stage(&format!("signature {} on input {}", pk, i), psbt);
         },
-    );
-
-    let serialized = serialize(&psbt);
-    println!("{}", base64::encode(&serialized));
+    )
+    .map_err(|e| Error::Signature(format!("Can't compute the sighash: {}", e)))?;
+    for (i, pk) in args.hwi.sign(&mut psbt)? {
+        say(format!("Hardware wallet signed input {} with {}", i, pk));
+        events.emit("signature_added", json!({ "input": i, "pubkey": pk }));
+        tracer.stage(&format!("signature {} on input {}", pk, i), &psbt);
+    }
+    for (i, path) in funded.spend_paths.iter().enumerate() {
+        let Some(path) = path else {
+            continue;
+        };
+        let needed = path.public_keys(&secp256k1)?;
+        let missing = sign::keep_signatures(&mut psbt.inputs[i], &needed);
+        if !missing.is_empty() {
+            let missing: Vec<String> = missing.iter().map(|pk| pk.to_string()).collect();
+            return Err(Error::Unsatisfied(format!(
+                "Input {} can't be spent by the chosen path, the keys given can't sign for {}",
+                i,
+                missing.join(", ")
+            )));
+        }
+    }
 
-    psbt.finalize_mut(&secp256k1).unwrap();
-    // println!("psbt: {:#?}", psbt);
+    let serialized = psbt.to_string();
+    report["psbt"] = json!(serialized);
+    if !json || args.out.to_file() {
+        args.out.store(psbt.clone())?;
+    }
+    if args.clipboard {
+        if let Err(e) = clipboard::write(&serialized) {
+            eprintln!("Can't copy the PSBT to the clipboard: {}", e);
+        }
+    }
 
-    let tx = psbt.extract_tx();
-    println!("raw: {}", tx.raw_hex());
-}
+    // With a key missing, the signatures may only be part of what's needed
+    // and the PSBT goes on to the other signers.
+    let partial = keys.len() + usize::from(args.hwi.enabled()) < 2;
+    let mut finalized = psbt.clone();
+    match build::finalize(&secp256k1, &mut finalized) {
+        Ok(()) => psbt = finalized,
+        Err(e) if partial => {
+            eprintln!(
+                "Not fully signed, pass the PSBT on to the other signers: {}",
+                e
+            );
+            if json {
+                report["complete"] = json!(false);
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    tracer.stage("finalized", &psbt);
 
-// Find the Outpoint by script pubkey.
-fn get_vout(tx: &Transaction, spk: Script) -> (OutPoint, TxOut) {
-    for (i, txout) in tx.clone().output.into_iter().enumerate() {
-        if spk == txout.script_pubkey {
-            return (OutPoint::new(tx.txid(), i as u32), txout);
+    let tx = psbt.extract_tx_unchecked_fee_rate();
+    events.emit(
+        "finalized",
+        json!({ "txid": tx.compute_txid().to_string() }),
+    );
+    say(format!("raw: {}", serialize_hex(&tx)));
+    if args.broadcast {
+        let mut backends = args.backends;
+        if let Some(url) = &args.funding.esplora {
+            backends.default_to(broadcast::Backend::Esplora(url.clone()));
         }
+        let txid = broadcast::push(&backends, &args.rpc, &tx, events)?;
+        say(format!("Broadcast {}", txid));
+    }
+    if let Some(wallet) = &args.wallet {
+        if let Err(e) = history::record(wallet, &tx, funded.total, args.label, args.broadcast) {
+            eprintln!(
+                "Can't record the transaction in the history of {}: {}",
+                wallet, e
+            );
+        }
+    }
+    if json {
+        report["complete"] = json!(true);
+        report["tx"] = json!(serialize_hex(&tx));
+        report["txid"] = json!(tx.compute_txid().to_string());
+        report["vsize"] = json!(tx.vsize());
+        report["broadcast"] = json!(args.broadcast);
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
     }
-    panic!("Only call get vout on functions which have the expected outpoint");
+    Ok(())
 }
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use miniscript::bitcoin::OutPoint;
+use miniscript::bitcoin::Psbt;
+
+/// Merge PSBTs for the same transaction, e.g. each carrying the signatures
+/// of a different cosigner.
+///
+/// Fails if the PSBTs are for different transactions, or hold different
+/// signatures by the same key, origins of the same key or scripts for an
+/// input.
+pub fn combine(psbts: Vec<Psbt>) -> Result<Psbt, String> {
+    let mut psbts = psbts.into_iter().enumerate();
+    let (_, mut combined) = psbts.next().ok_or("No PSBTs to combine")?;
+    let mut problems = Vec::new();
+    for (n, psbt) in psbts {
+        if psbt.unsigned_tx != combined.unsigned_tx {
+            return Err(format!(
+                "PSBT {} is for transaction {}, PSBT 0 for {}",
+                n,
+                psbt.unsigned_tx.compute_txid(),
+                combined.unsigned_tx.compute_txid()
+            ));
+        }
+        for (i, (ours, theirs)) in combined.inputs.iter().zip(&psbt.inputs).enumerate() {
+            for (pk, sig) in &theirs.partial_sigs {
+                if matches!(ours.partial_sigs.get(pk), Some(other) if other != sig) {
+                    problems.push(format!(
+                        "input {}: PSBT {} has a different signature by {}",
+                        i, n, pk
+                    ));
+                }
+            }
+            // Combining keeps our scripts and key origins, silently
+            // dropping different ones.
+            for (pk, origin) in &theirs.bip32_derivation {
+                if matches!(ours.bip32_derivation.get(pk), Some(other) if other != origin) {
+                    problems.push(format!(
+                        "input {}: PSBT {} derives {} differently",
+                        i, n, pk
+                    ));
+                }
+            }
+            let scripts = [
+                ("witness", &ours.witness_script, &theirs.witness_script),
+                ("redeem", &ours.redeem_script, &theirs.redeem_script),
+            ];
+            for (kind, ours, theirs) in scripts {
+                if let (Some(ours), Some(theirs)) = (ours, theirs) {
+                    if ours != theirs {
+                        problems.push(format!(
+                            "input {}: PSBT {} has a different {} script",
+                            i, n, kind
+                        ));
+                    }
+                }
+            }
+        }
+        combined
+            .combine(psbt)
+            .map_err(|e| format!("Can't combine PSBT {}: {}", n, e))?;
+    }
+    if !problems.is_empty() {
+        return Err(format!("The PSBTs conflict:\n  {}", problems.join("\n  ")));
+    }
+    Ok(combined)
+}
+
+/// Join PSBTs for different transactions into one spending all of their
+/// inputs to all of their outputs, e.g. to batch payments.
+///
+/// Fails if an outpoint is spent more than once, the transactions disagree
+/// on version or lock time, or an input is already signed since joining
+/// would invalidate its signatures.
+pub fn join(psbts: Vec<Psbt>) -> Result<Psbt, String> {
+    let mut psbts = psbts.into_iter().enumerate();
+    let (_, mut joined) = psbts.next().ok_or("No PSBTs to join")?;
+    let mut problems = Vec::new();
+    let mut spenders = BTreeMap::<OutPoint, usize>::new();
+    let mut check_inputs = |n: usize, psbt: &Psbt, problems: &mut Vec<String>| {
+        let inputs = psbt.unsigned_tx.input.iter().zip(&psbt.inputs);
+        for (i, (txin, input)) in inputs.enumerate() {
+            if let Some(other) = spenders.insert(txin.previous_output, n) {
+                problems.push(format!(
+                    "PSBT {} input {}: {} is also spent by PSBT {}",
+                    n, i, txin.previous_output, other
+                ));
+            }
+            if !input.partial_sigs.is_empty() || input.final_script_witness.is_some() {
+                problems.push(format!(
+                    "PSBT {} input {}: already signed, joining would invalidate the signatures",
+                    n, i
+                ));
+            }
+        }
+    };
+    check_inputs(0, &joined, &mut problems);
+
+    for (n, psbt) in psbts {
+        check_inputs(n, &psbt, &mut problems);
+        if psbt.unsigned_tx.version != joined.unsigned_tx.version {
+            problems.push(format!(
+                "PSBT {}: version {} differs from {}",
+                n, psbt.unsigned_tx.version, joined.unsigned_tx.version
+            ));
+        }
+        if psbt.unsigned_tx.lock_time != joined.unsigned_tx.lock_time {
+            problems.push(format!(
+                "PSBT {}: lock time {} differs from {}",
+                n, psbt.unsigned_tx.lock_time, joined.unsigned_tx.lock_time
+            ));
+        }
+        joined.unsigned_tx.input.extend(psbt.unsigned_tx.input);
+        joined.unsigned_tx.output.extend(psbt.unsigned_tx.output);
+        joined.inputs.extend(psbt.inputs);
+        joined.outputs.extend(psbt.outputs);
+        joined.xpub.extend(psbt.xpub);
+    }
+    if !problems.is_empty() {
+        return Err(format!(
+            "Can't join the PSBTs:\n  {}",
+            problems.join("\n  ")
+        ));
+    }
+    Ok(joined)
+}
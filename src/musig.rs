@@ -0,0 +1,1001 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use miniscript::bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use miniscript::bitcoin::hex::{DisplayHex, FromHex};
+use miniscript::bitcoin::psbt::raw::ProprietaryKey;
+use miniscript::bitcoin::psbt::Input;
+use miniscript::bitcoin::secp256k1::{self, schnorr, Parity, PublicKey, SecretKey};
+use miniscript::bitcoin::sighash::SighashCache;
+use miniscript::bitcoin::taproot::{self, TapTweakHash};
+use miniscript::bitcoin::{PrivateKey, Psbt, TapSighashType, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{annex, parse, secret, stage, wallet};
+
+// The proprietary PSBT fields MuSig2 signers exchange their keys, nonces
+// and partial signatures in, each keyed by the participant's public key.
+const SUBTYPE_PARTICIPANTS: u8 = 0x02;
+const SUBTYPE_PUB_NONCE: u8 = 0x03;
+const SUBTYPE_PARTIAL_SIG: u8 = 0x04;
+/// The order of the secp256k1 group, big endian.
+const ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+/// How long a secret nonce waits to be signed with before it's deleted.
+const SESSION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Args)]
+pub struct NonceArgs {
+    #[command(flatten)]
+    psbt: stage::PsbtArg,
+    #[command(flatten)]
+    out: stage::PsbtOut,
+    /// Our private key, one of the participants': `file:<PATH>`,
+    /// `env:<VAR>` or `-` to be prompted for it.
+    #[arg(long, value_parser = secret::parser(parse::private_key))]
+    key: PrivateKey,
+    /// The public key of a participant, ours included, whose aggregate is
+    /// the internal key of the inputs to sign. Repeat for each of them, in
+    /// any order. Only the first signer needs them, they're kept in the
+    /// PSBT for the others.
+    #[arg(long = "participant", value_parser = parse::public_key)]
+    participants: Vec<PublicKey>,
+}
+
+#[derive(Args)]
+pub struct PartialSignArgs {
+    #[command(flatten)]
+    psbt: stage::PsbtArg,
+    #[command(flatten)]
+    out: stage::PsbtOut,
+    /// The private key the nonce was made with, given the same ways.
+    #[arg(long, value_parser = secret::parser(parse::private_key))]
+    key: PrivateKey,
+}
+
+// The participants' keys aggregated as BIP327's KeyAgg does, then tweaked.
+struct KeyAgg {
+    q: PublicKey,
+    // Whether the accumulated sign flips of the tweaks negate the keys.
+    negated: bool,
+    // The accumulated tweak.
+    tweak: Option<SecretKey>,
+}
+
+// What every participant's signing needs of the nonces and the message.
+struct Session {
+    b: Option<SecretKey>,
+    r: PublicKey,
+    e: Option<SecretKey>,
+}
+
+// A secret nonce waiting in the data directory for `musig-partial-sign`,
+// bound to the sighash it was made for and encrypted to the key it signs
+// with. Everything but the nonce is in the clear, all of it authenticated.
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    /// Seconds since the epoch.
+    created: u64,
+    sighash: String,
+    pub_nonce: String,
+    sec_nonce: String,
+    mac: String,
+}
+
+/// Add our MuSig2 public nonce to every taproot input whose internal key
+/// aggregates the participants', keeping the secret half encrypted in the
+/// data directory until `musig-partial-sign` uses it up, for a day at most.
+pub fn nonce(args: NonceArgs) -> Result<(), String> {
+    let secp = secp256k1::Secp256k1::new();
+    let mut psbt = args.psbt.load()?;
+    expire_sessions();
+    let pk = args.key.inner.public_key(&secp);
+    let mut participants = args.participants.clone();
+    participants.sort_by_key(|key| key.serialize());
+    participants.dedup();
+    if !args.participants.is_empty() {
+        if participants.len() < 2 {
+            return Err("MuSig2 needs at least two distinct participants".to_string());
+        }
+        let agg = key_agg(&participants)?;
+        eprintln!(
+            "The participants aggregate to {}",
+            agg.q.x_only_public_key().0
+        );
+        let internal = Some(agg.q.x_only_public_key().0);
+        for input in &mut psbt.inputs {
+            if input.tap_internal_key == internal {
+                input.proprietary.insert(
+                    key(SUBTYPE_PARTICIPANTS, Vec::new()),
+                    participants
+                        .iter()
+                        .flat_map(|key| key.serialize())
+                        .collect(),
+                );
+            }
+        }
+    }
+
+    let msgs = key_path_msgs(&psbt)?;
+    let mut added = 0;
+    for (i, (input, msg)) in psbt.inputs.iter_mut().zip(msgs).enumerate() {
+        let Some(keys) = participants_of(input)? else {
+            continue;
+        };
+        if !keys.contains(&pk) {
+            return Err(format!(
+                "Input {} is signed by other participants than {}",
+                i, pk
+            ));
+        }
+        let agg = key_agg(&keys)?;
+        let aggpk = agg.q.x_only_public_key().0;
+        let (secnonce, pubnonce) = nonce_gen(&secp, &args.key.inner, &pk, &aggpk, &msg)?;
+        save_session(&args.key.inner, &msg, &pubnonce, &secnonce)?;
+        input.proprietary.insert(
+            key(SUBTYPE_PUB_NONCE, pk.serialize().to_vec()),
+            pubnonce.to_vec(),
+        );
+        eprintln!("Added the nonce of {} to input {}", pk, i);
+        added += 1;
+    }
+    if added == 0 {
+        return Err(
+            "No input's internal key aggregates the participants, pass them with --participant"
+                .to_string(),
+        );
+    }
+    args.out.store(psbt)
+}
+
+/// Add our MuSig2 partial signature to every input with the nonces of all
+/// its participants, deleting the secret nonce so it's never used twice.
+///
+/// A nonce only signs the sighash it was made for, so a PSBT changed since
+/// `musig-nonce` needs new nonces.
+pub fn partial_sign(args: PartialSignArgs) -> Result<(), String> {
+    let secp = secp256k1::Secp256k1::new();
+    let mut psbt = args.psbt.load()?;
+    expire_sessions();
+    let pk = args.key.inner.public_key(&secp);
+    let msgs = key_path_msgs(&psbt)?;
+    let mut signed = 0;
+    for (i, (input, msg)) in psbt.inputs.iter_mut().zip(msgs).enumerate() {
+        let Some(keys) = participants_of(input)? else {
+            continue;
+        };
+        let Some(ours) = input
+            .proprietary
+            .get(&key(SUBTYPE_PUB_NONCE, pk.serialize().to_vec()))
+            .cloned()
+        else {
+            continue;
+        };
+        let nonces = pub_nonces(input, &keys)
+            .map_err(|missing| format!("Input {} lacks the nonce of {}", i, missing))?;
+        let agg = tweaked(&secp, &keys, input)?;
+        let secnonce = claim_session(&args.key.inner, &msg, &ours)
+            .map_err(|e| format!("Input {}: {}", i, e))?;
+        if secnonce[64..] != pk.serialize() {
+            return Err(format!("Input {}: the secret nonce isn't {}'s", i, pk));
+        }
+        let session = session(&secp, &agg, nonce_agg(&nonces), &msg);
+        let k1 = scalar(secnonce[..32].try_into().unwrap());
+        let k2 = scalar(secnonce[32..64].try_into().unwrap());
+        let s = sign(k1, k2, &args.key.inner, &pk, &keys, &agg, &session);
+        input.proprietary.insert(
+            key(SUBTYPE_PARTIAL_SIG, pk.serialize().to_vec()),
+            bytes(s).to_vec(),
+        );
+        eprintln!("Added the partial signature of {} to input {}", pk, i);
+        signed += 1;
+    }
+    if signed == 0 {
+        return Err(format!(
+            "No input has a MuSig2 nonce of {}, run musig-nonce first",
+            pk
+        ));
+    }
+    args.out.store(psbt)
+}
+
+/// Aggregate the partial signatures of every input once all participants
+/// made theirs into its key path signature, ready to finalize.
+pub fn aggregate(args: stage::StageArgs) -> Result<(), String> {
+    let secp = secp256k1::Secp256k1::new();
+    let mut psbt = args.psbt.load()?;
+    let msgs = key_path_msgs(&psbt)?;
+    let mut aggregated = 0;
+    for (i, (input, msg)) in psbt.inputs.iter_mut().zip(msgs).enumerate() {
+        let Some(keys) = participants_of(input)? else {
+            continue;
+        };
+        let nonces = pub_nonces(input, &keys)
+            .map_err(|missing| format!("Input {} lacks the nonce of {}", i, missing))?;
+        let agg = tweaked(&secp, &keys, input)?;
+        let session = session(&secp, &agg, nonce_agg(&nonces), &msg);
+        let g = even(&agg.q);
+        let mut s = mul(session.e, if g { agg.tweak } else { neg(agg.tweak) });
+        for (pk, (r1, r2)) in keys.iter().zip(&nonces) {
+            let partial = input
+                .proprietary
+                .get(&key(SUBTYPE_PARTIAL_SIG, pk.serialize().to_vec()))
+                .ok_or_else(|| format!("Input {} lacks the partial signature of {}", i, pk))?;
+            let partial = <[u8; 32]>::try_from(&partial[..])
+                .ok()
+                .filter(|partial| *partial < ORDER)
+                .map(scalar)
+                .ok_or_else(|| format!("The partial signature of {} is malformed", pk))?;
+            // s⋅G = R + e⋅a⋅g⋅P for the participant's own R and P.
+            let mut r = point_add(Some(*r1), point_mul(&secp, Some(*r2), session.b));
+            if !even(&session.r) {
+                r = r.map(|r| r.negate(&secp));
+            }
+            let a = mul(session.e, coefficient(&keys, pk));
+            let a = if g != agg.negated { a } else { neg(a) };
+            if base_mul(&secp, partial) != point_add(r, point_mul(&secp, Some(*pk), a)) {
+                return Err(format!(
+                    "The partial signature of {} on input {} is invalid",
+                    pk, i
+                ));
+            }
+            s = add(s, partial);
+        }
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&session.r.x_only_public_key().0.serialize());
+        signature[32..].copy_from_slice(&bytes(s));
+        let signature = schnorr::Signature::from_slice(&signature).expect("64 bytes");
+        let output_key = agg.q.x_only_public_key().0;
+        secp.verify_schnorr(&signature, &msg, &output_key)
+            .map_err(|_| format!("The aggregate signature of input {} is invalid", i))?;
+        let sighash_type = match input.sighash_type {
+            None => TapSighashType::Default,
+            Some(ty) => ty
+                .taproot_hash_ty()
+                .map_err(|e| format!("Input {}: {}", i, e))?,
+        };
+        input.tap_key_sig = Some(taproot::Signature {
+            signature,
+            sighash_type,
+        });
+        eprintln!("Aggregated the key path signature of input {}", i);
+        aggregated += 1;
+    }
+    if aggregated == 0 {
+        return Err("No input is signed with MuSig2".to_string());
+    }
+    args.out.store(psbt)
+}
+
+fn key(subtype: u8, key: Vec<u8>) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: annex::PREFIX.to_vec(),
+        subtype,
+        key,
+    }
+}
+
+// The participants recorded on `input`, if it's signed with MuSig2.
+fn participants_of(input: &Input) -> Result<Option<Vec<PublicKey>>, String> {
+    let Some(value) = input
+        .proprietary
+        .get(&key(SUBTYPE_PARTICIPANTS, Vec::new()))
+    else {
+        return Ok(None);
+    };
+    value
+        .chunks(33)
+        .map(|key| PublicKey::from_slice(key).map_err(|e| format!("Bad participant key: {}", e)))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+// The public nonces of `keys` on `input`, or the first key without one.
+fn pub_nonces(input: &Input, keys: &[PublicKey]) -> Result<Vec<(PublicKey, PublicKey)>, String> {
+    keys.iter()
+        .map(|pk| {
+            input
+                .proprietary
+                .get(&key(SUBTYPE_PUB_NONCE, pk.serialize().to_vec()))
+                .filter(|nonce| nonce.len() == 66)
+                .and_then(|nonce| {
+                    let r1 = PublicKey::from_slice(&nonce[..33]).ok()?;
+                    let r2 = PublicKey::from_slice(&nonce[33..]).ok()?;
+                    Some((r1, r2))
+                })
+                .ok_or_else(|| pk.to_string())
+        })
+        .collect()
+}
+
+// The key path sighash of every input, for those that are taproot.
+fn key_path_msgs(psbt: &Psbt) -> Result<Vec<secp256k1::Message>, String> {
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut msgs = Vec::new();
+    for i in 0..psbt.inputs.len() {
+        if participants_of(&psbt.inputs[i])?.is_none() {
+            // Never signed, so any message will do.
+            msgs.push(secp256k1::Message::from_digest([0; 32]));
+            continue;
+        }
+        msgs.push(
+            annex::sighash_msg(psbt, i, &mut cache, None)
+                .map_err(|e| format!("Can't compute the sighash of input {}: {}", i, e))?,
+        );
+    }
+    Ok(msgs)
+}
+
+fn session_dir() -> PathBuf {
+    wallet::data_dir().join("musig")
+}
+
+// Where the secret nonce behind `pubnonce` waits to sign `msg`.
+fn session_path(msg: &secp256k1::Message, pubnonce: &[u8]) -> PathBuf {
+    session_dir().join(format!(
+        "{}-{}",
+        msg.as_ref().to_lower_hex_string(),
+        sha256::Hash::hash(pubnonce)
+    ))
+}
+
+// Store the secret nonce `secnonce` for signing `msg` with `sk`.
+fn save_session(
+    sk: &SecretKey,
+    msg: &secp256k1::Message,
+    pubnonce: &[u8; 66],
+    secnonce: &[u8; 97],
+) -> Result<(), String> {
+    let created = now()?;
+    let key = session_key(sk, msg, pubnonce);
+    let mut encrypted = *secnonce;
+    crypt(&key, &mut encrypted);
+    let file = SessionFile {
+        created,
+        sighash: msg.as_ref().to_lower_hex_string(),
+        pub_nonce: pubnonce.to_lower_hex_string(),
+        sec_nonce: encrypted.to_lower_hex_string(),
+        mac: session_mac(&key, created, &encrypted).to_lower_hex_string(),
+    };
+    let json = serde_json::to_vec(&file).map_err(|e| e.to_string())?;
+    let dir = session_dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    let path = session_path(msg, pubnonce);
+    // Anyone reading it along with our partial signature learns our key.
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&json))
+        .map_err(|e| format!("Can't save the secret nonce to {}: {}", path.display(), e))
+}
+
+// Take the secret nonce behind `pubnonce` for signing `msg` with `sk`, so
+// no one else can.
+//
+// The session file is renamed before it's read: of two signers racing for
+// it only one finds it. It's wiped before signing, so a failure halfway
+// can't leave the nonce to sign another message with.
+fn claim_session(
+    sk: &SecretKey,
+    msg: &secp256k1::Message,
+    pubnonce: &[u8],
+) -> Result<[u8; 97], String> {
+    let path = session_path(msg, pubnonce);
+    let claimed = PathBuf::from(format!("{}.claimed-{}", path.display(), process::id()));
+    match fs::rename(&path, &claimed) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let suffix = format!("-{}", sha256::Hash::hash(pubnonce));
+            let other_sighash = fs::read_dir(session_dir())
+                .into_iter()
+                .flatten()
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().ends_with(&suffix));
+            return Err(if other_sighash {
+                "its nonce was made for another sighash, the transaction changed since \
+                 musig-nonce: make new nonces"
+                    .to_string()
+            } else {
+                "there's no secret nonce for it, each signs once and expires after a day: \
+                 make new nonces"
+                    .to_string()
+            });
+        }
+        Err(e) => return Err(format!("Can't claim {}: {}", path.display(), e)),
+    }
+    let json = fs::read(&claimed);
+    wipe(&claimed).map_err(|e| format!("Can't delete {}: {}", claimed.display(), e))?;
+    let json = json.map_err(|e| format!("Can't read {}: {}", claimed.display(), e))?;
+
+    let corrupt = || format!("{} is corrupt", path.display());
+    let file: SessionFile = serde_json::from_slice(&json).map_err(|_| corrupt())?;
+    let mut secnonce: [u8; 97] = Vec::<u8>::from_hex(&file.sec_nonce)
+        .ok()
+        .and_then(|secnonce| secnonce.try_into().ok())
+        .ok_or_else(corrupt)?;
+    let key = session_key(sk, msg, pubnonce);
+    let bound = file.sighash == msg.as_ref().to_lower_hex_string()
+        && file.pub_nonce == pubnonce.to_lower_hex_string()
+        && file.mac == session_mac(&key, file.created, &secnonce).to_lower_hex_string();
+    if !bound {
+        return Err(format!(
+            "{} isn't a secret nonce of this key for this sighash",
+            path.display()
+        ));
+    }
+    if now()?.saturating_sub(file.created) > SESSION_LIFETIME.as_secs() {
+        return Err("its nonce expired: make new nonces".to_string());
+    }
+    crypt(&key, &mut secnonce);
+    Ok(secnonce)
+}
+
+// Delete the sessions left unused past their lifetime, along with any a
+// signer claimed and died before wiping.
+fn expire_sessions() {
+    for entry in fs::read_dir(session_dir()).into_iter().flatten().flatten() {
+        let expired = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > SESSION_LIFETIME));
+        if expired {
+            let _ = wipe(&entry.path());
+        }
+    }
+}
+
+// Overwrite the file at `path` with zeros before deleting it.
+fn wipe(path: &Path) -> std::io::Result<()> {
+    let len = fs::metadata(path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|mut file| {
+            file.write_all(&vec![0; len as usize])?;
+            file.sync_all()
+        })?;
+    fs::remove_file(path)
+}
+
+fn now() -> Result<u64, String> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .map_err(|e| e.to_string())
+}
+
+// The key encrypting the secret nonce behind `pubnonce`, which only the
+// holder of `sk` can derive and which only decrypts it for signing `msg`.
+fn session_key(sk: &SecretKey, msg: &secp256k1::Message, pubnonce: &[u8]) -> [u8; 32] {
+    tagged(
+        "miniscript-psbt/musig session",
+        &[&sk.secret_bytes(), msg.as_ref(), pubnonce],
+    )
+}
+
+// Encrypt or decrypt `data` by XORing it with a key stream from `key`.
+fn crypt(key: &[u8; 32], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(32).enumerate() {
+        let pad = tagged("miniscript-psbt/musig stream", &[key, &[i as u8]]);
+        for (byte, pad) in chunk.iter_mut().zip(pad) {
+            *byte ^= pad;
+        }
+    }
+}
+
+// The HMAC of a session file's creation time and encrypted nonce, the
+// rest of it being bound by the key already.
+fn session_mac(key: &[u8; 32], created: u64, encrypted: &[u8]) -> [u8; 32] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    engine.input(&created.to_be_bytes());
+    engine.input(encrypted);
+    hmac::Hmac::from_engine(engine).to_byte_array()
+}
+
+// The participants' aggregate with the taproot tweak of `input` applied,
+// after checking it's the input's internal key.
+fn tweaked<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    keys: &[PublicKey],
+    input: &Input,
+) -> Result<KeyAgg, String> {
+    let mut agg = key_agg(keys)?;
+    let internal = agg.q.x_only_public_key().0;
+    if input.tap_internal_key != Some(internal) {
+        return Err(format!(
+            "The participants aggregate to {}, not the input's internal key",
+            internal
+        ));
+    }
+    let t =
+        scalar(TapTweakHash::from_key_and_tweak(internal, input.tap_merkle_root).to_byte_array());
+    // The tweak of an x-only key applies to its even lift.
+    let negate = !even(&agg.q);
+    let q = if negate { agg.q.negate(secp) } else { agg.q };
+    agg.q = point_add(Some(q), base_mul(secp, t)).ok_or("The tweaked key is infinity")?;
+    agg.negated ^= negate;
+    agg.tweak = add(t, if negate { neg(agg.tweak) } else { agg.tweak });
+    Ok(agg)
+}
+
+// BIP327's KeyAgg of `keys`, in the order given.
+fn key_agg(keys: &[PublicKey]) -> Result<KeyAgg, String> {
+    let secp = secp256k1::Secp256k1::verification_only();
+    let q = keys
+        .iter()
+        .map(|pk| point_mul(&secp, Some(*pk), coefficient(keys, pk)))
+        .fold(None, point_add)
+        .ok_or("The participants' keys aggregate to infinity")?;
+    Ok(KeyAgg {
+        q,
+        negated: false,
+        tweak: None,
+    })
+}
+
+// The KeyAgg coefficient of `pk`, one for the first key differing from the
+// first one.
+fn coefficient(keys: &[PublicKey], pk: &PublicKey) -> Option<SecretKey> {
+    if keys.iter().find(|key| **key != keys[0]) == Some(pk) {
+        return Some(SecretKey::from_slice(&[[0; 31].as_slice(), &[1]].concat()).unwrap());
+    }
+    let list: Vec<u8> = keys.iter().flat_map(|key| key.serialize()).collect();
+    let l = tagged("KeyAgg list", &[&list]);
+    scalar(tagged("KeyAgg coefficient", &[&l, &pk.serialize()]))
+}
+
+// BIP327's NonceGen, with fresh randomness, returning the 97 byte secret
+// nonce and the 66 byte public one.
+fn nonce_gen<C: secp256k1::Signing>(
+    secp: &secp256k1::Secp256k1<C>,
+    sk: &SecretKey,
+    pk: &PublicKey,
+    aggpk: &XOnlyPublicKey,
+    msg: &secp256k1::Message,
+) -> Result<([u8; 97], [u8; 66]), String> {
+    let mut random = [0; 32];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .map_err(|e| format!("Can't read randomness: {}", e))?;
+    let mut rand = tagged("MuSig/aux", &[&random]);
+    for (r, k) in rand.iter_mut().zip(sk.secret_bytes()) {
+        *r ^= k;
+    }
+    let msg = msg.as_ref();
+    let k = |i: u8| {
+        scalar(tagged(
+            "MuSig/nonce",
+            &[
+                &rand,
+                &[33],
+                &pk.serialize(),
+                &[32],
+                &aggpk.serialize(),
+                &[1],
+                &(msg.len() as u64).to_be_bytes(),
+                msg,
+                &0u32.to_be_bytes(),
+                &[i],
+            ],
+        ))
+    };
+    let (Some(k1), Some(k2)) = (k(0), k(1)) else {
+        return Err("Drew a zero nonce, try again".to_string());
+    };
+    let mut secnonce = [0; 97];
+    secnonce[..32].copy_from_slice(&k1.secret_bytes());
+    secnonce[32..64].copy_from_slice(&k2.secret_bytes());
+    secnonce[64..].copy_from_slice(&pk.serialize());
+    let mut pubnonce = [0; 66];
+    pubnonce[..33].copy_from_slice(&k1.public_key(secp).serialize());
+    pubnonce[33..].copy_from_slice(&k2.public_key(secp).serialize());
+    Ok((secnonce, pubnonce))
+}
+
+// BIP327's NonceAgg of the participants' `nonces`.
+fn nonce_agg(nonces: &[(PublicKey, PublicKey)]) -> (Option<PublicKey>, Option<PublicKey>) {
+    let r1 = nonces.iter().map(|(r1, _)| Some(*r1)).fold(None, point_add);
+    let r2 = nonces.iter().map(|(_, r2)| Some(*r2)).fold(None, point_add);
+    (r1, r2)
+}
+
+// The nonce coefficient, final nonce and challenge of signing `msg` with
+// the aggregate nonce `(r1, r2)`.
+fn session<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    agg: &KeyAgg,
+    (r1, r2): (Option<PublicKey>, Option<PublicKey>),
+    msg: &secp256k1::Message,
+) -> Session {
+    // Nonces adding up to infinity encode as 33 zero bytes.
+    let encode = |r: Option<PublicKey>| r.map_or([0; 33], |r| r.serialize());
+    let q = agg.q.x_only_public_key().0.serialize();
+    let b = scalar(tagged(
+        "MuSig/noncecoef",
+        &[&encode(r1), &encode(r2), &q, msg.as_ref()],
+    ));
+    let generator = SecretKey::from_slice(&[[0; 31].as_slice(), &[1]].concat())
+        .unwrap()
+        .public_key(&secp256k1::Secp256k1::signing_only());
+    let r = point_add(r1, point_mul(secp, r2, b)).unwrap_or(generator);
+    let e = scalar(tagged(
+        "BIP0340/challenge",
+        &[&r.x_only_public_key().0.serialize(), &q, msg.as_ref()],
+    ));
+    Session { b, r, e }
+}
+
+// BIP327's Sign: the partial signature s = k1 + b⋅k2 + e⋅a⋅d of `sk`,
+// whose public key `pk` is one of `keys`, with the secret nonce `k1`, `k2`.
+// The nonces and the key are negated as needed for the final nonce and key
+// to have even y.
+fn sign(
+    k1: Option<SecretKey>,
+    k2: Option<SecretKey>,
+    sk: &SecretKey,
+    pk: &PublicKey,
+    keys: &[PublicKey],
+    agg: &KeyAgg,
+    session: &Session,
+) -> Option<SecretKey> {
+    let (k1, k2) = match even(&session.r) {
+        true => (k1, k2),
+        false => (neg(k1), neg(k2)),
+    };
+    let a = coefficient(keys, pk);
+    let d = match even(&agg.q) != agg.negated {
+        true => Some(*sk),
+        false => Some(sk.negate()),
+    };
+    add(add(k1, mul(session.b, k2)), mul(mul(session.e, a), d))
+}
+
+fn tagged(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for part in parts {
+        engine.input(part);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+fn even(point: &PublicKey) -> bool {
+    point.x_only_public_key().1 == Parity::Even
+}
+
+// Scalars modulo the group order are secret keys, or `None` for zero,
+// which no secret key is. Points are public keys, or `None` for infinity.
+
+// `bytes` as a big endian integer modulo the order.
+fn scalar(mut bytes: [u8; 32]) -> Option<SecretKey> {
+    if bytes >= ORDER {
+        // Less than twice the order, so once is enough.
+        let mut borrow = 0;
+        for (byte, order) in bytes.iter_mut().zip(ORDER).rev() {
+            let diff = *byte as i16 - order as i16 - borrow;
+            *byte = diff.rem_euclid(256) as u8;
+            borrow = (diff < 0) as i16;
+        }
+    }
+    SecretKey::from_slice(&bytes).ok()
+}
+
+fn bytes(scalar: Option<SecretKey>) -> [u8; 32] {
+    scalar.map_or([0; 32], |scalar| scalar.secret_bytes())
+}
+
+fn add(a: Option<SecretKey>, b: Option<SecretKey>) -> Option<SecretKey> {
+    match (a, b) {
+        (Some(a), Some(b)) => a.add_tweak(&b.into()).ok(),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+fn mul(a: Option<SecretKey>, b: Option<SecretKey>) -> Option<SecretKey> {
+    let (a, b) = (a?, b?);
+    Some(a.mul_tweak(&b.into()).expect("the order is prime"))
+}
+
+fn neg(a: Option<SecretKey>) -> Option<SecretKey> {
+    a.map(SecretKey::negate)
+}
+
+fn point_add(p: Option<PublicKey>, q: Option<PublicKey>) -> Option<PublicKey> {
+    match (p, q) {
+        (Some(p), Some(q)) => p.combine(&q).ok(),
+        (p, None) => p,
+        (None, q) => q,
+    }
+}
+
+fn point_mul<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    p: Option<PublicKey>,
+    k: Option<SecretKey>,
+) -> Option<PublicKey> {
+    let (p, k) = (p?, k?);
+    Some(p.mul_tweak(secp, &k.into()).expect("the order is prime"))
+}
+
+fn base_mul<C: secp256k1::Signing>(
+    secp: &secp256k1::Secp256k1<C>,
+    k: Option<SecretKey>,
+) -> Option<PublicKey> {
+    k.map(|k| k.public_key(secp))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::Once;
+
+    use super::*;
+
+    fn point(hex: &str) -> PublicKey {
+        PublicKey::from_slice(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+    }
+
+    // A point as a public nonce encodes it, 33 zero bytes for infinity.
+    fn encoded(point: Option<PublicKey>) -> String {
+        point
+            .map_or([0; 33], |point| point.serialize())
+            .to_upper_hex_string()
+    }
+
+    // Two points as a public nonce encodes them.
+    fn nonce(hex: &str) -> (PublicKey, PublicKey) {
+        (point(&hex[..66]), point(&hex[66..]))
+    }
+
+    fn decoded(hex: &str) -> Option<PublicKey> {
+        let bytes = Vec::<u8>::from_hex(hex).unwrap();
+        match bytes.iter().all(|b| *b == 0) {
+            true => None,
+            false => Some(PublicKey::from_slice(&bytes).unwrap()),
+        }
+    }
+
+    // BIP327's key_agg_vectors.json.
+    #[test]
+    fn key_agg_vectors() {
+        let pubkeys = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .map(point);
+        let cases: [(&[usize], &str); 4] = [
+            (
+                &[0, 1, 2],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ];
+        for (indices, expected) in cases {
+            let keys: Vec<PublicKey> = indices.iter().map(|&i| pubkeys[i]).collect();
+            let agg = key_agg(&keys).unwrap();
+            assert_eq!(
+                agg.q
+                    .x_only_public_key()
+                    .0
+                    .serialize()
+                    .to_upper_hex_string(),
+                expected
+            );
+        }
+    }
+
+    // BIP327's nonce_agg_vectors.json.
+    #[test]
+    fn nonce_agg_vectors() {
+        let pnonces = [
+            "020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E666\
+             03BA47FBC1834437B3212E89A84D8425E7BF12E0245D98262268EBDCB385D50641",
+            "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A6\
+             0248C264CDD57D3C24D79990B0F865674EB62A0F9018277A95011B41BFC193B833",
+            "020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E666\
+             02BA47FBC1834437B3212E89A84D8425E7BF12E0245D98262268EBDCB385D50641",
+            "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A6\
+             03BA47FBC1834437B3212E89A84D8425E7BF12E0245D98262268EBDCB385D50641",
+        ]
+        .map(nonce);
+        let cases = [
+            (
+                [0, 1],
+                "035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B\
+                 024725377345BDE0E9C33AF3C43C0A29A9249F2F2956FA8CFEB55C8573D0262DC8",
+            ),
+            // The second points add up to infinity.
+            (
+                [2, 3],
+                "035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B\
+                 000000000000000000000000000000000000000000000000000000000000000000",
+            ),
+        ];
+        for (indices, expected) in cases {
+            let nonces: Vec<_> = indices.iter().map(|&i| pnonces[i]).collect();
+            let (r1, r2) = nonce_agg(&nonces);
+            assert_eq!(format!("{}{}", encoded(r1), encoded(r2)), expected);
+        }
+    }
+
+    // BIP327's sign_verify_vectors.json, for its 32 byte message.
+    #[test]
+    fn sign_vectors() {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = SecretKey::from_slice(
+            &Vec::<u8>::from_hex(
+                "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let pubkeys = [
+            "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+        ]
+        .map(point);
+        assert_eq!(sk.public_key(&secp), pubkeys[0]);
+        let secnonce = Vec::<u8>::from_hex(
+            "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61\
+             FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7",
+        )
+        .unwrap();
+        let k1 = scalar(secnonce[..32].try_into().unwrap());
+        let k2 = scalar(secnonce[32..].try_into().unwrap());
+        let pnonces = [
+            "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA\
+             0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+            "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798\
+             0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+            "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE93\
+             03E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+            "0237C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA\
+             0387BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+        ]
+        .map(nonce);
+        assert_eq!(
+            (base_mul(&secp, k1), base_mul(&secp, k2)),
+            (Some(pnonces[0].0), Some(pnonces[0].1))
+        );
+        let aggnonces = [
+            "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61\
+             037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9",
+            "000000000000000000000000000000000000000000000000000000000000000000\
+             000000000000000000000000000000000000000000000000000000000000000000",
+        ];
+        let msg = secp256k1::Message::from_digest(
+            <[u8; 32]>::from_hex(
+                "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF",
+            )
+            .unwrap(),
+        );
+        let cases: [(&[usize], &[usize], usize, &str); 4] = [
+            (
+                &[0, 1, 2],
+                &[0, 1, 2],
+                0,
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                &[1, 0, 2],
+                &[1, 0, 2],
+                0,
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                &[1, 2, 0],
+                &[1, 2, 0],
+                0,
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+            // The nonces add up to infinity.
+            (
+                &[0, 1],
+                &[0, 3],
+                1,
+                "AE386064B26105404798F75DE2EB9AF5EDA5387B064B83D049CB7C5E08879531",
+            ),
+        ];
+        for (key_indices, nonce_indices, aggnonce, expected) in cases {
+            let keys: Vec<PublicKey> = key_indices.iter().map(|&i| pubkeys[i]).collect();
+            let nonces: Vec<_> = nonce_indices.iter().map(|&i| pnonces[i]).collect();
+            let (r1, r2) = nonce_agg(&nonces);
+            let hex = aggnonces[aggnonce];
+            assert_eq!((r1, r2), (decoded(&hex[..66]), decoded(&hex[66..])));
+            let agg = key_agg(&keys).unwrap();
+            let session = session(&secp, &agg, (r1, r2), &msg);
+            let s = sign(k1, k2, &sk, &pubkeys[0], &keys, &agg, &session);
+            assert_eq!(bytes(s).to_upper_hex_string(), expected);
+        }
+    }
+
+    // Point the data directory at a fresh temporary one, shared by the
+    // session tests, which each sign their own sighash.
+    fn temp_data_dir() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let dir = env::temp_dir().join(format!("miniscript-psbt-musig-{}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            env::set_var("MINISCRIPT_PSBT_DIR", dir);
+        });
+    }
+
+    // A session saved for signing the sighash `tag` repeats.
+    fn saved(tag: u8) -> (SecretKey, secp256k1::Message, [u8; 66], [u8; 97]) {
+        temp_data_dir();
+        let sk = SecretKey::from_slice(&[tag; 32]).unwrap();
+        let msg = secp256k1::Message::from_digest([tag; 32]);
+        let (pubnonce, secnonce) = ([tag; 66], [tag ^ 0xff; 97]);
+        save_session(&sk, &msg, &pubnonce, &secnonce).unwrap();
+        (sk, msg, pubnonce, secnonce)
+    }
+
+    #[test]
+    fn session_is_claimed_once() {
+        let (sk, msg, pubnonce, secnonce) = saved(1);
+        assert_eq!(claim_session(&sk, &msg, &pubnonce), Ok(secnonce));
+        let again = claim_session(&sk, &msg, &pubnonce).unwrap_err();
+        assert!(again.contains("there's no secret nonce"), "{}", again);
+    }
+
+    #[test]
+    fn session_of_another_sighash_is_rejected() {
+        let (sk, msg, pubnonce, secnonce) = saved(2);
+        let other = secp256k1::Message::from_digest([0x22; 32]);
+        let e = claim_session(&sk, &other, &pubnonce).unwrap_err();
+        assert!(e.contains("made for another sighash"), "{}", e);
+        assert_eq!(claim_session(&sk, &msg, &pubnonce), Ok(secnonce));
+    }
+
+    #[test]
+    fn session_with_a_bad_mac_is_rejected() {
+        let (sk, msg, pubnonce, _) = saved(3);
+        let path = session_path(&msg, &pubnonce);
+        let mut file: SessionFile = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        file.mac = "00".repeat(32);
+        fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+        let e = claim_session(&sk, &msg, &pubnonce).unwrap_err();
+        assert!(e.contains("isn't a secret nonce of this key"), "{}", e);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn expired_session_is_purged() {
+        let (_, old, old_nonce, _) = saved(4);
+        let (_, fresh, fresh_nonce, _) = saved(5);
+        let path = session_path(&old, &old_nonce);
+        File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now() - 2 * SESSION_LIFETIME))
+            .unwrap();
+        expire_sessions();
+        assert!(!path.exists());
+        assert!(session_path(&fresh, &fresh_nonce).exists());
+    }
+}
@@ -0,0 +1,443 @@
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use miniscript::bitcoin::base64::prelude::{Engine, BASE64_STANDARD};
+use miniscript::bitcoin::bip32::{DerivationPath, Xpriv};
+use miniscript::bitcoin::consensus::encode::deserialize_hex;
+use miniscript::bitcoin::hex::FromHex;
+use miniscript::bitcoin::script::PushBytesBuf;
+use miniscript::bitcoin::secp256k1::{self, Secp256k1};
+use miniscript::bitcoin::taproot::TAPROOT_ANNEX_PREFIX;
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::{
+    Address, Amount, EcdsaSighashType, FeeRate, Network, OutPoint, PrivateKey, ScriptBuf,
+    Transaction, TxOut,
+};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::Descriptor;
+
+use crate::bip47::PaymentCode;
+use crate::broadcast::Backend;
+use crate::create::Source;
+use crate::error::Error;
+use crate::select::CoinControl;
+use crate::{contacts, psbtv2, tpm};
+
+/// Parse a network: mainnet, testnet, testnet4, signet or regtest.
+pub fn network(s: &str) -> Result<Network, String> {
+    match s {
+        "mainnet" => Ok(Network::Bitcoin),
+        _ => Network::from_str(s)
+            .map_err(|_| "expected mainnet, testnet, testnet4, signet or regtest".to_string()),
+    }
+}
+
+/// Parse a hex encoded transaction.
+pub fn transaction(s: &str) -> Result<Transaction, String> {
+    deserialize_hex(s.trim()).map_err(|e| format!("not a valid transaction: {}", e))
+}
+
+/// Parse where a spend's coins come from: `node` for the node's UTXO set,
+/// or else a hex encoded funding transaction.
+pub fn source(s: &str) -> Result<Source, String> {
+    if s == "node" {
+        return Ok(Source::Node);
+    }
+    transaction(s).map(Source::Transaction)
+}
+
+/// Parse a base64 encoded PSBT, of version 0 or 2.
+pub fn psbt(s: &str) -> Result<Psbt, String> {
+    let bytes = BASE64_STANDARD
+        .decode(s.trim())
+        .map_err(|e| format!("not a valid PSBT: {}", e))?;
+    psbtv2::deserialize_any(&bytes).map_err(|e| format!("not a valid PSBT: {}", e))
+}
+
+/// Parse an address and check that it belongs to the network we operate on.
+pub fn address(s: &str) -> Result<Address, String> {
+    Address::from_str(s)
+        .map_err(|e| format!("not a valid address: {}", e))?
+        .require_network(crate::network())
+        .map_err(|_| {
+            format!(
+                "address is for another network, expected {}",
+                crate::network()
+            )
+        })
+}
+
+/// Parse where to send coins: an address, a BIP21 `bitcoin:` URI, the name
+/// of a saved contact, or a descriptor to derive the script from, prefixed
+/// with the derivation index as `<index>:` if it has wildcards.
+///
+/// A URI labelled with the name of a contact whose script differs gets a
+/// warning, as the address may have been swapped.
+pub fn recipient(s: &str) -> Result<ScriptBuf, String> {
+    let address_error = match address(s) {
+        Ok(address) => return Ok(address.script_pubkey()),
+        Err(e) => e,
+    };
+    if let Some(uri) = s
+        .get(..8)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("bitcoin:"))
+        .map(|_| &s[8..])
+    {
+        let (address, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let script_pubkey = self::address(address)?.script_pubkey();
+        let label = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("label="))
+            .map(percent_decode);
+        if let Some(contact) = label
+            .map(|label| contacts::find(&label))
+            .transpose()?
+            .flatten()
+        {
+            if contact.script_pubkey().ok() != Some(script_pubkey.clone()) {
+                eprintln!(
+                    "WARNING: {} is labelled {} but doesn't match the saved contact {}",
+                    address, contact.name, contact.recipient
+                );
+            }
+        }
+        return Ok(script_pubkey);
+    }
+    if let Some(contact) = contacts::find(s)? {
+        return contact.script_pubkey();
+    }
+    let descriptor = derived_descriptor(s).map_err(|e| {
+        format!(
+            "neither an address ({}) nor a descriptor ({})",
+            address_error, e
+        )
+    })?;
+    Ok(descriptor.script_pubkey())
+}
+
+/// Parse a descriptor to derive a single script from, prefixed with the
+/// derivation index as `<index>:` if it has wildcards.
+pub fn derived_descriptor(s: &str) -> Result<Descriptor<DefiniteDescriptorKey>, Error> {
+    let (index, desc) = match s.split_once(':') {
+        Some((index, desc)) if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) => {
+            let index: u32 = index
+                .parse()
+                .map_err(|e| format!("invalid derivation index: {}", e))?;
+            (Some(index), desc)
+        }
+        _ => (None, s),
+    };
+    let descriptor = ranged_descriptor(desc)?;
+    let index = match (descriptor.has_wildcard(), index) {
+        (true, None) => {
+            return Err(Error::Descriptor(
+                "the descriptor has wildcards, prefix it with the index to derive as <index>:"
+                    .to_string(),
+            ))
+        }
+        (false, Some(_)) => {
+            return Err(Error::Descriptor(
+                "the descriptor has no wildcards to derive an index of".to_string(),
+            ))
+        }
+        (_, index) => index.unwrap_or(0),
+    };
+    descriptor
+        .at_derivation_index(index)
+        .map_err(|e| Error::Descriptor(format!("can't derive index {}: {}", index, e)))
+}
+
+// Decode the %XX escapes of a URI parameter, leaving malformed ones as they
+// are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parse a BIP47 payment code, or the name of a contact saved with one.
+pub fn payment_code(s: &str) -> Result<PaymentCode, String> {
+    if let Some(contact) = contacts::find(s)? {
+        return PaymentCode::from_str(&contact.recipient)
+            .map_err(|_| format!("contact {} isn't a payment code", contact.name));
+    }
+    PaymentCode::from_str(s)
+}
+
+/// Parse an amount given either in satoshis or with an explicit
+/// denomination, e.g. `0.001 BTC`.
+pub fn amount(s: &str) -> Result<Amount, String> {
+    if let Ok(sats) = s.parse::<u64>() {
+        return Ok(Amount::from_sat(sats));
+    }
+    Amount::from_str(s).map_err(|e| {
+        format!(
+            "expected satoshis or an amount with denomination (e.g. \"0.001 BTC\"): {}",
+            e
+        )
+    })
+}
+
+/// Parse the hex encoded data of an OP_RETURN output into its script.
+pub fn op_return(s: &str) -> Result<ScriptBuf, String> {
+    let data = Vec::<u8>::from_hex(s.trim()).map_err(|e| format!("not valid hex: {}", e))?;
+    let data = PushBytesBuf::try_from(data).map_err(|e| e.to_string())?;
+    Ok(ScriptBuf::new_op_return(data))
+}
+
+/// Parse a descriptor and make sure it passes the miniscript sanity checks.
+pub fn descriptor(s: &str) -> Result<Descriptor<DefiniteDescriptorKey>, Error> {
+    let descriptor = Descriptor::<DefiniteDescriptorKey>::from_str(s)
+        .map_err(|e| Error::Descriptor(format!("not a valid descriptor: {}", e)))?;
+    descriptor
+        .sanity_check()
+        .map_err(|e| Error::Descriptor(format!("descriptor fails sanity check: {}", e)))?;
+    Ok(descriptor)
+}
+
+/// Parse an output or input index and the descriptor controlling it, in
+/// `<index>:<descriptor>` form.
+pub fn input_descriptor(s: &str) -> Result<(u32, Descriptor<DefiniteDescriptorKey>), Error> {
+    let (index, desc) = s.split_once(':').ok_or("expected <index>:<descriptor>")?;
+    let index = index.parse().map_err(|e| format!("invalid index: {}", e))?;
+    Ok((index, descriptor(desc)?))
+}
+
+/// Parse an outpoint and the descriptor controlling it, in
+/// `<txid>:<vout>:<descriptor>` form.
+pub fn input(s: &str) -> Result<(OutPoint, Descriptor<DefiniteDescriptorKey>), Error> {
+    let mut parts = s.splitn(3, ':');
+    let (Some(txid), Some(vout), Some(desc)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err("expected <txid>:<vout>:<descriptor>".into());
+    };
+    Ok((outpoint(&format!("{}:{}", txid, vout))?, descriptor(desc)?))
+}
+
+/// Parse a descriptor that may contain wildcards, checking it like
+/// [`descriptor`].
+pub fn ranged_descriptor(s: &str) -> Result<Descriptor<DescriptorPublicKey>, Error> {
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(s)
+        .map_err(|e| Error::Descriptor(format!("not a valid descriptor: {}", e)))?;
+    descriptor
+        .sanity_check()
+        .map_err(|e| Error::Descriptor(format!("descriptor fails sanity check: {}", e)))?;
+    Ok(descriptor)
+}
+
+/// Parse an inclusive range of derivation indexes in `a-b` form.
+pub fn range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let (start, end) = s.split_once('-').ok_or("expected <start>-<end>")?;
+    let start: u32 = start
+        .parse()
+        .map_err(|e| format!("invalid range start: {}", e))?;
+    let end: u32 = end
+        .parse()
+        .map_err(|e| format!("invalid range end: {}", e))?;
+    if start > end || end >= 1 << 31 {
+        return Err("expected start <= end < 2^31".to_string());
+    }
+    Ok(start..=end)
+}
+
+/// Parse a payment in `<recipient>:<amount>` form, with the recipient and
+/// amount as accepted by [`recipient`] and [`amount`].
+pub fn output(s: &str) -> Result<TxOut, String> {
+    let (to, value) = s.rsplit_once(':').ok_or("expected <recipient>:<amount>")?;
+    Ok(TxOut {
+        script_pubkey: recipient(to)?,
+        value: amount(value)?,
+    })
+}
+
+/// Parse a feerate in sat/vB, which may be fractional.
+pub fn feerate(s: &str) -> Result<FeeRate, String> {
+    let sat_per_vb: f64 = s
+        .parse()
+        .map_err(|e| format!("expected a feerate in sat/vB: {}", e))?;
+    if !sat_per_vb.is_finite() || sat_per_vb <= 0.0 {
+        return Err("expected a positive feerate".to_string());
+    }
+    // There are 250 vB in a kilo weight unit.
+    Ok(FeeRate::from_sat_per_kwu((sat_per_vb * 250.0).ceil() as u64))
+}
+
+/// Parse a duration such as `90s`, `45m`, `12h` or `30d` into seconds.
+pub fn duration(s: &str) -> Result<u64, String> {
+    let (number, unit) = s.split_at(s.len().saturating_sub(1));
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err("expected a number followed by s, m, h or d".to_string()),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid duration \"{}\"", s))
+}
+
+/// Parse an outpoint in `txid:vout` form.
+pub fn outpoint(s: &str) -> Result<OutPoint, String> {
+    OutPoint::from_str(s).map_err(|e| format!("expected <txid>:<vout>: {}", e))
+}
+
+/// Parse a coin control override, `include:<txid>:<vout>` or
+/// `exclude:<txid>:<vout>`.
+pub fn coin_control(s: &str) -> Result<CoinControl, String> {
+    match s.split_once(':') {
+        Some(("include", rest)) => outpoint(rest).map(CoinControl::Include),
+        Some(("exclude", rest)) => outpoint(rest).map(CoinControl::Exclude),
+        _ => Err("expected include:<txid>:<vout> or exclude:<txid>:<vout>".to_string()),
+    }
+}
+
+/// Parse a WIF encoded private key, an extended private key followed by
+/// the derivation path of its child to sign with, e.g. `tprv.../84h/1h/0h/0/5`,
+/// or `tpm:<name>` for one sealed to the TPM.
+pub fn private_key(s: &str) -> Result<PrivateKey, String> {
+    if let Some(name) = s.strip_prefix("tpm:") {
+        return tpm::unseal(name);
+    }
+    if let Some((key, path)) = s.split_once('/') {
+        let path = DerivationPath::from_str(&format!("m/{}", path))
+            .map_err(|e| format!("invalid derivation path: {}", e))?;
+        return xpriv(key)?
+            .derive_priv(&Secp256k1::new(), &path)
+            .map(|child| child.to_priv())
+            .map_err(|e| format!("can't derive {}: {}", path, e));
+    }
+    let key = PrivateKey::from_str(s).map_err(|e| format!("not a valid WIF private key: {}", e))?;
+    if key.network != crate::network().into() {
+        return Err(format!(
+            "key is for another network, expected {}",
+            crate::network()
+        ));
+    }
+    Ok(key)
+}
+
+/// Parse a hex encoded compressed public key.
+pub fn public_key(s: &str) -> Result<secp256k1::PublicKey, String> {
+    secp256k1::PublicKey::from_str(s).map_err(|e| format!("not a valid public key: {}", e))
+}
+
+/// Parse an extended private key and check that it belongs to the network
+/// we operate on.
+pub fn xpriv(s: &str) -> Result<Xpriv, String> {
+    let xpriv =
+        Xpriv::from_str(s).map_err(|e| format!("not a valid extended private key: {}", e))?;
+    if xpriv.network != crate::network().into() {
+        return Err(format!(
+            "key is for another network, expected {}",
+            crate::network()
+        ));
+    }
+    Ok(xpriv)
+}
+
+/// Parse a sighash type such as `all` or `single|anyonecanpay`, with or
+/// without the `SIGHASH_` prefixes.
+pub fn sighash_type(s: &str) -> Result<EcdsaSighashType, String> {
+    let normalized: Vec<String> = s
+        .split(['|', '+'])
+        .map(|part| {
+            let part = part.trim().to_uppercase();
+            if part.starts_with("SIGHASH_") {
+                part
+            } else {
+                format!("SIGHASH_{}", part)
+            }
+        })
+        .collect();
+    EcdsaSighashType::from_str(&normalized.join("|"))
+        .map_err(|_| "expected all, none or single, optionally with |anyonecanpay".to_string())
+}
+
+/// Parse an input index and the sighash type to sign it with, in
+/// `<index>:<type>` form.
+pub fn input_sighash(s: &str) -> Result<(usize, EcdsaSighashType), String> {
+    let (index, ty) = s.split_once(':').ok_or("expected <index>:<type>")?;
+    let index = index.parse().map_err(|e| format!("invalid index: {}", e))?;
+    Ok((index, sighash_type(ty)?))
+}
+
+/// Parse a hex encoded hashlock preimage, which miniscript requires to be
+/// 32 bytes.
+pub fn preimage(s: &str) -> Result<Vec<u8>, String> {
+    let preimage = Vec::<u8>::from_hex(s.trim()).map_err(|e| format!("not valid hex: {}", e))?;
+    if preimage.len() != 32 {
+        return Err(format!(
+            "a preimage has to be 32 bytes, not {}",
+            preimage.len()
+        ));
+    }
+    Ok(preimage)
+}
+
+/// Parse a hex encoded taproot annex, which BIP341 requires to start with
+/// the byte 0x50.
+pub fn annex(s: &str) -> Result<Vec<u8>, String> {
+    let annex = Vec::<u8>::from_hex(s.trim()).map_err(|e| format!("not valid hex: {}", e))?;
+    if annex.first() != Some(&TAPROOT_ANNEX_PREFIX) {
+        return Err(format!(
+            "an annex has to start with {:02x}",
+            TAPROOT_ANNEX_PREFIX
+        ));
+    }
+    Ok(annex)
+}
+
+/// Parse a broadcast backend: `core`, `esplora:<URL>` or `tor:<URL>`.
+pub fn backend(s: &str) -> Result<Backend, String> {
+    if s == "core" {
+        return Ok(Backend::Core);
+    }
+    let (kind, url) = s
+        .split_once(':')
+        .ok_or("expected core, esplora:<URL> or tor:<URL>")?;
+    let url = self::url(url)?;
+    match kind {
+        "esplora" => Ok(Backend::Esplora(url)),
+        // Onion services are encrypted end to end, so only plain HTTP is
+        // spoken through Tor.
+        "tor" if url.starts_with("https://") => {
+            Err("tor: speaks plain HTTP to onion services, use an http:// URL".to_string())
+        }
+        "tor" => Ok(Backend::Tor(url)),
+        _ => Err(format!(
+            "unknown backend \"{}\", expected core, esplora or tor",
+            kind
+        )),
+    }
+}
+
+/// Parse the URL of an HTTP API: `https://`, or `http://` for a server of
+/// our own or an onion service, which Tor encrypts already.
+pub fn url(s: &str) -> Result<String, String> {
+    if !s.starts_with("https://") && !s.starts_with("http://") {
+        return Err(format!(
+            "expected an https:// or http:// URL, not \"{}\"",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
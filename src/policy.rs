@@ -0,0 +1,398 @@
+use miniscript::bitcoin::absolute::LockTime;
+use miniscript::bitcoin::relative;
+use miniscript::bitcoin::transaction::Version;
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::VarInt;
+use miniscript::bitcoin::{Sequence, Weight};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorType};
+use miniscript::miniscript::satisfy::Placeholder;
+use miniscript::plan::{Assets, Plan};
+use miniscript::policy::Liftable;
+use miniscript::Descriptor;
+
+use crate::{build, estimate, sign};
+
+/// Bitcoin Core's default minimum relay feerate, in sat/vB.
+const MIN_RELAY_FEERATE: u64 = 1;
+/// The heaviest transaction Bitcoin Core relays.
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+/// The largest witness script Bitcoin Core relays.
+const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+/// The largest OP_RETURN output script Bitcoin Core relays.
+const MAX_OP_RETURN_RELAY: usize = 83;
+/// Lock times below this are block heights, above it UNIX timestamps.
+const LOCK_TIME_THRESHOLD: u32 = 500_000_000;
+
+/// Check the transaction of `psbt`, whose inputs spend `descriptors` in
+/// order, against Bitcoin Core's standardness rules once it is signed.
+///
+/// Every violation is reported, one per line, so they can all be fixed
+/// before any signatures are produced.
+pub fn check_standard(
+    psbt: &Psbt,
+    descriptors: &[&Descriptor<DefiniteDescriptorKey>],
+) -> Result<(), String> {
+    let tx = &psbt.unsigned_tx;
+    let mut violations = Vec::new();
+
+    let weight = estimate_weight(psbt, descriptors)?;
+    let vsize = weight.div_ceil(4);
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        violations.push(format!(
+            "transaction weight {} exceeds the standard maximum of {}",
+            weight, MAX_STANDARD_TX_WEIGHT
+        ));
+    }
+
+    let input_value = (0..psbt.inputs.len())
+        .map(|i| {
+            build::spent_utxo(psbt, i)
+                .map(|utxo| utxo.value.to_sat())
+                .ok_or_else(|| format!("Input {} has no UTXO", i))
+        })
+        .sum::<Result<u64, String>>()?;
+    let output_value: u64 = tx.output.iter().map(|o| o.value.to_sat()).sum();
+    match input_value.checked_sub(output_value) {
+        Some(fee) if fee < vsize * MIN_RELAY_FEERATE => violations.push(format!(
+            "fee of {} sat is below the minimum relay fee of {} sat for {} vB",
+            fee,
+            vsize * MIN_RELAY_FEERATE,
+            vsize
+        )),
+        Some(_) => {}
+        None => violations.push(format!(
+            "outputs spend {} sat but the inputs only have {} sat",
+            output_value, input_value
+        )),
+    }
+
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        if let Some(script) = &input.witness_script {
+            if script.len() > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+                violations.push(format!(
+                    "input {}: witness script of {} bytes exceeds the standard maximum of {}",
+                    i,
+                    script.len(),
+                    MAX_STANDARD_P2WSH_SCRIPT_SIZE
+                ));
+            }
+        }
+    }
+
+    for (i, output) in tx.output.iter().enumerate() {
+        let spk = &output.script_pubkey;
+        if spk.is_op_return() {
+            if spk.len() > MAX_OP_RETURN_RELAY {
+                violations.push(format!(
+                    "output {}: OP_RETURN script of {} bytes exceeds the standard maximum of {}",
+                    i,
+                    spk.len(),
+                    MAX_OP_RETURN_RELAY
+                ));
+            }
+            continue;
+        }
+        if !(spk.is_p2pkh() || spk.is_p2sh() || spk.is_witness_program()) {
+            violations.push(format!("output {}: non-standard script {}", i, spk));
+        }
+        if output.value < spk.minimal_non_dust() {
+            violations.push(format!(
+                "output {}: {} sat is below the dust threshold of {} sat",
+                i,
+                output.value.to_sat(),
+                spk.minimal_non_dust().to_sat()
+            ));
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The transaction would not be relayed:\n  {}",
+            violations.join("\n  ")
+        ))
+    }
+}
+
+/// Estimate the weight of `psbt`'s transaction once its inputs, which spend
+/// `descriptors` in order, are signed with their largest satisfactions.
+pub fn estimate_weight(
+    psbt: &Psbt,
+    descriptors: &[&Descriptor<DefiniteDescriptorKey>],
+) -> Result<u64, String> {
+    check_count(psbt, descriptors)?;
+    let satisfactions = descriptors
+        .iter()
+        .enumerate()
+        .map(|(i, descriptor)| {
+            max_satisfaction_weight(descriptor).map_err(|e| {
+                format!(
+                    "Can't estimate the satisfaction weight of input {}: {}",
+                    i, e
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(satisfied_weight(psbt, &satisfactions))
+}
+
+/// The weight of `psbt`'s transaction once its inputs are satisfied with
+/// witnesses of `satisfactions`' weights, in order.
+pub fn satisfied_weight(psbt: &Psbt, satisfactions: &[Weight]) -> u64 {
+    // The segwit marker and flag and each input's witness item count aren't
+    // counted while the witness is empty.
+    let mut weight = psbt.unsigned_tx.weight().to_wu() + 2;
+    for satisfaction in satisfactions {
+        weight += 1 + satisfaction.to_wu();
+    }
+    weight
+}
+
+/// The weight of `descriptor`'s largest satisfaction, as
+/// [`Descriptor::max_weight_to_satisfy`] counts it, less what the ECDSA
+/// signatures every spend path needs save when they're ground to a low R.
+pub fn max_satisfaction_weight(
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> Result<Weight, String> {
+    let weight = descriptor
+        .max_weight_to_satisfy()
+        .map_err(|e| e.to_string())?;
+    if !sign::low_r() {
+        return Ok(weight);
+    }
+    // Any satisfaction takes some spend path, so it has at least as many
+    // signatures as the path needing the fewest. Without paths nothing is
+    // saved, which only overestimates.
+    let signatures = estimate::spend_paths(descriptor)
+        .ok()
+        .and_then(|paths| paths.iter().map(|path| path.keys.len()).min())
+        .unwrap_or(0);
+    Ok(weight - low_r_savings(descriptor, signatures))
+}
+
+// What `signatures` ECDSA signatures ground to a low R save over the 73
+// bytes counted for them, a byte each where `descriptor` puts them.
+fn low_r_savings(descriptor: &Descriptor<DefiniteDescriptorKey>, signatures: usize) -> Weight {
+    if !sign::low_r() {
+        return Weight::ZERO;
+    }
+    let per_byte = match descriptor.desc_type() {
+        DescriptorType::Bare
+        | DescriptorType::Pkh
+        | DescriptorType::Sh
+        | DescriptorType::ShSortedMulti => 4,
+        // Schnorr signatures are always 64 bytes.
+        DescriptorType::Tr => 0,
+        _ => 1,
+    };
+    Weight::from_wu((per_byte * signatures) as u64)
+}
+
+/// The weight `plan`'s satisfaction of `descriptor` adds to its input,
+/// counted like [`max_satisfaction_weight`] so it can stand in for it.
+pub fn plan_weight(plan: &Plan, descriptor: &Descriptor<DefiniteDescriptorKey>) -> Weight {
+    // The plan's own sizes leave out the scripts pushed after the
+    // satisfaction, so only its items are taken from it.
+    let varint = |n: usize| VarInt::from(n).size();
+    let count = varint(plan.witness_template().len());
+    let items = match plan.witness_version() {
+        Some(_) => plan.witness_size() - count,
+        None => plan.scriptsig_size() - count,
+    };
+    // Nor do they count the length prefixes of a leaf's script and control
+    // block.
+    let prefixes: usize = plan
+        .witness_template()
+        .iter()
+        .map(|item| match item {
+            Placeholder::TapScript(script) => varint(script.len()),
+            Placeholder::TapControlBlock(control) => varint(control.serialize().len()),
+            _ => 0,
+        })
+        .sum();
+    let items = items + prefixes;
+    let script = || {
+        descriptor
+            .explicit_script()
+            .map_or(0, |script| script.len())
+    };
+    let push_opcode = |len: usize| match len {
+        0..=75 => 1,
+        76..=255 => 2,
+        _ => 3,
+    };
+    let wu = match descriptor.desc_type() {
+        DescriptorType::Bare | DescriptorType::Pkh => 4 * items,
+        DescriptorType::Sh | DescriptorType::ShSortedMulti => {
+            4 * (items + push_opcode(script()) + script())
+        }
+        DescriptorType::Wpkh | DescriptorType::Tr => items,
+        // The script_sig pushes the 22 byte witness program.
+        DescriptorType::ShWpkh => 4 * 23 + items,
+        DescriptorType::Wsh | DescriptorType::WshSortedMulti => items + varint(script()) + script(),
+        // The script_sig pushes the 34 byte witness program.
+        DescriptorType::ShWsh | DescriptorType::ShWshSortedMulti => {
+            4 * 35 + items + varint(script()) + script()
+        }
+    };
+    let signatures = plan
+        .witness_template()
+        .iter()
+        .filter(|item| {
+            matches!(
+                item,
+                Placeholder::EcdsaSigPk(_) | Placeholder::EcdsaSigPkHash(_)
+            )
+        })
+        .count();
+    Weight::from_wu(wu as u64) - low_r_savings(descriptor, signatures)
+}
+
+/// `assets` with its keys signing taproot inputs as we do, with an explicit
+/// sighash type making their signatures 65 bytes.
+pub fn explicit_sighash(mut assets: Assets) -> Assets {
+    assets.keys = assets
+        .keys
+        .into_iter()
+        .map(|(source, mut can_sign)| {
+            can_sign.taproot.sighash_default = false;
+            (source, can_sign)
+        })
+        .collect();
+    assets
+}
+
+/// The lowest lock time and sequence meeting the timelocks of
+/// `descriptor`, each only if every way of spending it needs one.
+///
+/// Timelocks of optional branches, e.g. a recovery path, are left alone
+/// since meeting them would needlessly delay the spend.
+pub fn needed_timelocks(
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> Result<(Option<u32>, Option<Sequence>), String> {
+    let policy = descriptor
+        .lift()
+        .map_err(|e| format!("Can't lift the descriptor to a policy: {}", e))?;
+    let satisfiable_at =
+        |lock_time: LockTime| !policy.clone().at_lock_time(lock_time).is_unsatisfiable();
+    let lock_time = if satisfiable_at(LockTime::ZERO) {
+        None
+    } else {
+        policy
+            .absolute_timelocks()
+            .into_iter()
+            .find(|&n| satisfiable_at(LockTime::from_consensus(n)))
+    };
+    let satisfiable_at = |age: relative::LockTime| !policy.clone().at_age(age).is_unsatisfiable();
+    let sequence = if satisfiable_at(relative::LockTime::ZERO) {
+        None
+    } else {
+        policy
+            .relative_timelocks()
+            .into_iter()
+            .map(Sequence::from_consensus)
+            .find(|sequence| sequence.to_relative_lock_time().is_some_and(satisfiable_at))
+    };
+    Ok((lock_time, sequence))
+}
+
+/// Check that the lock time and sequences of `psbt`'s transaction can
+/// satisfy the timelocks of `descriptors`, which its inputs spend in order.
+///
+/// Catches lock times disabled by final sequences, lock times of the wrong
+/// kind (height vs time) or too low for any `after()`, and `older()` in a
+/// transaction whose version doesn't enforce relative timelocks.
+pub fn check_locktime(
+    psbt: &Psbt,
+    descriptors: &[&Descriptor<DefiniteDescriptorKey>],
+) -> Result<(), String> {
+    let tx = &psbt.unsigned_tx;
+    let lock_time = tx.lock_time.to_consensus_u32();
+    let mut violations = Vec::new();
+
+    if lock_time != 0 && tx.input.iter().all(|txin| txin.sequence == Sequence::MAX) {
+        violations.push(format!(
+            "lock time {} has no effect, every input's sequence is final (0xffffffff)",
+            lock_time
+        ));
+    }
+
+    let kind = |n: u32| {
+        if n < LOCK_TIME_THRESHOLD {
+            "block height"
+        } else {
+            "timestamp"
+        }
+    };
+    check_count(psbt, descriptors)?;
+    for (i, descriptor) in descriptors.iter().enumerate() {
+        let policy = descriptor.lift().map_err(|e| {
+            format!(
+                "Can't lift the descriptor of input {} to a policy: {}",
+                i, e
+            )
+        })?;
+
+        let afters = policy.absolute_timelocks();
+        if lock_time != 0 && !afters.is_empty() {
+            let reasons: Vec<String> = afters
+                .iter()
+                .filter_map(|&n| {
+                    if kind(n) != kind(lock_time) {
+                        Some(format!(
+                            "after({}) needs a {} but lock time {} is a {}",
+                            n,
+                            kind(n),
+                            lock_time,
+                            kind(lock_time)
+                        ))
+                    } else if lock_time < n {
+                        Some(format!("after({}) needs a lock time of at least {}", n, n))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            if reasons.len() == afters.len() {
+                violations.push(format!(
+                    "input {}: lock time {} satisfies none of the descriptor's timelocks: {}",
+                    i,
+                    lock_time,
+                    reasons.join(", ")
+                ));
+            }
+        }
+
+        if tx.version < Version::TWO && !policy.relative_timelocks().is_empty() {
+            violations.push(format!(
+                "input {}: older() needs transaction version 2 but the version is {}",
+                i, tx.version
+            ));
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The timelocks can't be satisfied:\n  {}",
+            violations.join("\n  ")
+        ))
+    }
+}
+
+// Check that there is a descriptor for every input of `psbt`.
+fn check_count(
+    psbt: &Psbt,
+    descriptors: &[&Descriptor<DefiniteDescriptorKey>],
+) -> Result<(), String> {
+    if descriptors.len() != psbt.inputs.len() {
+        return Err(format!(
+            "{} descriptors given for {} inputs",
+            descriptors.len(),
+            psbt.inputs.len()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use clap::{ArgGroup, Args};
+use miniscript::bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{parse, wallet};
+
+/// Where to get the bitcoin price from to show fiat values, which is
+/// entirely opt-in.
+#[derive(Args)]
+#[command(group(ArgGroup::new("price_source").args(["price_url", "price_rpc"])))]
+pub struct PriceArgs {
+    /// Also show approximate values in this fiat currency, e.g. USD. They're
+    /// informational only, amounts are always in satoshis.
+    #[arg(long, requires = "price_source")]
+    fiat: Option<String>,
+    /// Fetch the price as JSON over HTTP from this URL, with `{currency}`
+    /// replaced by the lower case currency code.
+    #[arg(long, requires = "fiat")]
+    price_url: Option<String>,
+    /// Where the price is in the JSON the URL returns, as a JSON pointer.
+    #[arg(long, default_value = "/price")]
+    price_pointer: String,
+    /// Ask a JSON-RPC server speaking Bitcoin Core's protocol for the price
+    /// with `getprice <currency>`.
+    #[arg(long, requires = "fiat")]
+    price_rpc: Option<String>,
+    /// Reuse a price fetched this recently rather than fetching it again.
+    #[arg(long, value_parser = parse::duration, default_value = "10m")]
+    price_max_age: u64,
+}
+
+/// The price of a bitcoin in a fiat currency.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Price {
+    pub currency: String,
+    pub per_btc: f64,
+    /// Where the price came from.
+    pub source: String,
+    /// When it was fetched, in seconds since the UNIX epoch.
+    pub time: u64,
+}
+
+impl Price {
+    /// `amount` in the fiat currency, marked as approximate.
+    pub fn value(&self, amount: Amount) -> String {
+        format!("~{:.2} {}", self.fiat(amount), self.currency)
+    }
+
+    /// `amount` in the fiat currency.
+    pub fn fiat(&self, amount: Amount) -> f64 {
+        amount.to_btc() * self.per_btc
+    }
+
+    /// A line telling the user where the fiat values came from and that
+    /// they're only informational.
+    pub fn notice(&self) -> String {
+        format!(
+            "Fiat values are approximate and informational only, at the current price of \
+             {:.2} {}/BTC from {}, fetched {}s ago",
+            self.per_btc,
+            self.currency,
+            self.source,
+            wallet::now().saturating_sub(self.time)
+        )
+    }
+
+    /// The fiat value of `amount` for JSON output, marked as informational.
+    pub fn json(&self, amount: Amount) -> Value {
+        json!({
+            "value": (self.fiat(amount) * 100.0).round() / 100.0,
+            "currency": self.currency,
+            "informational": true,
+        })
+    }
+}
+
+impl PriceArgs {
+    /// The price to show fiat values at, if they were asked for.
+    ///
+    /// The price is only informational, so failing to get one is reported
+    /// rather than failing the command.
+    pub fn price(&self) -> Option<Price> {
+        let currency = self.fiat.as_ref()?.to_uppercase();
+        match self.cached_or_fetch(&currency) {
+            Ok(price) => Some(price),
+            Err(e) => {
+                eprintln!("Not showing {} values: {}", currency, e);
+                None
+            }
+        }
+    }
+
+    fn source(&self, currency: &str) -> String {
+        match (&self.price_url, &self.price_rpc) {
+            (Some(url), _) => url.replace("{currency}", &currency.to_lowercase()),
+            (None, Some(url)) => format!("getprice at {}", url),
+            (None, None) => unreachable!("clap requires a price source"),
+        }
+    }
+
+    fn cached_or_fetch(&self, currency: &str) -> Result<Price, String> {
+        let source = self.source(currency);
+        let mut cache = load_cache();
+        let now = wallet::now();
+        if let Some(price) = cache.iter().find(|price| {
+            price.currency == currency
+                && price.source == source
+                && now.saturating_sub(price.time) <= self.price_max_age
+        }) {
+            return Ok(price.clone());
+        }
+
+        let per_btc = match &self.price_url {
+            Some(_) => fetch_url(&source, &self.price_pointer)?,
+            None => fetch_rpc(self.price_rpc.as_ref().unwrap(), currency)?,
+        };
+        if !per_btc.is_finite() || per_btc <= 0.0 {
+            return Err(format!("{} returned a price of {}", source, per_btc));
+        }
+        let price = Price {
+            currency: currency.to_string(),
+            per_btc,
+            source,
+            time: now,
+        };
+        cache.retain(|cached| cached.currency != price.currency || cached.source != price.source);
+        cache.push(price.clone());
+        // Failing to cache only means fetching again next time.
+        let _ = fs::write(cache_path(), serde_json::to_string_pretty(&cache).unwrap());
+        Ok(price)
+    }
+}
+
+fn cache_path() -> PathBuf {
+    wallet::data_dir().join("prices.json")
+}
+
+fn load_cache() -> Vec<Price> {
+    fs::read_to_string(cache_path())
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn fetch_url(url: &str, pointer: &str) -> Result<f64, String> {
+    let response = minreq::get(url)
+        .with_timeout(10)
+        .send()
+        .map_err(|e| format!("Can't fetch {}: {}", url, e))?;
+    if response.status_code != 200 {
+        return Err(format!(
+            "{} answered {} {}",
+            url, response.status_code, response.reason_phrase
+        ));
+    }
+    let json: Value = response
+        .as_str()
+        .ok()
+        .and_then(|body| serde_json::from_str(body).ok())
+        .ok_or_else(|| format!("{} didn't return JSON", url))?;
+    let price = json
+        .pointer(pointer)
+        .ok_or_else(|| format!("{} returned no {}", url, pointer))?;
+    // Some sources quote prices as strings to keep their precision.
+    price
+        .as_f64()
+        .or_else(|| price.as_str().and_then(|s| s.parse().ok()))
+        .ok_or_else(|| format!("{} returned {} as the price", url, price))
+}
+
+fn fetch_rpc(url: &str, currency: &str) -> Result<f64, String> {
+    let client =
+        Client::new(url, Auth::None).map_err(|e| format!("Can't connect to {}: {}", url, e))?;
+    client
+        .call("getprice", &[json!(currency)])
+        .map_err(|e| format!("getprice at {} failed: {}", url, e))
+}
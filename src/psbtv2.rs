@@ -0,0 +1,287 @@
+use miniscript::bitcoin::consensus::encode::{deserialize, serialize};
+use miniscript::bitcoin::{
+    absolute, transaction, Amount, OutPoint, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Txid, Witness,
+};
+
+use crate::stage::PSBT_MAGIC;
+
+// The key types BIP370 adds to version 2 PSBTs, which carry the unsigned
+// transaction's fields in the maps instead of as a whole.
+const GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const GLOBAL_TX_VERSION: u8 = 0x02;
+const GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const GLOBAL_INPUT_COUNT: u8 = 0x04;
+const GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const GLOBAL_VERSION: u8 = 0xfb;
+const IN_PREVIOUS_TXID: u8 = 0x0e;
+const IN_OUTPUT_INDEX: u8 = 0x0f;
+const IN_SEQUENCE: u8 = 0x10;
+const IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+const OUT_AMOUNT: u8 = 0x03;
+const OUT_SCRIPT: u8 = 0x04;
+
+/// A PSBT map's key-value pairs, each key starting with its type.
+type Map = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Decode a binary PSBT of version 0 or 2, the latter turned into the
+/// version 0 PSBT of the same transaction.
+pub fn deserialize_any(bytes: &[u8]) -> Result<Psbt, String> {
+    let mut rest = bytes
+        .strip_prefix(PSBT_MAGIC)
+        .ok_or("not a PSBT, the magic bytes are missing")?;
+    let global = read_map(&mut rest)?;
+    if value(&global, GLOBAL_VERSION).map(u32_le).transpose()? != Some(2) {
+        return Psbt::deserialize(bytes).map_err(|e| e.to_string());
+    }
+    let count = |key_type| -> Result<usize, String> {
+        let value = value(&global, key_type).ok_or("version 2 PSBT without its counts")?;
+        Ok(read_compact(&mut &value[..])? as usize)
+    };
+    let (input_count, output_count) = (count(GLOBAL_INPUT_COUNT)?, count(GLOBAL_OUTPUT_COUNT)?);
+    let version = value(&global, GLOBAL_TX_VERSION)
+        .ok_or_else(|| "version 2 PSBT without a transaction version".to_string())
+        .and_then(u32_le)?;
+
+    let mut tx = Transaction {
+        version: transaction::Version(version as i32),
+        lock_time: absolute::LockTime::ZERO,
+        input: Vec::new(),
+        output: Vec::new(),
+    };
+    let mut inputs = Vec::new();
+    let (mut heights, mut times) = (Vec::new(), Vec::new());
+    for i in 0..input_count {
+        let map = read_map(&mut rest)?;
+        let txid = value(&map, IN_PREVIOUS_TXID)
+            .and_then(|txid| deserialize::<Txid>(txid).ok())
+            .ok_or_else(|| format!("input {} has no valid previous txid", i))?;
+        let vout = value(&map, IN_OUTPUT_INDEX)
+            .ok_or_else(|| format!("input {} has no output index", i))
+            .and_then(u32_le)?;
+        let sequence = value(&map, IN_SEQUENCE)
+            .map(u32_le)
+            .transpose()?
+            .map_or(Sequence::MAX, Sequence);
+        heights.push(
+            value(&map, IN_REQUIRED_HEIGHT_LOCKTIME)
+                .map(u32_le)
+                .transpose()?,
+        );
+        times.push(
+            value(&map, IN_REQUIRED_TIME_LOCKTIME)
+                .map(u32_le)
+                .transpose()?,
+        );
+        tx.input.push(TxIn {
+            previous_output: OutPoint::new(txid, vout),
+            script_sig: ScriptBuf::new(),
+            sequence,
+            witness: Witness::new(),
+        });
+        inputs.push(without(
+            map,
+            &[
+                IN_PREVIOUS_TXID,
+                IN_OUTPUT_INDEX,
+                IN_SEQUENCE,
+                IN_REQUIRED_TIME_LOCKTIME,
+                IN_REQUIRED_HEIGHT_LOCKTIME,
+            ],
+        ));
+    }
+    let mut outputs = Vec::new();
+    for i in 0..output_count {
+        let map = read_map(&mut rest)?;
+        let amount = value(&map, OUT_AMOUNT)
+            .and_then(|amount| <[u8; 8]>::try_from(amount).ok())
+            .ok_or_else(|| format!("output {} has no valid amount", i))?;
+        let script =
+            value(&map, OUT_SCRIPT).ok_or_else(|| format!("output {} has no script", i))?;
+        tx.output.push(TxOut {
+            value: Amount::from_sat(u64::from_le_bytes(amount)),
+            script_pubkey: ScriptBuf::from_bytes(script.to_vec()),
+        });
+        outputs.push(without(map, &[OUT_AMOUNT, OUT_SCRIPT]));
+    }
+    tx.lock_time = lock_time(&global, &heights, &times)?;
+
+    let mut global = without(
+        global,
+        &[
+            GLOBAL_TX_VERSION,
+            GLOBAL_FALLBACK_LOCKTIME,
+            GLOBAL_INPUT_COUNT,
+            GLOBAL_OUTPUT_COUNT,
+            GLOBAL_TX_MODIFIABLE,
+            GLOBAL_VERSION,
+        ],
+    );
+    global.insert(0, (vec![GLOBAL_UNSIGNED_TX], serialize(&tx)));
+    Psbt::deserialize(&write(&global, &inputs, &outputs)).map_err(|e| e.to_string())
+}
+
+/// Encode `psbt` as a binary version 2 PSBT, its transaction's fields
+/// spread over the maps.
+pub fn serialize_v2(psbt: &Psbt) -> Vec<u8> {
+    let v0 = psbt.serialize();
+    let mut rest = &v0[PSBT_MAGIC.len()..];
+    let tx = &psbt.unsigned_tx;
+    let read = |rest: &mut &[u8]| read_map(rest).expect("rust-bitcoin serialized it");
+
+    let mut global = without(read(&mut rest), &[GLOBAL_UNSIGNED_TX, GLOBAL_VERSION]);
+    global.push((vec![GLOBAL_TX_VERSION], tx.version.0.to_le_bytes().to_vec()));
+    global.push((
+        vec![GLOBAL_FALLBACK_LOCKTIME],
+        tx.lock_time.to_consensus_u32().to_le_bytes().to_vec(),
+    ));
+    global.push((vec![GLOBAL_INPUT_COUNT], compact(tx.input.len() as u64)));
+    global.push((vec![GLOBAL_OUTPUT_COUNT], compact(tx.output.len() as u64)));
+    global.push((vec![GLOBAL_VERSION], 2u32.to_le_bytes().to_vec()));
+    global.sort();
+
+    let inputs: Vec<Map> = tx
+        .input
+        .iter()
+        .map(|txin| {
+            let mut map = read(&mut rest);
+            let outpoint = txin.previous_output;
+            map.push((vec![IN_PREVIOUS_TXID], serialize(&outpoint.txid)));
+            map.push((vec![IN_OUTPUT_INDEX], outpoint.vout.to_le_bytes().to_vec()));
+            map.push((
+                vec![IN_SEQUENCE],
+                txin.sequence.to_consensus_u32().to_le_bytes().to_vec(),
+            ));
+            map.sort();
+            map
+        })
+        .collect();
+    let outputs: Vec<Map> = tx
+        .output
+        .iter()
+        .map(|txout| {
+            let mut map = read(&mut rest);
+            map.push((
+                vec![OUT_AMOUNT],
+                txout.value.to_sat().to_le_bytes().to_vec(),
+            ));
+            map.push((vec![OUT_SCRIPT], txout.script_pubkey.to_bytes()));
+            map.sort();
+            map
+        })
+        .collect();
+    write(&global, &inputs, &outputs)
+}
+
+// The lock time BIP370 has a version 2 PSBT's transaction use: the latest
+// height or time its inputs require, preferring heights when every input
+// requiring one can take either, or else the fallback.
+fn lock_time(
+    global: &Map,
+    heights: &[Option<u32>],
+    times: &[Option<u32>],
+) -> Result<absolute::LockTime, String> {
+    let requiring: Vec<usize> = (0..heights.len())
+        .filter(|&i| heights[i].is_some() || times[i].is_some())
+        .collect();
+    let latest = |required: &[Option<u32>]| {
+        requiring
+            .iter()
+            .map(|&i| required[i])
+            .collect::<Option<Vec<u32>>>()
+            .map(|locks| locks.into_iter().max().unwrap_or(0))
+    };
+    let lock = if requiring.is_empty() {
+        value(global, GLOBAL_FALLBACK_LOCKTIME)
+            .map(u32_le)
+            .transpose()?
+            .unwrap_or(0)
+    } else if let Some(height) = latest(heights) {
+        height
+    } else {
+        latest(times).ok_or("the inputs require both height and time lock times")?
+    };
+    Ok(absolute::LockTime::from_consensus(lock))
+}
+
+// The value of the key made of just `key_type`.
+fn value(map: &Map, key_type: u8) -> Option<&[u8]> {
+    map.iter()
+        .find(|(key, _)| key[..] == [key_type])
+        .map(|(_, value)| &value[..])
+}
+
+// `map` without the keys of `key_types` that have no key data.
+fn without(map: Map, key_types: &[u8]) -> Map {
+    map.into_iter()
+        .filter(|(key, _)| !(key.len() == 1 && key_types.contains(&key[0])))
+        .collect()
+}
+
+fn u32_le(bytes: &[u8]) -> Result<u32, String> {
+    <[u8; 4]>::try_from(bytes)
+        .map(u32::from_le_bytes)
+        .map_err(|_| "expected a 4 byte value".to_string())
+}
+
+fn read_map(rest: &mut &[u8]) -> Result<Map, String> {
+    let mut map = Map::new();
+    loop {
+        let len = read_compact(rest)? as usize;
+        if len == 0 {
+            return Ok(map);
+        }
+        let key = take(rest, len)?.to_vec();
+        let len = read_compact(rest)? as usize;
+        map.push((key, take(rest, len)?.to_vec()));
+    }
+}
+
+fn write(global: &Map, inputs: &[Map], outputs: &[Map]) -> Vec<u8> {
+    let mut out = PSBT_MAGIC.to_vec();
+    for map in std::iter::once(global).chain(inputs).chain(outputs) {
+        for (key, value) in map {
+            out.extend(compact(key.len() as u64));
+            out.extend(key);
+            out.extend(compact(value.len() as u64));
+            out.extend(value);
+        }
+        out.push(0);
+    }
+    out
+}
+
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if rest.len() < n {
+        return Err("truncated PSBT".to_string());
+    }
+    let (taken, tail) = rest.split_at(n);
+    *rest = tail;
+    Ok(taken)
+}
+
+// Bitcoin's variable length integers.
+fn read_compact(rest: &mut &[u8]) -> Result<u64, String> {
+    let first = take(rest, 1)?[0];
+    let len = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        n => return Ok(n as u64),
+    };
+    Ok(take(rest, len)?
+        .iter()
+        .rev()
+        .fold(0, |acc, &b| acc << 8 | b as u64))
+}
+
+fn compact(n: u64) -> Vec<u8> {
+    match n {
+        0..=0xfc => vec![n as u8],
+        0xfd..=0xffff => [&[0xfd][..], &(n as u16).to_le_bytes()].concat(),
+        0x1_0000..=0xffff_ffff => [&[0xfe][..], &(n as u32).to_le_bytes()].concat(),
+        _ => [&[0xff][..], &n.to_le_bytes()].concat(),
+    }
+}
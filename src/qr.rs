@@ -0,0 +1,461 @@
+use std::path::Path;
+
+/// Decode every QR code found in the image at `path`.
+pub fn decode_image(path: &Path) -> Result<Vec<String>, String> {
+    let image = image::open(path)
+        .map_err(|e| format!("Can't read image {}: {}", path.display(), e))?
+        .to_luma8();
+    let mut image = rqrr::PreparedImage::prepare(image);
+    let grids = image.detect_grids();
+    if grids.is_empty() {
+        return Err(format!("No QR code found in {}", path.display()));
+    }
+    grids
+        .iter()
+        .map(|grid| {
+            grid.decode()
+                .map(|(_, content)| content)
+                .map_err(|e| format!("Can't decode QR code in {}: {}", path.display(), e))
+        })
+        .collect()
+}
+
+/// Blank modules around a code, so scanners can find its edges.
+const QUIET_ZONE: usize = 4;
+/// How many pixels wide a module is in PNG images.
+const PNG_SCALE: u32 = 8;
+/// The characters alphanumeric mode packs 11 bits per pair of.
+const ALPHANUMERIC: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+// The error correction codewords per block and the number of blocks by
+// version, at the lowest level L: the codes shown are sharp, so their
+// capacity matters more than recovering damaged ones.
+const ECC_PER_BLOCK: [usize; 41] = [
+    0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28, 30,
+    30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+];
+const BLOCKS: [usize; 41] = [
+    0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13, 14,
+    15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25,
+];
+
+/// A QR code, as its grid of dark and light modules.
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    // Finder, timing and alignment patterns and format information,
+    // which data and masks leave alone.
+    function: Vec<bool>,
+}
+
+/// Encode `text` as the smallest QR code at error correction level L that
+/// holds it, in alphanumeric mode if it only has those characters, such as
+/// upper case UR parts, or else byte by byte.
+pub fn encode(text: &str) -> Result<QrCode, String> {
+    let alphanumeric = text.bytes().all(|b| ALPHANUMERIC.contains(&b));
+    let count_bits = |version: usize| match (alphanumeric, version) {
+        (true, 1..=9) => 9,
+        (true, 10..=26) => 11,
+        (true, _) => 13,
+        (false, 1..=9) => 8,
+        (false, _) => 16,
+    };
+    let data_bits = if alphanumeric {
+        text.len() / 2 * 11 + text.len() % 2 * 6
+    } else {
+        text.len() * 8
+    };
+    let version = (1..=40)
+        .find(|&version| {
+            text.len() < 1 << count_bits(version)
+                && 4 + count_bits(version) + data_bits <= data_codewords(version) * 8
+        })
+        .ok_or_else(|| format!("{} characters don't fit in a QR code", text.len()))?;
+
+    let mut bits = Vec::new();
+    push_bits(&mut bits, if alphanumeric { 0b0010 } else { 0b0100 }, 4);
+    push_bits(&mut bits, text.len(), count_bits(version));
+    if alphanumeric {
+        let value = |b: &u8| ALPHANUMERIC.iter().position(|c| c == b).unwrap();
+        for pair in text.as_bytes().chunks(2) {
+            match pair {
+                [a, b] => push_bits(&mut bits, value(a) * 45 + value(b), 11),
+                [a] => push_bits(&mut bits, value(a), 6),
+                _ => unreachable!(),
+            }
+        }
+    } else {
+        for b in text.bytes() {
+            push_bits(&mut bits, b as usize, 8);
+        }
+    }
+    // The terminator, then padding to whole bytes and the capacity.
+    let capacity = data_codewords(version) * 8;
+    let terminator = (capacity - bits.len()).min(4);
+    push_bits(&mut bits, 0, terminator);
+    let padding = bits.len().next_multiple_of(8) - bits.len();
+    push_bits(&mut bits, 0, padding);
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if bits.len() >= capacity {
+            break;
+        }
+        push_bits(&mut bits, pad, 8);
+    }
+    let data: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+        .collect();
+
+    let mut code = QrCode::blank(version);
+    code.draw_codewords(&interleave(version, &data));
+    let mask = (0..8)
+        .min_by_key(|&mask| {
+            code.apply_mask(mask);
+            code.draw_format(mask);
+            let penalty = code.penalty();
+            code.apply_mask(mask);
+            penalty
+        })
+        .unwrap();
+    code.apply_mask(mask);
+    code.draw_format(mask);
+    Ok(code)
+}
+
+impl QrCode {
+    /// Whether the module at column `x` and row `y` is dark, with the quiet
+    /// zone around the code light.
+    fn dark(&self, x: usize, y: usize) -> bool {
+        let (x, y) = (x.wrapping_sub(QUIET_ZONE), y.wrapping_sub(QUIET_ZONE));
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    /// Draw the code with half block characters, two rows of modules to a
+    /// line of text, in colors that don't depend on the terminal's theme.
+    pub fn to_terminal(&self) -> String {
+        let width = self.size + 2 * QUIET_ZONE;
+        let color = |dark: bool| if dark { 0 } else { 15 };
+        let mut out = String::new();
+        for y in (0..width).step_by(2) {
+            for x in 0..width {
+                out.push_str(&format!(
+                    "\x1b[38;5;{};48;5;{}m\u{2580}",
+                    color(self.dark(x, y)),
+                    color(self.dark(x, y + 1))
+                ));
+            }
+            out.push_str("\x1b[0m\n");
+        }
+        out
+    }
+
+    /// Write the code to `path` as a PNG image.
+    pub fn save_png(&self, path: &Path) -> Result<(), String> {
+        let width = (self.size + 2 * QUIET_ZONE) as u32 * PNG_SCALE;
+        image::GrayImage::from_fn(width, width, |x, y| {
+            let dark = self.dark((x / PNG_SCALE) as usize, (y / PNG_SCALE) as usize);
+            image::Luma([if dark { 0 } else { 255 }])
+        })
+        .save(path)
+        .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+
+    // A code of `version` with only its function patterns drawn.
+    fn blank(version: usize) -> QrCode {
+        let size = version * 4 + 17;
+        let mut code = QrCode {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+        for i in 0..size {
+            code.set_function(6, i, i % 2 == 0);
+            code.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4isize {
+                for dx in -4..=4isize {
+                    let (xx, yy) = (x.wrapping_add_signed(dx), y.wrapping_add_signed(dy));
+                    if xx < size && yy < size {
+                        let distance = dx.abs().max(dy.abs());
+                        code.set_function(xx, yy, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                // Skip the corners the finder patterns take.
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2..=2isize {
+                    for dx in -2..=2isize {
+                        code.set_function(
+                            x.wrapping_add_signed(dx),
+                            y.wrapping_add_signed(dy),
+                            dx.abs().max(dy.abs()) != 1,
+                        );
+                    }
+                }
+            }
+        }
+        // Reserve the format information, drawn once the mask is chosen.
+        code.draw_format(0);
+        if version >= 7 {
+            let mut remainder = version;
+            for _ in 0..12 {
+                remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+            }
+            let bits = version << 12 | remainder;
+            for i in 0..18 {
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                code.set_function(a, b, bits >> i & 1 == 1);
+                code.set_function(b, a, bits >> i & 1 == 1);
+            }
+        }
+        code
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    // Both copies of the error correction level and mask, and the dark
+    // module beside them.
+    fn draw_format(&mut self, mask: usize) {
+        // Level L's two bits are 01.
+        let data = 0b01 << 3 | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| bits >> i & 1 == 1;
+        let size = self.size;
+        for i in 0..=5 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    // Fill the non-function modules in the zigzag order, two columns at a
+    // time from the bottom right, skipping the vertical timing pattern.
+    fn draw_codewords(&mut self, data: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                let y = if upward {
+                    size - 1 - vertical
+                } else {
+                    vertical
+                };
+                for x in [right, right - 1] {
+                    if !self.function[y * size + x] && i < data.len() * 8 {
+                        self.modules[y * size + x] = data[i / 8] >> (7 - i % 8) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    // Masks are their own inverse, so applying one twice undoes it.
+    fn apply_mask(&mut self, mask: usize) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let i = y * self.size + x;
+                self.modules[i] ^= invert && !self.function[i];
+            }
+        }
+    }
+
+    // How hard the code is to scan, by the specification's rules: long
+    // runs and 2x2 blocks of one color, patterns looking like finders, and
+    // an imbalance of dark and light.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let at = |x: usize, y: usize| self.modules[y * size + x];
+        let mut penalty = 0;
+        for transposed in [false, true] {
+            for a in 0..size {
+                let line: Vec<bool> = (0..size)
+                    .map(|b| if transposed { at(a, b) } else { at(b, a) })
+                    .collect();
+                let mut run = 1;
+                for b in 1..=size {
+                    if b < size && line[b] == line[b - 1] {
+                        run += 1;
+                        continue;
+                    }
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+                const FINDER: [bool; 7] = [true, false, true, true, true, false, true];
+                for window in line.windows(11) {
+                    if (window[..7] == FINDER && window[7..].iter().all(|&dark| !dark))
+                        || (window[4..] == FINDER && window[..4].iter().all(|&dark| !dark))
+                    {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = at(x, y);
+                if at(x + 1, y) == color && at(x, y + 1) == color && at(x + 1, y + 1) == color {
+                    penalty += 3;
+                }
+            }
+        }
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        penalty
+            + (dark * 20)
+                .abs_diff(total * 10)
+                .div_ceil(total)
+                .saturating_sub(1)
+                * 10
+    }
+}
+
+// Append the `len` low bits of `value`, most significant first.
+fn push_bits(bits: &mut Vec<bool>, value: usize, len: usize) {
+    bits.extend((0..len).rev().map(|i| value >> i & 1 == 1));
+}
+
+// The modules of a version left for data and error correction, once the
+// function patterns are drawn.
+fn raw_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codewords(version: usize) -> usize {
+    raw_modules(version) / 8 - ECC_PER_BLOCK[version] * BLOCKS[version]
+}
+
+// The rows and columns of the centers of the alignment patterns.
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = (version * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    let mut positions: Vec<usize> = (0..count - 1)
+        .map(|i| version * 4 + 10 - i * step)
+        .collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+// Split the data into blocks, append each one's Reed-Solomon error
+// correction, and interleave the blocks codeword by codeword. The first
+// blocks are a codeword shorter when the data doesn't divide evenly.
+fn interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let (blocks, ecc_len) = (BLOCKS[version], ECC_PER_BLOCK[version]);
+    let raw = raw_modules(version) / 8;
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks - ecc_len;
+    let divisor = rs_divisor(ecc_len);
+    let mut rest = data;
+    let blocks: Vec<(Vec<u8>, Vec<u8>)> = (0..blocks)
+        .map(|i| {
+            let (block, tail) = rest.split_at(short_len + usize::from(i >= short_blocks));
+            rest = tail;
+            (block.to_vec(), rs_remainder(block, &divisor))
+        })
+        .collect();
+    let mut out = Vec::with_capacity(raw);
+    for i in 0..=short_len {
+        out.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+    }
+    for i in 0..ecc_len {
+        out.extend(blocks.iter().map(|(_, ecc)| ecc[i]));
+    }
+    out
+}
+
+// The generator polynomial of `degree` over GF(256), leading term omitted.
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut divisor = vec![0; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_multiply(root, 2);
+    }
+    divisor
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0; divisor.len()];
+    for &b in data {
+        let factor = b ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, &d) in remainder.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(d, factor);
+        }
+    }
+    remainder
+}
+
+// Multiplication in GF(256) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= ((y as u16 >> i) & 1) * x as u16;
+    }
+    z as u8
+}
@@ -0,0 +1,622 @@
+use clap::Args;
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::consensus::serialize;
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::{
+    secp256k1, Amount, EcdsaSighashType, FeeRate, OutPoint, PrivateKey, Sequence, Transaction,
+    TxOut, Txid,
+};
+use miniscript::descriptor::DefiniteDescriptorKey;
+use miniscript::Descriptor;
+
+use crate::artifact::{self, Artifact};
+use crate::{broadcast, build, parse, policy, rpc, secret, sign, stage, utxo};
+
+/// Bitcoin Core's default incremental relay feerate, in sat/vB.
+const INCREMENTAL_RELAY_FEERATE: u64 = 1;
+
+#[derive(Args)]
+pub struct CheckReplacementArgs {
+    /// The transaction being replaced.
+    #[arg(value_parser = parse::transaction)]
+    original: Transaction,
+    /// The replacement, as a base64 PSBT.
+    #[arg(value_parser = parse::psbt)]
+    replacement: Psbt,
+    /// Confirmed transactions whose outputs the original spends, to
+    /// compute its fee.
+    #[arg(long, value_parser = parse::transaction)]
+    funding: Vec<Transaction>,
+    /// Unconfirmed transactions, e.g. the original's ancestors, whose
+    /// outputs either transaction spends.
+    #[arg(long, value_parser = parse::transaction)]
+    unconfirmed: Vec<Transaction>,
+    /// The descriptor controlling the replacement's inputs, to estimate the
+    /// size of inputs that aren't finalized yet.
+    #[arg(long, value_parser = parse::descriptor)]
+    descriptor: Option<Descriptor<DefiniteDescriptorKey>>,
+}
+
+#[derive(Args)]
+pub struct ReplaceArgs {
+    /// The unconfirmed transaction to replace.
+    txid: Txid,
+    /// The feerate the replacement pays, in sat/vB.
+    #[arg(long, value_parser = parse::feerate)]
+    feerate: FeeRate,
+    /// The descriptor controlling the original's inputs.
+    #[arg(long, value_parser = parse::descriptor)]
+    descriptor: Descriptor<DefiniteDescriptorKey>,
+    /// A different descriptor for one of the original's inputs, as
+    /// `<index>:<descriptor>`. May be repeated.
+    #[arg(long = "input-descriptor", value_parser = parse::input_descriptor)]
+    input_descriptors: Vec<(u32, Descriptor<DefiniteDescriptorKey>)>,
+    /// A private key to sign the replacement with. May be repeated.
+    #[arg(long = "key", value_parser = secret::parser(parse::private_key), required = true)]
+    keys: Vec<PrivateKey>,
+    /// Also pay `<address>:<amount>` from the change, where the address may
+    /// be a saved contact. May be repeated.
+    #[arg(long = "add-output", value_parser = parse::output)]
+    add_outputs: Vec<TxOut>,
+    /// The index of the original's change output, which pays for the bump.
+    /// Defaults to the output paying back to one of the descriptors.
+    #[arg(long)]
+    change: Option<usize>,
+    /// Submit the replacement to the node once it's signed.
+    #[arg(long)]
+    broadcast: bool,
+    #[command(flatten)]
+    backends: broadcast::BackendArgs,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+}
+
+#[derive(Args)]
+pub struct BumpFeeArgs {
+    /// The transaction to replace, raw or as a PSBT, signed or not.
+    original: String,
+    /// The feerate the replacement pays, in sat/vB.
+    #[arg(long, value_parser = parse::feerate)]
+    feerate: FeeRate,
+    /// The descriptor controlling the original's inputs.
+    #[arg(long, value_parser = parse::descriptor)]
+    descriptor: Descriptor<DefiniteDescriptorKey>,
+    /// A different descriptor for one of the original's inputs, as
+    /// `<index>:<descriptor>`. May be repeated.
+    #[arg(long = "input-descriptor", value_parser = parse::input_descriptor)]
+    input_descriptors: Vec<(u32, Descriptor<DefiniteDescriptorKey>)>,
+    /// The index of the original's change output, which pays for the bump.
+    /// Defaults to the output paying back to one of the descriptors.
+    #[arg(long)]
+    change: Option<usize>,
+    /// Also spend an output of another transaction, as
+    /// `<txid>:<vout>:<descriptor>`, if the change can't pay for the bump
+    /// or there is none. Its value goes to the change, or else back to its
+    /// descriptor. May be repeated.
+    #[arg(long = "add-input", value_parser = parse::input)]
+    add_inputs: Vec<(OutPoint, Descriptor<DefiniteDescriptorKey>)>,
+    /// A raw transaction whose outputs the original or --add-input spends,
+    /// unless a PSBT original has them. May be repeated.
+    #[arg(long = "prev-tx", value_parser = parse::transaction)]
+    prev_txs: Vec<Transaction>,
+    #[command(flatten)]
+    out: stage::PsbtOut,
+}
+
+/// Fetch the original transaction from the node and build, sign and
+/// optionally broadcast a replacement spending the same inputs, paying for
+/// the higher feerate and any added outputs out of the change.
+pub fn replace(args: ReplaceArgs) -> Result<(), String> {
+    let node = args.rpc.connect()?;
+    let original = node.unconfirmed_transaction(&args.txid)?;
+    let prevouts = original
+        .input
+        .iter()
+        .map(|txin| node.prevout(&txin.previous_output))
+        .collect::<Result<Vec<rpc::Prevout>, String>>()?;
+
+    let descriptors = input_descriptors(&original, &args.descriptor, &args.input_descriptors)?;
+    let input_value: u64 = prevouts.iter().map(|p| p.txout.value.to_sat()).sum();
+    let original_fee = input_value
+        .checked_sub(original.output.iter().map(|o| o.value.to_sat()).sum())
+        .ok_or("The original spends more than its inputs")?;
+    if !signals_rbf(&original) {
+        println!(
+            "{} doesn't signal replaceability, the replacement relies on full RBF",
+            args.txid
+        );
+    }
+
+    let change = find_change(&original, &descriptors, args.change)?
+        .ok_or("No output pays back to the descriptors, pass --change <index>")?;
+
+    let mut psbt = replacement(&original, &args.add_outputs);
+    let mut missing = Vec::new();
+    for (i, ((input, descriptor), prevout)) in psbt
+        .inputs
+        .iter_mut()
+        .zip(&descriptors)
+        .zip(prevouts)
+        .enumerate()
+    {
+        // Taproot sighashes commit to every spent output, so only segwit v0
+        // signers want the whole previous transaction.
+        if prevout.tx.is_none() && !prevout.txout.script_pubkey.is_p2tr() {
+            missing.push(format!(
+                "input {} ({})",
+                i, psbt.unsigned_tx.input[i].previous_output
+            ));
+        }
+        build::update_input(input, descriptor, prevout.txout, prevout.tx.clone())?;
+        input.non_witness_utxo = prevout.tx;
+    }
+    if !missing.is_empty() {
+        println!(
+            "The node has no transaction index, so the previous transactions of {} \
+             couldn't be fetched and their non_witness_utxo is left empty; hardware \
+             signers may refuse to sign them. Restart the node with -txindex to include them.",
+            missing.join(", ")
+        );
+    }
+
+    let (fee, vsize, value) = pay_fee(&mut psbt, &descriptors, args.feerate, original_fee, change)?;
+    println!(
+        "Original fee {} sat, replacement fee {} sat for {} vB, change {} sat",
+        original_fee, fee, vsize, value
+    );
+
+    policy::check_standard(&psbt, &descriptors)?;
+    policy::check_locktime(&psbt, &descriptors)?;
+    let secp = secp256k1::Secp256k1::new();
+    sign::sign_inputs(
+        &secp,
+        &mut psbt,
+        &args.keys,
+        EcdsaSighashType::All,
+        |_, _, _| {},
+    )
+    .map_err(|e| format!("Can't compute the sighash: {}", e))?;
+    println!("{}", psbt);
+
+    build::finalize(&secp, &mut psbt)?;
+    let tx = psbt.extract_tx_unchecked_fee_rate();
+    println!("raw: {}", serialize_hex(&tx));
+    if args.broadcast {
+        println!("Broadcast {}", args.backends.broadcast(&args.rpc, &tx)?);
+    }
+    Ok(())
+}
+
+/// Rebuild a transaction, or the PSBT of one, paying a higher feerate out
+/// of its change, or out of another coin when the change can't cover it,
+/// and hand on the unsigned replacement for the signers to sign again.
+pub fn bump_fee(args: BumpFeeArgs) -> Result<(), String> {
+    let (original, utxos) = match artifact::detect(&args.original)? {
+        Artifact::Psbt(psbt) => {
+            let utxos = (0..psbt.inputs.len())
+                .map(|i| {
+                    build::spent_utxo(&psbt, i)
+                        .ok_or_else(|| format!("Input {} of the PSBT has no UTXO", i))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let prev_txs = psbt.inputs.into_iter().map(|input| input.non_witness_utxo);
+            (psbt.unsigned_tx, utxos.into_iter().zip(prev_txs).collect())
+        }
+        Artifact::Transaction(tx) => {
+            let utxos = tx
+                .input
+                .iter()
+                .map(|txin| {
+                    let prev_tx = args.prev_tx(txin.previous_output)?;
+                    let utxo = prev_tx
+                        .output
+                        .get(txin.previous_output.vout as usize)
+                        .cloned()
+                        .ok_or_else(|| format!("{} has no such output", txin.previous_output))?;
+                    Ok((utxo, Some(prev_tx.clone())))
+                })
+                .collect::<Result<Vec<(TxOut, Option<Transaction>)>, String>>()?;
+            (tx, utxos)
+        }
+        Artifact::Descriptor(_) => {
+            return Err("Expected a transaction or PSBT, not a descriptor".to_string())
+        }
+    };
+
+    let mut descriptors = input_descriptors(&original, &args.descriptor, &args.input_descriptors)?;
+    let input_value: u64 = utxos.iter().map(|(utxo, _)| utxo.value.to_sat()).sum();
+    let original_fee = input_value
+        .checked_sub(original.output.iter().map(|o| o.value.to_sat()).sum())
+        .ok_or("The original spends more than its inputs")?;
+    if !signals_rbf(&original) {
+        eprintln!(
+            "{} doesn't signal replaceability, the replacement relies on full RBF",
+            original.compute_txid()
+        );
+    }
+    let change = find_change(&original, &descriptors, args.change)?;
+    if change.is_none() && args.add_inputs.is_empty() {
+        return Err(
+            "No output pays back to the descriptors, pass --change <index> or --add-input"
+                .to_string(),
+        );
+    }
+
+    let mut psbt = replacement(&original, &[]);
+    for ((input, descriptor), (utxo, prev_tx)) in
+        psbt.inputs.iter_mut().zip(&descriptors).zip(utxos)
+    {
+        build::update_input(input, descriptor, utxo, prev_tx)?;
+    }
+
+    let paid = match change {
+        Some(change) => pay_fee(&mut psbt, &descriptors, args.feerate, original_fee, change),
+        None => Err("The original has no change to pay the bump out of".to_string()),
+    };
+    let (fee, vsize, value) = match paid {
+        Ok(paid) => paid,
+        Err(e) if args.add_inputs.is_empty() => {
+            return Err(format!("{}, pass --add-input to spend another coin", e))
+        }
+        Err(_) => {
+            // Start over from the original outputs, with the added coins'
+            // value going to the change, or to a new change output back to
+            // the first of them.
+            psbt.unsigned_tx.output = original.output.clone();
+            let change = match change {
+                Some(change) => change,
+                None => {
+                    let script_pubkey = args.add_inputs[0].1.script_pubkey();
+                    build::add_output(
+                        &mut psbt,
+                        TxOut {
+                            script_pubkey,
+                            value: Amount::ZERO,
+                        },
+                    );
+                    psbt.unsigned_tx.output.len() - 1
+                }
+            };
+            for (outpoint, descriptor) in &args.add_inputs {
+                let prev_tx = args.prev_tx(*outpoint)?;
+                let (outpoint, utxo) = utxo::find(prev_tx, Some(*outpoint), descriptor)?;
+                eprintln!("Adding {} worth {} sat", outpoint, utxo.value.to_sat());
+                build::add_input(&mut psbt, outpoint);
+                let i = psbt.inputs.len() - 1;
+                psbt.unsigned_tx.input[i].sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+                let change_out = &mut psbt.unsigned_tx.output[change];
+                change_out.value = change_out
+                    .value
+                    .checked_add(utxo.value)
+                    .ok_or("The change overflows")?;
+                build::update_input(&mut psbt.inputs[i], descriptor, utxo, Some(prev_tx.clone()))?;
+                descriptors.push(descriptor);
+            }
+            pay_fee(&mut psbt, &descriptors, args.feerate, original_fee, change)?
+        }
+    };
+    eprintln!(
+        "Original fee {} sat, replacement fee {} sat for {} vB, change {} sat",
+        original_fee, fee, vsize, value
+    );
+
+    policy::check_standard(&psbt, &descriptors)?;
+    policy::check_locktime(&psbt, &descriptors)?;
+    args.out.store(psbt)
+}
+
+impl BumpFeeArgs {
+    // The --prev-tx creating `outpoint`.
+    fn prev_tx(&self, outpoint: OutPoint) -> Result<&Transaction, String> {
+        self.prev_txs
+            .iter()
+            .find(|tx| tx.compute_txid() == outpoint.txid)
+            .ok_or_else(|| {
+                format!(
+                    "Transaction {} is unknown, pass it with --prev-tx",
+                    outpoint.txid
+                )
+            })
+    }
+}
+
+// The descriptor of each input of `original`: `descriptor` unless
+// `overrides` names another for the input.
+fn input_descriptors<'a>(
+    original: &Transaction,
+    descriptor: &'a Descriptor<DefiniteDescriptorKey>,
+    overrides: &'a [(u32, Descriptor<DefiniteDescriptorKey>)],
+) -> Result<Vec<&'a Descriptor<DefiniteDescriptorKey>>, String> {
+    let mut descriptors = vec![descriptor; original.input.len()];
+    for (i, descriptor) in overrides {
+        *descriptors.get_mut(*i as usize).ok_or_else(|| {
+            format!(
+                "Input {} is out of range, the original has {} inputs",
+                i,
+                original.input.len()
+            )
+        })? = descriptor;
+    }
+    Ok(descriptors)
+}
+
+// The index of the original's change output: `change` if given, or else
+// the first output paying back to one of the descriptors, if any.
+fn find_change(
+    original: &Transaction,
+    descriptors: &[&Descriptor<DefiniteDescriptorKey>],
+    change: Option<usize>,
+) -> Result<Option<usize>, String> {
+    match change {
+        Some(change) if change < original.output.len() => Ok(Some(change)),
+        Some(change) => Err(format!(
+            "Output {} is out of range, the original has {} outputs",
+            change,
+            original.output.len()
+        )),
+        None => Ok(original.output.iter().position(|txout| {
+            descriptors
+                .iter()
+                .any(|d| d.script_pubkey() == txout.script_pubkey)
+        })),
+    }
+}
+
+// An unsigned replacement of `original` spending the same inputs to the
+// same outputs plus `add_outputs`.
+fn replacement(original: &Transaction, add_outputs: &[TxOut]) -> Psbt {
+    let outpoints: Vec<OutPoint> = original.input.iter().map(|i| i.previous_output).collect();
+    let outputs = original.output.iter().chain(add_outputs).cloned();
+    let mut psbt = build::create_psbt(&outpoints, outputs.collect());
+    psbt.unsigned_tx.version = original.version;
+    psbt.unsigned_tx.lock_time = original.lock_time;
+    // Keep relative timelocks, but make sure every input signals.
+    for (txin, original_in) in psbt.unsigned_tx.input.iter_mut().zip(&original.input) {
+        txin.sequence = if original_in.sequence < Sequence::ENABLE_LOCKTIME_NO_RBF {
+            original_in.sequence
+        } else {
+            Sequence::ENABLE_RBF_NO_LOCKTIME
+        };
+    }
+    psbt
+}
+
+// Pay the feerate asked for, and at least the original's fee plus the
+// replacement's own relay (BIP125 rules 3 and 4), out of output `change`.
+// Returns the fee, the replacement's vsize and what's left of the change.
+fn pay_fee(
+    psbt: &mut Psbt,
+    descriptors: &[&Descriptor<DefiniteDescriptorKey>],
+    feerate: FeeRate,
+    original_fee: u64,
+    change: usize,
+) -> Result<(u64, u64, u64), String> {
+    let input_value = (0..psbt.inputs.len())
+        .map(|i| build::spent_utxo(psbt, i).map(|utxo| utxo.value.to_sat()))
+        .sum::<Option<u64>>()
+        .ok_or("An input of the replacement has no UTXO")?;
+    let vsize = policy::estimate_weight(psbt, descriptors)?.div_ceil(4);
+    let fee = feerate
+        .fee_vb(vsize)
+        .ok_or("The fee overflows")?
+        .to_sat()
+        .max(original_fee + vsize * INCREMENTAL_RELAY_FEERATE);
+    let output_value: u64 = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|o| o.value.to_sat())
+        .sum();
+    let shortfall = (output_value + fee).saturating_sub(input_value);
+    let change_out = &mut psbt.unsigned_tx.output[change];
+    let value = change_out
+        .value
+        .to_sat()
+        .checked_sub(shortfall)
+        .ok_or_else(|| {
+            format!(
+                "The change output {} of {} sat can't cover the extra {} sat",
+                change,
+                change_out.value.to_sat(),
+                shortfall
+            )
+        })?;
+    change_out.value = Amount::from_sat(value);
+    if change_out.value < change_out.script_pubkey.minimal_non_dust() {
+        return Err(format!(
+            "Only {} sat of change would be left, below the dust threshold",
+            value
+        ));
+    }
+    Ok((fee, vsize, value))
+}
+
+/// Check the replacement against the BIP125 rules Bitcoin Core enforces,
+/// naming each rule it would fail.
+pub fn run(args: CheckReplacementArgs) -> Result<(), String> {
+    let original = &args.original;
+    let replacement = &args.replacement;
+    let mut violations = Vec::new();
+
+    let spent: Vec<OutPoint> = original.input.iter().map(|i| i.previous_output).collect();
+    if !replacement
+        .unsigned_tx
+        .input
+        .iter()
+        .any(|i| spent.contains(&i.previous_output))
+    {
+        return Err(format!(
+            "The replacement doesn't spend any input of {}, so it doesn't replace it",
+            original.compute_txid()
+        ));
+    }
+
+    // Rule 1: the original, or one of its unconfirmed ancestors, signals
+    // replaceability.
+    if !signals_rbf(original) {
+        let signaling = ancestors(original, &args.unconfirmed)
+            .into_iter()
+            .find(|tx| signals_rbf(tx));
+        match signaling {
+            Some(tx) => println!(
+                "{} doesn't signal replaceability but inherits it from {}",
+                original.compute_txid(),
+                tx.compute_txid()
+            ),
+            None => violations.push(format!(
+                "rule 1: {} doesn't signal replaceability and neither do its unconfirmed ancestors",
+                original.compute_txid()
+            )),
+        }
+    }
+
+    // Rule 2: no new unconfirmed inputs.
+    let unconfirmed: Vec<Txid> = args
+        .unconfirmed
+        .iter()
+        .map(|tx| tx.compute_txid())
+        .collect();
+    for (i, txin) in replacement.unsigned_tx.input.iter().enumerate() {
+        let outpoint = txin.previous_output;
+        if !spent.contains(&outpoint) && unconfirmed.contains(&outpoint.txid) {
+            violations.push(format!(
+                "rule 2: input {} spends {} which is unconfirmed and not spent by the original",
+                i, outpoint
+            ));
+        }
+    }
+
+    let prev_txs: Vec<&Transaction> = args.funding.iter().chain(&args.unconfirmed).collect();
+    let original_in = original
+        .input
+        .iter()
+        .map(|txin| {
+            let outpoint = txin.previous_output;
+            prev_txs
+                .iter()
+                .find(|tx| tx.compute_txid() == outpoint.txid)
+                .and_then(|tx| tx.output.get(outpoint.vout as usize))
+                .map(|txout| txout.value.to_sat())
+                .ok_or_else(|| {
+                    format!(
+                        "Pass the transaction creating {} with --funding or --unconfirmed",
+                        outpoint
+                    )
+                })
+        })
+        .sum::<Result<u64, String>>()?;
+    let original_fee = original_in
+        .checked_sub(original.output.iter().map(|o| o.value.to_sat()).sum())
+        .ok_or("The original spends more than its inputs")?;
+
+    let replacement_in = (0..replacement.inputs.len())
+        .map(|i| {
+            build::spent_utxo(replacement, i)
+                .map(|utxo| utxo.value.to_sat())
+                .ok_or_else(|| format!("Replacement input {} has no UTXO", i))
+        })
+        .sum::<Result<u64, String>>()?;
+    let replacement_fee = replacement_in
+        .checked_sub(
+            replacement
+                .unsigned_tx
+                .output
+                .iter()
+                .map(|o| o.value.to_sat())
+                .sum(),
+        )
+        .ok_or("The replacement spends more than its inputs")?;
+    let vsize = replacement_weight(replacement, args.descriptor.as_ref())?.div_ceil(4);
+
+    // Rule 3: the replacement pays at least the original's absolute fee.
+    if replacement_fee < original_fee {
+        violations.push(format!(
+            "rule 3: the replacement pays {} sat in fees, less than the original's {} sat",
+            replacement_fee, original_fee
+        ));
+    }
+    // Rule 4: it also pays for its own relay at the incremental feerate.
+    let required = original_fee + vsize * INCREMENTAL_RELAY_FEERATE;
+    if replacement_fee < required {
+        violations.push(format!(
+            "rule 4: the replacement pays {} sat in fees but needs at least {} sat, the original's {} sat plus {} sat/vB for its {} vB",
+            replacement_fee, required, original_fee, INCREMENTAL_RELAY_FEERATE, vsize
+        ));
+    }
+
+    println!(
+        "Original fee {} sat, replacement fee {} sat for {} vB",
+        original_fee, replacement_fee, vsize
+    );
+    if violations.is_empty() {
+        println!("The replacement satisfies BIP125");
+        Ok(())
+    } else {
+        Err(format!(
+            "The replacement would be rejected:\n  {}",
+            violations.join("\n  ")
+        ))
+    }
+}
+
+fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input
+        .iter()
+        .any(|txin| txin.sequence < Sequence::ENABLE_LOCKTIME_NO_RBF)
+}
+
+// The unconfirmed transactions `tx` descends from.
+fn ancestors<'a>(tx: &Transaction, unconfirmed: &'a [Transaction]) -> Vec<&'a Transaction> {
+    let mut ancestors: Vec<&Transaction> = Vec::new();
+    let mut queue = vec![tx];
+    while let Some(tx) = queue.pop() {
+        for txin in &tx.input {
+            let parent = unconfirmed
+                .iter()
+                .find(|parent| parent.compute_txid() == txin.previous_output.txid);
+            if let Some(parent) = parent {
+                if !ancestors
+                    .iter()
+                    .any(|a| a.compute_txid() == parent.compute_txid())
+                {
+                    ancestors.push(parent);
+                    queue.push(parent);
+                }
+            }
+        }
+    }
+    ancestors
+}
+
+// The weight of the replacement once signed, exact for finalized inputs and
+// estimated from the descriptor for the others.
+fn replacement_weight(
+    psbt: &Psbt,
+    descriptor: Option<&Descriptor<DefiniteDescriptorKey>>,
+) -> Result<u64, String> {
+    // The segwit marker and flag aren't counted while the witness is empty.
+    let mut weight = psbt.unsigned_tx.weight().to_wu() + 2;
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            let script_sig = input.final_script_sig.clone().unwrap_or_default();
+            // The empty script sig is already counted in the unsigned weight.
+            weight += (serialize(&script_sig).len() as u64 - 1) * 4;
+            weight += input
+                .final_script_witness
+                .as_ref()
+                .map_or(1, |witness| serialize(witness).len() as u64);
+            continue;
+        }
+        let descriptor = descriptor.ok_or_else(|| {
+            format!(
+                "Replacement input {} isn't finalized, pass --descriptor to estimate its size",
+                i
+            )
+        })?;
+        // The witness item count, then the satisfaction itself.
+        weight += 1 + policy::max_satisfaction_weight(descriptor)
+            .map_err(|e| format!("Can't estimate the satisfaction weight: {}", e))?
+            .to_wu();
+    }
+    Ok(weight)
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use clap::Args;
+use miniscript::bitcoin::absolute::LockTime;
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::{self, secp256k1, PrivateKey, Transaction, TxOut};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::Descriptor;
+
+use crate::artifact::Artifact;
+use crate::wallet::Profile;
+use crate::{annex, build, change, network, parse, policy, secret, sign, utxo};
+
+const HELP: &str = "\
+descriptor <descriptor>        set the descriptor controlling the inputs
+funding <rawtx>                remember a transaction whose outputs can be spent
+key <name> [file:<p>|env:<v>]  remember a private key under a name, typed
+                               without echo unless read from a file or variable
+add input <txid:vout>          spend an output of a remembered funding transaction
+add output <to> <amount>       pay an amount to an address, contact or descriptor
+locktime <height|time>         lock the transaction until a height or time
+annex <input> [hex]            set or clear the annex of a taproot input
+sign <name> [sighash]          sign every input with a remembered key
+show                           summarize the PSBT
+psbt                           print the PSBT as base64
+load <psbt>                    replace the PSBT with a base64 one
+finalize                       finalize the PSBT and print the transaction
+reset                          start over with an empty PSBT
+quit                           leave";
+
+#[derive(Args)]
+pub struct ReplArgs {
+    /// The descriptor controlling the inputs, can also be set in the session.
+    #[arg(long, value_parser = parse::descriptor)]
+    descriptor: Option<Descriptor<DefiniteDescriptorKey>>,
+    /// A wallet created with `setup` whose addresses outputs may pay change
+    /// to.
+    #[arg(long)]
+    wallet: Option<String>,
+}
+
+struct Session {
+    secp: secp256k1::Secp256k1<secp256k1::All>,
+    descriptor: Option<Descriptor<DefiniteDescriptorKey>>,
+    // Descriptors besides `descriptor` that outputs may pay change to.
+    wallets: Vec<Descriptor<DescriptorPublicKey>>,
+    funding: Vec<Transaction>,
+    keys: BTreeMap<String, PrivateKey>,
+    psbt: Psbt,
+}
+
+pub fn run(args: ReplArgs) -> Result<(), String> {
+    let wallets = match &args.wallet {
+        Some(name) => vec![parse::ranged_descriptor(&Profile::load(name)?.descriptor)?],
+        None => Vec::new(),
+    };
+    let mut session = Session {
+        secp: secp256k1::Secp256k1::new(),
+        descriptor: args.descriptor,
+        wallets,
+        funding: Vec::new(),
+        keys: BTreeMap::new(),
+        psbt: build::create_psbt(&[], vec![]),
+    };
+
+    println!("Type \"help\" for the list of commands.");
+    loop {
+        print!("> ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let line = match read_line()? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        match session.execute(&line) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+// Read a line from stdin without holding on to the lock, so commands can
+// prompt for confirmation. Returns `None` at the end of input.
+fn read_line() -> Result<Option<String>, String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(line)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+impl Session {
+    // Run a single command, returning whether the session should end.
+    fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["help"] => println!("{}", HELP),
+            ["quit"] | ["exit"] => return Ok(true),
+            ["descriptor", descriptor] => {
+                let descriptor = parse::descriptor(descriptor)?;
+                println!(
+                    "Address: {}",
+                    descriptor.address(network()).map_err(|e| e.to_string())?
+                );
+                self.descriptor = Some(descriptor);
+            }
+            ["funding", tx] => {
+                let tx = parse::transaction(tx)?;
+                println!(
+                    "Remembered {} with {} outputs",
+                    tx.compute_txid(),
+                    tx.output.len()
+                );
+                self.funding.push(tx);
+            }
+            ["key", name, source @ ..] if source.len() <= 1 => {
+                let wif = secret::read("Private key", source.first().unwrap_or(&"-"))?;
+                let key = parse::private_key(&wif)?;
+                println!("{}: {}", name, key.public_key(&self.secp));
+                self.keys.insert(name.to_string(), key);
+            }
+            ["add", "input", outpoint] => self.add_input(outpoint)?,
+            ["add", "output", recipient, amount @ ..] if !amount.is_empty() => {
+                let script_pubkey = parse::recipient(recipient)?;
+                let amount = parse::amount(&amount.join(" "))?;
+                build::add_output(
+                    &mut self.psbt,
+                    TxOut {
+                        script_pubkey,
+                        value: amount,
+                    },
+                );
+            }
+            ["sign", name] => self.sign(name, bitcoin::EcdsaSighashType::All)?,
+            ["sign", name, hash_ty] => self.sign(name, parse::sighash_type(hash_ty)?)?,
+            ["locktime", lock_time] => {
+                let lock_time = lock_time
+                    .parse()
+                    .map_err(|e| format!("Invalid lock time: {}", e))?;
+                build::set_lock_time(&mut self.psbt, LockTime::from_consensus(lock_time));
+            }
+            ["annex", input] => annex::set(&mut self.psbt, parse_index(input)?, None)?,
+            ["annex", input, hex] => annex::set(
+                &mut self.psbt,
+                parse_index(input)?,
+                Some(parse::annex(hex)?),
+            )?,
+            ["show"] => print!("{}", Artifact::Psbt(self.psbt.clone())),
+            ["psbt"] => println!("{}", self.psbt),
+            ["load", psbt] => self.psbt = parse::psbt(psbt)?,
+            ["finalize"] => {
+                let mut psbt = self.psbt.clone();
+                build::finalize(&self.secp, &mut psbt)?;
+                println!(
+                    "raw: {}",
+                    serialize_hex(&psbt.clone().extract_tx_unchecked_fee_rate())
+                );
+                self.psbt = psbt;
+            }
+            ["reset"] => self.psbt = build::create_psbt(&[], vec![]),
+            _ => return Err(format!("Unknown command \"{}\", try \"help\"", line.trim())),
+        }
+        Ok(false)
+    }
+
+    fn sign(&mut self, name: &str, hash_ty: bitcoin::EcdsaSighashType) -> Result<(), String> {
+        let key = *self
+            .keys
+            .get(name)
+            .ok_or_else(|| format!("No key named {}", name))?;
+        sign::check_utxos(&self.psbt, &self.funding)?;
+        if let Some(descriptor) = &self.descriptor {
+            let descriptors = vec![descriptor; self.psbt.inputs.len()];
+            policy::check_standard(&self.psbt, &descriptors)?;
+            policy::check_locktime(&self.psbt, &descriptors)?;
+        }
+        let mut ours = self.wallets.clone();
+        if let Some(descriptor) = &self.descriptor {
+            ours.push(parse::ranged_descriptor(&descriptor.to_string())?);
+        }
+        let ownership = change::classify(&self.psbt, &ours);
+        print!(
+            "{}",
+            change::Summary {
+                psbt: &self.psbt,
+                ownership: &ownership,
+            }
+        );
+
+        let risks = sign::risks(&self.psbt, &vec![hash_ty; self.psbt.inputs.len()]);
+        if !risks.is_empty() {
+            for risk in &risks {
+                println!("WARNING: {}", risk);
+            }
+            print!("Sign anyway? [y/N] ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+            let answer = read_line()?.unwrap_or_default();
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                return Err("Not signed".to_string());
+            }
+        }
+
+        sign::sign_inputs(&self.secp, &mut self.psbt, &[key], hash_ty, |_, i, pk| {
+            println!("Signed input {} with {}", i, pk)
+        })
+        .map_err(|e| format!("Can't compute the sighash: {}", e))
+    }
+
+    fn add_input(&mut self, outpoint: &str) -> Result<(), String> {
+        let outpoint = parse::outpoint(outpoint)?;
+        let descriptor = self
+            .descriptor
+            .as_ref()
+            .ok_or("Set a descriptor before adding inputs")?;
+        let tx = self
+            .funding
+            .iter()
+            .find(|tx| tx.compute_txid() == outpoint.txid)
+            .ok_or_else(|| format!("No funding transaction {} remembered", outpoint.txid))?;
+        let (outpoint, txout) = utxo::find(tx, Some(outpoint), descriptor)?;
+
+        build::add_input(&mut self.psbt, outpoint);
+        let input = self.psbt.inputs.last_mut().unwrap();
+        build::update_input(input, descriptor, txout, Some(tx.clone()))
+    }
+}
+
+fn parse_index(s: &str) -> Result<usize, String> {
+    s.parse()
+        .map_err(|e| format!("Invalid input index \"{}\": {}", s, e))
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use clap::Args;
+use miniscript::bitcoin::{OutPoint, ScriptBuf, TxOut};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::Descriptor;
+
+use crate::wallet::{Entry, Profile, State, Utxo};
+use crate::{parse, rpc};
+
+#[derive(Args)]
+pub struct RescanArgs {
+    /// The wallet to rescan.
+    wallet: String,
+    /// The first block to scan; coins received earlier aren't found.
+    #[arg(long, default_value_t = 0)]
+    from_height: u32,
+    /// The derivation indexes whose scripts are looked for.
+    #[arg(long, value_parser = parse::range, default_value = "0-999")]
+    range: RangeInclusive<u32>,
+    /// Match the wallet's scripts against each block's BIP158 compact
+    /// filter and only fetch the blocks that match; needs a node running
+    /// with -blockfilterindex.
+    #[arg(long)]
+    filters: bool,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+}
+
+/// Walk the node's blocks from the given height, matching every output
+/// against the wallet's scripts, and replace the wallet's coins and history
+/// with those found.
+pub fn run(args: RescanArgs) -> Result<(), String> {
+    let profile = Profile::load(&args.wallet)?;
+    let scripts = scripts(&profile, &args.range)?;
+    let node = args.rpc.connect()?;
+    let tip = node.tip_height()?;
+    if args.from_height > tip {
+        return Err(format!(
+            "Height {} is beyond the tip at {}",
+            args.from_height, tip
+        ));
+    }
+
+    // Only what the chain tells us is rebuilt: the wallet's contacts stay, as
+    // do the transactions we created, which are confirmed again if found.
+    let old = State::load(&args.wallet)?;
+    let mut state = State {
+        contacts: old.contacts,
+        history: old
+            .history
+            .into_iter()
+            .filter(|entry| entry.created)
+            .map(|entry| Entry { height: 0, ..entry })
+            .collect(),
+        ..State::default()
+    };
+    let mut unspent: HashMap<OutPoint, Utxo> = HashMap::new();
+    let fetched = scan(
+        &node,
+        &scripts,
+        args.from_height..=tip,
+        args.filters,
+        &mut unspent,
+        &mut state.history,
+    )?;
+
+    state.height = tip;
+    state.utxos = unspent.into_values().collect();
+    state
+        .utxos
+        .sort_by_key(|utxo| (utxo.height, utxo.outpoint.clone()));
+    let balance: u64 = state.utxos.iter().map(|utxo| utxo.value).sum();
+    println!(
+        "Scanned blocks {}-{} ({} fetched): {} transactions, {} unspent outputs worth {} sat",
+        args.from_height,
+        tip,
+        fetched,
+        state.history.len(),
+        state.utxos.len(),
+        balance
+    );
+    let path = state.save(&args.wallet)?;
+    println!("Saved the wallet state to {}", path.display());
+    Ok(())
+}
+
+/// Walk the node's blocks at `heights`, matching every output against
+/// `scripts`. Coins paying them are added to `unspent`, coins spent are
+/// removed from it, and each transaction doing either is appended to
+/// `history`, or confirms the entry already there.
+///
+/// With `filters` only blocks whose compact filter matches are fetched.
+/// Returns how many blocks were.
+pub fn scan(
+    node: &rpc::Node,
+    scripts: &HashMap<ScriptBuf, u32>,
+    heights: RangeInclusive<u32>,
+    filters: bool,
+    unspent: &mut HashMap<OutPoint, Utxo>,
+    history: &mut Vec<Entry>,
+) -> Result<usize, String> {
+    let (from, tip) = (*heights.start(), *heights.end());
+    let mut fetched = 0;
+    for height in heights {
+        let hash = node.block_hash(height)?;
+        // Filters cover the scripts of spent outputs too, so blocks
+        // spending our coins match as well as those paying us.
+        if filters
+            && !node
+                .block_filter(&hash)?
+                .match_any(&hash, scripts.keys().map(|spk| spk.as_bytes()))
+                .map_err(|e| format!("Can't read the filter of block {}: {}", height, e))?
+        {
+            continue;
+        }
+        let block = node.block(&hash)?;
+        fetched += 1;
+        for tx in &block.txdata {
+            let txid = tx.compute_txid();
+            let mut sent = 0;
+            let mut spent_all = true;
+            for txin in &tx.input {
+                match unspent.remove(&txin.previous_output) {
+                    Some(utxo) => sent += utxo.value,
+                    None => spent_all = false,
+                }
+            }
+            let mut received = 0;
+            for (vout, txout) in tx.output.iter().enumerate() {
+                if let Some(&index) = scripts.get(&txout.script_pubkey) {
+                    let outpoint = OutPoint::new(txid, vout as u32);
+                    received += txout.value.to_sat();
+                    unspent.insert(
+                        outpoint,
+                        Utxo {
+                            outpoint: outpoint.to_string(),
+                            value: txout.value.to_sat(),
+                            index,
+                            height,
+                        },
+                    );
+                }
+            }
+            if sent == 0 && received == 0 {
+                continue;
+            }
+            let txid = txid.to_string();
+            let time = u64::from(block.header.time);
+            let fee = (spent_all && sent > 0)
+                .then(|| sent.checked_sub(tx.output.iter().map(|o| o.value.to_sat()).sum()))
+                .flatten();
+            match history.iter_mut().find(|entry| entry.txid == txid) {
+                // Transactions we created get confirmed.
+                Some(entry) => {
+                    entry.height = height;
+                    entry.time = time;
+                    entry.received = received;
+                    entry.sent = sent;
+                    entry.fee = entry.fee.or(fee);
+                }
+                None => history.push(Entry {
+                    txid,
+                    height,
+                    received,
+                    sent,
+                    time,
+                    fee,
+                    label: None,
+                    created: false,
+                    broadcast: false,
+                }),
+            }
+        }
+        if height % 10_000 == 0 && height != from {
+            eprintln!("Scanned up to block {} of {}", height, tip);
+        }
+    }
+    Ok(fetched)
+}
+
+/// Map every script the wallet's descriptor derives in `range` to its
+/// derivation index, covering each path of a multipath descriptor.
+pub fn scripts(
+    profile: &Profile,
+    range: &RangeInclusive<u32>,
+) -> Result<HashMap<ScriptBuf, u32>, String> {
+    let descriptor = parse::ranged_descriptor(&profile.descriptor)?;
+    let descriptors = descriptor
+        .into_single_descriptors()
+        .map_err(|e| format!("Can't split the wallet's descriptor: {}", e))?;
+    let mut scripts = HashMap::new();
+    for descriptor in &descriptors {
+        for index in range.clone() {
+            let derived = descriptor
+                .at_derivation_index(index)
+                .map_err(|e| format!("Can't derive index {}: {}", index, e))?;
+            scripts.insert(derived.script_pubkey(), index);
+            if !descriptor.has_wildcard() {
+                break;
+            }
+        }
+    }
+    Ok(scripts)
+}
+
+/// The descriptor of the wallet's coin `outpoint`, paying `utxo` to the
+/// derivation index `index` of one of `descriptors`, the single path
+/// descriptors of the wallet.
+pub fn derive(
+    descriptors: &[Descriptor<DescriptorPublicKey>],
+    outpoint: &OutPoint,
+    index: u32,
+    utxo: &TxOut,
+) -> Result<Descriptor<DefiniteDescriptorKey>, String> {
+    descriptors
+        .iter()
+        .map(|descriptor| descriptor.at_derivation_index(index))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Can't derive index {}: {}", index, e))?
+        .into_iter()
+        .find(|descriptor| descriptor.script_pubkey() == utxo.script_pubkey)
+        .ok_or_else(|| format!("{} doesn't pay the wallet's index {}", outpoint, index))
+}
@@ -0,0 +1,283 @@
+use std::ops::RangeInclusive;
+
+use clap::Args;
+use miniscript::bitcoin::bip32::Xpriv;
+use miniscript::bitcoin::hashes::{hash160, sha256, Hash};
+use miniscript::bitcoin::opcodes::OP_TRUE;
+use miniscript::bitcoin::psbt::Input;
+use miniscript::bitcoin::sighash::Prevouts;
+use miniscript::bitcoin::{
+    secp256k1, Amount, EcdsaSighashType, OutPoint, PrivateKey, PubkeyHash, ScriptBuf,
+    TapSighashType, TxOut, Txid, Witness,
+};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::interpreter::{Interpreter, KeySigPair, SatisfiedConstraint};
+use miniscript::Descriptor;
+
+use crate::chain::Chain;
+use crate::create::Source;
+use crate::error::Error;
+use crate::{build, parse, rpc, secret, sign, stage, utxo};
+
+/// What the hash of a proof's message is prefixed with, as BIP127 does.
+const MESSAGE_PREFIX: &str = "Proof-of-Reserves: ";
+
+#[derive(Args)]
+pub struct ProveArgs {
+    /// The descriptor whose coins to prove control of.
+    #[arg(value_parser = parse::ranged_descriptor)]
+    descriptor: Descriptor<DescriptorPublicKey>,
+    /// The raw transaction whose outputs paying the descriptor are the
+    /// coins, or `node` for every confirmed output paying it that the node
+    /// of the --rpc-* options, or the --esplora server, finds in its UTXO
+    /// set.
+    #[arg(value_parser = parse::source)]
+    rawtx: Source,
+    /// The message the proof commits to, such as the auditor's challenge
+    /// and the date, so it can't be replayed for another audit.
+    #[arg(long)]
+    message: String,
+    /// The derivation indexes to look for coins at, for descriptors with
+    /// wildcards.
+    #[arg(long, value_parser = parse::range, default_value = "0-999")]
+    range: RangeInclusive<u32>,
+    /// Look the coins up through this Esplora HTTP API instead of a node.
+    #[arg(long, value_name = "URL", value_parser = parse::url)]
+    esplora: Option<String>,
+    /// A private key to sign the proof with, as `file:<PATH>`, `env:<VAR>`
+    /// or `-` to be prompted for it. May be repeated. Without any keys the
+    /// proof is left unsigned, for the cosigners to `sign` and `finalize`.
+    #[arg(long = "key", value_parser = secret::parser(parse::private_key))]
+    keys: Vec<PrivateKey>,
+    /// An extended private key to derive the keys the inputs' BIP32
+    /// derivations name from. May be repeated.
+    #[arg(long = "xpriv", value_parser = secret::parser(parse::xpriv))]
+    xprivs: Vec<Xpriv>,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+    #[command(flatten)]
+    out: stage::PsbtOut,
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    psbt: stage::PsbtArg,
+    /// The message the proof must commit to.
+    #[arg(long)]
+    message: String,
+    /// Check the coins are unspent through this Esplora HTTP API instead
+    /// of a node.
+    #[arg(long, value_name = "URL", value_parser = parse::url)]
+    esplora: Option<String>,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+}
+
+/// Build the BIP127 proof that the descriptor's coins are under our
+/// control: a PSBT spending them along with an input committing to the
+/// message, which spends an output that doesn't exist so the transaction
+/// can never be mined. It's signed with the keys given and finalized once
+/// complete.
+pub fn prove(args: ProveArgs) -> Result<(), Error> {
+    let coins = match &args.rawtx {
+        Source::Transaction(tx) => {
+            utxo::paying(std::slice::from_ref(tx), &args.descriptor, &args.range)?
+        }
+        Source::Node => Chain::connect(args.esplora.as_deref(), &args.rpc)?
+            .utxos(&args.descriptor, &args.range)?,
+    };
+
+    let mut outpoints = vec![challenge(&args.message)];
+    let mut inputs = vec![challenge_input()];
+    let mut total = Amount::ZERO;
+    for coin in coins {
+        let mut input = Input::default();
+        build::update_input(&mut input, &coin.descriptor, coin.txout.clone(), coin.tx)?;
+        outpoints.push(coin.outpoint);
+        inputs.push(input);
+        total += coin.txout.value;
+    }
+    if outpoints.len() == 1 {
+        return Err(Error::UtxoNotFound(
+            "No coins pay the descriptor".to_string(),
+        ));
+    }
+
+    let output = TxOut {
+        value: total,
+        script_pubkey: unspendable(),
+    };
+    let mut psbt = build::create_psbt(&outpoints, vec![output]);
+    psbt.inputs = inputs;
+    eprintln!(
+        "Proving control of {} sat in {} coins",
+        total.to_sat(),
+        outpoints.len() - 1
+    );
+
+    let secp = secp256k1::Secp256k1::new();
+    let mut keys = args.keys;
+    for key in sign::derive_keys(&secp, &psbt, &args.xprivs)? {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    if !keys.is_empty() {
+        let coins: Vec<usize> = (1..psbt.inputs.len()).collect();
+        sign::sign_selected_inputs(
+            &secp,
+            &mut psbt,
+            &coins,
+            &keys,
+            EcdsaSighashType::All,
+            |_, i, pk| eprintln!("Signed input {} with {}", i, pk),
+        )
+        .map_err(|e| Error::Other(format!("Can't compute the sighash: {}", e)))?;
+        let mut finalized = psbt.clone();
+        match build::finalize(&secp, &mut finalized) {
+            Ok(()) => psbt = finalized,
+            Err(e) => eprintln!("The proof isn't complete yet: {}", e),
+        }
+    }
+    Ok(args.out.store(psbt)?)
+}
+
+/// Check a finalized proof of reserves: that it commits to the message,
+/// that every coin it spends is confirmed and still unspent, and that its
+/// signatures are valid against the coins as the UTXO set has them, not as
+/// the PSBT claims them. Prints what the coins are worth.
+pub fn verify(args: VerifyArgs) -> Result<(), Error> {
+    let psbt = args.psbt.load()?;
+    let tx = stage::extract_tx(psbt)?;
+    if tx.input.first().map(|txin| txin.previous_output) != Some(challenge(&args.message)) {
+        return Err(Error::Other(
+            "The proof doesn't commit to the message".to_string(),
+        ));
+    }
+    if tx.input.len() < 2 {
+        return Err(Error::Other("The proof spends no coins".to_string()));
+    }
+    if tx.output.len() != 1 {
+        return Err(Error::Other(format!(
+            "A proof has a single output, this one has {}",
+            tx.output.len()
+        )));
+    }
+    for (i, txin) in tx.input.iter().enumerate() {
+        if tx.input[..i]
+            .iter()
+            .any(|other| other.previous_output == txin.previous_output)
+        {
+            return Err(Error::Other(format!(
+                "Input {} spends {} again",
+                i, txin.previous_output
+            )));
+        }
+    }
+
+    let chain = Chain::connect(args.esplora.as_deref(), &args.rpc)?;
+    let mut prevouts = vec![challenge_utxo()];
+    for (i, txin) in tx.input.iter().enumerate().skip(1) {
+        let outpoint = txin.previous_output;
+        let utxo = chain.unspent(&outpoint)?.ok_or_else(|| {
+            Error::Other(format!(
+                "Input {} spends {}, which isn't a confirmed unspent output",
+                i, outpoint
+            ))
+        })?;
+        prevouts.push(utxo);
+    }
+
+    let secp = secp256k1::Secp256k1::verification_only();
+    let mut total = Amount::ZERO;
+    for (i, txin) in tx.input.iter().enumerate().skip(1) {
+        let utxo = &prevouts[i];
+        let interpreter = Interpreter::from_txdata(
+            &utxo.script_pubkey,
+            &txin.script_sig,
+            &txin.witness,
+            txin.sequence,
+            tx.lock_time,
+        )
+        .map_err(|e| Error::Signature(format!("Input {}: {}", i, e)))?;
+        for constraint in interpreter.iter(&secp, &tx, i, &Prevouts::All(&prevouts)) {
+            let constraint =
+                constraint.map_err(|e| Error::Signature(format!("Input {}: {}", i, e)))?;
+            if let SatisfiedConstraint::PublicKey { key_sig }
+            | SatisfiedConstraint::PublicKeyHash { key_sig, .. } = constraint
+            {
+                if anyone_can_pay(&key_sig) {
+                    return Err(Error::Signature(format!(
+                        "Input {}: signed with ANYONECANPAY, which doesn't commit to the \
+                         message",
+                        i
+                    )));
+                }
+            }
+        }
+        println!(
+            "Input {} ({}) holds {} sat",
+            i,
+            txin.previous_output,
+            utxo.value.to_sat()
+        );
+        total += utxo.value;
+    }
+    println!(
+        "The proof controls {} sat in {} coins",
+        total.to_sat(),
+        tx.input.len() - 1
+    );
+    Ok(())
+}
+
+/// The output the first input of a proof spends, whose txid is the hash of
+/// the message.
+pub fn challenge(message: &str) -> OutPoint {
+    let hash = sha256::Hash::hash(format!("{}{}", MESSAGE_PREFIX, message).as_bytes());
+    OutPoint::new(Txid::from_byte_array(hash.to_byte_array()), 0)
+}
+
+// The challenge input, spending an OP_TRUE output worth nothing and final
+// from the start, so signers and finalizers leave it alone.
+fn challenge_input() -> Input {
+    Input {
+        witness_utxo: Some(challenge_utxo()),
+        final_script_sig: Some(ScriptBuf::new()),
+        final_script_witness: Some(Witness::new()),
+        ..Default::default()
+    }
+}
+
+fn challenge_utxo() -> TxOut {
+    TxOut {
+        value: Amount::ZERO,
+        script_pubkey: ScriptBuf::from_bytes(vec![OP_TRUE.to_u8()]),
+    }
+}
+
+// The output a proof pays its coins to, a P2PKH whose key hash is the
+// HASH160 of a zero byte, as BDK's proofs of reserves use.
+fn unspendable() -> ScriptBuf {
+    ScriptBuf::new_p2pkh(&PubkeyHash::from_raw_hash(hash160::Hash::hash(&[0])))
+}
+
+// Whether the signature leaves the other inputs, the challenge among them,
+// out of what it signs.
+fn anyone_can_pay(key_sig: &KeySigPair) -> bool {
+    match key_sig {
+        KeySigPair::Ecdsa(_, sig) => matches!(
+            sig.sighash_type,
+            EcdsaSighashType::AllPlusAnyoneCanPay
+                | EcdsaSighashType::NonePlusAnyoneCanPay
+                | EcdsaSighashType::SinglePlusAnyoneCanPay
+        ),
+        KeySigPair::Schnorr(_, sig) => matches!(
+            sig.sighash_type,
+            TapSighashType::AllPlusAnyoneCanPay
+                | TapSighashType::NonePlusAnyoneCanPay
+                | TapSighashType::SinglePlusAnyoneCanPay
+        ),
+    }
+}
@@ -0,0 +1,255 @@
+use std::env;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use bitcoincore_rpc::json::ScanTxOutRequest;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use clap::Args;
+use miniscript::bitcoin::bip158::BlockFilter;
+use miniscript::bitcoin::{
+    Block, BlockHash, Network, OutPoint, ScriptBuf, Transaction, TxOut, Txid,
+};
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::Descriptor;
+
+use crate::network;
+
+/// How to reach the Bitcoin Core node backing a command.
+#[derive(Args)]
+pub struct RpcArgs {
+    /// The node's RPC URL, by default the network's RPC port on localhost.
+    #[arg(long)]
+    rpc_url: Option<String>,
+    /// The node's cookie file, by default .cookie in the network's
+    /// directory under ~/.bitcoin.
+    #[arg(long, conflicts_with = "rpc_user")]
+    rpc_cookie: Option<PathBuf>,
+    /// The RPC user name, instead of a cookie file.
+    #[arg(long, requires = "rpc_password")]
+    rpc_user: Option<String>,
+    /// The RPC password.
+    #[arg(long, requires = "rpc_user")]
+    rpc_password: Option<String>,
+}
+
+/// A connection to a Bitcoin Core node.
+pub struct Node {
+    client: Client,
+    /// Whether the node indexes every transaction, rather than only
+    /// serving those in its mempool.
+    txindex: bool,
+    /// Whether the node serves BIP158 compact block filters.
+    filter_index: bool,
+}
+
+/// An output being spent, along with the transaction creating it if the
+/// node could provide it.
+pub struct Prevout {
+    pub txout: TxOut,
+    pub tx: Option<Transaction>,
+}
+
+impl RpcArgs {
+    /// Connect to the node and find out which indexes it has.
+    pub fn connect(&self) -> Result<Node, String> {
+        // Bitcoin Core's defaults for each network.
+        let (port, dir) = match network() {
+            Network::Bitcoin => (8332, ""),
+            Network::Testnet => (18332, "testnet3"),
+            Network::Testnet4 => (48332, "testnet4"),
+            Network::Signet => (38332, "signet"),
+            _ => (18443, "regtest"),
+        };
+        let url = self
+            .rpc_url
+            .clone()
+            .unwrap_or_else(|| format!("http://127.0.0.1:{}", port));
+        let auth = match (&self.rpc_user, &self.rpc_password) {
+            (Some(user), Some(password)) => Auth::UserPass(user.clone(), password.clone()),
+            _ => Auth::CookieFile(match &self.rpc_cookie {
+                Some(path) => path.clone(),
+                None => PathBuf::from(env::var("HOME").map_err(|_| "HOME is not set")?)
+                    .join(".bitcoin")
+                    .join(dir)
+                    .join(".cookie"),
+            }),
+        };
+        let client =
+            Client::new(&url, auth).map_err(|e| format!("Can't connect to {}: {}", url, e))?;
+        let index_info = client
+            .get_index_info()
+            .map_err(|e| format!("Can't query {}: {}", url, e))?;
+        Ok(Node {
+            client,
+            txindex: index_info.txindex.is_some(),
+            filter_index: index_info.basic_block_filter_index.is_some(),
+        })
+    }
+}
+
+impl Node {
+    /// Fetch `txid`, which must still be waiting in the mempool.
+    pub fn unconfirmed_transaction(&self, txid: &Txid) -> Result<Transaction, String> {
+        let info = self
+            .client
+            .get_raw_transaction_info(txid, None)
+            .map_err(|e| format!("Can't fetch {}: {}", txid, e))?;
+        if info.confirmations.is_some_and(|n| n > 0) {
+            return Err(format!("{} is already confirmed", txid));
+        }
+        info.transaction()
+            .map_err(|e| format!("The node returned an invalid {}: {}", txid, e))
+    }
+
+    /// Look up the output `outpoint` refers to and, when the node can serve
+    /// it, the whole transaction creating it.
+    ///
+    /// Without a transaction index only mempool transactions can be fetched,
+    /// so for confirmed ones just the output itself is returned.
+    pub fn prevout(&self, outpoint: &OutPoint) -> Result<Prevout, String> {
+        match self.client.get_raw_transaction(&outpoint.txid, None) {
+            Ok(tx) => {
+                let txout = tx
+                    .output
+                    .get(outpoint.vout as usize)
+                    .cloned()
+                    .ok_or_else(|| format!("{} has no such output", outpoint))?;
+                return Ok(Prevout {
+                    txout,
+                    tx: Some(tx),
+                });
+            }
+            Err(e) if self.txindex => {
+                return Err(format!("Can't fetch {}: {}", outpoint.txid, e));
+            }
+            Err(_) => {}
+        }
+        // Confirmed outputs are found without a transaction index, even if
+        // a mempool transaction spends them.
+        let confirmed = self
+            .client
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(false))
+            .map_err(|e| format!("Can't look up {}: {}", outpoint, e))?
+            .ok_or_else(|| {
+                format!(
+                    "Can't find {}: it's neither in the mempool nor an unspent confirmed output, \
+                     and the node has no transaction index (-txindex) to look further",
+                    outpoint
+                )
+            })?;
+        Ok(Prevout {
+            txout: TxOut {
+                value: confirmed.value,
+                script_pubkey: ScriptBuf::from_bytes(confirmed.script_pub_key.hex),
+            },
+            tx: None,
+        })
+    }
+
+    /// The output `outpoint` refers to if it's confirmed and unspent, even
+    /// by a transaction in the mempool.
+    pub fn unspent(&self, outpoint: &OutPoint) -> Result<Option<TxOut>, String> {
+        let unspent = self
+            .client
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))
+            .map_err(|e| format!("Can't look up {}: {}", outpoint, e))?;
+        Ok(unspent
+            .filter(|utxo| utxo.confirmations > 0)
+            .map(|utxo| TxOut {
+                value: utxo.value,
+                script_pubkey: ScriptBuf::from_bytes(utxo.script_pub_key.hex),
+            }))
+    }
+
+    /// Find the confirmed unspent outputs paying `descriptor` with
+    /// `scantxoutset`, at the derivation indexes `range` if it has
+    /// wildcards.
+    ///
+    /// The scan walks the node's whole UTXO set and takes a while.
+    pub fn scan(
+        &self,
+        descriptor: &Descriptor<DescriptorPublicKey>,
+        range: &RangeInclusive<u32>,
+    ) -> Result<Vec<(OutPoint, TxOut)>, String> {
+        // Each path of a multipath descriptor is scanned on its own.
+        let requests = descriptor
+            .clone()
+            .into_single_descriptors()
+            .map_err(|e| format!("Can't split the descriptor: {}", e))?
+            .into_iter()
+            .map(|descriptor| {
+                if descriptor.has_wildcard() {
+                    ScanTxOutRequest::Extended {
+                        desc: descriptor.to_string(),
+                        range: (u64::from(*range.start()), u64::from(*range.end())),
+                    }
+                } else {
+                    ScanTxOutRequest::Single(descriptor.to_string())
+                }
+            })
+            .collect::<Vec<_>>();
+        let result = self
+            .client
+            .scan_tx_out_set_blocking(&requests)
+            .map_err(|e| format!("Can't scan the UTXO set: {}", e))?;
+        if result.success == Some(false) {
+            return Err("The node aborted the UTXO set scan".to_string());
+        }
+        Ok(result
+            .unspents
+            .into_iter()
+            .map(|utxo| {
+                (
+                    OutPoint::new(utxo.txid, utxo.vout),
+                    TxOut {
+                        value: utxo.amount,
+                        script_pubkey: utxo.script_pub_key,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    /// The height of the node's best block.
+    pub fn tip_height(&self) -> Result<u32, String> {
+        self.client
+            .get_block_count()
+            .map(|height| height as u32)
+            .map_err(|e| format!("Can't get the block count: {}", e))
+    }
+
+    /// The hash of the block at `height` of the node's best chain.
+    pub fn block_hash(&self, height: u32) -> Result<BlockHash, String> {
+        self.client
+            .get_block_hash(height.into())
+            .map_err(|e| format!("Can't get the hash of block {}: {}", height, e))
+    }
+
+    /// Fetch the block `hash`.
+    pub fn block(&self, hash: &BlockHash) -> Result<Block, String> {
+        self.client
+            .get_block(hash)
+            .map_err(|e| format!("Can't fetch block {}: {}", hash, e))
+    }
+
+    /// Fetch the basic BIP158 compact filter of block `hash`.
+    pub fn block_filter(&self, hash: &BlockHash) -> Result<BlockFilter, String> {
+        if !self.filter_index {
+            return Err(
+                "The node doesn't serve compact block filters, restart it with -blockfilterindex"
+                    .to_string(),
+            );
+        }
+        self.client
+            .get_block_filter(hash)
+            .map(|result| result.to_filter())
+            .map_err(|e| format!("Can't fetch the filter of block {}: {}", hash, e))
+    }
+
+    /// Submit `tx` to the node's mempool.
+    pub fn broadcast(&self, tx: &Transaction) -> Result<Txid, String> {
+        self.client
+            .send_raw_transaction(tx)
+            .map_err(|e| format!("The node rejected {}: {}", tx.compute_txid(), e))
+    }
+}
@@ -0,0 +1,282 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Args, Subcommand};
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::{secp256k1, EcdsaSighashType, PrivateKey, Psbt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::hooks::{self, Lifecycle};
+use crate::wallet::data_dir;
+use crate::{broadcast, build, parse, rpc, secret, sign};
+
+#[derive(Subcommand)]
+pub enum ScheduleCommand {
+    /// Sign a PSBT now and hold it until a delay has passed, unless the
+    /// job is cancelled or checked in on first.
+    Add(AddArgs),
+    /// Restart the delay of a job, postponing its release.
+    CheckIn {
+        /// The job to postpone.
+        name: String,
+    },
+    /// Cancel a job, destroying its signatures.
+    Cancel {
+        /// The job to cancel.
+        name: String,
+    },
+    /// List the pending jobs and when they're released.
+    List,
+    /// Release jobs as they come due, until interrupted.
+    Run(RunArgs),
+}
+
+#[derive(Args)]
+pub struct AddArgs {
+    /// A name to refer to the job by.
+    name: String,
+    /// The base64 PSBT to sign and hold.
+    #[arg(value_parser = parse::psbt)]
+    psbt: Psbt,
+    /// How long to hold the signed PSBT, e.g. 30d.
+    #[arg(long, value_parser = parse::duration)]
+    delay: u64,
+    /// A private key to sign with. May be repeated.
+    #[arg(long = "key", value_parser = secret::parser(parse::private_key), required = true)]
+    keys: Vec<PrivateKey>,
+    /// Broadcast the transaction once released, rather than only
+    /// publishing it.
+    #[arg(long)]
+    broadcast: bool,
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// How often to look for due jobs.
+    #[arg(long, value_parser = parse::duration, default_value = "60s")]
+    interval: u64,
+    /// Release the jobs that are due and exit, e.g. when run from cron.
+    #[arg(long)]
+    once: bool,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+    #[command(flatten)]
+    backends: broadcast::BackendArgs,
+    #[command(flatten)]
+    hooks: hooks::HookArgs,
+}
+
+/// A signed PSBT waiting to be released.
+#[derive(Serialize, Deserialize)]
+struct Job {
+    name: String,
+    psbt: String,
+    /// The delay in seconds, restarted by each check-in.
+    delay: u64,
+    /// When the job is released, in seconds since the UNIX epoch.
+    release_at: u64,
+    broadcast: bool,
+}
+
+impl Job {
+    fn dir() -> PathBuf {
+        data_dir().join("schedule")
+    }
+
+    fn path(name: &str) -> PathBuf {
+        Self::dir().join(format!("{}.json", name))
+    }
+
+    fn load(name: &str) -> Result<Job, String> {
+        let path = Self::path(name);
+        let json = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read job {} at {}: {}", name, path.display(), e))?;
+        serde_json::from_str(&json)
+            .map_err(|e| format!("Job {} at {} is corrupt: {}", name, path.display(), e))
+    }
+
+    // Every pending job, the next to be released first.
+    fn load_all() -> Result<Vec<Job>, String> {
+        let dir = Self::dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let entries =
+            fs::read_dir(&dir).map_err(|e| format!("Can't read {}: {}", dir.display(), e))?;
+        let mut jobs = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| format!("Can't read {}: {}", dir.display(), e))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let name = path.file_stem().unwrap().to_string_lossy();
+                jobs.push(Self::load(&name)?);
+            }
+        }
+        jobs.sort_by_key(|job| job.release_at);
+        Ok(jobs)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = Self::path(&self.name);
+        fs::create_dir_all(Self::dir())
+            .map_err(|e| format!("Can't create {}: {}", Self::dir().display(), e))?;
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&path, json).map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+
+    fn remove(&self) -> Result<(), String> {
+        let path = Self::path(&self.name);
+        fs::remove_file(&path).map_err(|e| format!("Can't remove {}: {}", path.display(), e))
+    }
+}
+
+pub fn run(command: ScheduleCommand) -> Result<(), String> {
+    match command {
+        ScheduleCommand::Add(args) => add(args),
+        ScheduleCommand::CheckIn { name } => {
+            let mut job = Job::load(&name)?;
+            job.release_at = now() + job.delay;
+            job.save()?;
+            println!("{} is now released in {}", name, remaining(job.release_at));
+            Ok(())
+        }
+        ScheduleCommand::Cancel { name } => {
+            Job::load(&name)?.remove()?;
+            println!("Cancelled {}, its signatures are gone", name);
+            Ok(())
+        }
+        ScheduleCommand::List => {
+            for job in Job::load_all()? {
+                println!(
+                    "{}: released in {}{}",
+                    job.name,
+                    remaining(job.release_at),
+                    if job.broadcast { " and broadcast" } else { "" }
+                );
+            }
+            Ok(())
+        }
+        ScheduleCommand::Run(args) => run_due(args),
+    }
+}
+
+fn add(args: AddArgs) -> Result<(), String> {
+    if args.name.is_empty() || args.name.contains(['/', '\\']) {
+        return Err(format!("Invalid job name \"{}\"", args.name));
+    }
+    if Job::path(&args.name).exists() {
+        return Err(format!("Job {} already exists", args.name));
+    }
+    let secp = secp256k1::Secp256k1::new();
+    let mut psbt = args.psbt;
+    sign::sign_inputs(
+        &secp,
+        &mut psbt,
+        &args.keys,
+        EcdsaSighashType::All,
+        |_, _, _| {},
+    )
+    .map_err(|e| format!("Can't compute the sighash: {}", e))?;
+    let job = Job {
+        name: args.name,
+        psbt: psbt.to_string(),
+        delay: args.delay,
+        release_at: now() + args.delay,
+        broadcast: args.broadcast,
+    };
+    job.save()?;
+    println!(
+        "Signed and holding {}, released in {} unless checked in on or cancelled",
+        job.name,
+        remaining(job.release_at)
+    );
+    Ok(())
+}
+
+// Release every due job, then keep waiting for the next ones unless `once`.
+fn run_due(args: RunArgs) -> Result<(), String> {
+    loop {
+        for job in Job::load_all()? {
+            if job.release_at > now() {
+                break;
+            }
+            if let Err(e) = release(&job, &args) {
+                eprintln!("Can't release {}: {}", job.name, e);
+            }
+        }
+        if args.once {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(args.interval));
+    }
+}
+
+// Publish the job's transaction, or its PSBT if it still lacks signatures,
+// broadcasting the transaction if asked to. The job is removed once its
+// result is written next to it.
+fn release(job: &Job, args: &RunArgs) -> Result<(), String> {
+    let mut psbt = parse::psbt(&job.psbt)?;
+    let secp = secp256k1::Secp256k1::verification_only();
+    let (released, extension) = match build::finalize(&secp, &mut psbt) {
+        Ok(()) => {
+            let tx = psbt.extract_tx_unchecked_fee_rate();
+            let txid = tx.compute_txid().to_string();
+            args.hooks.fire(
+                Lifecycle::FullySigned,
+                &format!("Scheduled job {} released {}, fully signed", job.name, txid),
+                json!({ "job": job.name, "txid": txid, "tx": serialize_hex(&tx) }),
+            );
+            if job.broadcast {
+                println!(
+                    "{}: broadcast {}",
+                    job.name,
+                    args.backends.broadcast(&args.rpc, &tx)?
+                );
+                args.hooks.fire(
+                    Lifecycle::Broadcast,
+                    &format!("Scheduled job {} broadcast {}", job.name, txid),
+                    json!({ "job": job.name, "txid": txid }),
+                );
+            }
+            (serialize_hex(&tx), "tx")
+        }
+        Err(e) => {
+            eprintln!("{} can't be finalized, releasing the PSBT: {}", job.name, e);
+            args.hooks.fire(
+                Lifecycle::PsbtCreated,
+                &format!(
+                    "Scheduled job {} released a PSBT needing signatures",
+                    job.name
+                ),
+                json!({ "job": job.name, "psbt": psbt.to_string() }),
+            );
+            (psbt.to_string(), "psbt")
+        }
+    };
+    let path = Job::dir().join(format!("{}.{}", job.name, extension));
+    fs::write(&path, &released).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+    job.remove()?;
+    println!("{}: released to {}", job.name, path.display());
+    println!("{}", released);
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the clock is after 1970")
+        .as_secs()
+}
+
+// How long until `release_at`, roughly, for humans.
+fn remaining(release_at: u64) -> String {
+    match release_at.saturating_sub(now()) {
+        s if s < 60 * 60 => format!("{}m {}s", s / 60, s % 60),
+        s if s < 24 * 60 * 60 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
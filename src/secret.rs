@@ -0,0 +1,154 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::builder::TypedValueParser;
+use clap::error::ErrorKind;
+use clap::Arg;
+use miniscript::bitcoin::PrivateKey;
+
+use crate::parse;
+
+/// Whether secrets may be given literally on the command line.
+static ARGV_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Accept secrets given literally on the command line, before anything is
+/// parsed.
+pub fn allow_argv() {
+    ARGV_ALLOWED.store(true, Ordering::Relaxed);
+}
+
+/// A value parser for arguments carrying a secret, such as a private key or
+/// a mnemonic, read with [`read`] and then parsed with its function.
+#[derive(Clone)]
+pub struct Secret<T>(fn(&str) -> Result<T, String>);
+
+/// Parse a secret argument with `parse` once read.
+pub fn parser<T>(parse: fn(&str) -> Result<T, String>) -> Secret<T> {
+    Secret(parse)
+}
+
+/// Take a secret argument as it's read, e.g. a passphrase.
+pub fn string(s: &str) -> Result<String, String> {
+    Ok(s.to_string())
+}
+
+impl<T: Clone + Send + Sync + 'static> TypedValueParser for Secret<T> {
+    type Value = T;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&Arg>,
+        value: &OsStr,
+    ) -> Result<T, clap::Error> {
+        let name = match arg {
+            Some(arg) => match arg.get_long() {
+                Some(long) => format!("--{}", long),
+                None => format!("<{}>", arg.get_id().as_str().to_uppercase()),
+            },
+            None => "the secret".to_string(),
+        };
+        value
+            .to_str()
+            .ok_or_else(|| "not valid UTF-8".to_string())
+            .and_then(|value| read(&name, value))
+            .and_then(|value| (self.0)(&value))
+            .map_err(|e| {
+                clap::Error::raw(
+                    ErrorKind::InvalidValue,
+                    format!("invalid value for {}: {}\n", name, e),
+                )
+                .with_cmd(cmd)
+            })
+    }
+}
+
+/// Read the secret `value` of the argument `name` points to: the contents
+/// of the file of `file:<path>`, the environment variable of `env:<var>`,
+/// or what's typed at a hidden prompt for `-`.
+///
+/// Anything else is the secret itself, visible to other users in `ps` and
+/// kept in the shell history, so it's refused unless
+/// `--allow-insecure-argv` accepts it.
+pub fn read(name: &str, value: &str) -> Result<String, String> {
+    if value == "-" {
+        return prompt_hidden(&format!("{}: ", name));
+    }
+    if let Some(path) = value.strip_prefix("file:") {
+        return read_file(Path::new(path));
+    }
+    if let Some(var) = value.strip_prefix("env:") {
+        return read_env(var);
+    }
+    // A key sealed to the TPM is named, not given.
+    if value.starts_with("tpm:") || ARGV_ALLOWED.load(Ordering::Relaxed) {
+        return Ok(value.to_string());
+    }
+    Err(
+        "secrets on the command line show in ps and the shell history, pass file:<PATH>, \
+         env:<VAR> or - to be prompted for it, or --allow-insecure-argv"
+            .to_string(),
+    )
+}
+
+/// The private key in the file at `file` or the environment variable `env`,
+/// whichever is given.
+pub fn private_key(file: Option<&Path>, env: Option<&str>) -> Result<Option<PrivateKey>, String> {
+    let value = match (file, env) {
+        (Some(path), _) => read_file(path)?,
+        (None, Some(var)) => read_env(var)?,
+        (None, None) => return Ok(None),
+    };
+    parse::private_key(&value).map(Some)
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|contents| contents.trim().to_string())
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))
+}
+
+fn read_env(var: &str) -> Result<String, String> {
+    env::var(var).map_err(|_| format!("${} isn't set", var))
+}
+
+/// Prompt on the terminal itself, so a PSBT can still be piped in, with
+/// echo turned off for the answer.
+pub fn prompt_hidden(msg: &str) -> Result<String, String> {
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| format!("Can't prompt without a terminal: {}", e))?;
+    let stty = |setting: &str| -> Result<(), String> {
+        let tty = tty.try_clone().map_err(|e| e.to_string())?;
+        Command::new("stty")
+            .arg(setting)
+            .stdin(tty)
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| format!("Can't run stty: {}", e))
+            .and_then(|status| match status.success() {
+                true => Ok(()),
+                false => Err(format!("stty {} failed", setting)),
+            })
+    };
+    (&tty)
+        .write_all(msg.as_bytes())
+        .map_err(|e| e.to_string())?;
+    stty("-echo")?;
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+    stty("echo")?;
+    (&tty).write_all(b"\n").map_err(|e| e.to_string())?;
+    match read {
+        Ok(0) => Err("Prompt aborted".to_string()),
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,159 @@
+use clap::ValueEnum;
+use miniscript::bitcoin::{Amount, OutPoint};
+
+/// How many combinations branch and bound tries before giving up, as
+/// Bitcoin Core bounds it.
+const BNB_TRIES: u32 = 100_000;
+
+/// How to pick the coins a spend pays from, out of those it may spend.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Strategy {
+    /// The largest coins first, until they pay for the outputs and the
+    /// fee: the fewest inputs, usually with change.
+    LargestFirst,
+    /// Branch and bound: the coins adding up closest to the outputs and
+    /// the fee, by less than a change output would cost, so none is
+    /// needed. Falls back to the largest first when no combination does.
+    #[value(name = "bnb")]
+    BranchAndBound,
+}
+
+/// An override of the coins picked.
+#[derive(Clone, Copy)]
+pub enum CoinControl {
+    /// Always spend this output.
+    Include(OutPoint),
+    /// Never spend this output.
+    Exclude(OutPoint),
+}
+
+/// A coin that may be spent.
+pub struct Candidate {
+    /// What the coin is worth once the fee of spending it is paid, in sat;
+    /// negative when spending it costs more.
+    pub effective: i64,
+    /// Whether it's spent whatever the strategy picks.
+    pub required: bool,
+}
+
+/// The coins picked.
+pub struct Selection {
+    /// The indexes of the candidates picked, in order.
+    pub picked: Vec<usize>,
+    /// Whether they pay for the outputs and the fee without change, what
+    /// they're worth beyond that being less than a change output costs.
+    pub changeless: bool,
+}
+
+/// Pick coins out of `candidates`, the required ones and then as many as
+/// `strategy` needs for their effective values to reach `target`.
+/// Changeless selections may exceed it by up to `cost_of_change`, the fee
+/// of creating a change output and spending it later.
+pub fn select(
+    strategy: Strategy,
+    candidates: &[Candidate],
+    target: Amount,
+    cost_of_change: Amount,
+) -> Result<Selection, String> {
+    let target = target.to_sat() as i64;
+    let upper = target + cost_of_change.to_sat() as i64;
+    let mut picked: Vec<usize> = (0..candidates.len())
+        .filter(|&i| candidates[i].required)
+        .collect();
+    let mut value: i64 = picked.iter().map(|&i| candidates[i].effective).sum();
+    // Coins costing more to spend than they're worth only make it worse.
+    let mut pool: Vec<usize> = (0..candidates.len())
+        .filter(|&i| !candidates[i].required && candidates[i].effective > 0)
+        .collect();
+    pool.sort_by_key(|&i| std::cmp::Reverse(candidates[i].effective));
+
+    if let Strategy::BranchAndBound = strategy {
+        let values: Vec<i64> = pool.iter().map(|&i| candidates[i].effective).collect();
+        if let Some(chosen) = branch_and_bound(&values, value, target, upper) {
+            picked.extend(chosen.into_iter().map(|j| pool[j]));
+            picked.sort();
+            return Ok(Selection {
+                picked,
+                changeless: true,
+            });
+        }
+        eprintln!("No combination of coins avoids change, picking the largest first");
+    }
+    for i in pool {
+        if value >= target {
+            break;
+        }
+        picked.push(i);
+        value += candidates[i].effective;
+    }
+    if value < target {
+        return Err(format!(
+            "Insufficient funds: the coins are worth {} sat once the fees of spending them are \
+             paid, short of the {} sat needed",
+            value, target
+        ));
+    }
+    picked.sort();
+    Ok(Selection {
+        picked,
+        changeless: false,
+    })
+}
+
+// Search depth first, largest coins first, for the combination of `values`
+// that together with `base` lands in `target..=upper` closest to `target`,
+// returning the indexes of its values.
+fn branch_and_bound(values: &[i64], base: i64, target: i64, upper: i64) -> Option<Vec<usize>> {
+    // What the values from each index on add up to, to give up on branches
+    // that can't reach the target anymore.
+    let mut remaining = vec![0; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1] + values[i];
+    }
+    let mut search = Search {
+        values,
+        remaining,
+        target,
+        upper,
+        tries: 0,
+        chosen: Vec::new(),
+        best: None,
+    };
+    search.explore(0, base);
+    search.best.map(|(_, chosen)| chosen)
+}
+
+struct Search<'a> {
+    values: &'a [i64],
+    remaining: Vec<i64>,
+    target: i64,
+    upper: i64,
+    tries: u32,
+    chosen: Vec<usize>,
+    // The smallest excess over the target found, and its values.
+    best: Option<(i64, Vec<usize>)>,
+}
+
+impl Search<'_> {
+    fn explore(&mut self, i: usize, value: i64) {
+        self.tries += 1;
+        if self.tries > BNB_TRIES || value > self.upper {
+            return;
+        }
+        if value >= self.target {
+            // Adding more would only raise the excess.
+            let excess = value - self.target;
+            if self.best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                self.best = Some((excess, self.chosen.clone()));
+            }
+            return;
+        }
+        if i == self.values.len() || value + self.remaining[i] < self.target {
+            return;
+        }
+        self.chosen.push(i);
+        self.explore(i + 1, value + self.values[i]);
+        self.chosen.pop();
+        self.explore(i + 1, value);
+    }
+}
@@ -0,0 +1,328 @@
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use clap::Args;
+use miniscript::bitcoin::hashes::{sha256, Hash};
+use miniscript::bitcoin::{secp256k1, Address, Amount, Network, PrivateKey, Txid};
+use miniscript::descriptor::DefiniteDescriptorKey;
+use miniscript::Descriptor;
+
+/// How long a node started for the self-test has to answer RPCs.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// What each scenario funds its descriptor with.
+const FUNDING: Amount = Amount::from_sat(100_000);
+
+#[derive(Args)]
+pub struct SelftestArgs {
+    /// The bitcoind to start a throwaway regtest node with.
+    #[arg(long, default_value = "bitcoind")]
+    bitcoind: PathBuf,
+}
+
+/// A kind of descriptor the self-test funds and spends.
+#[derive(Clone, Copy, Debug)]
+pub enum Scenario {
+    /// A single key segwit v0 output.
+    Wpkh,
+    /// A 2-of-2 segwit v0 multisig.
+    WshMulti,
+    /// A key that can only spend 3 blocks after the coin confirms.
+    Timelock,
+    /// A taproot output spent with its internal key.
+    TaprootKeyPath,
+    /// A taproot output spent through the key on its only leaf.
+    TaprootScriptPath,
+}
+
+impl Scenario {
+    pub const ALL: [Scenario; 5] = [
+        Scenario::Wpkh,
+        Scenario::WshMulti,
+        Scenario::Timelock,
+        Scenario::TaprootKeyPath,
+        Scenario::TaprootScriptPath,
+    ];
+
+    // The descriptor over `pks`, and the indexes of the keys spending it.
+    fn descriptor(self, pks: &[String]) -> (String, Vec<usize>) {
+        match self {
+            Scenario::Wpkh => (format!("wpkh({})", pks[0]), vec![0]),
+            Scenario::WshMulti => (format!("wsh(multi(2,{},{}))", pks[0], pks[1]), vec![0, 1]),
+            Scenario::Timelock => (format!("wsh(and_v(v:pk({}),older(3)))", pks[0]), vec![0]),
+            Scenario::TaprootKeyPath => (format!("tr({})", pks[0]), vec![0]),
+            Scenario::TaprootScriptPath => (format!("tr({},pk({}))", pks[0], pks[1]), vec![1]),
+        }
+    }
+
+    // The confirmations the coin needs before it can be spent.
+    fn maturity(self) -> u64 {
+        match self {
+            Scenario::Timelock => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Drives the command line tool `exe` through a spend against a regtest
+/// node, the way a user would.
+pub struct Pipeline<'a> {
+    exe: &'a Path,
+    rpc_url: &'a str,
+    cookie: &'a Path,
+    /// The data directory of the tool, kept apart from the user's.
+    data_dir: PathBuf,
+    client: Client,
+}
+
+impl<'a> Pipeline<'a> {
+    /// Connect to the regtest node at `rpc_url`, whose wallet funds the
+    /// scenarios and receives what they spend. One is created if none is
+    /// loaded.
+    pub fn new(
+        exe: &'a Path,
+        rpc_url: &'a str,
+        cookie: &'a Path,
+        data_dir: PathBuf,
+    ) -> Result<Pipeline<'a>, String> {
+        let client = Client::new(rpc_url, Auth::CookieFile(cookie.to_path_buf()))
+            .map_err(|e| format!("Can't connect to {}: {}", rpc_url, e))?;
+        let wallets = client
+            .list_wallets()
+            .map_err(|e| format!("Can't list the node's wallets: {}", e))?;
+        if wallets.is_empty() {
+            client
+                .create_wallet("selftest", None, None, None, None)
+                .map_err(|e| format!("Can't create a wallet: {}", e))?;
+        }
+        Ok(Pipeline {
+            exe,
+            rpc_url,
+            cookie,
+            data_dir,
+            client,
+        })
+    }
+
+    /// Fund the scenario's descriptor from the node's wallet, then create,
+    /// sign, finalize and broadcast a spend of it with the tool, returning
+    /// the spend's txid once it confirms.
+    pub fn run(&self, scenario: Scenario) -> Result<Txid, String> {
+        let secp = secp256k1::Secp256k1::new();
+        let keys: Vec<PrivateKey> = (0..2u8)
+            .map(|i| {
+                let seed =
+                    sha256::Hash::hash(&[b"miniscript-psbt selftest".as_slice(), &[i]].concat());
+                secp256k1::SecretKey::from_slice(seed.as_byte_array())
+                    .map(|key| PrivateKey::new(key, Network::Regtest))
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<_, _>>()?;
+        let pks: Vec<String> = keys
+            .iter()
+            .map(|key| key.public_key(&secp).to_string())
+            .collect();
+        let (descriptor, signers) = scenario.descriptor(&pks);
+        let address = Descriptor::<DefiniteDescriptorKey>::from_str(&descriptor)
+            .and_then(|d| d.address(Network::Regtest))
+            .map_err(|e| format!("Can't derive the address of {}: {}", descriptor, e))?;
+
+        // Coinbases only mature after 100 blocks.
+        let miner = self.wallet_address()?;
+        if self.rpc(self.client.get_balance(None, None))? < FUNDING * 2 {
+            self.mine(101, &miner)?;
+        }
+        let funding = self.rpc(
+            self.client
+                .send_to_address(&address, FUNDING, None, None, None, None, None, None),
+        )?;
+        let funding_tx = self.rpc(self.client.get_raw_transaction_hex(&funding, None))?;
+        self.mine(1, &miner)?;
+
+        let amount = FUNDING.to_sat().to_string();
+        let recipient = miner.to_string();
+        let mut psbt = self.cli(
+            &[],
+            &[
+                "create",
+                &funding_tx,
+                &recipient,
+                &amount,
+                &descriptor,
+                "--subtract-fee",
+            ],
+        )?;
+        for &i in &signers {
+            let var = format!("MINISCRIPT_PSBT_SELFTEST_KEY_{}", i);
+            let key = format!("env:{}", var);
+            psbt = self.cli(
+                &[(&var, &keys[i].to_wif())],
+                &["sign", &psbt, "--key", &key],
+            )?;
+        }
+        let finalized = self.cli(&[], &["finalize", &psbt])?;
+
+        let cookie = self.cookie.display().to_string();
+        let broadcast = [
+            "broadcast",
+            &finalized,
+            "--rpc-url",
+            self.rpc_url,
+            "--rpc-cookie",
+            &cookie,
+        ];
+        if scenario.maturity() > 1 {
+            if self.cli(&[], &broadcast).is_ok() {
+                return Err(format!(
+                    "{:?}: the node accepted the spend before the timelock expired",
+                    scenario
+                ));
+            }
+            self.mine(scenario.maturity() - 1, &miner)?;
+        }
+        let out = self.cli(&[], &broadcast)?;
+        let txid = out
+            .strip_prefix("Broadcast ")
+            .and_then(|txid| Txid::from_str(txid).ok())
+            .ok_or_else(|| format!("Unexpected broadcast output: {}", out))?;
+        self.mine(1, &miner)?;
+        let confirmations = self
+            .rpc(self.client.get_transaction(&txid, None))?
+            .info
+            .confirmations;
+        if confirmations < 1 {
+            return Err(format!("{:?}: {} didn't confirm", scenario, txid));
+        }
+        Ok(txid)
+    }
+
+    fn wallet_address(&self) -> Result<Address, String> {
+        Ok(self
+            .rpc(self.client.get_new_address(None, None))?
+            .assume_checked())
+    }
+
+    fn mine(&self, blocks: u64, to: &Address) -> Result<(), String> {
+        self.rpc(self.client.generate_to_address(blocks, to))
+            .map(|_| ())
+    }
+
+    fn rpc<T>(&self, result: bitcoincore_rpc::Result<T>) -> Result<T, String> {
+        result.map_err(|e| format!("{}: {}", self.rpc_url, e))
+    }
+
+    // Run the tool with `args` and the environment variables `vars`,
+    // returning what it printed.
+    fn cli(&self, vars: &[(&str, &str)], args: &[&str]) -> Result<String, String> {
+        let output = Command::new(self.exe)
+            .args(["--network", "regtest"])
+            .args(args)
+            .env("MINISCRIPT_PSBT_DIR", &self.data_dir)
+            .envs(vars.iter().copied())
+            .output()
+            .map_err(|e| format!("Can't run {}: {}", self.exe.display(), e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "`{}` failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+/// A throwaway regtest node, stopped and deleted when dropped.
+struct Regtest {
+    process: Child,
+    dir: PathBuf,
+    url: String,
+    cookie: PathBuf,
+}
+
+impl Regtest {
+    fn start(bitcoind: &Path) -> Result<Regtest, String> {
+        let dir = env::temp_dir().join(format!("miniscript-psbt-selftest-{}", std::process::id()));
+        fs::create_dir_all(&dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map_err(|e| format!("Can't find a free port: {}", e))?
+            .port();
+        let process = Command::new(bitcoind)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", dir.display()))
+            .arg(format!("-rpcport={}", port))
+            .args(["-listen=0", "-fallbackfee=0.0001", "-printtoconsole=0"])
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Can't start {}: {}", bitcoind.display(), e))?;
+        let mut node = Regtest {
+            process,
+            url: format!("http://127.0.0.1:{}", port),
+            cookie: dir.join("regtest").join(".cookie"),
+            dir,
+        };
+        let started = Instant::now();
+        loop {
+            if let Ok(Some(status)) = node.process.try_wait() {
+                return Err(format!("bitcoind exited with {}", status));
+            }
+            let ready = node.cookie.exists()
+                && Client::new(&node.url, Auth::CookieFile(node.cookie.clone())).is_ok_and(
+                    |client| {
+                        // Any answer will do, whatever the node's version.
+                        client
+                            .call::<serde_json::Value>("getblockchaininfo", &[])
+                            .is_ok()
+                    },
+                );
+            if ready {
+                return Ok(node);
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                return Err("bitcoind didn't answer in time".to_string());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for Regtest {
+    fn drop(&mut self) {
+        let stopped = Client::new(&self.url, Auth::CookieFile(self.cookie.clone()))
+            .is_ok_and(|client| client.stop().is_ok());
+        if !stopped {
+            let _ = self.process.kill();
+        }
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Start a regtest node and run every scenario against it with this
+/// executable.
+pub fn run(args: SelftestArgs) -> Result<(), String> {
+    let node = Regtest::start(&args.bitcoind)?;
+    let exe = env::current_exe().map_err(|e| format!("Can't find this executable: {}", e))?;
+    let pipeline = Pipeline::new(&exe, &node.url, &node.cookie, node.dir.join("cli"))?;
+    let mut failed = 0;
+    for scenario in Scenario::ALL {
+        match pipeline.run(scenario) {
+            Ok(txid) => println!("{:?}: ok, {} confirmed", scenario, txid),
+            Err(e) => {
+                println!("{:?}: FAILED: {}", scenario, e);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} of {} scenarios failed", n, Scenario::ALL.len())),
+    }
+}
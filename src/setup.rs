@@ -0,0 +1,179 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use miniscript::bitcoin::NetworkKind;
+use miniscript::descriptor::{DescriptorPublicKey, DescriptorSecretKey, Wildcard};
+use miniscript::Descriptor;
+
+use crate::wallet::{Profile, Signer};
+use crate::{network, qr};
+
+/// Walk through collecting the cosigners' keys, choosing a policy and
+/// verifying the first address before saving the wallet profile.
+pub fn run() -> Result<(), String> {
+    println!("This wizard creates a multisig wallet profile.\n");
+    let name = prompt("Wallet name: ")?;
+    if name.is_empty() || name.contains(['/', '\\']) {
+        return Err(format!("Invalid wallet name \"{}\"", name));
+    }
+    if Profile::path(&name).exists() {
+        return Err(format!("Wallet {} already exists", name));
+    }
+
+    let count = prompt_number("Number of cosigners: ", 1, 20)?;
+    let mut signers = Vec::new();
+    for i in 1..=count {
+        signers.push(collect_signer(&format!("Cosigner {}", i))?);
+    }
+
+    println!("\nPolicy templates:");
+    println!("  1) k-of-n multisig");
+    println!("  2) k-of-n multisig with a timelocked recovery key");
+    let template = prompt_number("Template: ", 1, 2)?;
+    let threshold = prompt_number(&format!("Signatures required (1-{}): ", count), 1, count)?;
+    let keys: Vec<&str> = signers.iter().map(|s| s.key.as_str()).collect();
+
+    let descriptor = match template {
+        1 => format!("wsh(sortedmulti({},{}))", threshold, keys.join(",")),
+        _ => {
+            let recovery = collect_signer("Recovery key")?;
+            let blocks = prompt_number(
+                "Blocks until the recovery key can spend alone (1-65535): ",
+                1,
+                65535,
+            )?;
+            let descriptor = format!(
+                "wsh(or_d(multi({},{}),and_v(v:pk({}),older({}))))",
+                threshold,
+                keys.join(","),
+                recovery.key,
+                blocks
+            );
+            signers.push(recovery);
+            descriptor
+        }
+    };
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&descriptor)
+        .map_err(|e| format!("Can't build the descriptor: {}", e))?;
+    descriptor
+        .sanity_check()
+        .map_err(|e| format!("Descriptor fails sanity check: {}", e))?;
+    println!("\nDescriptor: {}", descriptor);
+
+    let address = descriptor
+        .at_derivation_index(0)
+        .map_err(|e| format!("Can't derive an address: {}", e))?
+        .address(network())
+        .map_err(|e| format!("Can't derive an address: {}", e))?;
+    println!("\nFirst receive address: {}", address);
+    println!("Register the descriptor on every signing device and compare this address.");
+    for signer in &signers {
+        if !confirm(&format!(
+            "Does {}'s device show the same address? [y/N] ",
+            signer.name
+        ))? {
+            return Err(format!(
+                "{} doesn't confirm the address, the wallet was not saved",
+                signer.name
+            ));
+        }
+    }
+
+    let profile = Profile {
+        name,
+        network: network().to_string(),
+        descriptor: descriptor.to_string(),
+        signers,
+    };
+    let path = profile.save()?;
+    println!("\nSaved wallet {} to {}", profile.name, path.display());
+    Ok(())
+}
+
+fn collect_signer(label: &str) -> Result<Signer, String> {
+    println!("\n{}", label);
+    let name = prompt("  Name: ")?;
+    loop {
+        let source = prompt("  Key from [p]aste, [f]ile or [q]r image: ")?;
+        let key = match source.as_str() {
+            "p" | "paste" => prompt("  Extended public key: "),
+            "f" | "file" => {
+                let path = prompt("  File: ")?;
+                fs::read_to_string(&path)
+                    .map(|s| s.trim().to_string())
+                    .map_err(|e| format!("Can't read {}: {}", path, e))
+            }
+            "q" | "qr" => {
+                let path = prompt("  Image: ")?;
+                qr::decode_image(Path::new(&path)).map(|codes| codes.concat())
+            }
+            _ => continue,
+        };
+        match key.and_then(|key| parse_signer_key(&key)) {
+            Ok(key) => return Ok(Signer { name, key }),
+            Err(e) => println!("  {}", e),
+        }
+    }
+}
+
+// Check a cosigner's key and make it ranged over the receive chain if it
+// isn't already.
+fn parse_signer_key(s: &str) -> Result<String, String> {
+    if DescriptorSecretKey::from_str(s).is_ok() {
+        return Err(
+            "That's a private key, keep it secret and paste the extended public key instead"
+                .to_string(),
+        );
+    }
+    let key = DescriptorPublicKey::from_str(s).map_err(|e| format!("Not a valid key: {}", e))?;
+    let xpub = match &key {
+        DescriptorPublicKey::XPub(xpub) => xpub,
+        DescriptorPublicKey::Single(_) => {
+            return Err("Expected an extended public key, not a single key".to_string())
+        }
+        DescriptorPublicKey::MultiXPub(_) => {
+            return Err("Expected an extended public key with a single path".to_string())
+        }
+    };
+    if xpub.xkey.network != network().into() {
+        let kind = match xpub.xkey.network {
+            NetworkKind::Main => "mainnet",
+            NetworkKind::Test => "a test network",
+        };
+        return Err(format!(
+            "Key is for {}, expected a key for {}",
+            kind,
+            network()
+        ));
+    }
+    Ok(match xpub.wildcard {
+        Wildcard::None => format!("{}/0/*", key),
+        _ => key.to_string(),
+    })
+}
+
+fn prompt(msg: &str) -> Result<String, String> {
+    print!("{}", msg);
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Err("Setup aborted".to_string()),
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn prompt_number(msg: &str, min: u32, max: u32) -> Result<u32, String> {
+    loop {
+        match prompt(msg)?.parse() {
+            Ok(n) if (min..=max).contains(&n) => return Ok(n),
+            _ => println!("Enter a number between {} and {}", min, max),
+        }
+    }
+}
+
+fn confirm(msg: &str) -> Result<bool, String> {
+    Ok(matches!(prompt(msg)?.to_lowercase().as_str(), "y" | "yes"))
+}
@@ -0,0 +1,924 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::Args;
+use miniscript::bitcoin::bip32::{DerivationPath, Xpriv};
+use miniscript::bitcoin::blockdata::script::Instruction;
+use miniscript::bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+use miniscript::bitcoin::hex::DisplayHex;
+use miniscript::bitcoin::key::TapTweak;
+use miniscript::bitcoin::psbt::Input;
+use miniscript::bitcoin::sighash::SighashCache;
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::{self, secp256k1, PrivateKey, ScriptBuf, Transaction, TxOut};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::psbt::{PsbtInputExt, SighashError};
+use miniscript::Descriptor;
+
+use crate::error::Error;
+use crate::guard::SigningPolicy;
+use crate::{annex, bip39, hwi, network, parse, secret, slip39, stage, yubihsm};
+
+/// Whether ECDSA signatures are ground to a low R value.
+static LOW_R: AtomicBool = AtomicBool::new(false);
+
+/// Grind the ECDSA signatures made from now on to a low R value, as Bitcoin
+/// Core does, so they're never longer than 71 bytes, and estimate fees for
+/// that size.
+pub fn grind_low_r() {
+    LOW_R.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`grind_low_r`] was called.
+pub fn low_r() -> bool {
+    LOW_R.load(Ordering::Relaxed)
+}
+
+#[derive(Args)]
+pub struct SignArgs {
+    #[command(flatten)]
+    psbt: stage::PsbtArg,
+    #[command(flatten)]
+    out: stage::PsbtOut,
+    /// A private key to sign with, as `file:<PATH>`, `env:<VAR>` or `-` to
+    /// be prompted for it, or `tpm:<name>` for one sealed to the TPM. May be
+    /// repeated.
+    #[arg(long = "key", value_parser = secret::parser(parse::private_key))]
+    keys: Vec<PrivateKey>,
+    /// An extended private key, the master key or one of the descriptor's,
+    /// to derive the keys the inputs' BIP32 derivations name. May be
+    /// repeated.
+    #[arg(long = "xpriv", value_parser = secret::parser(parse::xpriv))]
+    xprivs: Vec<Xpriv>,
+    /// Recover the master key from SLIP-39 shares entered interactively.
+    #[arg(long)]
+    slip39: bool,
+    #[command(flatten)]
+    mnemonic: bip39::MnemonicArgs,
+    #[command(flatten)]
+    sighashes: SighashArgs,
+    /// Sign despite an unusual sighash type or output.
+    #[arg(long)]
+    allow_risky: bool,
+    /// Refuse to sign unless the transaction keeps to the signing policy
+    /// in this JSON or TOML file, whose optional keys are `max_amount`,
+    /// `destinations`, `change`, `min_fee`, `max_fee` and `sighashes`.
+    /// Sighash types other than ALL need both the policy's `sighashes` and
+    /// --allow-risky.
+    #[arg(long, value_name = "PATH")]
+    policy: Option<PathBuf>,
+    /// Only sign the inputs spending this descriptor, filling in the
+    /// scripts and key origins it gives them. For PSBTs from another
+    /// coordinator, such as Bitcoin Core's walletcreatefundedpsbt, which
+    /// leaves them out for descriptors it doesn't know. May be repeated.
+    #[arg(long = "descriptor", value_parser = parse::ranged_descriptor)]
+    descriptors: Vec<Descriptor<DescriptorPublicKey>>,
+    /// The derivation indexes to match inputs against, for descriptors with
+    /// wildcards.
+    #[arg(long, value_parser = parse::range, default_value = "0-999", requires = "descriptors")]
+    range: RangeInclusive<u32>,
+    /// A 32 byte hex preimage to reveal, for inputs whose scripts have a
+    /// sha256(), hash256(), ripemd160() or hash160() of it. May be
+    /// repeated.
+    #[arg(long = "preimage", value_parser = parse::preimage)]
+    preimages: Vec<Vec<u8>>,
+    /// A raw transaction creating an output the PSBT spends, to check the
+    /// input's witness UTXO against when the PSBT doesn't carry it as its
+    /// non-witness UTXO. Every input but taproot ones needs one. May be
+    /// repeated.
+    #[arg(long = "prev-tx", value_parser = parse::transaction)]
+    prev_txs: Vec<Transaction>,
+    #[command(flatten)]
+    yubihsm: yubihsm::YubiHsmArgs,
+    #[command(flatten)]
+    hwi: hwi::HwiArgs,
+}
+
+/// The sighash types to sign with, for every input or one at a time.
+#[derive(Args)]
+pub struct SighashArgs {
+    /// The sighash type to sign with.
+    #[arg(long, value_parser = parse::sighash_type, default_value = "all")]
+    sighash: bitcoin::EcdsaSighashType,
+    /// The sighash type to sign one input with instead, as `<index>:<type>`,
+    /// e.g. `0:single|anyonecanpay` to let others add inputs and outputs
+    /// but keep the input's own output. May be repeated.
+    #[arg(long = "input-sighash", value_parser = parse::input_sighash)]
+    input_sighashes: Vec<(usize, bitcoin::EcdsaSighashType)>,
+}
+
+impl SighashArgs {
+    /// The sighash type of each input of `psbt`.
+    ///
+    /// Fails if an index is out of range, or an input would sign with
+    /// SIGHASH_SINGLE without an output at its index: for segwit that
+    /// commits to no output at all, letting anyone take the coins.
+    pub fn resolve(&self, psbt: &Psbt) -> Result<Vec<bitcoin::EcdsaSighashType>, String> {
+        let mut hash_tys = vec![self.sighash; psbt.inputs.len()];
+        for &(i, hash_ty) in &self.input_sighashes {
+            *hash_tys.get_mut(i).ok_or_else(|| {
+                format!(
+                    "--input-sighash names input {} but there are only {}",
+                    i,
+                    psbt.inputs.len()
+                )
+            })? = hash_ty;
+        }
+        let outputs = psbt.unsigned_tx.output.len();
+        let orphaned: Vec<String> = hash_tys
+            .iter()
+            .enumerate()
+            .filter(|(i, hash_ty)| {
+                use bitcoin::EcdsaSighashType::{Single, SinglePlusAnyoneCanPay};
+                matches!(hash_ty, Single | SinglePlusAnyoneCanPay) && *i >= outputs
+            })
+            .map(|(i, _)| i.to_string())
+            .collect();
+        if !orphaned.is_empty() {
+            return Err(format!(
+                "SIGHASH_SINGLE needs an output at the input's index, the transaction has {} \
+                 outputs but these inputs would sign with it: {}",
+                outputs,
+                orphaned.join(", ")
+            ));
+        }
+        Ok(hash_tys)
+    }
+}
+
+/// Add the signatures of every key given or derived to the PSBT and print
+/// it.
+pub fn run(args: SignArgs) -> Result<(), Error> {
+    let secp = secp256k1::Secp256k1::new();
+    let mut psbt = args.psbt.load()?;
+    let policy = args
+        .policy
+        .as_deref()
+        .map(SigningPolicy::load)
+        .transpose()?;
+    let mut xprivs = args.xprivs;
+    xprivs.extend(args.mnemonic.master()?);
+    if args.slip39 {
+        let secret = slip39::recover_interactively()?;
+        xprivs.push(
+            Xpriv::new_master(network(), &secret)
+                .map_err(|e| format!("Can't derive the master key: {}", e))?,
+        );
+    }
+    let selected = if args.descriptors.is_empty() {
+        // Finalized inputs, such as the challenge of a proof of reserves,
+        // are signed already.
+        (0..psbt.inputs.len())
+            .filter(|&i| {
+                psbt.inputs[i].final_script_sig.is_none()
+                    && psbt.inputs[i].final_script_witness.is_none()
+            })
+            .collect()
+    } else {
+        let selected = match_descriptors(&mut psbt, &args.descriptors, &args.range)
+            .map_err(Error::Descriptor)?;
+        if selected.is_empty() {
+            return Err(Error::Descriptor(
+                "None of the inputs spend the descriptors".to_string(),
+            ));
+        }
+        selected
+    };
+    let mut keys = args.keys;
+    for key in derive_keys(&secp, &psbt, &xprivs)? {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    add_preimages(&mut psbt, &args.preimages)?;
+    let hsm = args.yubihsm.open()?;
+    if keys.is_empty() && hsm.is_none() && !args.hwi.enabled() && args.preimages.is_empty() {
+        return Err(if xprivs.is_empty() {
+            "Pass --key, --xpriv, --mnemonic, --slip39, --yubihsm-key or --hww to sign with".into()
+        } else {
+            "None of the inputs' BIP32 derivations come from the extended keys".into()
+        });
+    }
+
+    check_utxos(&psbt, &args.prev_txs)?;
+    let hash_tys = args.sighashes.resolve(&psbt)?;
+    if let Some(policy) = &policy {
+        policy.check(&psbt, &hash_tys, &args.descriptors, &args.prev_txs)?;
+    }
+    let risks = risks(&psbt, &hash_tys);
+    if !risks.is_empty() && !args.allow_risky {
+        return Err(Error::Other(format!(
+            "Refusing to sign, pass --allow-risky to go ahead:\n  {}",
+            risks.join("\n  ")
+        )));
+    }
+    sign_with_types(&secp, &mut psbt, &selected, &keys, &hash_tys, |_, i, pk| {
+        eprintln!("Signed input {} with {}", i, pk)
+    })
+    .map_err(|e| Error::Signature(format!("Can't compute the sighash: {}", e)))?;
+    if let Some(hsm) = hsm {
+        match hsm.sign_psbt(&secp, &mut psbt, &hash_tys)? {
+            0 => eprintln!("No input is derived from {} on the HSM", hsm.public_key()),
+            n => eprintln!("Signed {} inputs with {} on the HSM", n, hsm.public_key()),
+        }
+    }
+    if args.hwi.enabled() {
+        let added = args.hwi.sign(&mut psbt)?;
+        if added.is_empty() {
+            eprintln!("WARNING: The hardware wallet added no signatures");
+        }
+        for (i, pk) in added {
+            eprintln!("Signed input {} with {} on the hardware wallet", i, pk);
+        }
+    }
+    Ok(args.out.store(psbt)?)
+}
+
+/// Find the inputs of `psbt` spending one of `descriptors` at an index in
+/// `range`, and update each with the descriptor's scripts and key origins,
+/// returning their indexes.
+///
+/// An input's `non_witness_utxo`, whose txid the input commits to, takes
+/// precedence over a `witness_utxo` claiming a different output.
+pub fn match_descriptors(
+    psbt: &mut Psbt,
+    descriptors: &[Descriptor<DescriptorPublicKey>],
+    range: &RangeInclusive<u32>,
+) -> Result<Vec<usize>, String> {
+    let mut derived: HashMap<ScriptBuf, Descriptor<DefiniteDescriptorKey>> = HashMap::new();
+    for descriptor in descriptors {
+        let singles = descriptor
+            .clone()
+            .into_single_descriptors()
+            .map_err(|e| format!("Can't split {}: {}", descriptor, e))?;
+        for single in singles {
+            for index in range.clone() {
+                let definite = single
+                    .at_derivation_index(index)
+                    .map_err(|e| format!("Can't derive index {}: {}", index, e))?;
+                derived.insert(definite.script_pubkey(), definite);
+                if !single.has_wildcard() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut selected = Vec::new();
+    for (i, (txin, input)) in psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .zip(&mut psbt.inputs)
+        .enumerate()
+    {
+        let prevout = txin.previous_output;
+        let spent: Option<TxOut> = match &input.non_witness_utxo {
+            Some(tx) => {
+                if tx.compute_txid() != prevout.txid {
+                    return Err(format!(
+                        "Input {}: non-witness UTXO is {}, not the spent {}",
+                        i,
+                        tx.compute_txid(),
+                        prevout.txid
+                    ));
+                }
+                let spent = tx
+                    .output
+                    .get(prevout.vout as usize)
+                    .cloned()
+                    .ok_or_else(|| format!("Input {}: {} has no such output", i, prevout))?;
+                if input
+                    .witness_utxo
+                    .as_ref()
+                    .is_some_and(|utxo| *utxo != spent)
+                {
+                    return Err(format!(
+                        "Input {}: witness UTXO doesn't match {} of the non-witness UTXO",
+                        i, prevout
+                    ));
+                }
+                Some(spent)
+            }
+            None => input.witness_utxo.clone(),
+        };
+        let Some(spent) = spent else {
+            continue;
+        };
+        let Some(descriptor) = derived.get(&spent.script_pubkey) else {
+            continue;
+        };
+        input
+            .update_with_descriptor_unchecked(descriptor)
+            .map_err(|e| format!("Input {}: can't update it with the descriptor: {}", i, e))?;
+        if descriptor.desc_type().segwit_version().is_some() {
+            input.witness_utxo = Some(spent);
+        }
+        selected.push(i);
+    }
+    Ok(selected)
+}
+
+/// Compute the sighash message of every input of `psbt` into `msgs`,
+/// committing to the annex of inputs that have one.
+///
+/// Both the cache and the buffer are owned by the caller so they can be
+/// reused across inputs and repeated invocations without reallocating.
+pub fn sighash_msgs<T: Borrow<Transaction>>(
+    psbt: &Psbt,
+    cache: &mut SighashCache<T>,
+    msgs: &mut Vec<secp256k1::Message>,
+) -> Result<(), SighashError> {
+    msgs.clear();
+    msgs.reserve(psbt.inputs.len());
+    for i in 0..psbt.inputs.len() {
+        msgs.push(annex::sighash_msg(psbt, i, cache, None)?);
+    }
+    Ok(())
+}
+
+/// Check that every input's UTXO matches the output it spends in the
+/// previous transaction, taken from `prev_txs` or the input's
+/// `non_witness_utxo`. Taproot inputs can do without it, and finalized
+/// ones are signed already.
+///
+/// The sighash commits to the spent value and script, so a coordinator
+/// inflating or swapping a `witness_utxo` could otherwise trick us into
+/// signing away more than we think.
+pub fn check_utxos(psbt: &Psbt, prev_txs: &[Transaction]) -> Result<(), Error> {
+    for (i, (txin, input)) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs).enumerate() {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        let prevout = txin.previous_output;
+        let spent = verified_utxo(psbt, i, prev_txs)
+            .map_err(|e| Error::UtxoMismatch(format!("{}, pass it with --prev-tx", e)))?;
+        if let Some(utxo) = &input.witness_utxo {
+            if utxo.value != spent.value {
+                return Err(Error::UtxoMismatch(format!(
+                    "Input {}: witness UTXO claims {} sat but {} is worth {} sat",
+                    i,
+                    utxo.value.to_sat(),
+                    prevout,
+                    spent.value.to_sat()
+                )));
+            }
+            if utxo.script_pubkey != spent.script_pubkey {
+                return Err(Error::UtxoMismatch(format!(
+                    "Input {}: witness UTXO script {} doesn't match {} of {}",
+                    i, utxo.script_pubkey, spent.script_pubkey, prevout
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The output input `i` of `psbt` spends, as far as it can be trusted: a
+/// taproot input's `witness_utxo`, which its signature commits to along
+/// with every other input's, or else the output of the previous
+/// transaction, from `prev_txs` or the input's `non_witness_utxo`, whose
+/// txid is checked against the outpoint.
+///
+/// Segwit v0 signatures only commit to their own input's value, so a
+/// coordinator understating the others' could hide a fee.
+pub fn verified_utxo(psbt: &Psbt, i: usize, prev_txs: &[Transaction]) -> Result<TxOut, String> {
+    let input = &psbt.inputs[i];
+    let prevout = psbt.unsigned_tx.input[i].previous_output;
+    let prev_tx = prev_txs
+        .iter()
+        .chain(&input.non_witness_utxo)
+        .find(|tx| tx.compute_txid() == prevout.txid);
+    match (prev_tx, &input.witness_utxo) {
+        (Some(prev_tx), _) => prev_tx
+            .output
+            .get(prevout.vout as usize)
+            .cloned()
+            .ok_or_else(|| format!("Input {}: {} has no such output", i, prevout)),
+        (None, Some(utxo)) if utxo.script_pubkey.is_p2tr() => Ok(utxo.clone()),
+        (None, _) if input.non_witness_utxo.is_some() => Err(format!(
+            "Input {}: its non-witness UTXO isn't the transaction {}",
+            i, prevout.txid
+        )),
+        (None, _) => Err(format!(
+            "Input {}: previous transaction {} unknown",
+            i, prevout.txid
+        )),
+    }
+}
+
+/// List the reasons signing `psbt`'s inputs with `hash_tys`, one per input,
+/// deserves a second look: a sighash type other than ALL, one conflicting
+/// with an input's requested type, no input committing to every output, or
+/// an output paying a witness version we can't know the rules of.
+pub fn risks(psbt: &Psbt, hash_tys: &[bitcoin::EcdsaSighashType]) -> Vec<String> {
+    let mut risks = Vec::new();
+    let mut unusual: Vec<(bitcoin::EcdsaSighashType, Vec<String>)> = Vec::new();
+    for (i, hash_ty) in hash_tys.iter().enumerate() {
+        if *hash_ty == bitcoin::EcdsaSighashType::All {
+            continue;
+        }
+        match unusual.iter_mut().find(|(ty, _)| ty == hash_ty) {
+            Some((_, inputs)) => inputs.push(i.to_string()),
+            None => unusual.push((*hash_ty, vec![i.to_string()])),
+        }
+    }
+    for (hash_ty, inputs) in unusual {
+        let which = if inputs.len() == hash_tys.len() {
+            "every input".to_string()
+        } else {
+            format!("input {}", inputs.join(", "))
+        };
+        risks.push(format!(
+            "signing {} with {} lets others change parts of the transaction",
+            which, hash_ty
+        ));
+    }
+    let commits_to_outputs = |hash_ty: &bitcoin::EcdsaSighashType| {
+        use bitcoin::EcdsaSighashType::{All, AllPlusAnyoneCanPay};
+        matches!(hash_ty, All | AllPlusAnyoneCanPay)
+    };
+    if !hash_tys.is_empty() && !hash_tys.iter().any(commits_to_outputs) {
+        risks.push(
+            "no input commits to every output, others can add outputs taking the rest of the \
+             inputs"
+                .to_string(),
+        );
+    }
+    for (i, (input, hash_ty)) in psbt.inputs.iter().zip(hash_tys).enumerate() {
+        if let Some(ty) = input.sighash_type.filter(|ty| *ty != (*hash_ty).into()) {
+            risks.push(format!(
+                "input {} asks for {} but we would sign with {}",
+                i, ty, hash_ty
+            ));
+        }
+    }
+    for (i, output) in psbt.unsigned_tx.output.iter().enumerate() {
+        let spk = &output.script_pubkey;
+        let known = match spk.witness_version() {
+            Some(bitcoin::WitnessVersion::V0) => true,
+            Some(bitcoin::WitnessVersion::V1) => spk.is_p2tr(),
+            Some(_) => false,
+            None => true,
+        };
+        if !known {
+            risks.push(format!(
+                "output {} pays an unknown witness program {}, it may be spendable by anyone or no one",
+                i, spk
+            ));
+        }
+    }
+    risks
+}
+
+/// Sign every input of `psbt` with each of `keys` and add the resulting
+/// partial signatures, calling `on_signature` after each one is added.
+/// Each input's sighash type is set to `hash_ty`.
+///
+/// Taproot inputs get Schnorr signatures: on the key path for the key that
+/// is the input's internal key, or whose BIP86 tweak is the output key of an
+/// input without scripts, and on every leaf a key appears in.
+///
+/// Sighashes are computed once per input and shared between all keys, and
+/// public keys are derived once up front rather than per input.
+pub fn sign_inputs<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    psbt: &mut Psbt,
+    keys: &[PrivateKey],
+    hash_ty: bitcoin::EcdsaSighashType,
+    on_signature: impl FnMut(&Psbt, usize, &bitcoin::PublicKey),
+) -> Result<(), SighashError> {
+    let all: Vec<usize> = (0..psbt.inputs.len()).collect();
+    sign_selected_inputs(secp, psbt, &all, keys, hash_ty, on_signature)
+}
+
+/// Like [`sign_inputs`], but only sign and set the sighash type of the
+/// inputs at the indexes in `selected`, leaving the others untouched.
+pub fn sign_selected_inputs<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    psbt: &mut Psbt,
+    selected: &[usize],
+    keys: &[PrivateKey],
+    hash_ty: bitcoin::EcdsaSighashType,
+    mut on_signature: impl FnMut(&Psbt, usize, &bitcoin::PublicKey),
+) -> Result<(), SighashError> {
+    let pubkeys: Vec<bitcoin::PublicKey> = keys.iter().map(|k| k.public_key(secp)).collect();
+
+    for &i in selected {
+        psbt.inputs[i].sighash_type = Some(hash_ty.into());
+    }
+    let mut cache = SighashCache::new(psbt.unsigned_tx.clone());
+    let mut msgs = Vec::new();
+    if selected.len() == psbt.inputs.len() {
+        sighash_msgs(psbt, &mut cache, &mut msgs)?;
+    } else {
+        for &i in selected {
+            msgs.push(annex::sighash_msg(psbt, i, &mut cache, None)?);
+        }
+    }
+
+    for (&i, msg) in selected.iter().zip(&msgs) {
+        let is_taproot = psbt.inputs[i]
+            .witness_utxo
+            .as_ref()
+            .is_some_and(|utxo| utxo.script_pubkey.is_p2tr());
+        for (key, pk) in keys.iter().zip(&pubkeys) {
+            if is_taproot {
+                sign_taproot(secp, psbt, i, key, msg, &mut cache, hash_ty.into())?;
+            } else {
+                // Keys the input's derivations don't name, such as those
+                // of other inputs, have nothing to sign here.
+                let derivations = &psbt.inputs[i].bip32_derivation;
+                if !derivations.is_empty() && !derivations.contains_key(&pk.inner) {
+                    continue;
+                }
+                let signature = match low_r() {
+                    true => secp.sign_ecdsa_low_r(msg, &key.inner),
+                    false => secp.sign_ecdsa(msg, &key.inner),
+                };
+                assert!(secp.verify_ecdsa(msg, &signature, &pk.inner).is_ok());
+                psbt.inputs[i].partial_sigs.insert(
+                    *pk,
+                    bitcoin::ecdsa::Signature {
+                        signature,
+                        sighash_type: hash_ty,
+                    },
+                );
+            }
+            on_signature(psbt, i, pk);
+        }
+    }
+    Ok(())
+}
+
+/// Like [`sign_selected_inputs`], but sign each input with its own
+/// sighash type from `hash_tys`, one per input of `psbt`.
+pub fn sign_with_types<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    psbt: &mut Psbt,
+    selected: &[usize],
+    keys: &[PrivateKey],
+    hash_tys: &[bitcoin::EcdsaSighashType],
+    mut on_signature: impl FnMut(&Psbt, usize, &bitcoin::PublicKey),
+) -> Result<(), SighashError> {
+    let mut types: Vec<bitcoin::EcdsaSighashType> = Vec::new();
+    for &i in selected {
+        if !types.contains(&hash_tys[i]) {
+            types.push(hash_tys[i]);
+        }
+    }
+    for hash_ty in types {
+        let group: Vec<usize> = selected
+            .iter()
+            .copied()
+            .filter(|&i| hash_tys[i] == hash_ty)
+            .collect();
+        sign_selected_inputs(secp, psbt, &group, keys, hash_ty, &mut on_signature)?;
+    }
+    Ok(())
+}
+
+/// Drop the signatures of `input` made by keys other than `keys`, so it's
+/// only satisfied with theirs, returning those of `keys` that didn't sign.
+pub fn keep_signatures(input: &mut Input, keys: &[bitcoin::PublicKey]) -> Vec<bitcoin::PublicKey> {
+    let xonly: Vec<secp256k1::XOnlyPublicKey> = keys
+        .iter()
+        .map(|pk| pk.inner.x_only_public_key().0)
+        .collect();
+    input.partial_sigs.retain(|pk, _| keys.contains(pk));
+    input
+        .tap_script_sigs
+        .retain(|(pk, _), _| xonly.contains(pk));
+    if !input.tap_internal_key.is_some_and(|pk| xonly.contains(&pk)) {
+        input.tap_key_sig = None;
+    }
+    keys.iter()
+        .zip(&xonly)
+        .filter(|(pk, xonly)| {
+            let signed = input.partial_sigs.contains_key(pk)
+                || input
+                    .tap_script_sigs
+                    .keys()
+                    .any(|(signer, _)| signer == *xonly)
+                || (input.tap_key_sig.is_some() && input.tap_internal_key == Some(**xonly));
+            !signed
+        })
+        .map(|(pk, _)| *pk)
+        .collect()
+}
+
+// Add `key`'s Schnorr signatures to taproot input `i`, with `key_path_msg`
+// being the input's key path sighash.
+fn sign_taproot<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    psbt: &mut Psbt,
+    i: usize,
+    key: &PrivateKey,
+    key_path_msg: &secp256k1::Message,
+    cache: &mut SighashCache<Transaction>,
+    sighash_type: bitcoin::TapSighashType,
+) -> Result<(), SighashError> {
+    let keypair = secp256k1::Keypair::from_secret_key(secp, &key.inner);
+    let (xonly, _) = keypair.x_only_public_key();
+
+    // A coordinator may leave out the internal key of a key path only
+    // output, which is then ours if tweaking our key without a script tree
+    // gives the output key.
+    let input = &mut psbt.inputs[i];
+    if input.tap_internal_key.is_none() && input.tap_merkle_root.is_none() {
+        let (output_key, _) = xonly.tap_tweak(secp, None);
+        let spk = input.witness_utxo.as_ref().map(|utxo| &utxo.script_pubkey);
+        if spk.is_some_and(|spk| spk.as_bytes()[2..] == output_key.serialize()) {
+            input.tap_internal_key = Some(xonly);
+        }
+    }
+    if input.tap_internal_key == Some(xonly) {
+        let tweaked = keypair.tap_tweak(secp, input.tap_merkle_root).to_keypair();
+        let signature = secp.sign_schnorr_no_aux_rand(key_path_msg, &tweaked);
+        assert!(secp
+            .verify_schnorr(&signature, key_path_msg, &tweaked.x_only_public_key().0)
+            .is_ok());
+        input.tap_key_sig = Some(bitcoin::taproot::Signature {
+            signature,
+            sighash_type,
+        });
+    }
+
+    let leaves = psbt.inputs[i]
+        .tap_key_origins
+        .get(&xonly)
+        .map(|(leaves, _)| leaves.clone())
+        .unwrap_or_default();
+    for leaf in leaves {
+        let msg = annex::sighash_msg(psbt, i, cache, Some(leaf))?;
+        let signature = secp.sign_schnorr_no_aux_rand(&msg, &keypair);
+        assert!(secp.verify_schnorr(&signature, &msg, &xonly).is_ok());
+        psbt.inputs[i].tap_script_sigs.insert(
+            (xonly, leaf),
+            bitcoin::taproot::Signature {
+                signature,
+                sighash_type,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Derive the keys of `xprivs` that `psbt`'s inputs are signed with, found
+/// by matching their fingerprints against the inputs' BIP32 derivations.
+///
+/// An extended key that isn't the master key of a derivation, such as an
+/// account key, signs for it if deriving the end of the path below the
+/// key's depth gives the derivation's public key. A master key's
+/// derivation that doesn't give its public key is skipped with a warning.
+pub fn derive_keys<C: secp256k1::Signing>(
+    secp: &secp256k1::Secp256k1<C>,
+    psbt: &Psbt,
+    xprivs: &[Xpriv],
+) -> Result<Vec<PrivateKey>, String> {
+    let mut keys = Vec::new();
+    for input in &psbt.inputs {
+        let origins = input
+            .bip32_derivation
+            .iter()
+            .map(|(pk, origin)| (pk.x_only_public_key().0, origin))
+            .chain(
+                input
+                    .tap_key_origins
+                    .iter()
+                    .map(|(xonly, (_, origin))| (*xonly, origin)),
+            );
+        for (xonly, (fingerprint, path)) in origins {
+            for xpriv in xprivs {
+                let key = if xpriv.fingerprint(secp) == *fingerprint {
+                    let child = xpriv
+                        .derive_priv(secp, path)
+                        .map_err(|e| format!("Can't derive {}: {}", path, e))?;
+                    // A matching fingerprint may be a collision, or the
+                    // origin a lie.
+                    let derived = child.private_key.x_only_public_key(secp).0;
+                    if derived != xonly {
+                        eprintln!(
+                            "WARNING: Not signing for {}: [{}/{}] derives {} instead",
+                            xonly, fingerprint, path, derived
+                        );
+                        continue;
+                    }
+                    child.to_priv()
+                } else {
+                    let depth = xpriv.depth as usize;
+                    if depth == 0 || path.len() <= depth {
+                        continue;
+                    }
+                    let rest = DerivationPath::from(&path[depth..]);
+                    let Ok(child) = xpriv.derive_priv(secp, &rest) else {
+                        continue;
+                    };
+                    if child.private_key.x_only_public_key(secp).0 != xonly {
+                        continue;
+                    }
+                    child.to_priv()
+                };
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Add each of `preimages` to the inputs of `psbt` whose scripts lock coins
+/// to its SHA256, HASH256, RIPEMD160 or HASH160, so finalizing can satisfy
+/// their hashlocks.
+///
+/// Fails if a preimage unlocks none of the inputs, which likely means it's
+/// mistyped.
+pub fn add_preimages(psbt: &mut Psbt, preimages: &[Vec<u8>]) -> Result<(), String> {
+    for preimage in preimages {
+        let sha256 = sha256::Hash::hash(preimage);
+        let hash256 = sha256d::Hash::hash(preimage);
+        let ripemd160 = ripemd160::Hash::hash(preimage);
+        let hash160 = hash160::Hash::hash(preimage);
+        let mut unlocked = false;
+        for (i, input) in psbt.inputs.iter_mut().enumerate() {
+            let scripts = input
+                .witness_script
+                .iter()
+                .chain(&input.redeem_script)
+                .chain(input.tap_scripts.values().map(|(script, _)| script));
+            let pushes: Vec<&[u8]> = scripts
+                .flat_map(|script| script.instructions())
+                .filter_map(|instruction| match instruction {
+                    Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes()),
+                    _ => None,
+                })
+                .collect();
+            let locked = |hash: &[u8]| pushes.contains(&hash);
+            let mut added = Vec::new();
+            if locked(sha256.as_byte_array()) {
+                input.sha256_preimages.insert(sha256, preimage.clone());
+                added.push("sha256");
+            }
+            if locked(hash256.as_byte_array()) {
+                input.hash256_preimages.insert(hash256, preimage.clone());
+                added.push("hash256");
+            }
+            if locked(ripemd160.as_byte_array()) {
+                input
+                    .ripemd160_preimages
+                    .insert(ripemd160, preimage.clone());
+                added.push("ripemd160");
+            }
+            if locked(hash160.as_byte_array()) {
+                input.hash160_preimages.insert(hash160, preimage.clone());
+                added.push("hash160");
+            }
+            if !added.is_empty() {
+                eprintln!("Added the {} preimage to input {}", added.join(" and "), i);
+                unlocked = true;
+            }
+        }
+        if !unlocked {
+            return Err(format!(
+                "None of the inputs is locked to a hash of the preimage {}",
+                preimage.as_hex()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Verify every partial and taproot signature of `psbt` against the sighash
+/// it claims to commit to, naming the signer of each bad one by key and,
+/// when known, master fingerprint.
+pub fn verify_signatures<C: secp256k1::Verification>(
+    secp: &secp256k1::Secp256k1<C>,
+    psbt: &Psbt,
+) -> Result<(), String> {
+    // Sighashes are computed on a copy whose inputs' sighash type is set to
+    // that of the signature being checked.
+    let mut scratch = psbt.clone();
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut problems = Vec::new();
+
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        let required = input.sighash_type;
+        for (pk, sig) in &input.partial_sigs {
+            let signer = signer_name(input, &pk.inner.x_only_public_key().0, pk);
+            if let Some(ty) = required.filter(|ty| *ty != sig.sighash_type.into()) {
+                problems.push(format!(
+                    "input {}: {} signed with {} but the input requires {}",
+                    i, signer, sig.sighash_type, ty
+                ));
+                continue;
+            }
+            scratch.inputs[i].sighash_type = Some(sig.sighash_type.into());
+            let msg = annex::sighash_msg(&scratch, i, &mut cache, None)
+                .map_err(|e| format!("input {}: can't compute the sighash: {}", i, e))?;
+            if secp.verify_ecdsa(&msg, &sig.signature, &pk.inner).is_err() {
+                problems.push(format!("input {}: invalid signature by {}", i, signer));
+            }
+        }
+
+        let mut schnorr_sigs = Vec::new();
+        if let Some(sig) = &input.tap_key_sig {
+            let output_key = input
+                .witness_utxo
+                .as_ref()
+                .filter(|utxo| utxo.script_pubkey.is_p2tr())
+                .and_then(|utxo| {
+                    secp256k1::XOnlyPublicKey::from_slice(&utxo.script_pubkey.as_bytes()[2..]).ok()
+                });
+            match output_key {
+                Some(key) => schnorr_sigs.push((key, None, sig)),
+                None => problems.push(format!(
+                    "input {}: key path signature but the UTXO isn't taproot",
+                    i
+                )),
+            }
+        }
+        for ((key, leaf), sig) in &input.tap_script_sigs {
+            schnorr_sigs.push((*key, Some(*leaf), sig));
+        }
+        for (key, leaf, sig) in schnorr_sigs {
+            let signer = match leaf {
+                None => format!("the key path ({})", key),
+                Some(_) => signer_name(input, &key, key),
+            };
+            scratch.inputs[i].sighash_type = Some(sig.sighash_type.into());
+            let msg = annex::sighash_msg(&scratch, i, &mut cache, leaf)
+                .map_err(|e| format!("input {}: can't compute the sighash: {}", i, e))?;
+            if secp.verify_schnorr(&sig.signature, &msg, &key).is_err() {
+                problems.push(format!("input {}: invalid signature by {}", i, signer));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Bad signatures:\n  {}", problems.join("\n  ")))
+    }
+}
+
+// Describe a signer by its key, adding the master fingerprint from the
+// input's derivation paths if it's there.
+fn signer_name(
+    input: &Input,
+    xonly: &secp256k1::XOnlyPublicKey,
+    key: impl std::fmt::Display,
+) -> String {
+    let fingerprint = input
+        .bip32_derivation
+        .iter()
+        .find(|(pk, _)| pk.x_only_public_key().0 == *xonly)
+        .map(|(_, (fingerprint, _))| *fingerprint)
+        .or_else(|| {
+            input
+                .tap_key_origins
+                .get(xonly)
+                .map(|(_, (fingerprint, _))| *fingerprint)
+        });
+    match fingerprint {
+        Some(fingerprint) => format!("{} (fingerprint {})", key, fingerprint),
+        None => key.to_string(),
+    }
+}
+
+/// The BIP143 preimage of segwit v0 input `i`, whose double SHA256 is its
+/// sighash, or `None` for other inputs or when the PSBT lacks the scripts
+/// to build it.
+pub fn segwit_v0_preimage(
+    psbt: &Psbt,
+    i: usize,
+    cache: &mut SighashCache<&Transaction>,
+) -> Option<Vec<u8>> {
+    let input = &psbt.inputs[i];
+    let utxo = input.witness_utxo.as_ref()?;
+    let program = if utxo.script_pubkey.is_p2sh() {
+        input.redeem_script.as_ref()?
+    } else {
+        &utxo.script_pubkey
+    };
+    let script_code = if program.is_p2wpkh() {
+        program.p2wpkh_script_code()?
+    } else if program.is_p2wsh() {
+        input.witness_script.clone()?
+    } else {
+        return None;
+    };
+    let ty = input
+        .sighash_type
+        .map(|ty| ty.ecdsa_hash_ty())
+        .unwrap_or(Ok(bitcoin::EcdsaSighashType::All))
+        .ok()?;
+    let mut preimage = Vec::new();
+    cache
+        .segwit_v0_encode_signing_data_to(&mut preimage, i, &script_code, utxo.value, ty)
+        .ok()?;
+    Some(preimage)
+}
@@ -0,0 +1,515 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use miniscript::bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+
+use crate::secret;
+
+/// The SLIP-39 wordlist, one word per line in index order.
+const WORDLIST: &str = include_str!("slip39_wordlist.txt");
+/// Words of a share that aren't its value: two for the identifier and
+/// iteration exponent, two for the group and member parameters and three
+/// for the checksum.
+const METADATA_WORDS: usize = 7;
+/// PBKDF2 iterations per Feistel round, before the iteration exponent.
+const BASE_ITERATIONS: u32 = 2500;
+/// Shares are evaluated at their index; these two points of the polynomial
+/// hold the secret and its digest.
+const SECRET_INDEX: u8 = 255;
+const DIGEST_INDEX: u8 = 254;
+
+/// One SLIP-39 share, as parsed from its mnemonic.
+#[derive(Clone)]
+pub struct Share {
+    identifier: u16,
+    extendable: bool,
+    iteration_exponent: u8,
+    group_index: u8,
+    group_threshold: u8,
+    group_count: u8,
+    member_index: u8,
+    member_threshold: u8,
+    value: Vec<u8>,
+}
+
+impl FromStr for Share {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let indexes = s
+            .split_whitespace()
+            .map(|word| {
+                let word = word.to_lowercase();
+                WORDLIST
+                    .lines()
+                    .position(|w| w == word)
+                    .map(|i| i as u32)
+                    .ok_or_else(|| format!("\"{}\" isn't a SLIP-39 word", word))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        if indexes.len() < METADATA_WORDS + 13 {
+            return Err(format!(
+                "A share has at least 20 words, this one has {}",
+                indexes.len()
+            ));
+        }
+
+        let extendable = indexes[1] >> 4 & 1 == 1;
+        let customization: &[u8] = if extendable {
+            b"shamir_extendable"
+        } else {
+            b"shamir"
+        };
+        let values = customization
+            .iter()
+            .map(|&b| b as u32)
+            .chain(indexes.clone());
+        if rs1024_polymod(values) != 1 {
+            return Err("The share's checksum doesn't match, check for typos".to_string());
+        }
+
+        // The value's bits are padded at the front up to whole words.
+        let value_words = &indexes[4..indexes.len() - 3];
+        let padding = value_words.len() * 10 % 16;
+        if padding > 8 {
+            return Err("The share's value has an invalid length".to_string());
+        }
+        let mut bits = Vec::with_capacity(value_words.len() * 10);
+        for index in value_words {
+            bits.extend((0..10).rev().map(|i| index >> i & 1));
+        }
+        if bits[..padding].iter().any(|&bit| bit != 0) {
+            return Err("The share's padding isn't zero".to_string());
+        }
+        let value: Vec<u8> = bits[padding..]
+            .chunks(8)
+            .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+            .collect();
+
+        let share = Share {
+            identifier: (indexes[0] << 5 | indexes[1] >> 5) as u16,
+            extendable,
+            iteration_exponent: (indexes[1] & 0xf) as u8,
+            group_index: (indexes[2] >> 6) as u8,
+            group_threshold: (indexes[2] >> 2 & 0xf) as u8 + 1,
+            group_count: ((indexes[2] & 3) << 2 | indexes[3] >> 8) as u8 + 1,
+            member_index: (indexes[3] >> 4 & 0xf) as u8,
+            member_threshold: (indexes[3] & 0xf) as u8 + 1,
+            value,
+        };
+        if share.group_threshold > share.group_count {
+            return Err(format!(
+                "The share needs {} groups but claims there are only {}",
+                share.group_threshold, share.group_count
+            ));
+        }
+        Ok(share)
+    }
+}
+
+/// Recover the master secret protected by `shares` and `passphrase`.
+///
+/// Each group's shares are combined into the group's share of the secret,
+/// and those of enough groups into the encrypted master secret, checking
+/// the digest at every step that needs more than one share.
+pub fn combine(shares: &[Share], passphrase: &str) -> Result<Vec<u8>, String> {
+    let first = shares.first().ok_or("No shares given")?;
+    check_compatible(shares)?;
+    let mut groups: BTreeMap<u8, Vec<&Share>> = BTreeMap::new();
+    for share in shares {
+        groups.entry(share.group_index).or_default().push(share);
+    }
+    let mut group_secrets = Vec::new();
+    for (index, members) in &groups {
+        let threshold = members[0].member_threshold;
+        if members.len() < threshold as usize {
+            continue;
+        }
+        let points: Vec<(u8, &[u8])> = members
+            .iter()
+            .map(|share| (share.member_index, &share.value[..]))
+            .collect();
+        let secret =
+            recover(threshold, &points).map_err(|e| format!("Group {}: {}", index + 1, e))?;
+        group_secrets.push((*index, secret));
+    }
+    if group_secrets.len() < first.group_threshold as usize {
+        return Err(format!(
+            "{} of {} groups are complete but {} are needed",
+            group_secrets.len(),
+            first.group_count,
+            first.group_threshold
+        ));
+    }
+    let points: Vec<(u8, &[u8])> = group_secrets
+        .iter()
+        .map(|(index, secret)| (*index, &secret[..]))
+        .collect();
+    let encrypted = recover(first.group_threshold, &points)?;
+    Ok(decrypt(&encrypted, passphrase.as_bytes(), first))
+}
+
+/// Prompt for shares until enough groups are complete, then for the
+/// passphrase, and recover the master secret.
+///
+/// The shares and passphrase are typed at the terminal with echo off, and
+/// the progress goes to stderr, so stdout only carries the command's
+/// result.
+pub fn recover_interactively() -> Result<Vec<u8>, String> {
+    eprintln!("Enter the SLIP-39 shares one at a time, in any order.");
+    let mut shares: Vec<Share> = Vec::new();
+    loop {
+        let line = secret::prompt_hidden(&format!("Share {}: ", shares.len() + 1))?;
+        let share = match line.parse::<Share>() {
+            Ok(share) => share,
+            Err(e) => {
+                eprintln!("  {}", e);
+                continue;
+            }
+        };
+        let mut candidate = shares.clone();
+        candidate.push(share);
+        if let Err(e) = check_compatible(&candidate) {
+            eprintln!("  {}", e);
+            continue;
+        }
+        shares = candidate;
+
+        let first = &shares[0];
+        let mut complete = 0;
+        for group in 0..first.group_count {
+            let members: Vec<&Share> = shares.iter().filter(|s| s.group_index == group).collect();
+            if let Some(member) = members.first() {
+                eprintln!(
+                    "  group {} of {}: {} of {} shares",
+                    group + 1,
+                    first.group_count,
+                    members.len(),
+                    member.member_threshold
+                );
+                if members.len() >= member.member_threshold as usize {
+                    complete += 1;
+                }
+            }
+        }
+        if complete >= first.group_threshold {
+            break;
+        }
+        eprintln!(
+            "  {} of {} needed groups complete",
+            complete, first.group_threshold
+        );
+    }
+    let passphrase = secret::prompt_hidden("Passphrase (empty for none): ")?;
+    combine(&shares, &passphrase)
+}
+
+// Check that `shares` belong to the same secret and don't repeat or
+// contradict each other.
+fn check_compatible(shares: &[Share]) -> Result<(), String> {
+    let Some(first) = shares.first() else {
+        return Ok(());
+    };
+    for (i, share) in shares.iter().enumerate() {
+        if (
+            share.identifier,
+            share.extendable,
+            share.iteration_exponent,
+            share.group_threshold,
+            share.group_count,
+            share.value.len(),
+        ) != (
+            first.identifier,
+            first.extendable,
+            first.iteration_exponent,
+            first.group_threshold,
+            first.group_count,
+            first.value.len(),
+        ) {
+            return Err("The share belongs to a different secret".to_string());
+        }
+        for other in &shares[..i] {
+            if other.group_index != share.group_index {
+                continue;
+            }
+            if other.member_threshold != share.member_threshold {
+                return Err(format!(
+                    "Shares of group {} disagree on its threshold",
+                    share.group_index + 1
+                ));
+            }
+            if other.member_index == share.member_index {
+                return Err(format!(
+                    "Share {} of group {} was already given",
+                    share.member_index + 1,
+                    share.group_index + 1
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Recover the secret shared among `points` with `threshold`, checking its
+// digest unless a single share is the secret itself.
+fn recover(threshold: u8, points: &[(u8, &[u8])]) -> Result<Vec<u8>, String> {
+    if threshold == 1 {
+        return Ok(points[0].1.to_vec());
+    }
+    let points = &points[..threshold as usize];
+    let secret = interpolate(points, SECRET_INDEX);
+    let digest_share = interpolate(points, DIGEST_INDEX);
+    let (digest, random) = digest_share.split_at(4);
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(random);
+    engine.input(&secret);
+    let expected = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+    if &expected.as_byte_array()[..4] != digest {
+        return Err("The shares don't recover a valid secret, one of them is wrong".to_string());
+    }
+    Ok(secret)
+}
+
+// Evaluate at `x` the polynomials over GF(256) going through `points`, one
+// per byte of the values.
+fn interpolate(points: &[(u8, &[u8])], x: u8) -> Vec<u8> {
+    let (exp, log) = gf256_tables();
+    let mul = |a: u8, b: u8| {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            exp[(log[a as usize] as usize + log[b as usize] as usize) % 255]
+        }
+    };
+    let div = |a: u8, b: u8| {
+        if a == 0 {
+            0
+        } else {
+            exp[(log[a as usize] as usize + 255 - log[b as usize] as usize) % 255]
+        }
+    };
+    let mut result = vec![0u8; points[0].1.len()];
+    for (i, (xi, yi)) in points.iter().enumerate() {
+        // The Lagrange basis polynomial of point i, evaluated at x.
+        let mut basis = 1;
+        for (j, (xj, _)) in points.iter().enumerate() {
+            if i != j {
+                basis = mul(basis, div(x ^ xj, xi ^ xj));
+            }
+        }
+        for (r, y) in result.iter_mut().zip(yi.iter()) {
+            *r ^= mul(basis, *y);
+        }
+    }
+    result
+}
+
+// Exponent and logarithm tables of GF(256) with the Rijndael polynomial and
+// generator 3.
+fn gf256_tables() -> ([u8; 255], [u8; 256]) {
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+    let mut value: u16 = 1;
+    for (i, e) in exp.iter_mut().enumerate() {
+        *e = value as u8;
+        log[value as usize] = i as u8;
+        value ^= value << 1;
+        if value & 0x100 != 0 {
+            value ^= 0x11b;
+        }
+    }
+    (exp, log)
+}
+
+// Undo the four round Feistel encryption of the master secret.
+fn decrypt(encrypted: &[u8], passphrase: &[u8], share: &Share) -> Vec<u8> {
+    let half = encrypted.len() / 2;
+    let (mut left, mut right) = (encrypted[..half].to_vec(), encrypted[half..].to_vec());
+    let mut salt_prefix = Vec::new();
+    if !share.extendable {
+        salt_prefix.extend_from_slice(b"shamir");
+        salt_prefix.extend_from_slice(&share.identifier.to_be_bytes());
+    }
+    let iterations = BASE_ITERATIONS << share.iteration_exponent;
+    for round in (0..4u8).rev() {
+        let mut password = vec![round];
+        password.extend_from_slice(passphrase);
+        let mut salt = salt_prefix.clone();
+        salt.extend_from_slice(&right);
+        let f = pbkdf2_sha256(&password, &salt, iterations, right.len());
+        let next: Vec<u8> = left.iter().zip(&f).map(|(l, f)| l ^ f).collect();
+        left = right;
+        right = next;
+    }
+    right.extend_from_slice(&left);
+    right
+}
+
+// PBKDF2 with HMAC-SHA256. The round keys are as long as half the master
+// secret, which may take several blocks.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, len: usize) -> Vec<u8> {
+    let keyed = hmac::HmacEngine::<sha256::Hash>::new(password);
+    let mut result = Vec::with_capacity(len.next_multiple_of(32));
+    for block in 1..=len.div_ceil(32) as u32 {
+        let mut engine = keyed.clone();
+        engine.input(salt);
+        engine.input(&block.to_be_bytes());
+        let mut u = hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+        let mut t = u;
+        for _ in 1..iterations {
+            let mut engine = keyed.clone();
+            engine.input(&u);
+            u = hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+            for (t, u) in t.iter_mut().zip(&u) {
+                *t ^= u;
+            }
+        }
+        result.extend_from_slice(&t);
+    }
+    result.truncate(len);
+    result
+}
+
+// The RS1024 checksum of the share words, seeded with the customization
+// string; a valid share leaves 1.
+fn rs1024_polymod(values: impl IntoIterator<Item = u32>) -> u32 {
+    const GENERATOR: [u32; 10] = [
+        0xe0e040, 0x1c1c080, 0x3838100, 0x7070200, 0xe0e0009, 0x1c0c2412, 0x38086c24, 0x3090fc48,
+        0x21b1f890, 0x3f3f120,
+    ];
+    let mut checksum = 1;
+    for value in values {
+        let top = checksum >> 20;
+        checksum = (checksum & 0xfffff) << 10 ^ value;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if top >> i & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use miniscript::bitcoin::hex::DisplayHex;
+
+    use super::*;
+
+    // Recover the master secret of the test vectors' mnemonics, all
+    // protected with the passphrase "TREZOR".
+    fn master_secret(mnemonics: &[&str]) -> Result<String, String> {
+        let shares = mnemonics
+            .iter()
+            .map(|mnemonic| mnemonic.parse())
+            .collect::<Result<Vec<Share>, _>>()?;
+        Ok(combine(&shares, "TREZOR")?.to_lower_hex_string())
+    }
+
+    #[test]
+    fn valid_vectors() {
+        let vectors: &[(&[&str], &str)] = &[
+            // Valid mnemonic without sharing (128 bits)
+            (
+                &[
+                    "duckling enlarge academic academic agency result length solution fridge \
+                   kidney coal piece deal husband erode duke ajar critical decision keyboard",
+                ],
+                "bb54aac4b89dc868ba37d9cc21b2cece",
+            ),
+            // Basic sharing 2-of-3 (128 bits)
+            (
+                &[
+                    "shadow pistol academic always adequate wildlife fancy gross oasis \
+                     cylinder mustang wrist rescue view short owner flip making coding armed",
+                    "shadow pistol academic acid actress prayer class unknown daughter \
+                     sweater depict flip twice unkind craft early superior advocate guest \
+                     smoking",
+                ],
+                "b43ceb7e57a0ea8766221624d01b0864",
+            ),
+            // Valid mnemonic without sharing (256 bits)
+            (
+                &[
+                    "theory painting academic academic armed sweater year military elder \
+                   discuss acne wildlife boring employer fused large satoshi bundle carbon \
+                   diagnose anatomy hamster leaves tracks paces beyond phantom capital marvel \
+                   lips brave detect luck",
+                ],
+                "989baf9dcaad5b10ca33dfd8cc75e42477025dce88ae83e75a230086a0e00e92",
+            ),
+            // Basic sharing 2-of-3 (256 bits)
+            (
+                &[
+                    "humidity disease academic always aluminum jewelry energy woman receiver \
+                     strategy amuse duckling lying evidence network walnut tactics forget \
+                     hairy rebound impulse brother survive clothes stadium mailman rival \
+                     ocean reward venture always armed unwrap",
+                    "humidity disease academic agency actress jacket gross physics cylinder \
+                     solution fake mortgage benefit public busy prepare sharp friar change \
+                     work slow purchase ruler again tricycle involve viral wireless mixture \
+                     anatomy desert cargo upgrade",
+                ],
+                "c938b319067687e990e05e0da0ecce1278f75ff58d9853f19dcaeed5de104aae",
+            ),
+            // Valid extendable mnemonic without sharing (128 bits)
+            (
+                &[
+                    "testify swimming academic academic column loyalty smear include exotic \
+                   bedroom exotic wrist lobe cover grief golden smart junior estimate learn",
+                ],
+                "1679b4516e0ee5954351d288a838f45e",
+            ),
+        ];
+        for (mnemonics, secret) in vectors {
+            assert_eq!(master_secret(mnemonics).as_deref(), Ok(*secret));
+        }
+    }
+
+    #[test]
+    fn invalid_vectors() {
+        let vectors: &[&[&str]] = &[
+            // Mnemonic with invalid checksum (128 bits)
+            &[
+                "duckling enlarge academic academic agency result length solution fridge \
+               kidney coal piece deal husband erode duke ajar critical decision kidney",
+            ],
+            // Mnemonic with invalid padding (128 bits)
+            &[
+                "duckling enlarge academic academic email result length solution fridge \
+               kidney coal piece deal husband erode duke ajar music cargo fitness",
+            ],
+            // Basic sharing 2-of-3 (128 bits), one share short
+            &[
+                "shadow pistol academic always adequate wildlife fancy gross oasis cylinder \
+               mustang wrist rescue view short owner flip making coding armed",
+            ],
+            // Mnemonics with different identifiers (128 bits)
+            &[
+                "adequate smoking academic acid debut wine petition glen cluster slow rhyme \
+                 slow simple epidemic rumor junk tracks treat olympic tolerate",
+                "adequate stay academic agency agency formal party ting frequent learn \
+                 upstairs remember smear leaf damage anatomy ladle market hush corner",
+            ],
+            // Mnemonics with different iteration exponents (128 bits)
+            &[
+                "peasant leaves academic acid desert exact olympic math alive axle trial \
+                 tackle drug deny decent smear dominant desert bucket remind",
+                "peasant leader academic agency cultural blessing percent network envelope \
+                 medal junk primary human pumps jacket fragment payroll ticket evoke voice",
+            ],
+        ];
+        for mnemonics in vectors {
+            assert!(master_secret(mnemonics).is_err(), "{:?}", mnemonics);
+        }
+    }
+
+    #[test]
+    fn round_keys_span_blocks() {
+        // RFC 7914, section 11.
+        assert_eq!(
+            pbkdf2_sha256(b"passwd", b"salt", 1, 64).to_lower_hex_string(),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc\
+             49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::PathBuf;
+
+use clap::Args;
+use miniscript::bitcoin::base64::prelude::{Engine, BASE64_STANDARD};
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::{secp256k1, Psbt, Transaction};
+
+use crate::error::Error;
+use crate::{build, parse, psbtv2, ur};
+
+/// The magic bytes starting a binary PSBT, as BIP174 defines them.
+pub const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// A PSBT handed from one stage of a spend to the next.
+#[derive(Args)]
+pub struct PsbtArg {
+    /// The base64 PSBT, read from stdin if omitted or `-`.
+    #[arg(conflicts_with_all = ["psbt_in", "ur"])]
+    psbt: Option<String>,
+    /// Read the PSBT from this file instead, binary or base64, such as one
+    /// saved by Sparrow, Electrum or a Coldcard.
+    #[arg(long, conflicts_with = "ur")]
+    psbt_in: Option<PathBuf>,
+    /// Read the PSBT from UR crypto-psbt parts instead, as their text or
+    /// images of their QR codes, in any order, such as an air-gapped
+    /// signer shows.
+    #[arg(long, num_args = 1..)]
+    ur: Vec<String>,
+}
+
+/// Where a stage hands its PSBT on to.
+#[derive(Args)]
+pub struct PsbtOut {
+    /// Write the PSBT to this file instead of stdout.
+    #[arg(long)]
+    psbt_out: Option<PathBuf>,
+    /// Write the file in the binary format rather than base64.
+    #[arg(long, requires = "psbt_out")]
+    binary: bool,
+    /// Show the PSBT in the terminal as an animated QR code of UR
+    /// crypto-psbt parts instead, for an air-gapped signer to scan, until
+    /// interrupted.
+    #[arg(long, conflicts_with = "psbt_out")]
+    qr: bool,
+    /// Write the QR codes of the UR crypto-psbt parts as PNG images
+    /// instead, named <PREFIX>-1.png, <PREFIX>-2.png and so on.
+    #[arg(long, value_name = "PREFIX", conflicts_with_all = ["psbt_out", "qr"])]
+    qr_png: Option<PathBuf>,
+    /// The PSBT version to write: 0, with the whole unsigned transaction
+    /// as older signers expect, or 2 (BIP370), with each input's outpoint
+    /// and sequence and each output's amount and script in their own maps.
+    #[arg(long, default_value_t = 0, value_parser = psbt_version)]
+    psbt_version: u32,
+}
+
+/// The arguments of a stage taking a PSBT and handing it on.
+#[derive(Args)]
+pub struct StageArgs {
+    #[command(flatten)]
+    pub psbt: PsbtArg,
+    #[command(flatten)]
+    pub out: PsbtOut,
+}
+
+impl PsbtArg {
+    /// Load the PSBT from the argument, the file, the UR parts or stdin.
+    pub fn load(&self) -> Result<Psbt, String> {
+        if !self.ur.is_empty() {
+            return ur::load(&self.ur);
+        }
+        if let Some(path) = &self.psbt_in {
+            let bytes =
+                fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+            return decode(&bytes);
+        }
+        match self.psbt.as_deref() {
+            Some(psbt) if psbt != "-" => parse::psbt(psbt),
+            _ => {
+                let mut stdin = io::stdin();
+                if stdin.is_terminal() {
+                    return Err("Pass the PSBT or pipe it in".to_string());
+                }
+                let mut psbt = Vec::new();
+                stdin
+                    .read_to_end(&mut psbt)
+                    .map_err(|e| format!("Can't read the PSBT from stdin: {}", e))?;
+                decode(&psbt)
+            }
+        }
+    }
+}
+
+impl PsbtOut {
+    /// Whether the PSBT goes to a file or QR codes rather than stdout.
+    pub fn to_file(&self) -> bool {
+        self.psbt_out.is_some() || self.qr || self.qr_png.is_some()
+    }
+
+    /// Hand the PSBT on to the next stage: written to the file, shown or
+    /// saved as QR codes, or as base64 on stdout.
+    pub fn store(&self, psbt: Psbt) -> Result<(), String> {
+        let psbt = self.serialize(&psbt);
+        if self.qr {
+            return ur::show(&psbt);
+        }
+        if let Some(prefix) = &self.qr_png {
+            return ur::save_pngs(&psbt, prefix);
+        }
+        let Some(path) = &self.psbt_out else {
+            println!("{}", BASE64_STANDARD.encode(&psbt));
+            return Ok(());
+        };
+        let bytes = if self.binary {
+            psbt
+        } else {
+            format!("{}\n", BASE64_STANDARD.encode(&psbt)).into_bytes()
+        };
+        fs::write(path, bytes).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+        eprintln!("Wrote the PSBT to {}", path.display());
+        Ok(())
+    }
+
+    /// `psbt` in the binary format of the version asked for.
+    pub fn serialize(&self, psbt: &Psbt) -> Vec<u8> {
+        match self.psbt_version {
+            2 => psbtv2::serialize_v2(psbt),
+            _ => psbt.serialize(),
+        }
+    }
+}
+
+fn psbt_version(s: &str) -> Result<u32, String> {
+    match s {
+        "0" => Ok(0),
+        "2" => Ok(2),
+        _ => Err("the PSBT version is 0 or 2".to_string()),
+    }
+}
+
+/// Decode a PSBT in the binary format, told apart by its magic bytes, or
+/// as base64 text.
+pub fn decode(bytes: &[u8]) -> Result<Psbt, String> {
+    if bytes.starts_with(PSBT_MAGIC) {
+        return psbtv2::deserialize_any(bytes).map_err(|e| format!("not a valid PSBT: {}", e));
+    }
+    let text = std::str::from_utf8(bytes)
+        .map_err(|_| "not a PSBT, neither binary nor base64".to_string())?;
+    parse::psbt(text)
+}
+
+/// Extract the transaction of a finalized PSBT, naming the inputs that
+/// aren't.
+pub fn extract_tx(psbt: Psbt) -> Result<Transaction, Error> {
+    let unfinalized: Vec<String> = psbt
+        .inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| {
+            input.final_script_sig.is_none() && input.final_script_witness.is_none()
+        })
+        .map(|(i, _)| i.to_string())
+        .collect();
+    if !unfinalized.is_empty() {
+        return Err(Error::Finalize(format!(
+            "The PSBT isn't finalized, these inputs have no final scripts: {}",
+            unfinalized.join(", ")
+        )));
+    }
+    psbt.extract_tx()
+        .map_err(|e| Error::Finalize(format!("Can't extract the transaction: {}", e)))
+}
+
+/// Check every signature of the PSBT and turn them into final scripts.
+pub fn finalize(args: StageArgs) -> Result<(), Error> {
+    let mut psbt = args.psbt.load()?;
+    build::finalize(&secp256k1::Secp256k1::verification_only(), &mut psbt)?;
+    Ok(args.out.store(psbt)?)
+}
+
+/// Print the raw transaction of a finalized PSBT.
+pub fn extract(arg: PsbtArg) -> Result<(), Error> {
+    println!("{}", serialize_hex(&extract_tx(arg.load()?)?));
+    Ok(())
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::Duration;
+
+use clap::{ArgGroup, Args};
+use miniscript::bitcoin::bip32::Xpriv;
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::{
+    secp256k1, Amount, EcdsaSighashType, FeeRate, OutPoint, PrivateKey, Psbt, ScriptBuf,
+    Transaction, TxOut,
+};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::Descriptor;
+use serde_json::json;
+
+use crate::chain::Chain;
+use crate::hooks::Lifecycle;
+use crate::wallet::{self, Profile, State};
+use crate::{
+    broadcast, build, history, hooks, parse, policy, rescan, rpc, secret, sign, utxo, Utxo,
+};
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("signing").args(["keys", "private_keys"]).multiple(true)))]
+pub struct SweepArgs {
+    /// The wallet to watch and sweep.
+    #[arg(required_unless_present = "descriptor")]
+    wallet: Option<String>,
+    /// Sweep every coin paying this descriptor once instead of watching a
+    /// wallet, e.g. to migrate it, found in the --tx transactions or else
+    /// in the UTXO set of the node or the --esplora server. No change is
+    /// left, the fee comes out of what the destination receives.
+    #[arg(
+        long,
+        value_parser = parse::ranged_descriptor,
+        conflicts_with_all = ["wallet", "threshold", "min_confirmations", "lock_for", "interval", "once", "filters"]
+    )]
+    descriptor: Option<Descriptor<DescriptorPublicKey>>,
+    /// A transaction whose outputs paying the --descriptor are swept. May
+    /// be repeated.
+    #[arg(long = "tx", value_parser = parse::transaction, requires = "descriptor")]
+    txs: Vec<Transaction>,
+    /// Look the --descriptor's coins up through this Esplora HTTP API
+    /// instead of a node, and broadcast through it unless --via says
+    /// otherwise.
+    #[arg(long, value_name = "URL", value_parser = parse::url, requires = "descriptor", conflicts_with = "txs")]
+    esplora: Option<String>,
+    /// Where to sweep to, an address or the cold wallet's descriptor,
+    /// prefixed with `<index>:` if it has wildcards.
+    #[arg(long, value_parser = parse::recipient)]
+    to: ScriptBuf,
+    /// Only sweep once the confirmed coins add up to at least this much.
+    #[arg(long, value_parser = parse::amount, default_value = "0")]
+    threshold: Amount,
+    /// How many confirmations a coin needs before it's swept.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    min_confirmations: u32,
+    /// The feerate the sweep pays, in sat/vB.
+    #[arg(long, value_parser = parse::feerate, default_value = "1")]
+    feerate: FeeRate,
+    /// An extended private key to sign the sweep with, the master key or
+    /// one of the descriptor's keys. May be repeated; without any, the
+    /// unsigned PSBT is printed for the signers.
+    #[arg(long = "key", value_parser = secret::parser(parse::xpriv))]
+    keys: Vec<Xpriv>,
+    /// A private key to sign the sweep with, for descriptors of single
+    /// keys rather than extended ones. May be repeated.
+    #[arg(long = "private-key", value_parser = secret::parser(parse::private_key))]
+    private_keys: Vec<PrivateKey>,
+    /// Broadcast the sweep once the keys fully sign it.
+    #[arg(long, requires = "signing")]
+    broadcast: bool,
+    /// How long to lock swept coins so later checks don't sweep them again
+    /// before the sweep confirms.
+    #[arg(long, value_parser = parse::duration, default_value = "1d")]
+    lock_for: u64,
+    /// How often to look for new blocks.
+    #[arg(long, value_parser = parse::duration, default_value = "10m")]
+    interval: u64,
+    /// Check once and exit, e.g. when run from cron.
+    #[arg(long)]
+    once: bool,
+    /// The derivation indexes whose scripts are looked for.
+    #[arg(long, value_parser = parse::range, default_value = "0-999")]
+    range: RangeInclusive<u32>,
+    /// Only fetch blocks whose BIP158 compact filter matches the wallet's
+    /// scripts; needs a node running with -blockfilterindex.
+    #[arg(long)]
+    filters: bool,
+    #[command(flatten)]
+    rpc: rpc::RpcArgs,
+    #[command(flatten)]
+    backends: broadcast::BackendArgs,
+    #[command(flatten)]
+    hooks: hooks::HookArgs,
+}
+
+/// Watch the wallet's descriptor for confirmed coins and sweep them to the
+/// cold destination whenever they reach the threshold, or sweep the
+/// --descriptor's coins once.
+pub fn run(mut args: SweepArgs) -> Result<(), String> {
+    if let Some(descriptor) = &args.descriptor {
+        let coins = if args.txs.is_empty() {
+            Chain::connect(args.esplora.as_deref(), &args.rpc)?.utxos(descriptor, &args.range)?
+        } else {
+            utxo::paying(&args.txs, descriptor, &args.range)?
+        };
+        if coins.is_empty() {
+            return Err("No coins pay the descriptor".to_string());
+        }
+        if let Some(url) = &args.esplora {
+            args.backends
+                .default_to(broadcast::Backend::Esplora(url.clone()));
+        }
+        return sweep(&args, None, &coins);
+    }
+    let wallet = args.wallet.clone().expect("required without --descriptor");
+    let profile = Profile::load(&wallet)?;
+    let scripts = rescan::scripts(&profile, &args.range)?;
+    let descriptors = parse::ranged_descriptor(&profile.descriptor)?
+        .into_single_descriptors()
+        .map_err(|e| format!("Can't split the wallet's descriptor: {}", e))?;
+    let node = args.rpc.connect()?;
+    loop {
+        let mut state = State::load(&wallet)?;
+        let tip = node.tip_height()?;
+        // A wallet that was never scanned starts from the genesis block.
+        let from = if state.height == 0 && state.history.is_empty() {
+            0
+        } else {
+            state.height + 1
+        };
+        if from <= tip {
+            let pending: Vec<String> = state
+                .history
+                .iter()
+                .filter(|entry| entry.created && entry.height == 0)
+                .map(|entry| entry.txid.clone())
+                .collect();
+            let mut unspent = HashMap::new();
+            for utxo in state.utxos.drain(..) {
+                unspent.insert(parse::outpoint(&utxo.outpoint)?, utxo);
+            }
+            rescan::scan(
+                &node,
+                &scripts,
+                from..=tip,
+                args.filters,
+                &mut unspent,
+                &mut state.history,
+            )?;
+            state.height = tip;
+            state.utxos = unspent.into_values().collect();
+            state
+                .utxos
+                .sort_by_key(|utxo| (utxo.height, utxo.outpoint.clone()));
+            state.save(&wallet)?;
+            for entry in &state.history {
+                if entry.height > 0 && pending.contains(&entry.txid) {
+                    args.hooks.fire(
+                        Lifecycle::Confirmed,
+                        &format!(
+                            "Transaction {} of {} confirmed in block {}",
+                            entry.txid, wallet, entry.height
+                        ),
+                        json!({ "wallet": wallet, "txid": entry.txid, "height": entry.height }),
+                    );
+                }
+            }
+        }
+
+        let locked = wallet::locked_coins(&wallet)?;
+        let coins: Vec<(OutPoint, u32)> = state
+            .utxos
+            .iter()
+            .filter(|utxo| utxo.height + args.min_confirmations - 1 <= tip)
+            .filter(|utxo| !locked.iter().any(|lock| lock.outpoint == utxo.outpoint))
+            .map(|utxo| Ok((parse::outpoint(&utxo.outpoint)?, utxo.index)))
+            .collect::<Result<_, String>>()?;
+        let balance: u64 = state
+            .utxos
+            .iter()
+            .filter(|utxo| coins.iter().any(|(o, _)| o.to_string() == utxo.outpoint))
+            .map(|utxo| utxo.value)
+            .sum();
+        if coins.is_empty() {
+            println!("Block {}: nothing ready to sweep", tip);
+        } else if balance < args.threshold.to_sat() {
+            println!(
+                "Block {}: {} sat ready to sweep, below the threshold of {} sat",
+                tip,
+                balance,
+                args.threshold.to_sat()
+            );
+        } else if let Err(e) = wallet_coins(&node, &descriptors, &coins)
+            .and_then(|coins| sweep(&args, Some(&wallet), &coins))
+        {
+            eprintln!("Can't sweep at block {}: {}", tip, e);
+        }
+
+        if args.once {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(args.interval));
+    }
+}
+
+// The wallet's `coins`, each with the derivation index of the script it
+// pays, with their outputs and descriptors.
+fn wallet_coins(
+    node: &rpc::Node,
+    descriptors: &[Descriptor<DescriptorPublicKey>],
+    coins: &[(OutPoint, u32)],
+) -> Result<Vec<Utxo>, String> {
+    coins
+        .iter()
+        .map(|(outpoint, index)| {
+            let prevout = node.prevout(outpoint)?;
+            Ok(Utxo {
+                outpoint: *outpoint,
+                descriptor: rescan::derive(descriptors, outpoint, *index, &prevout.txout)?,
+                txout: prevout.txout,
+                tx: prevout.tx,
+            })
+        })
+        .collect()
+}
+
+// Spend `coins` to the destination, signing and broadcasting as far as the
+// arguments allow. Coins of a wallet are locked and the sweep recorded in
+// its history.
+fn sweep(args: &SweepArgs, wallet: Option<&str>, coins: &[Utxo]) -> Result<(), String> {
+    let outpoints: Vec<OutPoint> = coins.iter().map(|coin| coin.outpoint).collect();
+    let mut psbt = build::create_psbt(
+        &outpoints,
+        vec![TxOut {
+            script_pubkey: args.to.clone(),
+            value: Amount::ZERO,
+        }],
+    );
+    let mut total = Amount::ZERO;
+    for (input, coin) in psbt.inputs.iter_mut().zip(coins) {
+        total = total
+            .checked_add(coin.txout.value)
+            .ok_or("The input values overflow")?;
+        input.non_witness_utxo = coin.tx.clone();
+        build::update_input(input, &coin.descriptor, coin.txout.clone(), coin.tx.clone())?;
+    }
+    let descriptors: Vec<&Descriptor<DefiniteDescriptorKey>> =
+        coins.iter().map(|coin| &coin.descriptor).collect();
+
+    let vsize = policy::estimate_weight(&psbt, &descriptors)?.div_ceil(4);
+    let fee = args.feerate.fee_vb(vsize).ok_or("The fee overflows")?;
+    let output = &mut psbt.unsigned_tx.output[0];
+    output.value = total.checked_sub(fee).ok_or_else(|| {
+        format!(
+            "The fee of {} sat exceeds the {} sat swept",
+            fee.to_sat(),
+            total.to_sat()
+        )
+    })?;
+    policy::check_standard(&psbt, &descriptors)?;
+    if let Some(wallet) = wallet {
+        wallet::lock_coins(wallet, &outpoints, args.lock_for)?;
+    }
+    println!(
+        "Sweeping {} coins worth {} sat, paying a fee of {} sat",
+        coins.len(),
+        total.to_sat(),
+        fee.to_sat()
+    );
+
+    if args.keys.is_empty() && args.private_keys.is_empty() {
+        println!("{}", psbt);
+        created(args, wallet, &psbt);
+        return Ok(());
+    }
+    let secp = secp256k1::Secp256k1::new();
+    let mut keys = args.private_keys.clone();
+    for key in sign::derive_keys(&secp, &psbt, &args.keys)? {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    sign::sign_inputs(&secp, &mut psbt, &keys, EcdsaSighashType::All, |_, _, _| {})
+        .map_err(|e| format!("Can't compute the sighash: {}", e))?;
+    if let Err(e) = build::finalize(&secp, &mut psbt) {
+        println!("The keys don't complete the sweep ({}), sign the PSBT:", e);
+        println!("{}", psbt);
+        created(args, wallet, &psbt);
+        return Ok(());
+    }
+    let tx = psbt.extract_tx_unchecked_fee_rate();
+    let txid = tx.compute_txid();
+    args.hooks.fire(
+        Lifecycle::FullySigned,
+        &format!("Sweep {}{} is fully signed", txid, of(wallet)),
+        json!({ "wallet": wallet, "txid": txid.to_string(), "tx": serialize_hex(&tx) }),
+    );
+    if args.broadcast {
+        println!(
+            "Broadcast sweep {}",
+            args.backends.broadcast(&args.rpc, &tx)?
+        );
+        args.hooks.fire(
+            Lifecycle::Broadcast,
+            &format!("Broadcast sweep {}{}", txid, of(wallet)),
+            json!({ "wallet": wallet, "txid": txid.to_string() }),
+        );
+    }
+    println!("raw: {}", serialize_hex(&tx));
+    if let Some(wallet) = wallet {
+        let label = Some("sweep to cold storage".to_string());
+        if let Err(e) = history::record(wallet, &tx, total, label, args.broadcast) {
+            eprintln!("Can't record the sweep in the wallet's history: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// Tell the hooks about a sweep PSBT waiting for signatures.
+fn created(args: &SweepArgs, wallet: Option<&str>, psbt: &Psbt) {
+    args.hooks.fire(
+        Lifecycle::PsbtCreated,
+        &format!(
+            "Sweep {}{} needs signing",
+            psbt.unsigned_tx.compute_txid(),
+            of(wallet)
+        ),
+        json!({
+            "wallet": wallet,
+            "txid": psbt.unsigned_tx.compute_txid().to_string(),
+            "psbt": psbt.to_string(),
+        }),
+    );
+}
+
+// " of <wallet>" in the hooks' messages, if a wallet is swept.
+fn of(wallet: Option<&str>) -> String {
+    wallet
+        .map(|wallet| format!(" of {}", wallet))
+        .unwrap_or_default()
+}
@@ -0,0 +1,267 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::str::FromStr;
+
+use clap::Subcommand;
+use miniscript::bitcoin::{secp256k1, PrivateKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{parse, secret, wallet};
+
+#[derive(Subcommand)]
+pub enum TpmCommand {
+    /// Seal a private key to this machine's TPM2, so `tpm:<name>` can be
+    /// passed wherever a private key is expected. Only the sealed blob is
+    /// stored on disk and only this TPM can unseal it.
+    Seal {
+        /// The name to pass as `tpm:<name>`.
+        name: String,
+        /// The WIF private key to seal: `file:<PATH>`, `env:<VAR>` or `-`
+        /// to be prompted for it, which is what happens without it too.
+        #[arg(long, value_parser = secret::parser(parse::private_key))]
+        key: Option<PrivateKey>,
+        /// Only unseal while these PCRs hold the values they hold now, e.g.
+        /// sha256:0,2,4,7 to also refuse after the boot chain changed.
+        #[arg(long)]
+        pcrs: Option<String>,
+    },
+    /// List the keys sealed to the TPM.
+    List,
+    /// Delete a sealed key. The TPM keeps nothing, so it's gone for good.
+    Remove {
+        /// The name of the key.
+        name: String,
+    },
+}
+
+/// A key sealed to the TPM, whose blobs are stored next to it.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    name: String,
+    public_key: String,
+    pcrs: Option<String>,
+}
+
+pub fn run(command: TpmCommand) -> Result<(), String> {
+    match command {
+        TpmCommand::Seal { name, key, pcrs } => seal(&name, key, pcrs),
+        TpmCommand::List => {
+            let mut found = false;
+            for entry in fs::read_dir(dir()).into_iter().flatten().flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    let sealed = load(&path)?;
+                    let pcrs = match &sealed.pcrs {
+                        Some(pcrs) => format!(", bound to PCRs {}", pcrs),
+                        None => String::new(),
+                    };
+                    println!("tpm:{} {}{}", sealed.name, sealed.public_key, pcrs);
+                    found = true;
+                }
+            }
+            if !found {
+                println!("No keys sealed to the TPM");
+            }
+            Ok(())
+        }
+        TpmCommand::Remove { name } => {
+            let path = blob(&name, "json");
+            if !path.exists() {
+                return Err(format!("No key sealed as {}", name));
+            }
+            for ext in ["json", "pub", "priv"] {
+                let path = blob(&name, ext);
+                fs::remove_file(&path)
+                    .map_err(|e| format!("Can't remove {}: {}", path.display(), e))?;
+            }
+            println!("Removed {}", name);
+            Ok(())
+        }
+    }
+}
+
+/// Unseal the private key sealed to the TPM as `name`.
+pub fn unseal(name: &str) -> Result<PrivateKey, String> {
+    let path = blob(name, "json");
+    if !path.exists() {
+        return Err(format!("No key sealed as {}, see `tpm list`", name));
+    }
+    let sealed = load(&path)?;
+    let wif = with_scratch(name, |scratch| {
+        load_object(name, scratch)?;
+        let mut unseal = tool("tpm2_unseal");
+        unseal.arg("-c").arg(scratch.join("key.ctx"));
+        if let Some(pcrs) = &sealed.pcrs {
+            unseal.arg("-p").arg(format!("pcr:{}", pcrs));
+        }
+        run_tool(&mut unseal, None)
+    })?;
+    let wif =
+        String::from_utf8(wif).map_err(|_| format!("The TPM unsealed garbage for {}", name))?;
+    PrivateKey::from_str(wif.trim())
+        .map_err(|_| format!("The TPM unsealed an invalid key for {}", name))
+}
+
+fn seal(name: &str, key: Option<PrivateKey>, pcrs: Option<String>) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(format!(
+            "Invalid name \"{}\", use letters, digits, '-', '_' and '.'",
+            name
+        ));
+    }
+    if blob(name, "json").exists() {
+        return Err(format!("A key is already sealed as {}", name));
+    }
+    let key = match key {
+        Some(key) => key,
+        None => {
+            let wif = secret::read(&format!("WIF private key to seal as {}", name), "-")?;
+            parse::private_key(&wif).map_err(|e| format!("Not a valid private key: {}", e))?
+        }
+    };
+
+    fs::create_dir_all(dir()).map_err(|e| format!("Can't create {}: {}", dir().display(), e))?;
+    with_scratch(name, |scratch| {
+        let primary = scratch.join("primary.ctx");
+        create_primary(&primary)?;
+        let mut create = tool("tpm2_create");
+        create.arg("-C").arg(&primary);
+        if let Some(pcrs) = &pcrs {
+            let policy = scratch.join("policy.dat");
+            run_tool(
+                tool("tpm2_createpolicy")
+                    .args(["--policy-pcr", "-l", pcrs, "-L"])
+                    .arg(&policy),
+                None,
+            )?;
+            // Without userwithauth the PCR policy is the only way in.
+            create
+                .arg("-L")
+                .arg(&policy)
+                .args(["-a", "fixedtpm|fixedparent"]);
+        }
+        create
+            .args(["-i", "-", "-u"])
+            .arg(blob(name, "pub"))
+            .arg("-r")
+            .arg(blob(name, "priv"));
+        run_tool(&mut create, Some(key.to_wif().as_bytes()))?;
+        Ok(())
+    })?;
+
+    let sealed = Sealed {
+        name: name.to_string(),
+        public_key: key.public_key(&secp256k1::Secp256k1::new()).to_string(),
+        pcrs,
+    };
+    let path = blob(name, "json");
+    fs::write(&path, serde_json::to_string_pretty(&sealed).unwrap())
+        .map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+    // Make sure the key comes back out before anyone deletes their copy.
+    if unseal(name).ok() != Some(key) {
+        for ext in ["json", "pub", "priv"] {
+            let _ = fs::remove_file(blob(name, ext));
+        }
+        return Err("The TPM doesn't unseal the key it sealed, nothing was kept".to_string());
+    }
+    println!("Sealed {} as tpm:{}", sealed.public_key, name);
+    Ok(())
+}
+
+// Load the sealed object `name` under the storage primary key, into
+// `scratch`/key.ctx.
+fn load_object(name: &str, scratch: &Path) -> Result<(), String> {
+    let primary = scratch.join("primary.ctx");
+    create_primary(&primary)?;
+    run_tool(
+        tool("tpm2_load")
+            .arg("-C")
+            .arg(&primary)
+            .arg("-u")
+            .arg(blob(name, "pub"))
+            .arg("-r")
+            .arg(blob(name, "priv"))
+            .arg("-c")
+            .arg(scratch.join("key.ctx")),
+        None,
+    )
+    .map(drop)
+}
+
+// The primary key is derived from the TPM's owner seed and the template, so
+// it comes out the same every time and never has to be stored.
+fn create_primary(ctx: &Path) -> Result<(), String> {
+    run_tool(
+        tool("tpm2_createprimary")
+            .args(["-C", "o", "-g", "sha256", "-G", "ecc", "-c"])
+            .arg(ctx),
+        None,
+    )
+    .map(drop)
+}
+
+// Run `f` with a scratch directory for the TPM's context files, removing it
+// afterwards.
+fn with_scratch<T>(name: &str, f: impl FnOnce(&Path) -> Result<T, String>) -> Result<T, String> {
+    let scratch = dir().join(format!(".{}.{}", name, process::id()));
+    fs::create_dir_all(&scratch)
+        .map_err(|e| format!("Can't create {}: {}", scratch.display(), e))?;
+    let result = f(&scratch);
+    let _ = fs::remove_dir_all(&scratch);
+    result
+}
+
+fn tool(name: &str) -> Command {
+    let mut command = Command::new(name);
+    command.arg("-Q");
+    command
+}
+
+// Run a tpm2-tools command, feeding it `input`, and return what it prints.
+fn run_tool(command: &mut Command, input: Option<&[u8]>) -> Result<Vec<u8>, String> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Can't run {}, are tpm2-tools installed? {}", name, e))?;
+    let mut stdin = child.stdin.take().unwrap();
+    if let Some(input) = input {
+        stdin
+            .write_all(input)
+            .map_err(|e| format!("Can't talk to {}: {}", name, e))?;
+    }
+    drop(stdin);
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Can't talk to {}: {}", name, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+fn dir() -> PathBuf {
+    wallet::data_dir().join("tpm")
+}
+
+fn blob(name: &str, ext: &str) -> PathBuf {
+    dir().join(format!("{}.{}", name, ext))
+}
+
+fn load(path: &Path) -> Result<Sealed, String> {
+    let json =
+        fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    serde_json::from_str(&json).map_err(|e| format!("{} is corrupt: {}", path.display(), e))
+}
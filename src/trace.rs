@@ -0,0 +1,91 @@
+use miniscript::bitcoin::psbt::{self, Psbt};
+
+/// Reports the PSBT after each stage of processing on stderr.
+///
+/// With one `-v` only a summary of what changed is printed, with `-vv` the
+/// serialized PSBT follows each summary so it can be fed to other tools.
+pub struct Tracer {
+    verbosity: u8,
+    last: Option<Psbt>,
+}
+
+impl Tracer {
+    pub fn new(verbosity: u8) -> Self {
+        Tracer {
+            verbosity,
+            last: None,
+        }
+    }
+
+    pub fn stage(&mut self, stage: &str, psbt: &Psbt) {
+        if self.verbosity == 0 {
+            return;
+        }
+
+        let changes = match &self.last {
+            Some(last) => changes(last, psbt),
+            None => vec![format!(
+                "{} input(s), {} output(s)",
+                psbt.inputs.len(),
+                psbt.outputs.len()
+            )],
+        };
+        eprintln!("[{}] {}", stage, changes.join("; "));
+        if self.verbosity >= 2 {
+            eprintln!("{}", psbt);
+        }
+        self.last = Some(psbt.clone());
+    }
+}
+
+// Describe which fields differ between two versions of a PSBT.
+fn changes(before: &Psbt, after: &Psbt) -> Vec<String> {
+    let mut changes = Vec::new();
+    if before.unsigned_tx != after.unsigned_tx {
+        changes.push("unsigned transaction".to_string());
+    }
+    for (i, (b, a)) in before.inputs.iter().zip(&after.inputs).enumerate() {
+        let fields = input_changes(b, a);
+        if !fields.is_empty() {
+            changes.push(format!("input {}: {}", i, fields.join(", ")));
+        }
+    }
+    for (i, (b, a)) in before.outputs.iter().zip(&after.outputs).enumerate() {
+        if b != a {
+            changes.push(format!("output {}", i));
+        }
+    }
+    if changes.is_empty() {
+        changes.push("no changes".to_string());
+    }
+    changes
+}
+
+fn input_changes(before: &psbt::Input, after: &psbt::Input) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    macro_rules! compare {
+        ($($field:ident),*) => {
+            $(if before.$field != after.$field {
+                fields.push(stringify!($field));
+            })*
+        };
+    }
+    compare!(
+        non_witness_utxo,
+        witness_utxo,
+        partial_sigs,
+        sighash_type,
+        redeem_script,
+        witness_script,
+        bip32_derivation,
+        final_script_sig,
+        final_script_witness,
+        tap_key_sig,
+        tap_script_sigs,
+        tap_scripts,
+        tap_key_origins,
+        tap_internal_key,
+        tap_merkle_root
+    );
+    fields
+}
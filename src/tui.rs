@@ -0,0 +1,453 @@
+use std::io;
+
+use clap::Args;
+use miniscript::bitcoin::consensus::encode::serialize_hex;
+use miniscript::bitcoin::Psbt;
+use miniscript::bitcoin::{self, secp256k1, Address, Amount, OutPoint, Transaction, TxOut};
+use miniscript::descriptor::DefiniteDescriptorKey;
+use miniscript::Descriptor;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::{build, clipboard, network, parse, policy, sign};
+
+#[derive(Args)]
+pub struct TuiArgs {
+    /// The descriptor controlling the wallet's coins.
+    #[arg(value_parser = parse::descriptor)]
+    descriptor: Descriptor<DefiniteDescriptorKey>,
+    /// Transactions funding the descriptor, their outputs paying it are the
+    /// coins available for spending.
+    #[arg(value_parser = parse::transaction, required = true)]
+    funding: Vec<Transaction>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pane {
+    Utxos,
+    Composer,
+    Inspector,
+}
+
+enum Mode {
+    Normal,
+    Editing,
+    KeyPrompt(String),
+}
+
+struct Utxo {
+    outpoint: OutPoint,
+    txout: TxOut,
+    // The funding transaction, for legacy and nested segwit descriptors.
+    tx: Transaction,
+    selected: bool,
+}
+
+struct App {
+    secp: secp256k1::Secp256k1<secp256k1::All>,
+    descriptor: Descriptor<DefiniteDescriptorKey>,
+    utxos: Vec<Utxo>,
+    utxo_list: ListState,
+    focus: Pane,
+    mode: Mode,
+    // Composer fields: recipient address or descriptor and amount.
+    fields: [String; 2],
+    field: usize,
+    psbt: Option<Psbt>,
+    finalized: Option<Transaction>,
+    status: String,
+    quit: bool,
+}
+
+const FIELD_NAMES: [&str; 2] = ["Recipient", "Amount"];
+
+pub fn run(args: TuiArgs) -> Result<(), String> {
+    let spk = args.descriptor.script_pubkey();
+    let utxos: Vec<Utxo> = args
+        .funding
+        .iter()
+        .flat_map(|tx| {
+            let txid = tx.compute_txid();
+            tx.output
+                .iter()
+                .enumerate()
+                .filter(|(_, txout)| txout.script_pubkey == spk)
+                .map(move |(vout, txout)| Utxo {
+                    outpoint: OutPoint::new(txid, vout as u32),
+                    txout: txout.clone(),
+                    tx: tx.clone(),
+                    selected: false,
+                })
+        })
+        .collect();
+    if utxos.is_empty() {
+        return Err("None of the funding transactions pay to the descriptor".to_string());
+    }
+
+    let mut app = App {
+        secp: secp256k1::Secp256k1::new(),
+        descriptor: args.descriptor,
+        utxos,
+        utxo_list: ListState::default().with_selected(Some(0)),
+        focus: Pane::Utxos,
+        mode: Mode::Normal,
+        fields: Default::default(),
+        field: 0,
+        psbt: None,
+        finalized: None,
+        status: "Select coins with space, tab to the composer.".to_string(),
+        quit: false,
+    };
+
+    let mut terminal =
+        ratatui::try_init().map_err(|e| format!("Can't initialize the terminal: {}", e))?;
+    let result = app.event_loop(&mut terminal);
+    ratatui::restore();
+    result.map_err(|e| format!("Terminal error: {}", e))?;
+
+    // Leave the result where scripts and the user can pick it up.
+    if let Some(psbt) = &app.psbt {
+        println!("{}", psbt);
+    }
+    if let Some(tx) = &app.finalized {
+        println!("raw: {}", serialize_hex(tx));
+    }
+    Ok(())
+}
+
+impl App {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.handle_key(key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        match &mut self.mode {
+            Mode::Editing => match key.code {
+                KeyCode::Enter | KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Backspace => {
+                    self.fields[self.field].pop();
+                }
+                KeyCode::Char(c) => self.fields[self.field].push(c),
+                _ => {}
+            },
+            Mode::KeyPrompt(input) => match key.code {
+                KeyCode::Enter => {
+                    let input = std::mem::take(input);
+                    self.mode = Mode::Normal;
+                    self.sign(&input);
+                }
+                KeyCode::Esc => {
+                    self.mode = Mode::Normal;
+                    self.status = "Signing cancelled.".to_string();
+                }
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            },
+            Mode::Normal => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+                KeyCode::Tab => {
+                    self.focus = match self.focus {
+                        Pane::Utxos => Pane::Composer,
+                        Pane::Composer => Pane::Inspector,
+                        Pane::Inspector => Pane::Utxos,
+                    }
+                }
+                _ => match self.focus {
+                    Pane::Utxos => self.utxos_key(key.code),
+                    Pane::Composer => self.composer_key(key.code),
+                    Pane::Inspector => self.inspector_key(key.code),
+                },
+            },
+        }
+    }
+
+    fn utxos_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Up => self.utxo_list.select_previous(),
+            KeyCode::Down => self.utxo_list.select_next(),
+            KeyCode::Char(' ') => {
+                if let Some(utxo) = self
+                    .utxo_list
+                    .selected()
+                    .and_then(|i| self.utxos.get_mut(i))
+                {
+                    utxo.selected = !utxo.selected;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn composer_key(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Up => self.field = self.field.saturating_sub(1),
+            KeyCode::Down => self.field = (self.field + 1).min(FIELD_NAMES.len() - 1),
+            KeyCode::Enter => self.mode = Mode::Editing,
+            KeyCode::Char('b') => self.compose(),
+            _ => {}
+        }
+    }
+
+    fn inspector_key(&mut self, code: KeyCode) {
+        if self.psbt.is_none() {
+            self.status = "Build a PSBT in the composer first.".to_string();
+            return;
+        }
+        match code {
+            KeyCode::Char('s') => self.mode = Mode::KeyPrompt(String::new()),
+            KeyCode::Char('f') => self.finalize(),
+            KeyCode::Char('c') => {
+                let psbt = self.psbt.as_ref().unwrap().to_string();
+                self.status = match clipboard::write(&psbt) {
+                    Ok(()) => "PSBT copied to the clipboard.".to_string(),
+                    Err(e) => format!("Can't copy the PSBT: {}", e),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn compose(&mut self) {
+        self.status = match self.try_compose() {
+            Ok(fee) => {
+                self.focus = Pane::Inspector;
+                format!("PSBT built paying {} sat in fees, press s to sign.", fee)
+            }
+            Err(e) => e,
+        };
+    }
+
+    fn try_compose(&mut self) -> Result<u64, String> {
+        let recipient = parse::recipient(self.fields[0].trim())?;
+        let amount = parse::amount(self.fields[1].trim())?.to_sat();
+        let selected: Vec<&Utxo> = self.utxos.iter().filter(|u| u.selected).collect();
+        if selected.is_empty() {
+            return Err("Select at least one coin to spend.".to_string());
+        }
+        let total: u64 = selected.iter().map(|u| u.txout.value.to_sat()).sum();
+        let fee = total
+            .checked_sub(amount)
+            .ok_or_else(|| format!("Selected coins only add up to {} sat.", total))?;
+
+        let outpoints: Vec<OutPoint> = selected.iter().map(|u| u.outpoint).collect();
+        let mut psbt = build::create_psbt(
+            &outpoints,
+            vec![TxOut {
+                script_pubkey: recipient,
+                value: Amount::from_sat(amount),
+            }],
+        );
+        for (input, utxo) in psbt.inputs.iter_mut().zip(&selected) {
+            build::update_input(
+                input,
+                &self.descriptor,
+                utxo.txout.clone(),
+                Some(utxo.tx.clone()),
+            )?;
+        }
+        let descriptors = vec![&self.descriptor; psbt.inputs.len()];
+        policy::check_standard(&psbt, &descriptors)?;
+        policy::check_locktime(&psbt, &descriptors)?;
+        self.psbt = Some(psbt);
+        self.finalized = None;
+        Ok(fee)
+    }
+
+    fn sign(&mut self, wif: &str) {
+        let key = match parse::private_key(wif.trim()) {
+            Ok(key) => key,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        let psbt = self.psbt.as_mut().unwrap();
+        self.status = match sign::sign_inputs(
+            &self.secp,
+            psbt,
+            &[key],
+            bitcoin::EcdsaSighashType::All,
+            |_, _, _| {},
+        ) {
+            Ok(()) => format!("Signed with {}.", key.public_key(&self.secp)),
+            Err(e) => format!("Can't compute the sighash: {}", e),
+        };
+    }
+
+    fn finalize(&mut self) {
+        let mut psbt = self.psbt.clone().unwrap();
+        self.status = match build::finalize(&self.secp, &mut psbt) {
+            Ok(()) => {
+                let tx = psbt.clone().extract_tx_unchecked_fee_rate();
+                let status = format!("Finalized {}, quit to print it.", tx.compute_txid());
+                self.psbt = Some(psbt);
+                self.finalized = Some(tx);
+                status
+            }
+            Err(e) => e.to_string(),
+        };
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [top, inspector, status] = Layout::vertical([
+            Constraint::Percentage(40),
+            Constraint::Min(6),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+        let [utxos, composer] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(top);
+
+        self.draw_utxos(frame, utxos);
+        self.draw_composer(frame, composer);
+        self.draw_inspector(frame, inspector);
+
+        let help = match self.mode {
+            Mode::Normal => match self.focus {
+                Pane::Utxos => "↑/↓ move  space select  tab next pane  q quit",
+                Pane::Composer => "↑/↓ field  enter edit  b build PSBT  tab next pane  q quit",
+                Pane::Inspector => "s sign  f finalize  c copy PSBT  tab next pane  q quit",
+            },
+            Mode::Editing => "type to edit  enter done",
+            Mode::KeyPrompt(_) => "enter the WIF private key  enter sign  esc cancel",
+        };
+        frame.render_widget(
+            Paragraph::new(vec![
+                Line::from(self.status.as_str()),
+                Line::styled(help, Style::default().fg(Color::DarkGray)),
+            ]),
+            status,
+        );
+    }
+
+    fn block(&self, title: &'static str, pane: Pane) -> Block<'static> {
+        let style = if self.focus == pane {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default()
+        };
+        Block::bordered().title(title).border_style(style)
+    }
+
+    fn draw_utxos(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .utxos
+            .iter()
+            .map(|utxo| {
+                ListItem::new(format!(
+                    "[{}] {} {:>12} sat",
+                    if utxo.selected { "x" } else { " " },
+                    utxo.outpoint,
+                    utxo.txout.value.to_sat()
+                ))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(self.block("Coins", Pane::Utxos))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.utxo_list);
+    }
+
+    fn draw_composer(&self, frame: &mut Frame, area: Rect) {
+        let mut lines: Vec<Line> = FIELD_NAMES
+            .iter()
+            .zip(&self.fields)
+            .enumerate()
+            .map(|(i, (name, value))| {
+                let editing = matches!(self.mode, Mode::Editing) && i == self.field;
+                let style = if i == self.field && self.focus == Pane::Composer {
+                    Style::default().add_modifier(Modifier::REVERSED)
+                } else {
+                    Style::default()
+                };
+                Line::styled(
+                    format!("{:<8} {}{}", name, value, if editing { "_" } else { "" }),
+                    style,
+                )
+            })
+            .collect();
+        let selected: u64 = self
+            .utxos
+            .iter()
+            .filter(|u| u.selected)
+            .map(|u| u.txout.value.to_sat())
+            .sum();
+        lines.push(Line::from(""));
+        lines.push(Line::from(format!("Selected {} sat", selected)));
+        frame.render_widget(
+            Paragraph::new(lines).block(self.block("Compose", Pane::Composer)),
+            area,
+        );
+    }
+
+    fn draw_inspector(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = Vec::new();
+        match &self.psbt {
+            None => lines.push(Line::from("No PSBT yet.")),
+            Some(psbt) => {
+                lines.push(Line::from(format!(
+                    "Spending {}",
+                    psbt.unsigned_tx.compute_txid()
+                )));
+                let mut total = 0;
+                for (txin, input) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs) {
+                    let value = input.witness_utxo.as_ref().map_or(0, |u| u.value.to_sat());
+                    total += value;
+                    let state = if input.final_script_witness.is_some() {
+                        "finalized".to_string()
+                    } else {
+                        format!("{} signature(s)", input.partial_sigs.len())
+                    };
+                    lines.push(Line::from(format!(
+                        "  in  {} {} sat, {}",
+                        txin.previous_output, value, state
+                    )));
+                }
+                let mut spent = 0;
+                for txout in &psbt.unsigned_tx.output {
+                    spent += txout.value.to_sat();
+                    let address = Address::from_script(&txout.script_pubkey, network())
+                        .map(|a| a.to_string())
+                        .unwrap_or_else(|_| format!("{:x}", txout.script_pubkey));
+                    lines.push(Line::from(format!(
+                        "  out {} {} sat",
+                        address,
+                        txout.value.to_sat()
+                    )));
+                }
+                lines.push(Line::from(format!(
+                    "  fee {} sat",
+                    total.saturating_sub(spent)
+                )));
+            }
+        }
+        if let Mode::KeyPrompt(input) = &self.mode {
+            lines.push(Line::from(""));
+            lines.push(Line::from(format!(
+                "Private key: {}_",
+                "*".repeat(input.len())
+            )));
+        }
+        frame.render_widget(
+            Paragraph::new(lines)
+                .wrap(Wrap { trim: false })
+                .block(self.block("PSBT", Pane::Inspector)),
+            area,
+        );
+    }
+}
@@ -0,0 +1,526 @@
+use std::collections::BTreeSet;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use clap::Subcommand;
+use miniscript::bitcoin::hashes::{sha256, Hash};
+use miniscript::bitcoin::Psbt;
+
+use crate::{psbtv2, qr, stage};
+
+/// The UR type of a PSBT, as BCR-2020-006 registers it. Newer wallets also
+/// send `psbt`, which is read the same.
+const UR_TYPE: &str = "crypto-psbt";
+/// The largest fragment of the PSBT a part carries, small enough for a QR
+/// code of about version 12 that fits a terminal and scans quickly.
+const MAX_FRAGMENT_LEN: usize = 150;
+/// The smallest fragment worth splitting into, as the reference encoder
+/// uses.
+const MIN_FRAGMENT_LEN: usize = 10;
+/// How long each part of an animated QR code is shown.
+const FRAME: Duration = Duration::from_millis(250);
+/// The 256 bytewords, four letters each, in the order of the byte they
+/// stand for. Minimal bytewords keep only the first and last letters.
+const BYTEWORDS: &str = "\
+    ableacidalsoapexaquaarchatomauntawayaxisbackbaldbarnbeltbetabias\
+    bluebodybragbrewbulbbuzzcalmcashcatschefcityclawcodecolacookcost\
+    cruxcurlcuspcyandarkdatadaysdelidicedietdoordowndrawdropdrumdull\
+    dutyeacheasyechoedgeepicevenexamexiteyesfactfairfernfigsfilmfish\
+    fizzflapflewfluxfoxyfreefrogfuelfundgalagamegeargemsgiftgirlglow\
+    goodgraygrimgurugushgyrohalfhanghardhawkheathelphighhillholyhope\
+    hornhutsicedideaidleinchinkyintoirisironitemjadejazzjoinjoltjowl\
+    judojugsjumpjunkjurykeepkenokeptkeyskickkilnkingkitekiwiknoblamb\
+    lavalazyleaflegsliarlimplionlistlogoloudloveluaulucklungmainmany\
+    mathmazememomenumeowmildmintmissmonknailnavyneednewsnextnoonnote\
+    numbobeyoboeomitonyxopenovalowlspaidpartpeckplaypluspoempoolpose\
+    puffpumapurrquadquizraceramprealredorichroadrockroofrubyruinruns\
+    rustsafesagascarsetssilkskewslotsoapsolosongstubsurfswantacotask\
+    taxitenttiedtimetinytoiltombtoystriptunatwinuglyundouniturgeuser\
+    vastveryvetovialvibeviewvisavoidvowswallwandwarmwaspwavewaxywebs\
+    whatwhenwhizwolfworkyankyawnyellyogayurtzapszerozestzinczonezoom";
+
+#[derive(Subcommand)]
+pub enum UrCommand {
+    /// Split a PSBT into UR crypto-psbt parts, printed one per line, or
+    /// shown or saved as QR codes with --qr or --qr-png.
+    Encode(stage::StageArgs),
+    /// Join UR crypto-psbt parts back into the base64 PSBT.
+    Decode {
+        /// The parts, as their text or images of their QR codes, in any
+        /// order. Beyond the first, fountain parts stand in for any that
+        /// were missed.
+        #[arg(required = true)]
+        parts: Vec<String>,
+    },
+}
+
+pub fn run(command: UrCommand) -> Result<(), String> {
+    match command {
+        UrCommand::Encode(args) => {
+            let psbt = args.psbt.load()?;
+            if args.out.to_file() {
+                return args.out.store(psbt);
+            }
+            let encoder = Encoder::new(&args.out.serialize(&psbt));
+            for seq in 1..=encoder.seq_len {
+                println!("{}", encoder.part(seq));
+            }
+            Ok(())
+        }
+        UrCommand::Decode { parts } => {
+            println!("{}", load(&parts)?);
+            Ok(())
+        }
+    }
+}
+
+/// Show the binary `psbt` as a QR code in the terminal, cycling through its parts and
+/// then endless fountain parts until interrupted if it takes several.
+pub fn show(psbt: &[u8]) -> Result<(), String> {
+    let mut stdout = io::stdout();
+    if !stdout.is_terminal() {
+        return Err("--qr needs a terminal to show the QR code on".to_string());
+    }
+    let encoder = Encoder::new(psbt);
+    let frame = |seq| qr::encode(&encoder.part(seq).to_uppercase()).map(|code| code.to_terminal());
+    if encoder.seq_len == 1 {
+        print!("{}", frame(1)?);
+        return Ok(());
+    }
+    eprintln!(
+        "Showing the PSBT as {} parts and fountain parts mixing them, press Ctrl-C once scanned",
+        encoder.seq_len
+    );
+    let mut lines = 0;
+    for seq in 1.. {
+        let frame = frame(seq)?;
+        // Draw over the last frame, clearing what a larger one left.
+        if lines > 0 {
+            print!("\x1b[{}A\x1b[J", lines);
+        }
+        print!("{}", frame);
+        stdout.flush().map_err(|e| e.to_string())?;
+        lines = frame.lines().count();
+        thread::sleep(FRAME);
+    }
+    Ok(())
+}
+
+/// Write the QR codes of the binary `psbt`'s parts as `<prefix>-1.png` and onwards.
+pub fn save_pngs(psbt: &[u8], prefix: &Path) -> Result<(), String> {
+    let encoder = Encoder::new(psbt);
+    for seq in 1..=encoder.seq_len {
+        let mut path = PathBuf::from(prefix).into_os_string();
+        path.push(format!("-{}.png", seq));
+        let path = PathBuf::from(path);
+        qr::encode(&encoder.part(seq).to_uppercase())?.save_png(&path)?;
+        eprintln!(
+            "Wrote part {} of {} to {}",
+            seq,
+            encoder.seq_len,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Read a PSBT from UR parts, as their text or images of their QR codes.
+pub fn load(parts: &[String]) -> Result<Psbt, String> {
+    let mut texts = Vec::new();
+    for part in parts {
+        if part.trim().to_lowercase().starts_with("ur:") {
+            texts.push(part.clone());
+        } else {
+            texts.extend(qr::decode_image(Path::new(part))?);
+        }
+    }
+    let message = decode(&texts)?;
+    let bytes = Cbor(&message).bytes()?;
+    psbtv2::deserialize_any(bytes).map_err(|e| format!("Invalid PSBT: {}", e))
+}
+
+/// Splits a PSBT into the parts of a multi-part UR, as BCR-2020-005's
+/// fountain encoder does: the first `seq_len` parts each carry a fragment,
+/// and those after mix several, so a scanner can fill in any it missed.
+pub struct Encoder {
+    fragments: Vec<Vec<u8>>,
+    message_len: usize,
+    checksum: u32,
+    pub seq_len: u32,
+}
+
+impl Encoder {
+    pub fn new(psbt: &[u8]) -> Encoder {
+        let mut message = Vec::new();
+        cbor_bytes(&mut message, psbt);
+        let fragment_len = fragment_len(message.len());
+        let checksum = crc32(&message);
+        let message_len = message.len();
+        message.resize(message_len.next_multiple_of(fragment_len), 0);
+        let fragments: Vec<Vec<u8>> = message.chunks(fragment_len).map(<[u8]>::to_vec).collect();
+        Encoder {
+            seq_len: fragments.len() as u32,
+            fragments,
+            message_len,
+            checksum,
+        }
+    }
+
+    /// The part numbered `seq`, from 1. A message fitting one fragment is a
+    /// single part UR without sequence numbers.
+    pub fn part(&self, seq: u32) -> String {
+        if self.seq_len == 1 {
+            let message = &self.fragments[0][..self.message_len];
+            return format!("ur:{}/{}", UR_TYPE, bytewords(message));
+        }
+        let mut fragment = vec![0; self.fragments[0].len()];
+        for i in choose_fragments(seq, self.seq_len, self.checksum) {
+            xor(&mut fragment, &self.fragments[i]);
+        }
+        let mut body = Vec::new();
+        cbor_head(&mut body, 4, 5);
+        cbor_head(&mut body, 0, seq as u64);
+        cbor_head(&mut body, 0, self.seq_len as u64);
+        cbor_head(&mut body, 0, self.message_len as u64);
+        cbor_head(&mut body, 0, self.checksum as u64);
+        cbor_bytes(&mut body, &fragment);
+        format!(
+            "ur:{}/{}-{}/{}",
+            UR_TYPE,
+            seq,
+            self.seq_len,
+            bytewords(&body)
+        )
+    }
+}
+
+/// Join UR parts, given in any order and with fountain parts standing in
+/// for missing ones, into the CBOR message they carry.
+pub fn decode(parts: &[String]) -> Result<Vec<u8>, String> {
+    // The sequence's length, message length and checksum, which every
+    // part must agree on.
+    let mut header = None;
+    let mut fragments: Vec<Option<Vec<u8>>> = Vec::new();
+    let mut mixed: Vec<(BTreeSet<usize>, Vec<u8>)> = Vec::new();
+    for part in parts {
+        let part = part.trim().to_lowercase();
+        let rest = part
+            .strip_prefix("ur:")
+            .ok_or_else(|| format!("Not a UR part: {}", part))?;
+        let (ur_type, rest) = rest
+            .split_once('/')
+            .ok_or_else(|| format!("Not a UR part: {}", part))?;
+        if ur_type != UR_TYPE && ur_type != "psbt" {
+            return Err(format!("Expected a {} UR, not {}", UR_TYPE, ur_type));
+        }
+        let Some((_, body)) = rest.split_once('/') else {
+            if parts.len() > 1 {
+                return Err("A single part UR can't be joined with other parts".to_string());
+            }
+            return from_bytewords(rest);
+        };
+        let body = from_bytewords(body)?;
+        let mut cbor = Cbor(&body);
+        let invalid = |e: String| format!("Invalid UR part {}: {}", part, e);
+        if cbor.head().map_err(invalid)? != (4, 5) {
+            return Err(invalid("not a five element array".to_string()));
+        }
+        let mut uint = || cbor.uint().map_err(invalid);
+        let (seq, seq_len, message_len, checksum) = (uint()?, uint()?, uint()?, uint()?);
+        let fragment = cbor.bytes().map_err(invalid)?.to_vec();
+        if seq == 0 {
+            return Err(invalid("parts are numbered from 1".to_string()));
+        }
+        match header {
+            None => {
+                if fragment.is_empty() || seq_len != message_len.div_ceil(fragment.len() as u64) {
+                    return Err(invalid("inconsistent lengths".to_string()));
+                }
+                header = Some((seq_len, message_len, checksum, fragment.len()));
+                fragments = vec![None; seq_len as usize];
+            }
+            Some(first) if first != (seq_len, message_len, checksum, fragment.len()) => {
+                return Err(format!(
+                    "Part {} belongs to a different UR than the others",
+                    part
+                ))
+            }
+            Some(_) => {}
+        }
+        let indexes = choose_fragments(seq as u32, seq_len as u32, checksum as u32);
+        mixed.push((indexes.into_iter().collect(), fragment));
+    }
+    let (_, message_len, checksum, _) = header.ok_or("No UR parts given")?;
+
+    // Peel the known fragments off the mixed ones until no more single
+    // fragments come out of them.
+    loop {
+        let mut progress = false;
+        for (indexes, fragment) in &mut mixed {
+            for i in indexes.clone() {
+                if let Some(known) = &fragments[i] {
+                    if indexes.len() > 1 {
+                        xor(fragment, known);
+                        indexes.remove(&i);
+                    }
+                }
+            }
+            if let Some(&i) = indexes.first().filter(|_| indexes.len() == 1) {
+                if fragments[i].is_none() {
+                    fragments[i] = Some(fragment.clone());
+                    progress = true;
+                }
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    let missing = fragments.iter().filter(|f| f.is_none()).count();
+    if missing > 0 {
+        return Err(format!(
+            "{} of {} fragments are still missing, scan more parts",
+            missing,
+            fragments.len()
+        ));
+    }
+    let mut message: Vec<u8> = fragments.into_iter().flatten().flatten().collect();
+    message.truncate(message_len as usize);
+    if crc32(&message) as u64 != checksum {
+        return Err("The joined UR's checksum doesn't match".to_string());
+    }
+    Ok(message)
+}
+
+// The fragment length splitting `len` bytes into the fewest fragments of
+// at most `MAX_FRAGMENT_LEN`, as evenly as possible.
+fn fragment_len(len: usize) -> usize {
+    let max_count = (len / MIN_FRAGMENT_LEN).max(1);
+    (1..=max_count)
+        .map(|count| len.div_ceil(count))
+        .find(|&fragment_len| fragment_len <= MAX_FRAGMENT_LEN)
+        .unwrap_or(MIN_FRAGMENT_LEN)
+}
+
+// The indexes of the fragments part `seq` mixes: its own for the first
+// `seq_len` parts, then a random degree of them drawn from a generator
+// seeded by the part and checksum, so encoder and decoder agree.
+fn choose_fragments(seq: u32, seq_len: u32, checksum: u32) -> Vec<usize> {
+    if seq <= seq_len {
+        return vec![seq as usize - 1];
+    }
+    let mut seed = seq.to_be_bytes().to_vec();
+    seed.extend(checksum.to_be_bytes());
+    let mut rng = Xoshiro256::new(&seed);
+    let degree = choose_degree(seq_len as usize, &mut rng);
+    let mut remaining: Vec<usize> = (0..seq_len as usize).collect();
+    let mut shuffled = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let i = rng.next_int(remaining.len() as u64 - 1) as usize;
+        shuffled.push(remaining.remove(i));
+    }
+    shuffled.truncate(degree);
+    shuffled
+}
+
+// Draw how many fragments to mix, degree d having a probability
+// proportional to 1/d, with Vose's alias method as the reference
+// implementation samples it.
+fn choose_degree(seq_len: usize, rng: &mut Xoshiro256) -> usize {
+    let weights: Vec<f64> = (1..=seq_len).map(|d| 1.0 / d as f64).collect();
+    let sum: f64 = weights.iter().sum();
+    let mut scaled: Vec<f64> = weights.iter().map(|w| w * seq_len as f64 / sum).collect();
+    let (mut small, mut large) = (Vec::new(), Vec::new());
+    for i in (0..seq_len).rev() {
+        if scaled[i] < 1.0 {
+            small.push(i);
+        } else {
+            large.push(i);
+        }
+    }
+    let mut probabilities = vec![0.0; seq_len];
+    let mut aliases = vec![0; seq_len];
+    while let (Some(&a), Some(&g)) = (small.last(), large.last()) {
+        small.pop();
+        large.pop();
+        probabilities[a] = scaled[a];
+        aliases[a] = g;
+        scaled[g] += scaled[a] - 1.0;
+        if scaled[g] < 1.0 {
+            small.push(g);
+        } else {
+            large.push(g);
+        }
+    }
+    for i in large.into_iter().chain(small) {
+        probabilities[i] = 1.0;
+    }
+    let (r1, r2) = (rng.next_double(), rng.next_double());
+    let i = (seq_len as f64 * r1) as usize;
+    1 + if r2 < probabilities[i] { i } else { aliases[i] }
+}
+
+// The xoshiro256** generator, seeded with the SHA256 of some bytes.
+struct Xoshiro256([u64; 4]);
+
+impl Xoshiro256 {
+    fn new(seed: &[u8]) -> Xoshiro256 {
+        let digest = sha256::Hash::hash(seed).to_byte_array();
+        let mut state = [0; 4];
+        for (word, bytes) in state.iter_mut().zip(digest.chunks(8)) {
+            *word = u64::from_be_bytes(bytes.try_into().unwrap());
+        }
+        Xoshiro256(state)
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.0;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    fn next_double(&mut self) -> f64 {
+        self.next() as f64 / (u64::MAX as f64 + 1.0)
+    }
+
+    // A number from 0 to `high` inclusive.
+    fn next_int(&mut self, high: u64) -> u64 {
+        (self.next_double() * (high + 1) as f64) as u64
+    }
+}
+
+fn xor(target: &mut [u8], other: &[u8]) {
+    for (t, o) in target.iter_mut().zip(other) {
+        *t ^= o;
+    }
+}
+
+// Minimal bytewords of `data` followed by its CRC32.
+fn bytewords(data: &[u8]) -> String {
+    let words = BYTEWORDS.as_bytes();
+    data.iter()
+        .chain(&crc32(data).to_be_bytes())
+        .flat_map(|&b| {
+            let word = &words[b as usize * 4..b as usize * 4 + 4];
+            [word[0] as char, word[3] as char]
+        })
+        .collect()
+}
+
+// Decode minimal bytewords, checking and dropping the CRC32 at the end.
+fn from_bytewords(s: &str) -> Result<Vec<u8>, String> {
+    let words = BYTEWORDS.as_bytes();
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("Invalid bytewords: {}", s));
+    }
+    let mut data = s
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            (0..256)
+                .find(|&b| words[b * 4] == pair[0] && words[b * 4 + 3] == pair[1])
+                .map(|b| b as u8)
+                .ok_or_else(|| format!("Invalid byteword {:?}", std::str::from_utf8(pair).unwrap()))
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    if data.len() < 4 {
+        return Err("The bytewords are too short for their checksum".to_string());
+    }
+    let checksum = data.split_off(data.len() - 4);
+    if crc32(&data).to_be_bytes() != checksum[..] {
+        return Err("The bytewords' checksum doesn't match".to_string());
+    }
+    Ok(data)
+}
+
+// CRC-32 as zlib and PNG compute it.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// The head of a CBOR item: its major type and argument.
+fn cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend([major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_head(out, 2, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// Reads the few CBOR items URs of PSBTs are made of.
+struct Cbor<'a>(&'a [u8]);
+
+impl<'a> Cbor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < n {
+            return Err("truncated CBOR".to_string());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn head(&mut self) -> Result<(u8, u64), String> {
+        let first = self.take(1)?[0];
+        let n = match first & 0x1f {
+            n @ 0..=23 => n as u64,
+            n @ 24..=27 => self
+                .take(1 << (n - 24))?
+                .iter()
+                .fold(0, |acc, &b| acc << 8 | b as u64),
+            _ => return Err("unsupported CBOR length".to_string()),
+        };
+        Ok((first >> 5, n))
+    }
+
+    fn uint(&mut self) -> Result<u64, String> {
+        match self.head()? {
+            (0, n) => Ok(n),
+            _ => Err("expected an unsigned integer".to_string()),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        match self.head()? {
+            (2, len) => self.take(len as usize),
+            _ => Err("expected a byte string".to_string()),
+        }
+    }
+}
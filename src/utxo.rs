@@ -0,0 +1,224 @@
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use miniscript::bitcoin::{Address, NetworkKind, OutPoint, Script, Transaction, TxOut};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::{Descriptor, ForEachKey};
+
+use crate::error::Error;
+use crate::{network, verify, Utxo};
+
+/// Find the output of `tx` paying to `descriptor`, or the output referenced by
+/// `outpoint` if one is given.
+///
+/// When nothing matches, the error explains what the descriptor expects next
+/// to what the transaction actually pays, along with likely causes.
+pub fn find(
+    tx: &Transaction,
+    outpoint: Option<OutPoint>,
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> Result<(OutPoint, TxOut), Error> {
+    let spk = descriptor.script_pubkey();
+    let txid = tx.compute_txid();
+
+    let found = match outpoint {
+        Some(outpoint) => {
+            if outpoint.txid != txid {
+                return Err(Error::UtxoNotFound(format!(
+                    "Outpoint {} doesn't reference the funding transaction {}",
+                    outpoint, txid
+                )));
+            }
+            let txout = tx.output.get(outpoint.vout as usize).ok_or_else(|| {
+                Error::UtxoNotFound(format!(
+                    "Outpoint {} is out of range, the funding transaction has {} outputs",
+                    outpoint,
+                    tx.output.len()
+                ))
+            })?;
+            Some(outpoint).filter(|_| txout.script_pubkey == spk)
+        }
+        None => tx
+            .output
+            .iter()
+            .position(|txout| txout.script_pubkey == spk)
+            .map(|vout| OutPoint::new(txid, vout as u32)),
+    };
+
+    match found {
+        Some(outpoint) => Ok((outpoint, tx.output[outpoint.vout as usize].clone())),
+        None => Err(Error::UtxoNotFound(mismatch_report(
+            tx, outpoint, descriptor,
+        ))),
+    }
+}
+
+/// Every output of `txs` paying `descriptor` at a derivation index in
+/// `range`, as a coin with its derived descriptor and creating transaction.
+pub fn paying(
+    txs: &[Transaction],
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    range: &RangeInclusive<u32>,
+) -> Result<Vec<Utxo>, Error> {
+    let descriptors = [descriptor.clone()];
+    let mut utxos = Vec::new();
+    for tx in txs {
+        for (vout, txout) in (0..).zip(&tx.output) {
+            if let Some((derived, _)) = verify::find(&descriptors, range, txout)? {
+                utxos.push(Utxo {
+                    outpoint: OutPoint::new(tx.compute_txid(), vout),
+                    txout: txout.clone(),
+                    descriptor: derived,
+                    tx: Some(tx.clone()),
+                });
+            }
+        }
+    }
+    Ok(utxos)
+}
+
+fn mismatch_report(
+    tx: &Transaction,
+    outpoint: Option<OutPoint>,
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> String {
+    let spk = descriptor.script_pubkey();
+    let mut report = String::new();
+
+    match outpoint {
+        Some(outpoint) => writeln!(
+            report,
+            "Outpoint {} doesn't pay to the descriptor.",
+            outpoint
+        ),
+        None => writeln!(
+            report,
+            "No output of funding transaction {} pays to the descriptor.",
+            tx.compute_txid()
+        ),
+    }
+    .unwrap();
+
+    writeln!(report, "\nDescriptor {}", descriptor).unwrap();
+    writeln!(report, "  script:  {:x}", spk).unwrap();
+    writeln!(report, "  address: {}", display_address(&spk)).unwrap();
+
+    writeln!(report, "\nFunding transaction outputs:").unwrap();
+    for (vout, txout) in tx.output.iter().enumerate() {
+        writeln!(
+            report,
+            "  {}: {} sat\n     script:  {:x}\n     address: {}",
+            vout,
+            txout.value.to_sat(),
+            txout.script_pubkey,
+            display_address(&txout.script_pubkey)
+        )
+        .unwrap();
+    }
+
+    writeln!(report, "\nHints:").unwrap();
+    if !tx
+        .output
+        .iter()
+        .any(|txout| script_type(&txout.script_pubkey) == script_type(&spk))
+    {
+        writeln!(
+            report,
+            "  - The descriptor produces a {} script but no output has that type, \
+             was the funding sent to a different descriptor type?",
+            script_type(&spk)
+        )
+        .unwrap();
+    }
+    if has_xpubs(descriptor) {
+        writeln!(
+            report,
+            "  - The descriptor contains extended keys, check that it was derived at \
+             the same index as the address that was funded."
+        )
+        .unwrap();
+    }
+    if let Some(kind) = xpub_network_mismatch(descriptor) {
+        let kind = match kind {
+            NetworkKind::Main => "mainnet",
+            NetworkKind::Test => "a test network",
+        };
+        writeln!(
+            report,
+            "  - The descriptor's extended keys are for {}, but we operate on {}.",
+            kind,
+            network()
+        )
+        .unwrap();
+    }
+    writeln!(
+        report,
+        "  - The descriptor's checksum is #{}; if it was typed without one, compare \
+         it against the checksum shown by the wallet that created it to rule out typos.",
+        checksum(descriptor)
+    )
+    .unwrap();
+
+    report
+}
+
+fn display_address(spk: &Script) -> String {
+    Address::from_script(spk, network())
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| "<none>".to_string())
+}
+
+/// Name the standard type of an output script.
+pub fn script_type(spk: &Script) -> &'static str {
+    if spk.is_p2wsh() {
+        "P2WSH"
+    } else if spk.is_p2wpkh() {
+        "P2WPKH"
+    } else if spk.is_p2tr() {
+        "P2TR"
+    } else if spk.is_p2sh() {
+        "P2SH"
+    } else if spk.is_p2pkh() {
+        "P2PKH"
+    } else {
+        "non-standard"
+    }
+}
+
+fn descriptor_xpubs(descriptor: &Descriptor<DefiniteDescriptorKey>) -> Vec<DescriptorPublicKey> {
+    let mut keys = Vec::new();
+    descriptor.for_each_key(|key| {
+        if let Ok(key @ DescriptorPublicKey::XPub(_)) =
+            DescriptorPublicKey::from_str(&key.to_string())
+        {
+            keys.push(key);
+        }
+        true
+    });
+    keys
+}
+
+fn has_xpubs(descriptor: &Descriptor<DefiniteDescriptorKey>) -> bool {
+    !descriptor_xpubs(descriptor).is_empty()
+}
+
+// Extended keys only distinguish mainnet from the test networks.
+fn xpub_network_mismatch(descriptor: &Descriptor<DefiniteDescriptorKey>) -> Option<NetworkKind> {
+    descriptor_xpubs(descriptor)
+        .into_iter()
+        .find_map(|key| match key {
+            DescriptorPublicKey::XPub(xpub) if xpub.xkey.network != network().into() => {
+                Some(xpub.xkey.network)
+            }
+            _ => None,
+        })
+}
+
+fn checksum(descriptor: &Descriptor<DefiniteDescriptorKey>) -> String {
+    let descriptor = descriptor.to_string();
+    descriptor
+        .split_once('#')
+        .map(|(_, checksum)| checksum.to_string())
+        .unwrap_or_default()
+}
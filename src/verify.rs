@@ -0,0 +1,425 @@
+use std::ops::RangeInclusive;
+
+use clap::Args;
+use miniscript::bitcoin::psbt::{Input, Output};
+use miniscript::bitcoin::{absolute, relative, secp256k1, Address, Psbt, TxOut};
+use miniscript::descriptor::{DefiniteDescriptorKey, DescriptorPublicKey};
+use miniscript::psbt::{PsbtInputExt, PsbtOutputExt};
+use miniscript::{Descriptor, ToPublicKey};
+
+use crate::change::{self, Ownership};
+use crate::error::Error;
+use crate::estimate::{self, Hashlock, SpendPath};
+use crate::{build, parse, sign, stage};
+
+#[derive(Args)]
+pub struct VerifyAddressArgs {
+    /// The descriptor the address should be derived from.
+    #[arg(value_parser = parse::ranged_descriptor)]
+    descriptor: Descriptor<DescriptorPublicKey>,
+    /// The address to verify.
+    #[arg(value_parser = parse::address)]
+    address: Address,
+    /// The derivation indexes to search, for descriptors with wildcards.
+    #[arg(long, value_parser = parse::range, default_value = "0-999")]
+    range: RangeInclusive<u32>,
+}
+
+/// A descriptor derived at an index, if it has wildcards.
+pub type Derived = (Descriptor<DefiniteDescriptorKey>, Option<u32>);
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    psbt: stage::PsbtArg,
+    /// The descriptor the inputs should be spending. May be repeated for
+    /// inputs spending different descriptors.
+    #[arg(long = "descriptor", value_parser = parse::ranged_descriptor, required = true)]
+    descriptors: Vec<Descriptor<DescriptorPublicKey>>,
+    /// The derivation indexes to search, for descriptors with wildcards.
+    #[arg(long, value_parser = parse::range, default_value = "0-999")]
+    range: RangeInclusive<u32>,
+    /// A descriptor change may go back to besides the inputs' ones, so
+    /// outputs claiming to be change are checked against it. May be
+    /// repeated.
+    #[arg(long = "change-descriptor", value_parser = parse::ranged_descriptor)]
+    change_descriptors: Vec<Descriptor<DescriptorPublicKey>>,
+}
+
+/// Search the descriptor's derivation range for the address and report the
+/// index it was derived at, failing if it isn't found.
+pub fn run(args: VerifyAddressArgs) -> Result<(), String> {
+    let range = if args.descriptor.has_wildcard() {
+        args.range
+    } else {
+        0..=0
+    };
+    let spk = args.address.script_pubkey();
+    for index in range.clone() {
+        let derived = args
+            .descriptor
+            .at_derivation_index(index)
+            .map_err(|e| format!("Can't derive index {}: {}", index, e))?
+            .script_pubkey();
+        if derived == spk {
+            if args.descriptor.has_wildcard() {
+                println!(
+                    "{} is derived from the descriptor at index {}",
+                    args.address, index
+                );
+            } else {
+                println!("{} is the descriptor's address", args.address);
+            }
+            return Ok(());
+        }
+    }
+    if args.descriptor.has_wildcard() {
+        Err(format!(
+            "{} is not derived from the descriptor at indexes {}-{}",
+            args.address,
+            range.start(),
+            range.end()
+        ))
+    } else {
+        Err(format!("{} is not the descriptor's address", args.address))
+    }
+}
+
+/// Check a PSBT received from the signers without finalizing it: every
+/// signature against the sighash it commits to, every input's scripts
+/// against the descriptor it spends, and which spend paths each input can
+/// take with what it has, naming what's still missing for the others.
+/// Outputs claiming to be change, by their scripts or key derivations, have
+/// to be derived from one of the descriptors with the scripts it gives.
+pub fn check(args: VerifyArgs) -> Result<(), Error> {
+    let psbt = args.psbt.load()?;
+    let bad_signatures = sign::verify_signatures(&secp256k1::Secp256k1::verification_only(), &psbt);
+    let mut mismatched = Vec::new();
+    let mut unsatisfied = Vec::new();
+
+    for (i, (txin, input)) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs).enumerate() {
+        let prevout = txin.previous_output;
+        let Some(utxo) = build::spent_utxo(&psbt, i) else {
+            println!("Input {} ({}) has no UTXO", i, prevout);
+            mismatched.push(i);
+            continue;
+        };
+        let Some((descriptor, index)) = find(&args.descriptors, &args.range, &utxo)? else {
+            println!("Input {} ({}) pays none of the descriptors", i, prevout);
+            mismatched.push(i);
+            continue;
+        };
+        match index {
+            Some(index) => println!(
+                "Input {} ({}) spends {} at index {}",
+                i, prevout, descriptor, index
+            ),
+            None => println!("Input {} ({}) spends {}", i, prevout, descriptor),
+        }
+
+        let differences = differences(input, &descriptor)?;
+        if differences.is_empty() {
+            println!("  the scripts match the descriptor");
+        } else {
+            for difference in &differences {
+                println!("  {}", difference);
+            }
+            mismatched.push(i);
+        }
+
+        let mut satisfiable = false;
+        for path in estimate::spend_paths(&descriptor)? {
+            let missing = missing(&psbt, i, &path);
+            if missing.is_empty() {
+                println!("  satisfiable: {}", path.describe().join(", "));
+                satisfiable = true;
+            } else {
+                println!(
+                    "  missing {} for: {}",
+                    missing.join(", "),
+                    path.describe().join(", ")
+                );
+            }
+        }
+        if !satisfiable {
+            unsatisfied.push(i);
+        }
+    }
+
+    let owners: Vec<_> = args
+        .descriptors
+        .iter()
+        .chain(&args.change_descriptors)
+        .cloned()
+        .collect();
+    let mut unverified = Vec::new();
+    let ownership = change::classify(&psbt, &owners);
+    for (i, (txout, ownership)) in psbt.unsigned_tx.output.iter().zip(ownership).enumerate() {
+        let (n, index) = match ownership {
+            Ownership::External => {
+                println!("Output {} ({} sat) is a payment", i, txout.value.to_sat());
+                continue;
+            }
+            Ownership::Unverified => {
+                println!(
+                    "Output {} ({} sat) claims to be change but pays none of the descriptors",
+                    i,
+                    txout.value.to_sat()
+                );
+                unverified.push(i);
+                continue;
+            }
+            Ownership::Change(n, index) => (n, index),
+        };
+        let descriptor = owners[n]
+            .at_derivation_index(index.unwrap_or(0))
+            .map_err(|e| Error::Descriptor(format!("Can't derive the change: {}", e)))?;
+        match index {
+            Some(index) => println!(
+                "Output {} ({} sat) is change to {} at index {}",
+                i,
+                txout.value.to_sat(),
+                descriptor,
+                index
+            ),
+            None => println!(
+                "Output {} ({} sat) is change to {}",
+                i,
+                txout.value.to_sat(),
+                descriptor
+            ),
+        }
+        let differences = output_differences(&psbt.outputs[i], &descriptor)?;
+        if differences.is_empty() {
+            println!("  the scripts match the descriptor");
+        } else {
+            for difference in &differences {
+                println!("  {}", difference);
+            }
+            unverified.push(i);
+        }
+    }
+
+    let list = |inputs: &[usize]| {
+        inputs
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if let Err(e) = bad_signatures {
+        return Err(Error::Signature(e));
+    }
+    if !mismatched.is_empty() {
+        return Err(Error::Descriptor(format!(
+            "These inputs don't match the descriptors: {}",
+            list(&mismatched)
+        )));
+    }
+    if !unverified.is_empty() {
+        return Err(Error::Descriptor(format!(
+            "These outputs claim to be change but don't match the descriptors: {}",
+            list(&unverified)
+        )));
+    }
+    if !unsatisfied.is_empty() {
+        return Err(Error::Unsatisfied(format!(
+            "These inputs can't be finalized yet: {}",
+            list(&unsatisfied)
+        )));
+    }
+    println!("Every signature is valid and every input can be finalized");
+    Ok(())
+}
+
+/// The descriptor paying `utxo`, derived at the index it's paid at, if any
+/// of `descriptors` does within `range`.
+pub fn find(
+    descriptors: &[Descriptor<DescriptorPublicKey>],
+    range: &RangeInclusive<u32>,
+    utxo: &TxOut,
+) -> Result<Option<Derived>, Error> {
+    for descriptor in descriptors {
+        let singles = descriptor
+            .clone()
+            .into_single_descriptors()
+            .map_err(|e| Error::Descriptor(format!("Can't split the descriptor: {}", e)))?;
+        let range = if descriptor.has_wildcard() {
+            range.clone()
+        } else {
+            0..=0
+        };
+        for index in range {
+            for single in &singles {
+                let derived = single.at_derivation_index(index).map_err(|e| {
+                    Error::Descriptor(format!("Can't derive index {}: {}", index, e))
+                })?;
+                if derived.script_pubkey() == utxo.script_pubkey {
+                    return Ok(Some((
+                        derived,
+                        Some(index).filter(|_| descriptor.has_wildcard()),
+                    )));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// How the scripts of `input` differ from what `descriptor` puts there.
+fn differences(
+    input: &Input,
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> Result<Vec<String>, String> {
+    let mut expected = Input::default();
+    expected
+        .update_with_descriptor_unchecked(descriptor)
+        .map_err(|e| format!("Can't update an input with the descriptor: {}", e))?;
+    let mut differences = Vec::new();
+    if build::needs_prev_tx(descriptor) && input.non_witness_utxo.is_none() {
+        differences.push("the non-witness UTXO is missing".to_string());
+    }
+    let mut compare = |name: &str, has: bool, expected: bool, equal: bool| match (has, expected) {
+        (false, true) => differences.push(format!("the {} is missing", name)),
+        (true, false) => differences.push(format!("the descriptor has no {}", name)),
+        (true, true) if !equal => {
+            differences.push(format!("the {} doesn't match the descriptor's", name))
+        }
+        _ => {}
+    };
+    compare(
+        "witness script",
+        input.witness_script.is_some(),
+        expected.witness_script.is_some(),
+        input.witness_script == expected.witness_script,
+    );
+    compare(
+        "redeem script",
+        input.redeem_script.is_some(),
+        expected.redeem_script.is_some(),
+        input.redeem_script == expected.redeem_script,
+    );
+    compare(
+        "taproot internal key",
+        input.tap_internal_key.is_some(),
+        expected.tap_internal_key.is_some(),
+        input.tap_internal_key == expected.tap_internal_key,
+    );
+    compare(
+        "taproot merkle root",
+        input.tap_merkle_root.is_some(),
+        expected.tap_merkle_root.is_some(),
+        input.tap_merkle_root == expected.tap_merkle_root,
+    );
+    // Only the leaves the PSBT has need to be the descriptor's, since
+    // spending one is enough.
+    if input
+        .tap_scripts
+        .iter()
+        .any(|(control, leaf)| expected.tap_scripts.get(control) != Some(leaf))
+    {
+        differences.push("a taproot leaf script isn't the descriptor's".to_string());
+    }
+    Ok(differences)
+}
+
+// Which of the scripts and key derivations the change `output` has aren't
+// what `descriptor` puts there.
+fn output_differences(
+    output: &Output,
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+) -> Result<Vec<String>, String> {
+    let mut expected = Output::default();
+    expected
+        .update_with_descriptor_unchecked(descriptor)
+        .map_err(|e| format!("Can't update an output with the descriptor: {}", e))?;
+    let mut differences = Vec::new();
+    let mut compare = |name: &str, equal: bool| {
+        if !equal {
+            differences.push(format!("the {} isn't the descriptor's", name))
+        }
+    };
+    compare(
+        "witness script",
+        output.witness_script.is_none() || output.witness_script == expected.witness_script,
+    );
+    compare(
+        "redeem script",
+        output.redeem_script.is_none() || output.redeem_script == expected.redeem_script,
+    );
+    compare(
+        "taproot internal key",
+        output.tap_internal_key.is_none() || output.tap_internal_key == expected.tap_internal_key,
+    );
+    compare(
+        "taproot tree",
+        output.tap_tree.is_none() || output.tap_tree == expected.tap_tree,
+    );
+    // Leaving a field out only keeps the signers from recognizing it as
+    // change, but one the descriptor doesn't give is a claim it can't back.
+    compare(
+        "key derivation",
+        output
+            .bip32_derivation
+            .iter()
+            .all(|(key, origin)| expected.bip32_derivation.get(key) == Some(origin)),
+    );
+    compare(
+        "taproot key derivation",
+        output
+            .tap_key_origins
+            .iter()
+            .all(|(key, origin)| expected.tap_key_origins.get(key) == Some(origin)),
+    );
+    Ok(differences)
+}
+
+// What input `i` of `psbt` lacks to be spent down `path`: signatures,
+// preimages, and the lock time or sequence.
+fn missing(psbt: &Psbt, i: usize, path: &SpendPath) -> Vec<String> {
+    let input = &psbt.inputs[i];
+    let txin = &psbt.unsigned_tx.input[i];
+    let mut missing = Vec::new();
+    for key in &path.keys {
+        let Some(key) = DefiniteDescriptorKey::new(key.clone()) else {
+            continue;
+        };
+        let pk = key.to_public_key();
+        let xonly = pk.inner.x_only_public_key().0;
+        let signed = input.partial_sigs.contains_key(&pk)
+            || input
+                .tap_script_sigs
+                .keys()
+                .any(|(signer, _)| *signer == xonly)
+            || (input.tap_key_sig.is_some() && input.tap_internal_key == Some(xonly));
+        if !signed {
+            missing.push(format!("a signature by {}", key));
+        }
+    }
+    for hash in &path.hashes {
+        let revealed = match hash {
+            Hashlock::Sha256(h) => input.sha256_preimages.contains_key(h),
+            Hashlock::Hash256(h) => input.hash256_preimages.contains_key(&h.to_raw_hash()),
+            Hashlock::Ripemd160(h) => input.ripemd160_preimages.contains_key(h),
+            Hashlock::Hash160(h) => input.hash160_preimages.contains_key(h),
+        };
+        if !revealed {
+            missing.push(format!("the preimage of {}", hash));
+        }
+    }
+    if let Some(after) = path.after {
+        if !absolute::LockTime::from(after).is_implied_by(psbt.unsigned_tx.lock_time)
+            || !txin.sequence.enables_absolute_lock_time()
+        {
+            missing.push(format!("a lock time of {}", after));
+        }
+    }
+    if let Some(older) = path.older {
+        if !relative::LockTime::from(older).is_implied_by_sequence(txin.sequence)
+            || psbt.unsigned_tx.version.0 < 2
+        {
+            missing.push(format!("a sequence of {}", older));
+        }
+    }
+    missing
+}
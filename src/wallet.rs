@@ -0,0 +1,313 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use miniscript::bitcoin::OutPoint;
+
+use serde::{Deserialize, Serialize};
+
+/// A wallet profile as saved by the setup wizard.
+#[derive(Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub network: String,
+    pub descriptor: String,
+    pub signers: Vec<Signer>,
+}
+
+/// One of the parties holding a key of the wallet.
+#[derive(Serialize, Deserialize)]
+pub struct Signer {
+    pub name: String,
+    pub key: String,
+}
+
+/// What a wallet learned about its coins from scanning the chain.
+#[derive(Serialize, Deserialize, Default)]
+pub struct State {
+    /// The last block scanned.
+    pub height: u32,
+    pub utxos: Vec<Utxo>,
+    pub history: Vec<Entry>,
+    /// BIP47 payment codes the wallet pays.
+    #[serde(default)]
+    pub contacts: Vec<Contact>,
+}
+
+/// An unspent output paying the wallet.
+#[derive(Serialize, Deserialize)]
+pub struct Utxo {
+    pub outpoint: String,
+    pub value: u64,
+    /// The derivation index of the script it pays.
+    pub index: u32,
+    pub height: u32,
+}
+
+/// A transaction moving the wallet's coins.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub txid: String,
+    /// The block confirming it, 0 while it's unconfirmed.
+    pub height: u32,
+    pub received: u64,
+    pub sent: u64,
+    /// When it was confirmed, or created while it's unconfirmed, in seconds
+    /// since the UNIX epoch.
+    #[serde(default)]
+    pub time: u64,
+    /// The fee, known if every input spends a coin of the wallet.
+    #[serde(default)]
+    pub fee: Option<u64>,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Whether we created it rather than finding it on the chain.
+    #[serde(default)]
+    pub created: bool,
+    /// Whether we broadcast it.
+    #[serde(default)]
+    pub broadcast: bool,
+}
+
+/// A BIP47 payment code the wallet pays.
+#[derive(Serialize, Deserialize)]
+pub struct Contact {
+    pub payment_code: String,
+    /// The transaction notifying the payment code of ours.
+    pub notification: Option<String>,
+    /// The index of the next payment to the payment code.
+    pub next_index: u32,
+}
+
+/// A coin reserved by a command spending it, so commands running at the
+/// same time don't spend it too.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Lock {
+    pub outpoint: String,
+    /// When the lock lapses, in seconds since the UNIX epoch.
+    pub expires: u64,
+}
+
+/// How long to wait for another process to finish updating the locks.
+const LOCK_WAIT: Duration = Duration::from_secs(10);
+/// How old a guard file has to be before it's taken to be left behind by a
+/// process that died while holding it.
+const STALE_GUARD: Duration = Duration::from_secs(30);
+
+/// Directory holding wallet profiles and state, `$MINISCRIPT_PSBT_DIR` or
+/// `~/.miniscript-psbt` by default.
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("MINISCRIPT_PSBT_DIR") {
+        return PathBuf::from(dir);
+    }
+    let home = env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .unwrap_or_default();
+    PathBuf::from(home).join(".miniscript-psbt")
+}
+
+impl Profile {
+    pub fn path(name: &str) -> PathBuf {
+        data_dir().join("wallets").join(format!("{}.json", name))
+    }
+
+    /// Load the profile of the wallet called `name`.
+    pub fn load(name: &str) -> Result<Profile, String> {
+        let path = Self::path(name);
+        let json = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read wallet {} at {}: {}", name, path.display(), e))?;
+        serde_json::from_str(&json)
+            .map_err(|e| format!("Wallet {} at {} is corrupt: {}", name, path.display(), e))
+    }
+
+    /// Save the profile, refusing to overwrite an existing wallet.
+    pub fn save(&self) -> Result<PathBuf, String> {
+        let path = Self::path(&self.name);
+        if path.exists() {
+            return Err(format!(
+                "Wallet {} already exists at {}",
+                self.name,
+                path.display()
+            ));
+        }
+        fs::create_dir_all(path.parent().unwrap())
+            .map_err(|e| format!("Can't create {}: {}", path.display(), e))?;
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&path, json).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+}
+
+impl State {
+    pub fn path(name: &str) -> PathBuf {
+        data_dir()
+            .join("wallets")
+            .join(format!("{}.state.json", name))
+    }
+
+    /// Load the state of the wallet called `name`, empty if it has none yet.
+    pub fn load(name: &str) -> Result<State, String> {
+        let path = Self::path(name);
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let json = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        serde_json::from_str(&json).map_err(|e| format!("{} is corrupt: {}", path.display(), e))
+    }
+
+    /// The contact for `payment_code`, added if the wallet hasn't paid it
+    /// before.
+    pub fn contact(&mut self, payment_code: &str) -> &mut Contact {
+        let i = match self
+            .contacts
+            .iter()
+            .position(|c| c.payment_code == payment_code)
+        {
+            Some(i) => i,
+            None => {
+                self.contacts.push(Contact {
+                    payment_code: payment_code.to_string(),
+                    notification: None,
+                    next_index: 0,
+                });
+                self.contacts.len() - 1
+            }
+        };
+        &mut self.contacts[i]
+    }
+
+    /// Add `entry` to the history, replacing an earlier entry for the same
+    /// transaction but keeping its label if `entry` has none.
+    pub fn record(&mut self, mut entry: Entry) {
+        match self.history.iter_mut().find(|e| e.txid == entry.txid) {
+            Some(existing) => {
+                entry.label = entry.label.or(existing.label.take());
+                *existing = entry;
+            }
+            None => self.history.push(entry),
+        }
+    }
+
+    /// Save the state of the wallet called `name`, replacing the old one.
+    pub fn save(&self, name: &str) -> Result<PathBuf, String> {
+        let path = Self::path(name);
+        fs::create_dir_all(path.parent().unwrap())
+            .map_err(|e| format!("Can't create {}: {}", path.display(), e))?;
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&path, json).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+}
+
+/// Lock `outpoints` of the wallet called `name` for `seconds`, failing
+/// without locking any of them if another command holds one already.
+pub fn lock_coins(name: &str, outpoints: &[OutPoint], seconds: u64) -> Result<(), String> {
+    update_locks(name, |locks| {
+        let taken: Vec<String> = outpoints
+            .iter()
+            .map(|outpoint| outpoint.to_string())
+            .filter(|outpoint| locks.iter().any(|lock| lock.outpoint == *outpoint))
+            .collect();
+        if !taken.is_empty() {
+            return Err(format!(
+                "Coins locked by another command:\n  {}",
+                taken.join("\n  ")
+            ));
+        }
+        let expires = now() + seconds;
+        locks.extend(outpoints.iter().map(|outpoint| Lock {
+            outpoint: outpoint.to_string(),
+            expires,
+        }));
+        Ok(())
+    })
+}
+
+/// Release the locks on `outpoints` of the wallet called `name`, or every
+/// lock if `outpoints` is empty.
+pub fn unlock_coins(name: &str, outpoints: &[OutPoint]) -> Result<(), String> {
+    update_locks(name, |locks| {
+        locks.retain(|lock| {
+            !outpoints.is_empty()
+                && !outpoints
+                    .iter()
+                    .any(|outpoint| outpoint.to_string() == lock.outpoint)
+        });
+        Ok(())
+    })
+}
+
+/// The coins of the wallet called `name` that are currently locked.
+pub fn locked_coins(name: &str) -> Result<Vec<Lock>, String> {
+    update_locks(name, |locks| Ok(locks.clone()))
+}
+
+// Run `f` over the unexpired locks of the wallet called `name` and save what
+// it leaves, holding a guard file so no other process does the same
+// meanwhile.
+fn update_locks<T>(
+    name: &str,
+    f: impl FnOnce(&mut Vec<Lock>) -> Result<T, String>,
+) -> Result<T, String> {
+    let dir = data_dir().join("wallets");
+    fs::create_dir_all(&dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    let path = dir.join(format!("{}.locks.json", name));
+    let guard = dir.join(format!("{}.locks.guard", name));
+    acquire(&guard)?;
+    let result = (|| {
+        let mut locks: Vec<Lock> = if path.exists() {
+            let json = fs::read_to_string(&path)
+                .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+            serde_json::from_str(&json)
+                .map_err(|e| format!("{} is corrupt: {}", path.display(), e))?
+        } else {
+            Vec::new()
+        };
+        let now = now();
+        locks.retain(|lock| lock.expires > now);
+        let result = f(&mut locks)?;
+        let json = serde_json::to_string_pretty(&locks).unwrap();
+        fs::write(&path, json).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+        Ok(result)
+    })();
+    let _ = fs::remove_file(&guard);
+    result
+}
+
+// Create the guard file `path`, waiting for whoever holds it to remove it.
+fn acquire(path: &Path) -> Result<(), String> {
+    let start = SystemTime::now();
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(format!("Can't create {}: {}", path.display(), e)),
+        }
+        let age = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if age.is_some_and(|age| age > STALE_GUARD) {
+            let _ = fs::remove_file(path);
+            continue;
+        }
+        if start.elapsed().unwrap_or_default() > LOCK_WAIT {
+            return Err(format!(
+                "Timed out waiting for another command to release {}",
+                path.display()
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// The current time in seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the clock is after 1970")
+        .as_secs()
+}
@@ -0,0 +1,224 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+
+use clap::Args;
+use miniscript::bitcoin::base64::prelude::{Engine, BASE64_STANDARD};
+use miniscript::bitcoin::hashes::{sha256, sha256d, Hash};
+use miniscript::bitcoin::sighash::SighashCache;
+use miniscript::bitcoin::{ecdsa, secp256k1, EcdsaSighashType, Psbt, PublicKey};
+
+use crate::{secret, sign};
+
+/// How to reach a YubiHSM2 holding a secp256k1 signing key, through
+/// Yubico's yubihsm-shell.
+#[derive(Args)]
+pub struct YubiHsmArgs {
+    /// Sign with the asymmetric key of this object ID on a YubiHSM2.
+    #[arg(long = "yubihsm-key")]
+    key_id: Option<u16>,
+    /// The yubihsm-connector to reach the HSM through.
+    #[arg(long, default_value = "http://127.0.0.1:12345")]
+    yubihsm_connector: String,
+    /// The object ID of the authentication key opening the session.
+    #[arg(long, default_value_t = 1)]
+    yubihsm_authkey: u16,
+    /// The authentication key's password, as `file:<PATH>`, `env:<VAR>` or
+    /// `-` to be prompted for it. It's handed to yubihsm-shell on its
+    /// stdin, and without it yubihsm-shell prompts for it itself.
+    #[arg(long, requires = "key_id", value_parser = secret::parser(secret::string))]
+    yubihsm_password: Option<String>,
+    /// Append the HSM's audit log to this file after signing, so every
+    /// signature it made is on record alongside the PSBT.
+    #[arg(long, requires = "key_id")]
+    yubihsm_audit_log: Option<PathBuf>,
+    /// The yubihsm-shell executable.
+    #[arg(long, default_value = "yubihsm-shell")]
+    yubihsm_shell: PathBuf,
+}
+
+/// A session with the key on the HSM.
+pub struct Hsm<'a> {
+    args: &'a YubiHsmArgs,
+    key_id: u16,
+    public_key: PublicKey,
+}
+
+impl YubiHsmArgs {
+    /// Look up the key on the HSM, if one was asked for.
+    pub fn open(&self) -> Result<Option<Hsm<'_>>, String> {
+        let Some(key_id) = self.key_id else {
+            return Ok(None);
+        };
+        let pem = shell(self, key_id, "get-public-key", &[], None)?;
+        let der = BASE64_STANDARD
+            .decode(
+                pem.lines()
+                    .filter(|line| !line.starts_with("-----"))
+                    .collect::<String>(),
+            )
+            .map_err(|e| format!("The HSM returned an invalid public key: {}", e))?;
+        // The uncompressed point ends the SubjectPublicKeyInfo.
+        let point = der
+            .get(der.len().saturating_sub(65)..)
+            .filter(|point| point.len() == 65)
+            .ok_or("The HSM returned an invalid public key")?;
+        let key = secp256k1::PublicKey::from_slice(point)
+            .map_err(|e| format!("Key {} on the HSM isn't a secp256k1 key: {}", key_id, e))?;
+        Ok(Some(Hsm {
+            args: self,
+            key_id,
+            public_key: PublicKey::new(key),
+        }))
+    }
+}
+
+impl Hsm<'_> {
+    /// The public key of the HSM's key.
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Sign every segwit v0 input of `psbt` whose derivations name the HSM's
+    /// key with its sighash type from `hash_tys`, returning how many were.
+    ///
+    /// The sighash is the double SHA256 of the input's BIP143 preimage and
+    /// the HSM hashes what it's given once with ecdsa-sha256, so it's handed
+    /// the preimage's single SHA256. Each signature is checked against the
+    /// sighash before it's added.
+    pub fn sign_psbt<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+        psbt: &mut Psbt,
+        hash_tys: &[EcdsaSighashType],
+    ) -> Result<usize, String> {
+        let tx = psbt.unsigned_tx.clone();
+        let mut cache = SighashCache::new(&tx);
+        let mut signed = 0;
+        for (i, &hash_ty) in hash_tys.iter().enumerate() {
+            if !psbt.inputs[i]
+                .bip32_derivation
+                .contains_key(&self.public_key.inner)
+            {
+                continue;
+            }
+            psbt.inputs[i].sighash_type = Some(hash_ty.into());
+            let preimage = sign::segwit_v0_preimage(psbt, i, &mut cache).ok_or_else(|| {
+                format!(
+                    "Input {} isn't a segwit v0 input the HSM can sign, it only makes ECDSA \
+                     signatures over BIP143 sighashes",
+                    i
+                )
+            })?;
+            let hashed = sha256::Hash::hash(&preimage);
+            let der = BASE64_STANDARD
+                .decode(
+                    shell(
+                        self.args,
+                        self.key_id,
+                        "sign-ecdsa",
+                        &["--algorithm=ecdsa-sha256", "--informat=binary"],
+                        Some(hashed.as_byte_array()),
+                    )?
+                    .trim(),
+                )
+                .map_err(|e| format!("The HSM returned an invalid signature: {}", e))?;
+            let mut signature = secp256k1::ecdsa::Signature::from_der(&der)
+                .map_err(|e| format!("The HSM returned an invalid signature: {}", e))?;
+            // The HSM doesn't produce low-S signatures, which are the only
+            // ones relayed.
+            signature.normalize_s();
+            let msg =
+                secp256k1::Message::from_digest(sha256d::Hash::hash(&preimage).to_byte_array());
+            secp.verify_ecdsa(&msg, &signature, &self.public_key.inner)
+                .map_err(|e| format!("The HSM's signature on input {} is invalid: {}", i, e))?;
+            psbt.inputs[i].partial_sigs.insert(
+                self.public_key,
+                ecdsa::Signature {
+                    signature,
+                    sighash_type: hash_ty,
+                },
+            );
+            signed += 1;
+        }
+        if let Some(path) = &self.args.yubihsm_audit_log {
+            let log = shell(self.args, self.key_id, "get-logs", &[], None)?;
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(log.as_bytes()))
+                .map_err(|e| format!("Can't append to {}: {}", path.display(), e))?;
+        }
+        Ok(signed)
+    }
+}
+
+// Run yubihsm-shell's `action` on key `key_id`, feeding it `input`, and
+// return what it prints.
+//
+// The password goes in on stdin rather than the command line, where any
+// local user could read it, so `input` is passed in a file only we can
+// read.
+fn shell(
+    args: &YubiHsmArgs,
+    key_id: u16,
+    action: &str,
+    extra: &[&str],
+    input: Option<&[u8]>,
+) -> Result<String, String> {
+    let input_file = match input {
+        Some(input) => {
+            let path = env::temp_dir().join(format!("miniscript-psbt-yubihsm-{}", process::id()));
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .and_then(|mut file| file.write_all(input))
+                .map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+            Some(path)
+        }
+        None => None,
+    };
+    let mut command = Command::new(&args.yubihsm_shell);
+    command
+        .arg(format!("--connector={}", args.yubihsm_connector))
+        .arg(format!("--authkey={}", args.yubihsm_authkey))
+        .arg(format!("--action={}", action))
+        .arg(format!("--object-id={}", key_id))
+        .args(extra)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(path) = &input_file {
+        command.arg(format!("--in={}", path.display()));
+    }
+    if args.yubihsm_password.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let output = command
+        .spawn()
+        .and_then(|mut child| {
+            if let (Some(password), Some(mut stdin)) = (&args.yubihsm_password, child.stdin.take())
+            {
+                stdin.write_all(format!("{}\n", password).as_bytes())?;
+            }
+            child.wait_with_output()
+        })
+        .map_err(|e| format!("Can't run {}: {}", args.yubihsm_shell.display(), e));
+    if let Some(path) = &input_file {
+        let _ = fs::remove_file(path);
+    }
+    let output = output?;
+    if !output.status.success() {
+        return Err(format!(
+            "yubihsm-shell {} failed: {}",
+            action,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
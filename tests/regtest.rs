@@ -0,0 +1,54 @@
+//! End-to-end spends against a regtest node started with the `bitcoind`
+//! crate. They need a bitcoind, so they're ignored by default: set
+//! `BITCOIND_EXE` or put one on the `PATH` and run
+//! `cargo test --test regtest -- --ignored`.
+
+use std::path::Path;
+
+use miniscript_psbt::selftest::{Pipeline, Scenario};
+
+fn spend(scenario: Scenario) {
+    let exe = bitcoind::exe_path().expect("bitcoind found, set BITCOIND_EXE");
+    let node = bitcoind::BitcoinD::new(exe).expect("bitcoind starts");
+    let rpc_url = node.rpc_url();
+    let pipeline = Pipeline::new(
+        Path::new(env!("CARGO_BIN_EXE_miniscript-psbt")),
+        &rpc_url,
+        &node.params.cookie_file,
+        node.workdir().join("cli"),
+    )
+    .expect("connects to bitcoind");
+    if let Err(e) = pipeline.run(scenario) {
+        panic!("{:?}: {}", scenario, e);
+    }
+}
+
+#[test]
+#[ignore = "needs bitcoind"]
+fn wpkh() {
+    spend(Scenario::Wpkh);
+}
+
+#[test]
+#[ignore = "needs bitcoind"]
+fn wsh_multi() {
+    spend(Scenario::WshMulti);
+}
+
+#[test]
+#[ignore = "needs bitcoind"]
+fn timelock() {
+    spend(Scenario::Timelock);
+}
+
+#[test]
+#[ignore = "needs bitcoind"]
+fn taproot_key_path() {
+    spend(Scenario::TaprootKeyPath);
+}
+
+#[test]
+#[ignore = "needs bitcoind"]
+fn taproot_script_path() {
+    spend(Scenario::TaprootScriptPath);
+}